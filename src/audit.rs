@@ -0,0 +1,188 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The configuration of the audit subsystem, with auditconfig(1M): the audit
+ * policy, the default audit flags for users and for non-attributable events,
+ * and the plugins (e.g., audit_binfile and audit_syslog) through which audit
+ * records are written.  The configured values are compared and set, so
+ * changes persist; they take effect once auditd has been refreshed.
+ */
+
+use std::collections::BTreeMap;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::ensure::{self, Exec};
+
+pub const AUDITD: &str = "svc:/system/auditd:default";
+const AUDITCONFIG: &str = "/usr/sbin/auditconfig";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditPlugin {
+    /**
+     * e.g., "audit_binfile" or "audit_syslog".
+     */
+    pub name: String,
+    pub active: bool,
+    /**
+     * Plugin attributes to set; e.g., "p_dir" = "/var/audit" for
+     * audit_binfile, or "p_flags" = "lo,ex" for audit_syslog.  Attributes
+     * not listed are left alone.
+     */
+    pub attributes: BTreeMap<String, String>,
+}
+
+/**
+ * The desired audit configuration.  Any settings which are None are left as
+ * they are, as are any plugins which are not listed.
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AuditConfig {
+    /**
+     * The audit policy, as for "auditconfig -setpolicy"; e.g., "cnt,argv".
+     */
+    pub policy: Option<String>,
+    /**
+     * The default audit flags for users; e.g., "lo,ex".
+     */
+    pub flags: Option<String>,
+    /**
+     * The audit flags for non-attributable events; e.g., "lo".
+     */
+    pub naflags: Option<String>,
+    pub plugins: Vec<AuditPlugin>,
+}
+
+/*
+ * Policies and flags are lists, compared without regard to order.
+ */
+fn list(s: &str) -> Vec<String> {
+    let mut l: Vec<String> = s.split(',')
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect();
+    l.sort();
+    l.dedup();
+    l
+}
+
+/*
+ * Find the configured value in the output of a "-get" option; e.g.,
+ *
+ *      configured user default audit flags = lo,ex(0x1000,0x1000)
+ *
+ * The masks in parentheses after flags are dropped.
+ */
+fn configured(log: &Logger, opt: &str) -> Result<String> {
+    let out = ensure::query_output(log, &[AUDITCONFIG, opt],
+        &Exec::default())?;
+
+    for l in out.lines() {
+        if let Some(v) = l.strip_prefix("configured ")
+            .and_then(|l| l.split_once(" = "))
+            .map(|(_, v)| v.trim())
+        {
+            return Ok(v.split('(').next().unwrap_or(v).to_string());
+        }
+    }
+    bail!("auditconfig {}: no configured value in {:?}", opt, out);
+}
+
+fn ensure_list(log: &Logger, what: &str, get: &str, set: &str, want: &str)
+    -> Result<bool>
+{
+    let have = configured(log, get)?;
+    if list(&have) == list(want) {
+        info!(log, "audit {} ok: {}", what, have);
+        return Ok(false);
+    }
+
+    info!(log, "audit {}: {} -> {}", what, have, want);
+    ensure::run(log, &[AUDITCONFIG, set, want])?;
+    Ok(true)
+}
+
+/*
+ * The state and attributes of a plugin, from "auditconfig -getplugin NAME":
+ *
+ *      Plugin: audit_binfile (active)
+ *              Attributes: p_dir=/var/audit;p_fsize=0;p_minfree=1;
+ */
+fn plugin(log: &Logger, name: &str)
+    -> Result<(bool, BTreeMap<String, String>)>
+{
+    let out = ensure::query_output(log, &[AUDITCONFIG, "-getplugin", name],
+        &Exec::default())?;
+
+    let mut active = None;
+    let mut attrs = BTreeMap::new();
+    for l in out.lines() {
+        let l = l.trim();
+        if let Some(p) = l.strip_prefix("Plugin: ") {
+            active = Some(p.ends_with("(active)"));
+        } else if let Some(a) = l.strip_prefix("Attributes: ") {
+            for kv in a.split(';') {
+                if let Some((k, v)) = kv.split_once('=') {
+                    attrs.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+    }
+
+    match active {
+        Some(active) => Ok((active, attrs)),
+        None => bail!("auditconfig -getplugin {}: unexpected output {:?}",
+            name, out),
+    }
+}
+
+fn ensure_plugin(log: &Logger, p: &AuditPlugin) -> Result<bool> {
+    let (active, attrs) = plugin(log, &p.name)?;
+
+    let differ: Vec<String> = p.attributes.iter()
+        .filter(|(k, v)| attrs.get(*k) != Some(*v))
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    if active == p.active && differ.is_empty() {
+        info!(log, "audit plugin {} ok", p.name);
+        return Ok(false);
+    }
+
+    info!(log, "audit plugin {}: active {} -> {}, attributes {:?}", p.name,
+        active, p.active, differ);
+    let state = if p.active { "active" } else { "inactive" };
+    let mut args = vec![AUDITCONFIG.to_string(), "-setplugin".to_string(),
+        p.name.clone(), state.to_string()];
+    if !differ.is_empty() {
+        args.push(differ.join(";"));
+    }
+    ensure::run(log, &args)?;
+    Ok(true)
+}
+
+/**
+ * Ensure the configured audit policy, flags, and plugins.  This does not
+ * enable or refresh auditd.
+ */
+pub fn configure(log: &Logger, ac: &AuditConfig) -> Result<bool> {
+    let mut changed = false;
+
+    if let Some(p) = &ac.policy {
+        changed |= ensure_list(log, "policy", "-getpolicy", "-setpolicy", p)?;
+    }
+    if let Some(f) = &ac.flags {
+        changed |= ensure_list(log, "flags", "-getflags", "-setflags", f)?;
+    }
+    if let Some(f) = &ac.naflags {
+        changed |= ensure_list(log, "non-attributable flags", "-getnaflags",
+            "-setnaflags", f)?;
+    }
+    for p in ac.plugins.iter() {
+        changed |= ensure_plugin(log, p)?;
+    }
+
+    Ok(changed)
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The audit trail is a record of every change confomat makes to a system,
+ * kept in "/var/confomat/audit.log" for change management.  Each change is a
+ * single JSON object on its own line, with the role and step which made it,
+ * when, and (where known) the state of the resource before and after; e.g.,
+ *
+ *      {"time":1601596800.5,"host":"web0","role":"nginx",
+ *          "step":"ensure_file","resource":"/etc/nginx/nginx.conf",
+ *          "action":"modify","what":"/etc/nginx/nginx.conf",
+ *          "before":"sha256:5e88...","after":"sha256:9f86..."}
+ *
+ * The file is only ever appended to, by every run, and changes which are not
+ * made (as in a dry run) are not recorded.  A failure to write to the trail
+ * is reported, but does not fail the run.
+ */
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use anyhow::{Result, bail};
+
+use super::common::STATE_DIR;
+use super::plan::{Action, Change};
+
+const TRAIL: &str = "audit.log";
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: f64,
+    host: &'a str,
+    role: &'a str,
+    step: &'a str,
+    resource: &'a str,
+    action: Action,
+    what: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after: Option<&'a str>,
+}
+
+pub struct AuditTrail {
+    host: String,
+    path: PathBuf,
+    /*
+     * The file is opened the first time there is a change to record, so that
+     * runs which change nothing do not need to create it.
+     */
+    file: Mutex<Option<File>>,
+}
+
+impl AuditTrail {
+    pub fn new(host: &str) -> AuditTrail {
+        AuditTrail::at(host, Path::new(STATE_DIR).join(TRAIL))
+    }
+
+    /**
+     * An audit trail kept in another file.
+     */
+    pub fn at(host: &str, path: PathBuf) -> AuditTrail {
+        AuditTrail {
+            host: host.to_string(),
+            path,
+            file: Mutex::new(None),
+        }
+    }
+
+    /**
+     * Record the changes made by a step.
+     */
+    pub fn record(&self, role: &str, step: &str, resource: &str,
+        changes: &[Change])
+        -> Result<()>
+    {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs_f64();
+
+        let mut out = String::new();
+        for c in changes.iter() {
+            out.push_str(&serde_json::to_string(&Entry {
+                time,
+                host: &self.host,
+                role,
+                step,
+                resource,
+                action: c.action,
+                what: &c.what,
+                before: c.before.as_deref(),
+                after: c.after.as_deref(),
+            })?);
+            out.push('\n');
+        }
+
+        let mut f = self.file.lock().unwrap();
+        if f.is_none() {
+            *f = match std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(&self.path)
+            {
+                Ok(f) => Some(f),
+                Err(e) => bail!("opening audit trail {}: {}",
+                    self.path.display(), e),
+            };
+        }
+
+        /*
+         * Write the entries for the step with a single call, so that the
+         * entries of steps in concurrent roles are not interleaved.
+         */
+        let f = f.as_mut().unwrap();
+        f.write_all(out.as_bytes())?;
+        f.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The boot archive on illumos holds the files needed to boot the kernel;
+ * e.g., /etc/system, the driver configuration, and the kernel modules
+ * themselves.  It must be updated with "bootadm update-archive" whenever one
+ * of those files changes, or the system boots with the old files (or, if the
+ * archive is found to be out of date, stops in the boot-archive service).
+ * Rather than each role doing so, we look at the changes made in the run,
+ * and update the archive once at the end of the run if any of them was to a
+ * file in the archive, or was a driver added or updated with add_drv(1M) or
+ * update_drv(1M).  A role may also ask for the update with
+ * Context::update_boot_archive().
+ */
+
+use std::path::{Path, PathBuf};
+
+use slog::{Logger, info};
+use anyhow::Result;
+
+use super::common::alt_root;
+use super::ensure::{self, Exec};
+use super::plan::{Action, Change};
+
+const BOOTADM: &str = "/sbin/bootadm";
+
+const DRIVER_COMMANDS: &[&str] = &["add_drv", "update_drv", "rem_drv"];
+
+/*
+ * The arguments for a bootadm(1M) subcommand, with the alternate root, if
+ * there is one.
+ */
+fn bootadm(cmd: &str) -> Vec<String> {
+    let mut args = vec![BOOTADM.to_string(), cmd.to_string()];
+    if let Some(root) = alt_root() {
+        args.push("-R".to_string());
+        args.push(root.display().to_string());
+    }
+    args
+}
+
+/*
+ * The files and directories in the boot archive, relative to the root; e.g.,
+ * "etc/system" or "kernel".
+ */
+fn archive(log: &Logger) -> Result<Vec<PathBuf>> {
+    let out = ensure::query_output(log, &bootadm("list-archive"),
+        &Exec::default())?;
+    Ok(out.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/*
+ * Does this change need the boot archive to be updated?  For a file, this can
+ * only be said once we know what is in the archive.
+ */
+fn file_changed(c: &Change) -> Option<PathBuf> {
+    if c.action == Action::Exec {
+        return None;
+    }
+    let p = Path::new(&c.what);
+    let rel = match alt_root() {
+        Some(root) => p.strip_prefix(root).ok()?,
+        None => p.strip_prefix("/").ok()?,
+    };
+    Some(rel.to_path_buf())
+}
+
+fn driver_changed(c: &Change) -> bool {
+    c.action == Action::Exec && c.what.split_whitespace().next()
+        .and_then(|cmd| Path::new(cmd).file_name())
+        .map(|n| DRIVER_COMMANDS.iter().any(|d| n == *d))
+        .unwrap_or(false)
+}
+
+/**
+ * Update the boot archive if any of the changes made in the run (or
+ * "requested", if set) need it.  Returns whether the archive was updated.
+ */
+pub fn update(log: &Logger, changes: &[Change], requested: bool)
+    -> Result<bool>
+{
+    let reason = if requested {
+        Some("requested by a role".to_string())
+    } else if let Some(c) = changes.iter().find(|c| driver_changed(c)) {
+        Some(format!("driver change: {}", c.what))
+    } else {
+        let files: Vec<PathBuf> = changes.iter()
+            .filter_map(file_changed)
+            .collect();
+        if files.is_empty() {
+            return Ok(false);
+        }
+
+        let archive = archive(log)?;
+        files.iter()
+            .find(|f| archive.iter().any(|a| f.starts_with(a)))
+            .map(|f| format!("/{} changed", f.display()))
+    };
+
+    match reason {
+        Some(reason) => {
+            info!(log, "updating boot archive: {}", reason);
+            ensure::run(log, &bootadm("update-archive"))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
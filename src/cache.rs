@@ -0,0 +1,189 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The no-change cache records, for each file whose contents confomat has
+ * ensured or used as a source, the SHA-256 hash of those contents along with
+ * the identity and timestamps of the file at that time.  If a later stat(2)
+ * shows the same inode, size, modification time, and change time, the file
+ * cannot have been altered since, and its contents need not be read again.
+ * The change time cannot be set by an unprivileged process, nor reset by
+ * touch(1), so a file altered behind our back will always be read.
+ *
+ * The cache is kept in "/var/confomat/cache.json", loaded when confomat
+ * starts and saved at the end of the run.  If it is missing or cannot be
+ * read, every file is simply read in full, as it would be without a cache.
+ */
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use slog::{Logger, info, warn};
+use anyhow::Result;
+
+use super::common::STATE_DIR;
+use super::ensure::{self, HashType};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    hash: String,
+}
+
+impl Entry {
+    fn matches(&self, md: &std::fs::Metadata) -> bool {
+        self.dev == md.dev() &&
+            self.ino == md.ino() &&
+            self.size == md.size() &&
+            self.mtime == (md.mtime(), md.mtime_nsec()) &&
+            self.ctime == (md.ctime(), md.ctime_nsec())
+    }
+}
+
+/*
+ * If the cache is not in use, this is None.
+ */
+static CACHE: Mutex<Option<BTreeMap<PathBuf, Entry>>> = Mutex::new(None);
+
+pub fn path() -> PathBuf {
+    Path::new(STATE_DIR).join("cache.json")
+}
+
+/**
+ * Load the cache from disk, and begin using it.
+ */
+pub fn load(log: &Logger) {
+    let p = path();
+
+    let entries = match std::fs::read_to_string(&p) {
+        Ok(s) => match serde_json::from_str(&s) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(log, "ignoring invalid cache {}: {}", p.display(), e);
+                BTreeMap::new()
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            warn!(log, "ignoring unreadable cache {}: {}", p.display(), e);
+            BTreeMap::new()
+        }
+    };
+
+    info!(log, "loaded {} cache entries from {}", entries.len(), p.display());
+    *CACHE.lock().unwrap() = Some(entries);
+}
+
+/**
+ * Write the cache back to disk, dropping entries for files which no longer
+ * match.  A failure to save the cache is reported, but is not an error.
+ */
+pub fn save(log: &Logger) {
+    let mut c = CACHE.lock().unwrap();
+    let entries = match c.as_mut() {
+        Some(entries) => entries,
+        None => return,
+    };
+
+    entries.retain(|p, e| match std::fs::symlink_metadata(p) {
+        Ok(md) => e.matches(&md),
+        Err(_) => false,
+    });
+
+    let p = path();
+    let tmp = p.with_extension("json.tmp");
+    let res = serde_json::to_string(&*entries)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| Ok(std::fs::write(&tmp, s)?))
+        .and_then(|_| Ok(std::fs::rename(&tmp, &p)?));
+    if let Err(e) = res {
+        warn!(log, "could not save cache {}: {}", p.display(), e);
+    }
+}
+
+pub fn enabled() -> bool {
+    CACHE.lock().unwrap().is_some()
+}
+
+pub fn hash(data: &[u8]) -> String {
+    let mut out = String::new();
+    for byt in sha2::Sha256::digest(data).iter() {
+        out.push_str(&format!("{:02x}", byt));
+    }
+    out
+}
+
+/**
+ * Does the file at this path still have the contents it had when we last
+ * recorded it, and do those contents have this hash?  Returns false if the
+ * cache is not in use, or knows nothing about the file.
+ */
+pub fn unchanged(p: &Path, hash: &str) -> bool {
+    let c = CACHE.lock().unwrap();
+    let e = match c.as_ref().and_then(|entries| entries.get(p)) {
+        Some(e) => e,
+        None => return false,
+    };
+
+    match std::fs::symlink_metadata(p) {
+        Ok(md) => e.hash == hash && e.matches(&md),
+        Err(_) => false,
+    }
+}
+
+/**
+ * Record that the file at this path has contents with this hash.  The caller
+ * must know this to be true; e.g., because it has just written the file, or
+ * compared its contents.
+ */
+pub fn record(p: &Path, hash: &str) {
+    let mut c = CACHE.lock().unwrap();
+    let entries = match c.as_mut() {
+        Some(entries) => entries,
+        None => return,
+    };
+
+    match std::fs::symlink_metadata(p) {
+        Ok(md) if md.is_file() => {
+            entries.insert(p.to_path_buf(), Entry {
+                dev: md.dev(),
+                ino: md.ino(),
+                size: md.size(),
+                mtime: (md.mtime(), md.mtime_nsec()),
+                ctime: (md.ctime(), md.ctime_nsec()),
+                hash: hash.to_string(),
+            });
+        }
+        _ => {
+            entries.remove(p);
+        }
+    }
+}
+
+/**
+ * Determine the SHA-256 hash of the contents of a file, reading the file only
+ * if it has changed since we last did so.
+ */
+pub fn hash_file(p: &Path) -> Result<String> {
+    if let Ok(md) = std::fs::symlink_metadata(p) {
+        let c = CACHE.lock().unwrap();
+        if let Some(e) = c.as_ref().and_then(|entries| entries.get(p)) {
+            if e.matches(&md) {
+                return Ok(e.hash.clone());
+            }
+        }
+    }
+
+    let hash = ensure::hash_file(p, &HashType::SHA256)?;
+    record(p, &hash);
+    Ok(hash)
+}
@@ -0,0 +1,294 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * TLS certificates from an ACME certificate authority, such as Let's Encrypt.
+ * Certificates are obtained with the lego(1) ACME client, which keeps the
+ * account and each certificate beneath "/var/confomat/acme".  A certificate
+ * is obtained when there is none yet, or when the names it should cover have
+ * changed, and is renewed when it expires within the renewal window; nothing
+ * is asked of the CA while it is current.  The current certificate is then
+ * installed, with the given ownership, where the services that use it
+ * expect to find it.  To restart those services when a certificate is
+ * renewed, notify a handler from the step.
+ */
+
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::{STATE_DIR, dry_run_skip};
+use super::ensure::{self, Exec, Ownership};
+use super::plan::{Action, Change};
+
+pub const LETS_ENCRYPT: &str =
+    "https://acme-v02.api.letsencrypt.org/directory";
+
+/*
+ * lego(1) is packaged in different places on different systems.
+ */
+const LEGO_PATHS: &[&str] = &[
+    "/usr/bin/lego",
+    "/usr/local/bin/lego",
+    "/opt/ooce/bin/lego",
+    "/opt/local/bin/lego",
+];
+
+const DEFAULT_RENEW_DAYS: u32 = 30;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Challenge {
+    /**
+     * HTTP-01: the CA fetches a token from the host, beneath
+     * "/.well-known/acme-challenge/".  With a webroot, the token is written
+     * beneath that directory for a running web server to serve; otherwise,
+     * lego listens on port 80 itself.
+     */
+    Http {
+        webroot: Option<String>,
+    },
+    /**
+     * DNS-01: the CA looks up a TXT record, which lego creates with the API
+     * of the named DNS provider (e.g., "route53" or "cloudflare").  The
+     * provider's credentials are passed in the environment, and are treated
+     * as secrets; e.g., ("CLOUDFLARE_DNS_API_TOKEN", "...").
+     */
+    Dns {
+        provider: String,
+        credentials: Vec<(String, String)>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Certificate {
+    /**
+     * The name for which the certificate is issued; e.g., "www.example.com"
+     * or "*.example.com" (which requires a DNS challenge).
+     */
+    pub hostname: String,
+    /**
+     * Any other names which the certificate must also cover.
+     */
+    pub alt_names: Vec<String>,
+    /**
+     * The contact address for the ACME account.
+     */
+    pub email: String,
+    pub challenge: Challenge,
+    /**
+     * The ACME directory URL of the CA.  The default is Let's Encrypt.
+     */
+    pub server: Option<String>,
+    /**
+     * Where to install the certificate (with the chain of intermediate
+     * certificates) and the private key.
+     */
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub owner: String,
+    pub group: String,
+    /**
+     * Obtain a new certificate once the current one expires within this many
+     * days.  The default is 30.
+     */
+    pub renew_days: Option<u32>,
+}
+
+impl Certificate {
+    fn names(&self) -> Vec<String> {
+        let mut names = vec![self.hostname.clone()];
+        for n in self.alt_names.iter() {
+            if !names.contains(n) {
+                names.push(n.clone());
+            }
+        }
+        names
+    }
+}
+
+fn acme_dir() -> PathBuf {
+    Path::new(STATE_DIR).join("acme")
+}
+
+fn lego() -> Result<&'static str> {
+    match LEGO_PATHS.iter().find(|p| Path::new(p).exists()) {
+        Some(p) => Ok(p),
+        None => bail!("the lego ACME client is not installed (looked for \
+            {})", LEGO_PATHS.join(", ")),
+    }
+}
+
+/*
+ * lego names the files for a certificate after its first name, with any
+ * wildcard replaced.
+ */
+fn issued(hostname: &str, ext: &str) -> PathBuf {
+    acme_dir().join("certificates")
+        .join(format!("{}.{}", hostname.replace('*', "_"), ext))
+}
+
+/*
+ * The DNS names in the subject alternative name extension of a certificate,
+ * from the text form printed by openssl(1):
+ *
+ *      X509v3 Subject Alternative Name:
+ *          DNS:example.com, DNS:www.example.com
+ */
+fn cert_names(log: &Logger, path: &Path) -> Result<Vec<String>> {
+    let p = path.to_str().unwrap();
+    let out = ensure::query_output(log, &["openssl", "x509", "-noout",
+        "-text", "-in", p], &Exec::default())?;
+
+    let mut lines = out.lines();
+    let mut names = Vec::new();
+    while let Some(l) = lines.next() {
+        if l.trim() == "X509v3 Subject Alternative Name:" {
+            if let Some(sans) = lines.next() {
+                names.extend(sans.split(',')
+                    .filter_map(|s| s.trim().strip_prefix("DNS:"))
+                    .map(|s| s.to_string()));
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/*
+ * What must be asked of the CA, and why: a new certificate ("run"), or the
+ * renewal of the one we have ("renew").
+ */
+enum Need {
+    Obtain(String),
+    Renew(String),
+}
+
+fn need(log: &Logger, cert: &Certificate) -> Result<Option<Need>> {
+    let path = issued(&cert.hostname, "crt");
+    if !path.exists() {
+        return Ok(Some(Need::Obtain("no certificate has been obtained"
+            .to_string())));
+    }
+
+    let mut want = cert.names();
+    want.sort();
+    let have = cert_names(log, &path)?;
+    if have != want {
+        return Ok(Some(Need::Obtain(format!("names {:?} -> {:?}", have,
+            want))));
+    }
+
+    /*
+     * "openssl x509 -checkend" fails if the certificate will have expired
+     * that many seconds from now.
+     */
+    let days = cert.renew_days.unwrap_or(DEFAULT_RENEW_DAYS);
+    let secs = (u64::from(days) * 86400).to_string();
+    let st = ensure::query_status(log, &["openssl", "x509", "-noout",
+        "-checkend", &secs, "-in", path.to_str().unwrap()])?;
+    if !st.success() {
+        return Ok(Some(Need::Renew(format!("expires within {} days",
+            days))));
+    }
+
+    Ok(None)
+}
+
+fn obtain(log: &Logger, cert: &Certificate, renew: bool) -> Result<()> {
+    /*
+     * The directory holds the private keys for the account and for every
+     * certificate.
+     */
+    let dir = acme_dir();
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+
+    let mut args = vec![lego()?.to_string(), "--accept-tos".to_string(),
+        "--email".to_string(), cert.email.clone(),
+        "--server".to_string(),
+        cert.server.as_deref().unwrap_or(LETS_ENCRYPT).to_string(),
+        "--path".to_string(), dir.to_str().unwrap().to_string()];
+    for n in cert.names() {
+        args.push("--domains".to_string());
+        args.push(n);
+    }
+
+    let mut opts = Exec::default();
+    match &cert.challenge {
+        Challenge::Http { webroot } => {
+            args.push("--http".to_string());
+            if let Some(w) = webroot {
+                args.push("--http.webroot".to_string());
+                args.push(w.clone());
+            }
+        }
+        Challenge::Dns { provider, credentials } => {
+            args.push("--dns".to_string());
+            args.push(provider.clone());
+            opts.secrets = credentials.clone();
+        }
+    }
+    if renew {
+        /*
+         * lego checks the expiry again itself, with the same window.
+         */
+        args.push("renew".to_string());
+        args.push("--days".to_string());
+        args.push(cert.renew_days.unwrap_or(DEFAULT_RENEW_DAYS).to_string());
+    } else {
+        args.push("run".to_string());
+    }
+
+    ensure::run_with(log, &args, &opts)
+}
+
+/**
+ * Ensure that a current certificate for these names has been obtained and is
+ * installed.
+ */
+pub fn ensure(log: &Logger, cert: &Certificate) -> Result<bool> {
+    if cert.hostname.is_empty() || cert.email.is_empty() {
+        bail!("a certificate requires a hostname and a contact email");
+    }
+    if cert.names().iter().any(|n| n.starts_with("*.")) &&
+        matches!(cert.challenge, Challenge::Http { .. })
+    {
+        bail!("wildcard names require a DNS challenge");
+    }
+
+    let mut did_work = false;
+
+    if let Some(need) = need(log, cert)? {
+        let (action, why, renew) = match need {
+            Need::Obtain(why) => (Action::Create, why, false),
+            Need::Renew(why) => (Action::Modify, why, true),
+        };
+        info!(log, "certificate for {}: {}", cert.hostname, why);
+        if dry_run_skip(log, Change::new(action,
+            format!("ACME certificate for {}", cert.hostname)).detail(why))
+        {
+            return Ok(true);
+        }
+        obtain(log, cert, renew)?;
+        did_work = true;
+    } else {
+        info!(log, "certificate for {} is current", cert.hostname);
+    }
+
+    let own = Ownership::Names(&cert.owner, &cert.group);
+    let crt = std::fs::read(issued(&cert.hostname, "crt"))?;
+    let key = std::fs::read(issued(&cert.hostname, "key"))?;
+    if ensure::contents(log, &cert.cert_path, &crt, &own, 0o644)? {
+        did_work = true;
+    }
+    if ensure::secret_contents(log, &cert.key_path, &key, &own,
+        0o600)?
+    {
+        did_work = true;
+    }
+
+    Ok(did_work)
+}
@@ -3,29 +3,415 @@
  */
 
 use atty::Stream;
-use slog::{Drain, Logger};
+use slog::{Drain, Level, Logger};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use anyhow::{Result, bail};
+
+use super::plan::{self, Change};
 
 pub use slog::{info, warn, error, debug, trace, o};
 
 /**
- * Initialise a logger which writes to stdout, and which does the right thing on
- * both an interactive terminal and when stdout is not a tty.
+ * Initialise a logger which writes to stdout (or to stderr, if "stderr" is
+ * set), and which does the right thing both on an interactive terminal and
+ * when the output is not a tty.  If output from concurrent activities will be
+ * interleaved, the full format is used even on a terminal, so that every line
+ * is prefixed with the role it belongs to.  Messages less severe than
+ * "level" are not logged.
  */
-pub fn init_log() -> Logger {
-    let dec = slog_term::TermDecorator::new().stdout().build();
-    if atty::is(Stream::Stdout) {
+pub fn init_log(interleaved: bool, stderr: bool, level: Level) -> Logger {
+    let (dec, stream) = if stderr {
+        (slog_term::TermDecorator::new().stderr().build(), Stream::Stderr)
+    } else {
+        (slog_term::TermDecorator::new().stdout().build(), Stream::Stdout)
+    };
+    if atty::is(stream) && !interleaved {
         let dr = Mutex::new(slog_term::CompactFormat::new(dec)
-            .build()).fuse();
+            .build()).filter_level(level).fuse();
         slog::Logger::root(dr, o!())
     } else {
         let dr = Mutex::new(slog_term::FullFormat::new(dec)
             .use_original_order()
-            .build()).fuse();
+            .build()).filter_level(level).fuse();
         slog::Logger::root(dr, o!())
     }
 }
 
+/**
+ * How much of what happens in a run is logged, as chosen with "-q" and "-v":
+ *
+ *  - Quiet: warnings and errors alone; the steps which changed something or
+ *    failed are listed as the run goes (see the display module).
+ *  - Normal: what is checked and done, and the commands that are run.
+ *  - Verbose: also the steps which are skipped, and the output of commands.
+ *  - Debug: also the diff for each change, and the time each step took.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+impl Verbosity {
+    pub fn level(self) -> Level {
+        match self {
+            Verbosity::Quiet => Level::Warning,
+            Verbosity::Normal => Level::Info,
+            Verbosity::Verbose => Level::Debug,
+            Verbosity::Debug => Level::Trace,
+        }
+    }
+}
+
+/*
+ * The directory in which confomat keeps its own state, such as the run lock.
+ */
+pub const STATE_DIR: &str = "/var/confomat";
+
+/**
+ * The error number from the last failed system call on this thread.
+ */
+pub fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/**
+ * In dry-run mode, the ensure primitives report the changes they would make
+ * without making them.  This is a property of the whole process, established
+ * once at startup.
+ */
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::SeqCst);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/**
+ * The error for something which confomat cannot do on this platform; e.g.,
+ * manage an SMF service on Linux.  A step which fails with this error either
+ * fails the run, as any other error would, or is skipped, as chosen with
+ * "--unsupported".
+ */
+#[derive(Debug)]
+pub struct Unsupported {
+    what: String,
+    platform: String,
+}
+
+impl Unsupported {
+    pub fn new(what: &str, platform: &str) -> Unsupported {
+        Unsupported {
+            what: what.to_string(),
+            platform: platform.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: unsupported on this platform ({})", self.what,
+            self.platform)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+static SKIP_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_skip_unsupported(skip: bool) {
+    SKIP_UNSUPPORTED.store(skip, Ordering::SeqCst);
+}
+
+pub fn skip_unsupported() -> bool {
+    SKIP_UNSUPPORTED.load(Ordering::SeqCst)
+}
+
+/**
+ * Record a change that is about to be made to the system, and check whether
+ * it should be skipped because we are in dry-run mode.  If so, the change is
+ * logged so that the operator can see what would have been done.
+ */
+pub fn dry_run_skip(log: &Logger, change: Change) -> bool {
+    let skip = dry_run();
+    if skip {
+        info!(log, "DRY RUN: would {}", change.summary());
+    }
+    if !change.details.is_empty() {
+        trace!(log, "{}:", change.summary());
+        for d in change.details.iter() {
+            trace!(log, "    {}", d);
+        }
+    }
+    plan::record(change);
+    skip
+}
+
+static ALT_ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/**
+ * With an alternate root (e.g., a mounted boot environment, the root of a
+ * zone, or an image being built), the file primitives and commands of each
+ * Context act on the tree beneath that directory, rather than on the running
+ * system.  Like dry-run mode, this is established once at startup.
+ */
+pub fn set_alt_root(root: Option<PathBuf>) {
+    *ALT_ROOT.lock().unwrap() = root;
+}
+
+pub fn alt_root() -> Option<PathBuf> {
+    ALT_ROOT.lock().unwrap().clone()
+}
+
+/**
+ * Where an absolute path on the system being configured is to be found from
+ * here: beneath the alternate root, if there is one, resolved as for
+ * resolve(), so that a symbolic link in the tree cannot lead us out of it.
+ * Relative paths, such as those of role files, are left alone.
+ */
+pub fn rooted<P: AsRef<Path>>(p: P) -> Result<PathBuf> {
+    let p = p.as_ref();
+    match alt_root() {
+        Some(root) if p.is_absolute() => resolve(&root, p),
+        _ => Ok(p.to_path_buf()),
+    }
+}
+
+/*
+ * The maximum number of symbolic links we will follow while resolving a path
+ * within a root.
+ */
+const MAX_SYMLINKS: u32 = 32;
+
+/**
+ * Resolve an absolute path, as seen from within a root (e.g., the alternate
+ * root, or the root of a zone), to a path here beneath that root.  Symbolic
+ * links in the intermediate components are followed as they would be by a
+ * process chrooted there: absolute targets are interpreted relative to the
+ * root, and ".." never ascends above it.  The final component is not
+ * followed, as the ensure primitives examine it with lstat(2) and will
+ * replace an unexpected symbolic link.
+ */
+pub fn resolve(root: &Path, path: &Path) -> Result<PathBuf> {
+    use std::io::ErrorKind;
+    use std::path::Component;
+
+    if !path.is_absolute() {
+        bail!("path {} must be absolute", path.display());
+    }
+
+    fn push_components(todo: &mut Vec<String>, p: &Path) -> Result<()> {
+        let mut comps = Vec::new();
+        for c in p.components() {
+            match c {
+                Component::RootDir | Component::CurDir => (),
+                Component::ParentDir => comps.push("..".to_string()),
+                Component::Normal(n) => match n.to_str() {
+                    Some(n) => comps.push(n.to_string()),
+                    None => bail!("path {} is not UTF-8", p.display()),
+                },
+                Component::Prefix(_) => bail!("unexpected path prefix"),
+            }
+        }
+        todo.extend(comps.into_iter().rev());
+        Ok(())
+    }
+
+    let mut todo = Vec::new();
+    push_components(&mut todo, path)?;
+
+    let mut cur: Vec<String> = Vec::new();
+    let mut hops = 0;
+    while let Some(c) = todo.pop() {
+        if c == ".." {
+            cur.pop();
+            continue;
+        }
+
+        if todo.is_empty() {
+            cur.push(c);
+            break;
+        }
+
+        let mut candidate = root.to_path_buf();
+        candidate.extend(cur.iter());
+        candidate.push(&c);
+
+        match std::fs::symlink_metadata(&candidate) {
+            Ok(md) if md.file_type().is_symlink() => {
+                hops += 1;
+                if hops > MAX_SYMLINKS {
+                    bail!("too many symbolic links resolving {} in {}",
+                        path.display(), root.display());
+                }
+
+                let target = std::fs::read_link(&candidate)?;
+                if target.is_absolute() {
+                    cur.clear();
+                }
+                push_components(&mut todo, &target)?;
+            }
+            Ok(md) if md.is_dir() => cur.push(c),
+            Ok(_) => bail!("{} is not a directory", candidate.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => cur.push(c),
+            Err(e) => bail!("resolving {}: {}", candidate.display(), e),
+        }
+    }
+
+    let mut out = root.to_path_buf();
+    out.extend(cur.iter());
+    Ok(out)
+}
+
+thread_local! {
+    /*
+     * The outermost step running on this thread, if any, with which to label
+     * the output of commands.
+     */
+    static STEP: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/**
+ * Note the step now running on this thread, returning the previous one.
+ */
+pub fn set_step_name(step: Option<String>) -> Option<String> {
+    STEP.with(|s| std::mem::replace(&mut *s.borrow_mut(), step))
+}
+
+pub fn step_name() -> Option<String> {
+    STEP.with(|s| s.borrow().clone())
+}
+
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    INTERRUPTED.store(sig, Ordering::SeqCst);
+}
+
+/**
+ * Rather than dying part way through a change on SIGINT or SIGTERM, note the
+ * signal so that the run can stop between steps.  The handler is reset once
+ * it fires, so that a second signal ends the process at once.
+ */
+pub fn catch_signals() {
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut sa: libc::sigaction = std::mem::zeroed();
+            sa.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as
+                libc::sighandler_t;
+            sa.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+            libc::sigemptyset(&mut sa.sa_mask);
+            libc::sigaction(sig, &sa, std::ptr::null_mut());
+        }
+    }
+}
+
+/**
+ * If the run has been interrupted by a signal, return the signal.
+ */
+pub fn interrupt_signal() -> Option<libc::c_int> {
+    match INTERRUPTED.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/**
+ * If the run has been interrupted by a signal, return its name.
+ */
+pub fn interrupted() -> Option<&'static str> {
+    match INTERRUPTED.load(Ordering::SeqCst) {
+        0 => None,
+        libc::SIGINT => Some("SIGINT"),
+        libc::SIGTERM => Some("SIGTERM"),
+        _ => Some("a signal"),
+    }
+}
+
+/**
+ * Quote an argument for the shell.
+ */
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/**
+ * A file of our own in a new temporary directory, which is removed, along with
+ * the directory, when this is dropped.
+ */
+pub struct TempFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/**
+ * Write "data" to a new file called "name", readable only by us, for a
+ * command to read; e.g., a candidate configuration to be checked before it
+ * is installed.  The file is in a directory of its own under the system
+ * temporary directory, which only we can enter, so nobody else can read the
+ * file, replace it, or have us write through a link they planted.
+ */
+pub fn temp_file(name: &str, data: &[u8]) -> Result<TempFile> {
+    use std::io::{ErrorKind, Write};
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+    use std::sync::atomic::AtomicUsize;
+
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+    if name.is_empty() || name.contains('/') {
+        bail!("invalid temporary file name {:?}", name);
+    }
+
+    let mut tries = 0;
+    let dir = loop {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let dir = std::env::temp_dir().join(format!("confomat.{}.{}.{}",
+            std::process::id(), SEQ.fetch_add(1, Ordering::SeqCst), nanos));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => break dir,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && tries < 100 => {
+                tries += 1;
+            }
+            Err(e) => bail!("creating {}: {}", dir.display(), e),
+        }
+    };
+
+    let tf = TempFile { path: dir.join(name), dir };
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tf.path)?;
+    f.write_all(data)?;
+    f.flush()?;
+    Ok(tf)
+}
+
+#[cfg_attr(not(any(feature = "smf", feature = "zones")), allow(dead_code))]
 pub fn sleep(s: u64) {
     std::thread::sleep(std::time::Duration::from_secs(s));
 }
@@ -66,3 +452,48 @@ impl OutputExt for std::process::Output {
         out
     }
 }
+
+/*
+ * Split a line of parseable ("-p") output from ipadm(1M) or dladm(1M).  Fields
+ * are separated by colons, and any colons within a field (e.g., in an IPv6
+ * address) are escaped with a backslash.
+ */
+pub fn split_parseable(line: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut esc = false;
+
+    for c in line.chars() {
+        if esc {
+            cur.push(c);
+            esc = false;
+        } else if c == '\\' {
+            esc = true;
+        } else if c == ':' {
+            out.push(cur);
+            cur = String::new();
+        } else {
+            cur.push(c);
+        }
+    }
+    out.push(cur);
+
+    out
+}
+
+/*
+ * Run a command that emits parseable output, returning the fields of each
+ * line.
+ */
+pub fn parseable(args: &[&str]) -> Result<Vec<Vec<String>>> {
+    let out = std::process::Command::new(args[0])
+        .env_clear()
+        .args(&args[1..])
+        .output()?;
+    if !out.status.success() {
+        bail!("{} failed: {}", args.join(" "), out.info());
+    }
+    let val = String::from_utf8(out.stdout)?;
+
+    Ok(val.lines().map(split_parseable).collect())
+}
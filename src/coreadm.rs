@@ -0,0 +1,147 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The core file settings managed with coreadm(1M): where core files are
+ * written, what they contain, and which kinds of core dump are enabled.
+ * coreadm(1M) applies each change to the running system and records it in
+ * /etc/coreadm.conf, so that it persists across reboots.
+ */
+
+use std::collections::BTreeMap;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::ensure::{self, Exec};
+
+const COREADM: &str = "/usr/bin/coreadm";
+
+/**
+ * The desired core file settings.  Any which are None are left as they are.
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CoreConfig {
+    /**
+     * The pattern for global core files; e.g., "/var/cores/core.%f.%p".
+     */
+    pub global_pattern: Option<String>,
+    /**
+     * The content of global core files, as coreadm(1M) shows it; e.g.,
+     * "default" or "default+ctf".
+     */
+    pub global_content: Option<String>,
+    /**
+     * The default pattern for per-process core files, which processes
+     * inherit from init; e.g., "core".
+     */
+    pub process_pattern: Option<String>,
+    pub process_content: Option<String>,
+    /**
+     * Whether global core files are written.
+     */
+    pub global: Option<bool>,
+    /**
+     * Whether per-process core files are written.
+     */
+    pub process: Option<bool>,
+    /**
+     * Whether global or per-process core files are written for set-id
+     * processes.
+     */
+    pub global_setid: Option<bool>,
+    pub process_setid: Option<bool>,
+    /**
+     * Whether a message is logged when a global core file is written.
+     */
+    pub logging: Option<bool>,
+}
+
+/*
+ * The current settings, as shown by coreadm(1M) with no arguments; e.g.,
+ *
+ *          global core file pattern: /var/cores/core.%f.%p
+ *          global core file content: default
+ *            init core file pattern: core
+ *            init core file content: default
+ *                 global core dumps: enabled
+ *            per-process core dumps: enabled
+ *           global setid core dumps: disabled
+ *      per-process setid core dumps: disabled
+ *          global core dump logging: disabled
+ *
+ * An unset pattern is empty.
+ */
+fn current(log: &Logger) -> Result<BTreeMap<String, String>> {
+    let out = ensure::query_output(log, &[COREADM], &Exec::default())?;
+
+    let mut settings = BTreeMap::new();
+    for l in out.lines() {
+        if let Some((k, v)) = l.split_once(':') {
+            settings.insert(k.trim().to_string(), v.trim().to_string());
+        }
+    }
+    Ok(settings)
+}
+
+/**
+ * Ensure the core file settings, with a single coreadm(1M) invocation for
+ * any that differ.
+ */
+pub fn ensure(log: &Logger, cc: &CoreConfig) -> Result<bool> {
+    let cur = current(log)?;
+    let get = |k: &str| -> Result<&str> {
+        match cur.get(k) {
+            Some(v) => Ok(v.as_str()),
+            None => bail!("coreadm did not report \"{}\"", k),
+        }
+    };
+
+    let mut args = vec![COREADM.to_string()];
+
+    for (flag, key, want) in [
+        ("-g", "global core file pattern", &cc.global_pattern),
+        ("-G", "global core file content", &cc.global_content),
+        ("-i", "init core file pattern", &cc.process_pattern),
+        ("-I", "init core file content", &cc.process_content),
+    ].iter() {
+        if let Some(want) = want {
+            let have = get(key)?;
+            if have != want {
+                info!(log, "{}: \"{}\" -> \"{}\"", key, have, want);
+                args.push(flag.to_string());
+                args.push(want.to_string());
+            }
+        }
+    }
+
+    for (opt, key, want) in [
+        ("global", "global core dumps", cc.global),
+        ("process", "per-process core dumps", cc.process),
+        ("global-setid", "global setid core dumps", cc.global_setid),
+        ("proc-setid", "per-process setid core dumps", cc.process_setid),
+        ("log", "global core dump logging", cc.logging),
+    ].iter() {
+        if let Some(want) = want {
+            let have = match get(key)? {
+                "enabled" => true,
+                "disabled" => false,
+                other => bail!("coreadm: unexpected {} \"{}\"", key, other),
+            };
+            if have != *want {
+                info!(log, "{}: {} -> {}", key, have, want);
+                args.push(if *want { "-e" } else { "-d" }.to_string());
+                args.push(opt.to_string());
+            }
+        }
+    }
+
+    if args.len() == 1 {
+        info!(log, "core file settings ok");
+        return Ok(false);
+    }
+
+    ensure::run(log, &args)?;
+    Ok(true)
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use serde::Deserialize;
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, Ownership};
+
+/**
+ * A subnet from which the DHCP server will offer leases.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DhcpSubnet {
+    pub network: String,
+    pub netmask: String,
+    /**
+     * The first and last address of the dynamic range, if any.  Without a
+     * range, only fixed host entries are served.
+     */
+    pub range: Option<(String, String)>,
+    #[serde(default)]
+    pub routers: Vec<String>,
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    pub domain: Option<String>,
+}
+
+/**
+ * A fixed address assignment for a particular MAC address.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DhcpHost {
+    pub name: String,
+    pub mac: String,
+    pub address: String,
+}
+
+/**
+ * The configuration of the ISC DHCP server.  This may be deserialised from
+ * role configuration with Context::config().
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DhcpConfig {
+    #[serde(default)]
+    pub authoritative: bool,
+    pub default_lease_time: Option<u32>,
+    pub max_lease_time: Option<u32>,
+    #[serde(default)]
+    pub subnets: Vec<DhcpSubnet>,
+    #[serde(default)]
+    pub hosts: Vec<DhcpHost>,
+}
+
+/**
+ * The locations of the pieces of the DHCP server, which differ depending on
+ * whether it has been installed from IPS or from pkgsrc.
+ */
+pub struct DhcpServer {
+    pub fmri: &'static str,
+    pub config: &'static str,
+    pub dhcpd: &'static str,
+}
+
+pub const DHCPD_IPS: DhcpServer = DhcpServer {
+    fmri: "svc:/network/service/dhcp:ipv4",
+    config: "/etc/dhcpd.conf",
+    dhcpd: "/usr/lib/dhcpd",
+};
+
+pub const DHCPD_PKGSRC: DhcpServer = DhcpServer {
+    fmri: "svc:/pkgsrc/isc-dhcpd:default",
+    config: "/opt/local/etc/dhcp/dhcpd.conf",
+    dhcpd: "/opt/local/sbin/dhcpd",
+};
+
+fn list(vals: &[String]) -> String {
+    vals.join(", ")
+}
+
+fn render(cfg: &DhcpConfig) -> String {
+    let mut out = String::new();
+    out.push_str("#\n# This file is managed by confomat.\n#\n\n");
+
+    if cfg.authoritative {
+        out.push_str("authoritative;\n");
+    }
+    if let Some(t) = cfg.default_lease_time {
+        out.push_str(&format!("default-lease-time {};\n", t));
+    }
+    if let Some(t) = cfg.max_lease_time {
+        out.push_str(&format!("max-lease-time {};\n", t));
+    }
+
+    for s in cfg.subnets.iter() {
+        out.push_str(&format!("\nsubnet {} netmask {} {{\n", s.network,
+            s.netmask));
+        if let Some((first, last)) = &s.range {
+            out.push_str(&format!("\trange {} {};\n", first, last));
+        }
+        if !s.routers.is_empty() {
+            out.push_str(&format!("\toption routers {};\n",
+                list(&s.routers)));
+        }
+        if !s.nameservers.is_empty() {
+            out.push_str(&format!("\toption domain-name-servers {};\n",
+                list(&s.nameservers)));
+        }
+        if let Some(d) = &s.domain {
+            out.push_str(&format!("\toption domain-name \"{}\";\n", d));
+        }
+        out.push_str("}\n");
+    }
+
+    for h in cfg.hosts.iter() {
+        out.push_str(&format!("\nhost {} {{\n", h.name));
+        out.push_str(&format!("\thardware ethernet {};\n", h.mac));
+        out.push_str(&format!("\tfixed-address {};\n", h.address));
+        out.push_str("}\n");
+    }
+
+    out
+}
+
+/*
+ * Ask dhcpd(8) to check the syntax of a candidate configuration file.
+ */
+fn validate(log: &Logger, srv: &DhcpServer, conf: &str) -> Result<()> {
+    let tmp = temp_file("dhcpd.conf", conf.as_bytes())?;
+
+    let out = std::process::Command::new(srv.dhcpd)
+        .env_clear()
+        .arg("-t")
+        .arg("-cf").arg(tmp.path())
+        .output()?;
+
+    if !out.status.success() {
+        bail!("dhcpd rejected candidate configuration: {}", out.info());
+    }
+
+    info!(log, "dhcpd accepted candidate configuration");
+    Ok(())
+}
+
+/**
+ * Render, validate, and install the DHCP server configuration.  Returns true
+ * if the configuration changed and the service must be restarted.
+ */
+pub fn configure(log: &Logger, srv: &DhcpServer, cfg: &DhcpConfig)
+    -> Result<bool>
+{
+    let conf = render(cfg);
+    validate(log, srv, &conf)?;
+
+    ensure::contents(log, srv.config, conf.as_bytes(),
+        &Ownership::Names("root", "sys"), 0o644)
+}
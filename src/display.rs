@@ -0,0 +1,272 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * When confomat is run interactively (standard output is a terminal, and
+ * roles are applied one at a time), the progress of the run is shown in place
+ * of the detailed log, of which only warnings and errors appear.  Each step
+ * is shown as it finishes, with a marker for its result:
+ *
+ *      nginx
+ *        ok       ensure_package web/server/nginx
+ *        changed  ensure_file /etc/opt/ooce/nginx/nginx.conf
+ *        FAILED   ensure_online svc:/network/http:nginx
+ *      nginx: failed (3 steps: 1 changed, 1 failed) in 4.2s
+ *
+ * and a status line shows the step under way, the number of steps so far in
+ * the role, and the time the role has taken.  Markers are in colour unless
+ * NO_COLOR is set in the environment.  When the output is not a terminal, or
+ * with "--no-progress", the log is written as before.
+ *
+ * With "-q", whether on a terminal or not, only the steps which changed
+ * something or failed are listed, with each change or the error; e.g.,
+ *
+ *      CHANGED nginx ensure_file /etc/opt/ooce/nginx/nginx.conf
+ *          modify /etc/opt/ooce/nginx/nginx.conf
+ *      FAILED nginx ensure_online svc:/network/http:nginx
+ *          service svc:/network/http:nginx is in maintenance
+ *
+ * so that a run from cron(1M) which does nothing produces no output.
+ */
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::journal::Event;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+struct State {
+    role_start: Instant,
+    /*
+     * The counts of top-level steps in the current role by result.
+     */
+    counts: BTreeMap<String, usize>,
+    /*
+     * The steps under way, outermost first.  Only those which are not run by
+     * another step are shown.
+     */
+    steps: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Progress,
+    Quiet,
+}
+
+pub struct Display {
+    style: Style,
+    color: bool,
+    run_start: Instant,
+    state: Mutex<State>,
+}
+
+fn marker(result: &str) -> (&'static str, &'static str) {
+    match result {
+        "unchanged" => ("ok", GREEN),
+        "changed" => ("changed", YELLOW),
+        "failed" => ("FAILED", RED),
+        "retried" => ("retry", YELLOW),
+        "skipped" => ("skipped", CYAN),
+        _ => ("-", CYAN),
+    }
+}
+
+fn secs(t: Instant) -> f64 {
+    t.elapsed().as_secs_f64()
+}
+
+impl Display {
+    pub fn new(style: Style) -> Display {
+        Display {
+            style,
+            color: std::env::var_os("NO_COLOR").is_none(),
+            run_start: Instant::now(),
+            state: Mutex::new(State {
+                role_start: Instant::now(),
+                counts: BTreeMap::new(),
+                steps: Vec::new(),
+            }),
+        }
+    }
+
+    fn paint(&self, colour: &str, s: &str) -> String {
+        if self.color {
+            format!("{}{}{}", colour, s, RESET)
+        } else {
+            s.to_string()
+        }
+    }
+
+    fn counts(st: &State) -> String {
+        let total: usize = st.counts.values().sum();
+        let mut out = format!("{} step{}", total,
+            if total == 1 { "" } else { "s" });
+        let some: Vec<String> = ["changed", "failed", "skipped"].iter()
+            .filter_map(|r| st.counts.get(*r).map(|n| format!("{} {}", n, r)))
+            .collect();
+        if !some.is_empty() {
+            out.push_str(&format!(": {}", some.join(", ")));
+        }
+        out
+    }
+
+    /*
+     * List a top-level step which changed something or failed.
+     */
+    fn quiet(&self, st: &State, event: &Event, role: &str) {
+        let mut out = std::io::stdout();
+        let step = event.step.unwrap_or("");
+        let resource = event.resource.unwrap_or("");
+
+        match (event.event, event.result) {
+            ("step_end", Some("changed")) => {
+                writeln!(out, "CHANGED {} {} {}", role, step, resource).ok();
+                for c in event.changes.unwrap_or(&[]).iter() {
+                    writeln!(out, "    {}", c.summary()).ok();
+                }
+            }
+            ("step_end", Some("failed")) => {
+                writeln!(out, "FAILED {} {} {}", role, step, resource).ok();
+                if let Some(e) = &event.error {
+                    writeln!(out, "    {}", e).ok();
+                }
+            }
+            /*
+             * A role may fail other than in a step; e.g., in a hook.
+             */
+            ("role_end", Some("failed"))
+                if !st.counts.contains_key("failed") =>
+            {
+                writeln!(out, "FAILED {}", role).ok();
+                if let Some(e) = &event.error {
+                    writeln!(out, "    {}", e).ok();
+                }
+            }
+            _ => (),
+        }
+        out.flush().ok();
+    }
+
+    fn progress(&self, st: &State, event: &Event, role: &str) {
+        let mut out = std::io::stdout();
+        let step = event.step.unwrap_or("");
+        let resource = event.resource.unwrap_or("");
+        let result = event.result.unwrap_or("");
+
+        match event.event {
+            "role_start" => {
+                writeln!(out, "{}", self.paint(BOLD, role)).ok();
+            }
+            "step_start" => {
+                write!(out, "{}  ... {} {} ({}, {:.0}s)", CLEAR_LINE, step,
+                    resource, Self::counts(st), secs(st.role_start)).ok();
+            }
+            "step_end" => {
+                let (m, colour) = marker(result);
+                write!(out, "{}  {} {} {}", CLEAR_LINE,
+                    self.paint(colour, &format!("{:<8}", m)), step,
+                    resource).ok();
+                match (event.duration_ms, &event.error) {
+                    (_, Some(e)) if result == "failed" => {
+                        write!(out, "\n           {}", e).ok();
+                    }
+                    (Some(ms), _) if ms >= 1000 => {
+                        write!(out, " ({:.1}s)", ms as f64 / 1000.0).ok();
+                    }
+                    _ => (),
+                }
+                writeln!(out).ok();
+            }
+            "role_end" => {
+                let (_, colour) = marker(result);
+                write!(out, "{}{}: {}", CLEAR_LINE, role,
+                    self.paint(colour, result)).ok();
+                if result == "not-attempted" {
+                    writeln!(out).ok();
+                } else if let Some(r) = event.reason {
+                    writeln!(out, " ({})", r).ok();
+                } else {
+                    writeln!(out, " ({}) in {:.1}s", Self::counts(st),
+                        secs(st.role_start)).ok();
+                }
+
+                /*
+                 * A role may fail other than in a step; e.g., in a hook.
+                 */
+                match &event.error {
+                    Some(e) if !st.counts.contains_key("failed") => {
+                        writeln!(out, "    {}", e).ok();
+                    }
+                    _ => (),
+                }
+            }
+            "run_end" => {
+                let colour = if result == "complete" { GREEN } else { RED };
+                writeln!(out, "{}run {} in {:.1}s", CLEAR_LINE,
+                    self.paint(colour, result), secs(self.run_start)).ok();
+            }
+            _ => (),
+        }
+        out.flush().ok();
+    }
+
+    /**
+     * Update the display for an event from the run.
+     */
+    pub fn event(&self, event: &Event) {
+        let mut st = self.state.lock().unwrap();
+        let role = match (event.role, event.instance) {
+            (Some(r), Some(i)) => format!("{}:{}", r, i),
+            (Some(r), None) => r.to_string(),
+            _ => String::new(),
+        };
+        let step = (event.step.unwrap_or("").to_string(),
+            event.resource.unwrap_or("").to_string());
+
+        let top = match event.event {
+            "role_start" => {
+                st.role_start = Instant::now();
+                st.counts.clear();
+                st.steps.clear();
+                true
+            }
+            "step_start" => {
+                st.steps.push(step);
+                st.steps.len() == 1
+            }
+            "step_end" => {
+                /*
+                 * Skipped steps end without having started.
+                 */
+                if st.steps.last() == Some(&step) {
+                    st.steps.pop();
+                }
+                st.steps.is_empty()
+            }
+            _ => true,
+        };
+        if !top {
+            return;
+        }
+        if event.event == "step_end" {
+            let result = event.result.unwrap_or("").to_string();
+            *st.counts.entry(result).or_insert(0) += 1;
+        }
+
+        match self.style {
+            Style::Progress => self.progress(&st, event, &role),
+            Style::Quiet => self.quiet(&st, event, &role),
+        }
+    }
+}
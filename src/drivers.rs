@@ -0,0 +1,247 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Device drivers on illumos: their registration with the system (see
+ * add_drv(1M) and update_drv(1M)), the device aliases which bind devices to
+ * them (recorded in /etc/driver_aliases), the permissions of their minor
+ * nodes (/etc/minor_perm), and the local properties in their driver.conf(4)
+ * files, which live in /etc/driver/drv so that they are not replaced when the
+ * driver is updated.  For example, to register a driver for a NIC we tune:
+ *
+ *      Driver {
+ *          name: "ixgbe".into(),
+ *          aliases: vec!["pciex8086,10fb".into()],
+ *          properties: vec![("default_mtu".into(), "9000".into())],
+ *          ..Default::default()
+ *      }
+ *
+ * Aliases and permissions which are not listed are left as they are.  The
+ * driver module itself must already be installed; e.g., from a package.
+ */
+
+use std::path::{Path, PathBuf};
+
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::{alt_root, dry_run, rooted};
+use super::ensure::{self, Ownership};
+
+const ADD_DRV: &str = "/usr/sbin/add_drv";
+const UPDATE_DRV: &str = "/usr/sbin/update_drv";
+const NAME_TO_MAJOR: &str = "/etc/name_to_major";
+const DRIVER_ALIASES: &str = "/etc/driver_aliases";
+const MINOR_PERM: &str = "/etc/minor_perm";
+const DRIVER_CONF_DIR: &str = "/etc/driver/drv";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Driver {
+    pub name: String,
+    /**
+     * Device aliases for the driver, as in /etc/driver_aliases; e.g.,
+     * "pciex8086,10fb".
+     */
+    pub aliases: Vec<String>,
+    /**
+     * The class of the driver (e.g., "scsi"), given when it is added.  The
+     * class of a driver which is already added is not changed.
+     */
+    pub class: Option<String>,
+    /**
+     * Permissions for minor nodes, as for "add_drv -m"; e.g.,
+     * "* 0600 root sys".
+     */
+    pub perms: Vec<String>,
+    /**
+     * Properties for the driver.conf(4) file in /etc/driver/drv, as
+     * (NAME, VALUE); e.g., ("default_mtu", "9000"), or ("name", "\"x\"") for
+     * a string.  If empty, the file is not managed.
+     */
+    pub properties: Vec<(String, String)>,
+}
+
+impl Driver {
+    pub fn conf_path(&self) -> PathBuf {
+        Path::new(DRIVER_CONF_DIR).join(format!("{}.conf", self.name))
+    }
+}
+
+/*
+ * Read a file of the driver database beneath the alternate root, if there is
+ * one, as a list of lines without comments.  A missing file is empty.
+ */
+fn read_db(path: &str) -> Result<Vec<String>> {
+    let path = rooted(path)?;
+    match std::fs::read_to_string(&path) {
+        Ok(s) => Ok(s.lines()
+            .map(|l| l.split('#').next().unwrap().trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => bail!("reading {}: {}", path.display(), e),
+    }
+}
+
+fn added(name: &str) -> Result<bool> {
+    Ok(read_db(NAME_TO_MAJOR)?.iter()
+        .any(|l| l.split_whitespace().next() == Some(name)))
+}
+
+fn aliases(name: &str) -> Result<Vec<String>> {
+    Ok(read_db(DRIVER_ALIASES)?.iter()
+        .filter_map(|l| {
+            let mut f = l.splitn(2, char::is_whitespace);
+            match (f.next(), f.next()) {
+                (Some(n), Some(a)) if n == name => {
+                    Some(a.trim().trim_matches('"').to_string())
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/*
+ * The minor node permissions for the driver, without its name, and with the
+ * fields separated by a single space; e.g., "* 0600 root sys".
+ */
+fn perms(name: &str) -> Result<Vec<String>> {
+    let prefix = format!("{}:", name);
+    Ok(read_db(MINOR_PERM)?.iter()
+        .filter_map(|l| l.strip_prefix(&prefix))
+        .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect())
+}
+
+fn quote_aliases(aliases: &[&String]) -> String {
+    aliases.iter()
+        .map(|a| format!("\"{}\"", a))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/*
+ * The arguments for add_drv(1M) or update_drv(1M), with the alternate root,
+ * if there is one.
+ */
+fn drv_cmd(cmd: &str) -> Vec<String> {
+    let mut args = vec![cmd.to_string()];
+    if let Some(root) = alt_root() {
+        args.push("-b".to_string());
+        args.push(root.display().to_string());
+    }
+    args
+}
+
+fn render_conf(d: &Driver) -> String {
+    let mut out = String::new();
+    for (k, v) in d.properties.iter() {
+        out.push_str(&format!("{}={};\n", k, v));
+    }
+    out
+}
+
+/**
+ * Ensure that the driver is added, with its aliases, minor node permissions,
+ * and properties.  Returns whether anything changed, and whether a reboot is
+ * required for the change to take full effect.
+ */
+pub fn ensure(log: &Logger, d: &Driver) -> Result<(bool, bool)> {
+    let mut did_work = false;
+    let mut reboot = false;
+
+    for (k, _) in d.properties.iter() {
+        if k.is_empty() || k.contains(|c: char| c.is_whitespace()
+            || c == '=' || c == ';')
+        {
+            bail!("driver {}: invalid property name \"{}\"", d.name, k);
+        }
+    }
+
+    /*
+     * The driver.conf(4) file goes first, so that a driver which is added
+     * attaches with its properties.
+     */
+    if !d.properties.is_empty() {
+        let dir = rooted(DRIVER_CONF_DIR)?;
+        ensure::directory(log, &dir, "root", "sys", 0o755)?;
+        if ensure::contents(log, rooted(d.conf_path())?,
+            render_conf(d).as_bytes(), &Ownership::Names("root", "sys"), 0o644)?
+        {
+            did_work = true;
+            if added(&d.name)? {
+                /*
+                 * The driver rereads its configuration when asked, but most
+                 * drivers only look at their properties when they attach to
+                 * a device.
+                 */
+                if alt_root().is_none() && !dry_run() {
+                    let es = ensure::query_status(log, &[UPDATE_DRV,
+                        d.name.as_str()])?;
+                    if !es.success() {
+                        warn!(log, "update_drv {} failed: {:?}", d.name, es);
+                    }
+                }
+                reboot = true;
+            }
+        }
+    }
+
+    if !added(&d.name)? {
+        info!(log, "adding driver {}", d.name);
+        let mut args = drv_cmd(ADD_DRV);
+        if !d.aliases.is_empty() {
+            args.push("-i".to_string());
+            args.push(quote_aliases(&d.aliases.iter().collect::<Vec<_>>()));
+        }
+        if let Some(c) = &d.class {
+            args.push("-c".to_string());
+            args.push(c.to_string());
+        }
+        for p in d.perms.iter() {
+            args.push("-m".to_string());
+            args.push(p.to_string());
+        }
+        args.push(d.name.to_string());
+        ensure::run(log, &args)?;
+        return Ok((true, reboot));
+    }
+
+    let have = aliases(&d.name)?;
+    let missing: Vec<&String> = d.aliases.iter()
+        .filter(|a| !have.contains(a))
+        .collect();
+    if !missing.is_empty() {
+        info!(log, "adding aliases to driver {}: {:?}", d.name, missing);
+        let mut args = drv_cmd(UPDATE_DRV);
+        args.push("-a".to_string());
+        args.push("-i".to_string());
+        args.push(quote_aliases(&missing));
+        args.push(d.name.to_string());
+        ensure::run(log, &args)?;
+        did_work = true;
+    }
+
+    let have = perms(&d.name)?;
+    for p in d.perms.iter() {
+        let want = p.split_whitespace().collect::<Vec<_>>().join(" ");
+        if have.contains(&want) {
+            continue;
+        }
+        info!(log, "adding permissions to driver {}: {}", d.name, want);
+        let mut args = drv_cmd(UPDATE_DRV);
+        args.push("-a".to_string());
+        args.push("-m".to_string());
+        args.push(want);
+        args.push(d.name.to_string());
+        ensure::run(log, &args)?;
+        did_work = true;
+    }
+
+    if !did_work {
+        info!(log, "driver {} ok", d.name);
+    }
+    Ok((did_work, reboot))
+}
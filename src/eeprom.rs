@@ -0,0 +1,76 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Boot parameters on illumos, as read and set with eeprom(1M); e.g., the
+ * console device ("console"), the arguments for the kernel ("boot-args"), or
+ * the speed of a serial console ("ttya-mode").  On x86 systems, these are
+ * kept in /boot/solaris/bootenv.rc.  A change takes effect at the next boot.
+ */
+
+use std::collections::BTreeMap;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::dry_run_skip;
+use super::ensure::{self, Exec};
+use super::plan::{Action, Change};
+
+const EEPROM: &str = "/usr/sbin/eeprom";
+
+/*
+ * The parameters which are set, from eeprom(1M) with no arguments; e.g.,
+ *
+ *      console=ttya
+ *      ttya-mode=115200,8,n,1,-
+ *      boot-args: data not available.
+ */
+fn current(log: &Logger) -> Result<BTreeMap<String, String>> {
+    let out = ensure::query_output(log, &[EEPROM], &Exec::default())?;
+
+    Ok(out.lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect())
+}
+
+/**
+ * Ensure that the boot parameter "name" has this value.
+ */
+pub fn ensure(log: &Logger, name: &str, value: &str) -> Result<bool> {
+    if name.is_empty() || name.contains(|c: char| c == '='
+        || c.is_whitespace())
+    {
+        bail!("invalid eeprom parameter name \"{}\"", name);
+    }
+
+    let have = current(log)?.remove(name);
+    match &have {
+        Some(have) if have == value => {
+            info!(log, "eeprom {} ok ({:?})", name, have);
+            return Ok(false);
+        }
+        Some(have) => {
+            info!(log, "eeprom {} is {:?}, want {:?}", name, have, value);
+        }
+        None => {
+            info!(log, "eeprom {} not set, want {:?}", name, value);
+        }
+    }
+
+    let mut change = Change::new(Action::Modify,
+        format!("eeprom parameter {}", name))
+        .detail(format!("{:?} -> {:?}", have, value))
+        .after(value);
+    if let Some(have) = &have {
+        change = change.before(have.as_str());
+    }
+    if dry_run_skip(log, change) {
+        return Ok(true);
+    }
+
+    ensure::run(log, &[EEPROM, &format!("{}={}", name, value)])?;
+    Ok(true)
+}
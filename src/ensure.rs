@@ -4,18 +4,34 @@
 
 use std::path::{Path, PathBuf};
 use std::fs::{DirBuilder, File};
-use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::process::CommandExt;
 use std::ffi::CString;
-use std::io::{Read, BufRead, BufReader, BufWriter};
+use std::io::{Read, Write, BufRead, BufReader, BufWriter};
 use std::process::{Command, Stdio};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use digest::Digest;
-use slog::{Logger, info, warn, error};
+use slog::{Logger, info, warn, error, debug};
 use anyhow::{Result, bail, anyhow};
 
+use super::cache;
+use super::error::Error;
+use super::sys;
+use super::osops::os_ops;
+use super::common::{alt_root, dry_run_skip, errno, interrupt_signal,
+    shell_quote, step_name};
+
+const ZLOGIN: &str = "/usr/sbin/zlogin";
+use super::plan::{self, Action, Change};
+
 #[allow(dead_code)]
 #[derive(Debug, PartialEq)]
 pub enum HashType {
     SHA1,
+    SHA256,
     MD5,
     None,
 }
@@ -33,12 +49,23 @@ pub enum Id {
     Id(u32),
 }
 
+impl std::fmt::Display for Id {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Id::Name(n) => write!(f, "{}", n),
+            Id::Id(i) => write!(f, "{}", i),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct FileInfo {
     pub filetype: FileType,
     pub perms: u32,
     pub owner: Id,
     pub group: Id,
+    pub uid: u32,
+    pub gid: u32,
     pub target: Option<PathBuf>, /* for symbolic links */
 }
 
@@ -56,7 +83,7 @@ pub fn check<P: AsRef<Path>>(p: P) -> Result<Option<FileInfo>> {
     }));
     let (r, e, st) = unsafe {
         let r = libc::lstat(cname.as_ptr(), st);
-        let e = *libc::___errno();
+        let e = errno();
         (r, e, Box::from_raw(st))
     };
     if r != 0 {
@@ -64,7 +91,7 @@ pub fn check<P: AsRef<Path>>(p: P) -> Result<Option<FileInfo>> {
             return Ok(None);
         }
 
-        bail!("lstat({}): errno {}", name, e);
+        return Err(Error::os(format!("lstat({})", name), name, e).into());
     }
 
     let fmt = st.st_mode & libc::S_IFMT;
@@ -85,13 +112,13 @@ pub fn check<P: AsRef<Path>>(p: P) -> Result<Option<FileInfo>> {
         None
     };
 
-    let owner = if let Some(p) = super::illumos::get_passwd_by_id(st.st_uid)? {
+    let owner = if let Some(p) = os_ops().passwd_by_id(st.st_uid)? {
         Id::Name(p.name.unwrap())
     } else {
         Id::Id(st.st_uid)
     };
 
-    let group = if let Some(g) = super::illumos::get_group_by_id(st.st_gid)? {
+    let group = if let Some(g) = os_ops().group_by_id(st.st_gid)? {
         Id::Name(g.name.unwrap())
     } else {
         Id::Id(st.st_gid)
@@ -104,27 +131,61 @@ pub fn check<P: AsRef<Path>>(p: P) -> Result<Option<FileInfo>> {
         perms,
         owner,
         group,
+        uid: st.st_uid,
+        gid: st.st_gid,
         target,
     }))
 }
 
+/**
+ * The desired ownership of a file may be specified either by user and group
+ * name, as resolved in the current zone, or by numeric ID; e.g., when managing
+ * files within the root of another zone, which may have a different user
+ * database.
+ */
+#[derive(Debug, PartialEq)]
+pub enum Ownership<'a> {
+    Names(&'a str, &'a str),
+    #[cfg_attr(not(feature = "zones"), allow(dead_code))]
+    Ids(u32, u32),
+}
+
+/**
+ * Change the ownership of a path to that of the named user and group.  With
+ * an alternate root, the names are those in the passwd and group files
+ * within it, rather than those of the running system.
+ */
 pub fn chown<P: AsRef<Path>>(path: P, owner: &str, group: &str) -> Result<()> {
-    let (o, g) = match (super::illumos::get_passwd_by_name(owner)?,
-        super::illumos::get_group_by_name(group)?)
-    {
-        (Some(o), Some(g)) => (o.uid, g.gid),
-        _ => bail!("{}:{} not real user/group", owner, group),
+    let ids = match alt_root() {
+        Some(root) => (
+            sys::get_id_from_file(&root.join("etc/passwd"), owner)?,
+            sys::get_id_from_file(&root.join("etc/group"), group)?,
+        ),
+        None => (
+            os_ops().passwd_by_name(owner)?.map(|pw| pw.uid),
+            os_ops().group_by_name(group)?.map(|gr| gr.gid),
+        ),
+    };
+    let (o, g) = match ids {
+        (Some(o), Some(g)) => (o, g),
+        _ => return Err(Error::NotFound(format!("user or group {}:{}", owner,
+            group)).into()),
     };
 
+    chown_ids(path, o, g)
+}
+
+pub fn chown_ids<P: AsRef<Path>>(path: P, o: u32, g: u32) -> Result<()> {
     let cname = CString::new(path.as_ref().to_str().unwrap().to_string())?;
     let (r, e) = unsafe {
         let r = libc::lchown(cname.as_ptr(), o, g);
-        let e = *libc::___errno();
+        let e = errno();
         (r, e)
     };
     if r != 0 {
-        bail!("lchown({}, {}, {}): errno {}", path.as_ref().display(),
-            owner, group, e);
+        let p = path.as_ref().display().to_string();
+        return Err(Error::os(format!("lchown({}, {}, {})", p, o, g), &p,
+            e).into());
     }
 
     Ok(())
@@ -133,6 +194,13 @@ pub fn chown<P: AsRef<Path>>(path: P, owner: &str, group: &str) -> Result<()> {
 pub fn perms<P: AsRef<Path>>(log: &Logger, p: P, owner: &str, group: &str,
     perms: u32)
     -> Result<bool>
+{
+    perms_as(log, p, &Ownership::Names(owner, group), perms)
+}
+
+pub fn perms_as<P: AsRef<Path>>(log: &Logger, p: P, own: &Ownership,
+    perms: u32)
+    -> Result<bool>
 {
     let p = p.as_ref();
     let log = log.new(slog::o!("path" => p.display().to_string()));
@@ -141,7 +209,7 @@ pub fn perms<P: AsRef<Path>>(log: &Logger, p: P, owner: &str, group: &str,
     let fi = if let Some(fi) = check(p)? {
         fi
     } else {
-        bail!("{} does not exist", p.display());
+        return Err(Error::NotFound(p.display().to_string()).into());
     };
 
     /*
@@ -152,30 +220,69 @@ pub fn perms<P: AsRef<Path>>(log: &Logger, p: P, owner: &str, group: &str,
         did_work = true;
         info!(log, "perms are {:o}, should be {:o}", fi.perms, perms);
 
-        let cname = CString::new(p.to_str().unwrap().to_string())?;
-        let (r, e) = unsafe {
-            let r = libc::chmod(cname.as_ptr(), perms);
-            let e = *libc::___errno();
-            (r, e)
-        };
-        if r != 0 {
-            bail!("lchmod({}, {:o}): errno {}", p.display(), perms, e);
-        }
+        if !dry_run_skip(&log, Change::new(Action::Modify,
+            p.display().to_string())
+            .detail(format!("mode {:o} -> {:o}", fi.perms, perms))
+            .before(format!("mode {:o}", fi.perms))
+            .after(format!("mode {:o}", perms)))
+        {
+            let cname = CString::new(p.to_str().unwrap().to_string())?;
+            let (r, e) = unsafe {
+                let r = libc::chmod(cname.as_ptr(), perms);
+                let e = errno();
+                (r, e)
+            };
+            if r != 0 {
+                return Err(Error::os(format!("lchmod({}, {:o})",
+                    p.display(), perms), &p.display().to_string(), e).into());
+            }
 
-        info!(log, "chmod ok");
+            info!(log, "chmod ok");
+        }
     }
 
-    match (fi.owner, fi.group) {
-        (Id::Name(o), Id::Name(g)) if o == owner && g == group => {
+    match (own, fi.owner, fi.group) {
+        (Ownership::Names(owner, group), Id::Name(o), Id::Name(g))
+            if &o == owner && &g == group =>
+        {
             info!(log, "ownership already OK ({}:{})", o, g);
         }
-        (o, g) => {
+        (Ownership::Names(owner, group), o, g) => {
             did_work = true;
             info!(log, "ownership wrong ({:?}:{:?}, not {}:{})", o, g,
                 owner, group);
-            chown(p, owner, group)?;
-
-            info!(log, "chown ok");
+            if !dry_run_skip(&log, Change::new(Action::Modify,
+                p.display().to_string())
+                .detail(format!("owner {}:{} -> {}:{}", o, g, owner,
+                group))
+                .before(format!("owner {}:{}", o, g))
+                .after(format!("owner {}:{}", owner, group)))
+            {
+                chown(p, owner, group)?;
+
+                info!(log, "chown ok");
+            }
+        }
+        (Ownership::Ids(uid, gid), _, _)
+            if fi.uid == *uid && fi.gid == *gid =>
+        {
+            info!(log, "ownership already OK ({}:{})", uid, gid);
+        }
+        (Ownership::Ids(uid, gid), _, _) => {
+            did_work = true;
+            info!(log, "ownership wrong ({}:{}, not {}:{})", fi.uid, fi.gid,
+                uid, gid);
+            if !dry_run_skip(&log, Change::new(Action::Modify,
+                p.display().to_string())
+                .detail(format!("owner {}:{} -> {}:{}", fi.uid, fi.gid, uid,
+                gid))
+                .before(format!("owner {}:{}", fi.uid, fi.gid))
+                .after(format!("owner {}:{}", uid, gid)))
+            {
+                chown_ids(p, *uid, *gid)?;
+
+                info!(log, "chown ok");
+            }
         }
     };
 
@@ -185,6 +292,13 @@ pub fn perms<P: AsRef<Path>>(log: &Logger, p: P, owner: &str, group: &str,
 pub fn directory<P: AsRef<Path>>(log: &Logger, dir: P, owner: &str,
     group: &str, mode: u32)
     -> Result<bool>
+{
+    directory_as(log, dir, &Ownership::Names(owner, group), mode)
+}
+
+pub fn directory_as<P: AsRef<Path>>(log: &Logger, dir: P, own: &Ownership,
+    mode: u32)
+    -> Result<bool>
 {
     let dir = dir.as_ref();
     let mut did_work = false;
@@ -194,13 +308,24 @@ pub fn directory<P: AsRef<Path>>(log: &Logger, dir: P, owner: &str,
          * The path exists already.  Make sure it is a directory.
          */
         if fi.filetype != FileType::Directory {
-            bail!("{} is {:?}, not a directory", dir.display(), fi.filetype);
+            return Err(Error::WrongType {
+                path: dir.to_path_buf(),
+                expected: FileType::Directory,
+                found: fi.filetype,
+            }.into());
         }
     } else {
         /*
          * Create the directory, and all missing parents:
          */
         did_work = true;
+        if dry_run_skip(log, Change::new(Action::Create,
+            dir.display().to_string())
+            .detail(format!("directory, mode {:o}", mode))
+            .after(format!("directory, mode {:o}", mode)))
+        {
+            return Ok(did_work);
+        }
         info!(log, "creating directory: {}", dir.display());
         DirBuilder::new()
             .recursive(true)
@@ -213,13 +338,57 @@ pub fn directory<P: AsRef<Path>>(log: &Logger, dir: P, owner: &str,
         check(dir)?.expect("directory should now exist");
     }
 
-    if perms(log, dir, owner, group, mode)? {
+    if perms_as(log, dir, own, mode)? {
         did_work = true;
     }
 
     Ok(did_work)
 }
 
+/*
+ * Describe a pending change to the contents of a file for the plan, as a
+ * unified diff if both the old and new contents are text.
+ */
+fn content_change(dst: &Path, data: &[u8]) -> Result<Change> {
+    let old = match check(dst)? {
+        Some(fi) if fi.filetype == FileType::File => Some(std::fs::read(dst)?),
+        _ => None,
+    };
+    let action = if old.is_some() { Action::Modify } else { Action::Create };
+    let mut c = Change::new(action, dst.display().to_string())
+        .after(format!("sha256:{}", cache::hash(data)));
+    if let Some(old) = &old {
+        c = c.before(format!("sha256:{}", cache::hash(old)));
+    }
+
+    let old = old.unwrap_or_default();
+    Ok(match (std::str::from_utf8(&old), std::str::from_utf8(data)) {
+        (Ok(o), Ok(n)) => c.details(plan::diff(o, n)),
+        _ => c.detail(format!("{} bytes of binary data", data.len())),
+    })
+}
+
+/*
+ * Remove a file or symbolic link, unless we are in dry-run mode.
+ */
+fn unlink(log: &Logger, p: &Path) -> Result<()> {
+    let mut c = Change::new(Action::Remove, p.display().to_string());
+    match check(p)? {
+        Some(fi) if fi.filetype == FileType::File => {
+            c = c.before(format!("sha256:{}",
+                hash_file(p, &HashType::SHA256)?));
+        }
+        Some(FileInfo { target: Some(t), .. }) => {
+            c = c.before(format!("symbolic link to {}", t.display()));
+        }
+        _ => (),
+    }
+    if !dry_run_skip(log, c) {
+        std::fs::remove_file(p)?;
+    }
+    Ok(())
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Create {
     IfMissing,
@@ -271,6 +440,177 @@ fn compare<P1: AsRef<Path>, P2: AsRef<Path>>(src: P1, dst: P2) -> Result<bool> {
     }
 }
 
+/**
+ * Ensure that a file exists with exactly the provided contents.  If the file
+ * must be written, the new contents are written to a temporary file in the
+ * same directory which is then renamed into place, so that readers never see a
+ * partially written file.
+ */
+pub fn contents<P: AsRef<Path>>(log: &Logger, dst: P, data: &[u8],
+    own: &Ownership, mode: u32)
+    -> Result<bool>
+{
+    write_contents(log, dst.as_ref(), data, own, mode, false)
+}
+
+/**
+ * As for contents(), but for data which must not be disclosed: the data is
+ * not shown in the plan or the JSON log, and its hash is not cached.
+ */
+pub fn secret_contents<P: AsRef<Path>>(log: &Logger, dst: P, data: &[u8],
+    own: &Ownership, mode: u32)
+    -> Result<bool>
+{
+    write_contents(log, dst.as_ref(), data, own, mode, true)
+}
+
+fn write_contents(log: &Logger, dst: &Path, data: &[u8], own: &Ownership,
+    mode: u32, secret: bool)
+    -> Result<bool>
+{
+    let mut did_work = false;
+    let hash = if secret { None } else { Some(cache::hash(data)) };
+    let cached = hash.as_ref().map(|h| cache::unchanged(dst, h))
+        .unwrap_or(false);
+
+    let do_write = if let Some(fi) = check(dst)? {
+        if fi.filetype == FileType::File {
+            if cached || std::fs::read(dst)? == data {
+                info!(log, "file {} exists, with correct contents",
+                    dst.display());
+                false
+            } else {
+                warn!(log, "file {} exists, with wrong contents",
+                    dst.display());
+                true
+            }
+        } else {
+            warn!(log, "file {} exists, of type {:?}, unlinking",
+                dst.display(), fi.filetype);
+            unlink(log, dst)?;
+            true
+        }
+    } else {
+        info!(log, "file {} does not exist", dst.display());
+        true
+    };
+
+    if do_write {
+        did_work = true;
+
+        let change = if secret {
+            let action = if check(dst)?.is_some() {
+                Action::Modify
+            } else {
+                Action::Create
+            };
+            Change::new(action, dst.display().to_string())
+                .detail(format!("{} bytes of secret data", data.len()))
+        } else {
+            content_change(dst, data)?
+        };
+        if dry_run_skip(log, change) {
+            return Ok(did_work);
+        }
+
+        let fname = match dst.file_name() {
+            Some(f) => f.to_string_lossy().to_string(),
+            None => return Err(Error::validation(dst.display().to_string(),
+                "not a file path").into()),
+        };
+        let tmp = dst.with_file_name(format!(".{}.confomat", fname));
+        if check(&tmp)?.is_some() {
+            std::fs::remove_file(&tmp)?;
+        }
+
+        /*
+         * The file is created readable only by us, and given its ownership
+         * and mode before anything is written to it, so that the contents
+         * (which may be secret) are never exposed with the wrong permissions.
+         */
+        info!(log, "writing {} bytes to {} ...", data.len(), dst.display());
+        let mut f = std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        perms_as(log, &tmp, own, mode)?;
+        f.write_all(data)?;
+        f.flush()?;
+        drop(f);
+
+        std::fs::rename(&tmp, dst)?;
+    }
+
+    if perms_as(log, dst, own, mode)? {
+        did_work = true;
+    }
+    if let Some(hash) = &hash {
+        cache::record(dst, hash);
+    }
+
+    info!(log, "ok!");
+    Ok(did_work)
+}
+
+/**
+ * Ensure that a file of "KEY=value" lines, such as those in /etc/default,
+ * contains the given settings.  An existing line for each key (or a
+ * commented-out line of the form "#KEY=...") is replaced in place, and keys
+ * not present in the file are appended.  All other lines are preserved.
+ */
+pub fn key_values<P: AsRef<Path>>(log: &Logger, dst: P,
+    settings: &[(&str, &str)], own: &Ownership, mode: u32)
+    -> Result<bool>
+{
+    let dst = dst.as_ref();
+
+    let orig = match std::fs::read_to_string(dst) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(Error::io(&format!("reading {}", dst.display()), dst,
+                e));
+        }
+    };
+
+    let mut lines: Vec<String> = orig.lines().map(|l| l.to_string()).collect();
+    for (key, value) in settings.iter() {
+        let want = format!("{}={}", key, value);
+
+        let is_key = |l: &str, commented: bool| {
+            let l = if commented {
+                l.trim_start_matches('#').trim_start()
+            } else {
+                l
+            };
+            l.split('=').next().map(|k| k.trim() == *key).unwrap_or(false) &&
+                l.contains('=')
+        };
+
+        if let Some(i) = lines.iter().position(|l| is_key(l, false)) {
+            if lines[i] != want {
+                info!(log, "{}: \"{}\" -> \"{}\"", dst.display(), lines[i],
+                    want);
+                lines[i] = want;
+            }
+        } else if let Some(i) = lines.iter()
+            .position(|l| l.starts_with('#') && is_key(l, true))
+        {
+            info!(log, "{}: \"{}\" -> \"{}\"", dst.display(), lines[i], want);
+            lines[i] = want;
+        } else {
+            info!(log, "{}: adding \"{}\"", dst.display(), want);
+            lines.push(want);
+        }
+    }
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+
+    contents(log, dst, out.as_bytes(), own, mode)
+}
+
 pub fn removed<P: AsRef<Path>>(log: &Logger, dst: P) -> Result<()> {
     let dst = dst.as_ref();
 
@@ -280,11 +620,14 @@ pub fn removed<P: AsRef<Path>>(log: &Logger, dst: P) -> Result<()> {
                 info!(log, "file {} exists (as {:?}), removing",
                     dst.display(), fi.filetype);
 
-                std::fs::remove_file(dst)?;
+                unlink(log, dst)?;
             }
             t => {
-                bail!("file {} exists as {:?}, unexpected type",
-                    dst.display(), t);
+                return Err(Error::WrongType {
+                    path: dst.to_path_buf(),
+                    expected: FileType::File,
+                    found: t,
+                }.into());
             }
         }
     } else {
@@ -297,6 +640,12 @@ pub fn removed<P: AsRef<Path>>(log: &Logger, dst: P) -> Result<()> {
 
 pub fn file<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, src: P1, dst: P2,
     owner: &str, group: &str, mode: u32, create: Create) -> Result<bool>
+{
+    file_as(log, src, dst, &Ownership::Names(owner, group), mode, create)
+}
+
+pub fn file_as<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, src: P1,
+    dst: P2, own: &Ownership, mode: u32, create: Create) -> Result<bool>
 {
     let src = src.as_ref();
     let dst = dst.as_ref();
@@ -317,22 +666,28 @@ pub fn file<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, src: P1, dst: P2,
                  * Avoid clobbering an unexpected symlink when we have been
                  * asked to preserve in the face of modifications.
                  */
-                bail!("{} should be a file, but is a {:?}",
-                    dst.display(), fi.filetype);
+                return Err(Error::WrongType {
+                    path: dst.to_path_buf(),
+                    expected: FileType::File,
+                    found: fi.filetype,
+                }.into());
             }
             Create::Always if fi.filetype == FileType::File => {
                 /*
                  * Check the contents of the file to make sure it matches
                  * what we expect.
                  */
-                if compare(src, dst)? {
+                if (cache::enabled() &&
+                    cache::unchanged(dst, &cache::hash_file(src)?)) ||
+                    compare(src, dst)?
+                {
                     info!(log, "file {} exists, with correct contents",
                         dst.display());
                     false
                 } else {
                     warn!(log, "file {} exists, with wrong contents, unlinking",
                         dst.display());
-                    std::fs::remove_file(dst)?;
+                    unlink(log, dst)?;
                     true
                 }
             }
@@ -343,7 +698,7 @@ pub fn file<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, src: P1, dst: P2,
                  */
                 warn!(log, "file {} exists, of type {:?}, unlinking",
                     dst.display(), fi.filetype);
-                std::fs::remove_file(dst)?;
+                unlink(log, dst)?;
                 true
             }
         }
@@ -354,13 +709,22 @@ pub fn file<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, src: P1, dst: P2,
 
     if do_copy {
         did_work = true;
+        if dry_run_skip(log, content_change(dst,
+            &std::fs::read(src)?)?
+            .detail(format!("copied from {}", src.display())))
+        {
+            return Ok(did_work);
+        }
         info!(log, "copying {} -> {} ...", src.display(), dst.display());
         std::fs::copy(src, dst)?;
     }
 
-    if perms(log, dst, owner, group, mode)? {
+    if perms_as(log, dst, own, mode)? {
         did_work = true;
     }
+    if create == Create::Always && cache::enabled() {
+        cache::record(dst, &cache::hash_file(src)?);
+    }
 
     info!(log, "ok!");
     Ok(did_work)
@@ -383,7 +747,7 @@ pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, dst: P1,
             } else {
                 warn!(log, "link target wrong: want {}, got {}; unlinking",
                     target.display(), fitarget.display());
-                std::fs::remove_file(dst)?;
+                unlink(log, dst)?;
                 true
             }
         } else {
@@ -392,7 +756,7 @@ pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, dst: P1,
              */
             warn!(log, "file {} exists, of type {:?}, unlinking",
                 dst.display(), fi.filetype);
-            std::fs::remove_file(dst)?;
+            unlink(log, dst)?;
             true
         }
     } else {
@@ -402,6 +766,13 @@ pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(log: &Logger, dst: P1,
 
     if do_link {
         did_work = true;
+        if dry_run_skip(log, Change::new(Action::Create,
+            dst.display().to_string())
+            .detail(format!("symbolic link to {}", target.display()))
+            .after(format!("symbolic link to {}", target.display())))
+        {
+            return Ok(did_work);
+        }
         info!(log, "linking {} -> {} ...", dst.display(), target.display());
         std::os::unix::fs::symlink(target, dst)?;
     }
@@ -428,6 +799,7 @@ pub fn hash_file<P: AsRef<Path>>(p: P, hashtype: &HashType) -> Result<String> {
     let mut digest: Box<dyn digest::DynDigest> = match hashtype {
         HashType::MD5 => Box::new(md5::Md5::new()),
         HashType::SHA1 => Box::new(sha1::Sha1::new()),
+        HashType::SHA256 => Box::new(sha2::Sha256::new()),
         HashType::None => panic!("None unexpected"),
     };
 
@@ -453,6 +825,13 @@ fn sleep(s: u64) {
     std::thread::sleep(std::time::Duration::from_secs(s));
 }
 
+/*
+ * A hash as it appears in the audit log; e.g., "sha256:9f86...".
+ */
+fn hash_label(hashtype: &HashType, hash: &str) -> String {
+    format!("{}:{}", format!("{:?}", hashtype).to_lowercase(), hash)
+}
+
 pub fn download_file<P: AsRef<Path>>(log: &Logger, url: &str, p: P, hash: &str,
     hashtype: HashType) -> Result<()>
 {
@@ -465,6 +844,12 @@ pub fn download_file<P: AsRef<Path>>(log: &Logger, url: &str, p: P, hash: &str,
         if let Some(fi) = check(p)? {
             if fi.filetype != FileType::File {
                 warn!(log, "type {:?} unexpected; unlinking", fi.filetype);
+                if dry_run_skip(log, Change::new(Action::Modify,
+                    p.display().to_string())
+                    .detail(format!("download from {}", url)))
+                {
+                    break;
+                }
                 std::fs::remove_file(p)?;
                 continue;
             }
@@ -480,6 +865,14 @@ pub fn download_file<P: AsRef<Path>>(log: &Logger, url: &str, p: P, hash: &str,
                 break;
             } else {
                 warn!(log, "does not match; unlinking");
+                if dry_run_skip(log, Change::new(Action::Modify,
+                    p.display().to_string())
+                    .detail(format!("download from {} (hash {})", url, hash))
+                    .before(hash_label(&hashtype, &actual_hash))
+                    .after(hash_label(&hashtype, hash)))
+                {
+                    break;
+                }
                 std::fs::remove_file(p)?;
                 continue;
             }
@@ -487,6 +880,13 @@ pub fn download_file<P: AsRef<Path>>(log: &Logger, url: &str, p: P, hash: &str,
 
         info!(log, "file {} does not exist; downloading from {}", p.display(),
             url);
+        if dry_run_skip(log, Change::new(Action::Create,
+            p.display().to_string())
+            .detail(format!("download from {}", url))
+            .after(hash_label(&hashtype, hash)))
+        {
+            break;
+        }
         let mut res = reqwest::blocking::get(url)?;
         if !res.status().is_success() {
             warn!(log, "HTTP {:?}; retrying...", res.status());
@@ -515,12 +915,38 @@ pub fn download_file<P: AsRef<Path>>(log: &Logger, url: &str, p: P, hash: &str,
     Ok(())
 }
 
-fn spawn_reader<T>(log: &Logger, name: &str, stream: Option<T>)
+/*
+ * The number of lines of output from a command to include when reporting that
+ * it failed.
+ */
+const TAIL_LINES: usize = 20;
+
+/*
+ * What is logged in place of the value of a secret.
+ */
+const REDACTED: &str = "********";
+
+type Tail = Arc<Mutex<VecDeque<String>>>;
+
+/*
+ * Log each line of output from a command as it arrives, labelled with the
+ * step that ran the command, if any.  A carriage return also ends a line, so
+ * that the progress reports of commands like pkg(1) are seen as they are
+ * made, rather than when the whole operation finishes.
+ */
+fn spawn_reader<T>(log: &Logger, name: &str, stream: Option<T>, tail: &Tail,
+    capture: Option<Arc<Mutex<Vec<u8>>>>, secrets: &[String], show: bool)
     -> Option<std::thread::JoinHandle<()>>
 where
     T: Read + Send + 'static,
 {
     let name = name.to_string();
+    let secrets = secrets.to_vec();
+    let prefix = match step_name() {
+        Some(step) => format!("{} {}", step, name),
+        None => name.clone(),
+    };
+    let tail = Arc::clone(tail);
     let stream = match stream {
         Some(stream) => stream,
         None => return None,
@@ -530,60 +956,590 @@ where
 
     Some(std::thread::spawn(move || {
         let mut r = BufReader::new(stream);
+        let mut buf: Vec<u8> = Vec::new();
 
-        loop {
-            let mut buf = String::new();
+        let emit = |buf: &mut Vec<u8>| {
+            let mut line = String::from_utf8_lossy(buf).trim().to_string();
+            buf.clear();
+
+            /*
+             * A command may well print a secret it was given; e.g., when
+             * tracing a shell script.
+             */
+            for s in secrets.iter() {
+                line = line.replace(s.as_str(), REDACTED);
+            }
+
+            /*
+             * The output is only logged with "-v", unless it is to be shown,
+             * but the last lines of it are kept for the error if the command
+             * fails.
+             */
+            if !line.is_empty() {
+                if show {
+                    info!(log, "{}| {}", prefix, line);
+                } else {
+                    debug!(log, "{}| {}", prefix, line);
+                }
+
+                let mut tail = tail.lock().unwrap();
+                if tail.len() == TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(format!("{}| {}", name, line));
+            }
+        };
 
-            match r.read_line(&mut buf) {
-                Ok(0) => {
+        loop {
+            let avail = match r.fill_buf() {
+                Ok([]) => {
                     /*
                      * EOF.
                      */
+                    emit(&mut buf);
                     return;
                 }
-                Ok(_) => {
-                    let s = buf.trim();
-
-                    if !s.is_empty() {
-                        info!(log, "{}| {}", name, s);
-                    }
+                Ok(avail) => avail,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => {
+                    /*
+                     * On some systems, reading from the master side of a
+                     * pseudo-terminal fails, rather than returning EOF, once
+                     * the slave side has been closed.
+                     */
+                    emit(&mut buf);
+                    return;
                 }
                 Err(e) => {
                     error!(log, "failed to read {}: {}", name, e);
                     std::process::exit(100);
                 }
+            };
+
+            let used = match avail.iter().position(|&b| b == b'\n' ||
+                b == b'\r')
+            {
+                Some(n) => {
+                    buf.extend_from_slice(&avail[..n]);
+                    emit(&mut buf);
+                    n + 1
+                }
+                None => {
+                    buf.extend_from_slice(avail);
+                    avail.len()
+                }
+            };
+            if let Some(c) = &capture {
+                c.lock().unwrap().extend_from_slice(&avail[..used]);
             }
+            r.consume(used);
         }
     }))
 }
 
+/**
+ * Options for running a command with run_with() or query_with().
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Exec {
+    /**
+     * If the command has not finished after this long, kill it and every
+     * other process in its process group, and fail.
+     */
+    pub timeout: Option<Duration>,
+    /**
+     * Run the command as this user, with only the user's groups and the
+     * basic privileges, rather than as root.
+     */
+    pub user: Option<String>,
+    /**
+     * Run the command in this non-global zone, with zlogin(1).  If a user is
+     * also given, the command runs as that user within the zone.
+     */
+    pub zone: Option<String>,
+    /**
+     * Run the command in this directory, rather than our own working
+     * directory.
+     */
+    pub dir: Option<PathBuf>,
+    /**
+     * The shell with which run_shell() and query_shell() run scripts.  The
+     * default is DEFAULT_SHELL.
+     */
+    pub shell: Option<String>,
+    /**
+     * The exit statuses which mean the command succeeded; e.g., 0 and 1 for
+     * diff(1).  If empty, only 0 does.
+     */
+    pub ok_codes: Vec<i32>,
+    /**
+     * Variables to add to the environment of the command, on top of the
+     * baseline (see BASE_PATH).
+     */
+    pub env: Vec<(String, String)>,
+    /**
+     * Variables to add to the environment whose values are secret; e.g.,
+     * passwords or API tokens.  The values are not logged, and are redacted
+     * from the output of the command wherever it is logged or reported.
+     * Secrets cannot be passed to a command in a zone, as zlogin(1) would
+     * have them in its arguments.
+     */
+    pub secrets: Vec<(String, String)>,
+    /**
+     * Pass on our own environment to the command, apart from the locale,
+     * rather than starting from the baseline.
+     */
+    pub inherit_env: bool,
+    /**
+     * Run the command with a pseudo-terminal as its controlling terminal and
+     * as its stdin, stdout and stderr, for tools which refuse to run, or
+     * behave differently, without one.  The two streams of output are then
+     * one.  Nothing is written to the terminal, so a command which prompts
+     * for input will wait forever, unless there is also a timeout.
+     */
+    pub pty: bool,
+    /**
+     * Run the command chrooted to this directory, with "dir", if given,
+     * within it.  The methods of a Context use the alternate root, if there
+     * is one, unless this or a zone is given.  A user is still looked up on
+     * the running system.
+     */
+    pub root: Option<PathBuf>,
+    /**
+     * Log the output of the command even without "-v"; e.g., for a run of
+     * confomat in a zone, whose output is its log.
+     */
+    pub show_output: bool,
+}
+
+impl Exec {
+    fn succeeded(&self, es: &std::process::ExitStatus) -> bool {
+        match es.code() {
+            Some(c) if self.ok_codes.is_empty() => c == 0,
+            Some(c) => self.ok_codes.contains(&c),
+            None => false,
+        }
+    }
+}
+
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+
+/*
+ * Commands start from a minimal environment, rather than inheriting ours, so
+ * that what they do does not depend on how confomat was started; e.g., from
+ * an interactive shell, or from cron(1M).  The baseline is this PATH, the
+ * "C" locale so that output we parse is not translated, and those of our own
+ * variables in PASS_VARS which are set; e.g., so that pkg(1) still uses a
+ * proxy, ssh(1) still reaches the agent, and git(1) and gpg(1) still find
+ * the configuration in our home directory.  A command run as another user
+ * has the HOME, USER and LOGNAME of that user instead.
+ */
+pub const BASE_PATH: &str = "/usr/sbin:/usr/bin:/sbin";
+
+const PASS_VARS: &[&str] = &["TZ", "http_proxy", "https_proxy", "no_proxy",
+    "HOME", "USER", "LOGNAME", "SSH_AUTH_SOCK"];
+
+const LOCALE_VARS: &[&str] = &["LANG", "LC_CTYPE", "LC_NUMERIC", "LC_TIME",
+    "LC_COLLATE", "LC_MONETARY", "LC_MESSAGES", "LC_ALL"];
+
+/*
+ * The arguments with which to run a shell script.  The script is passed as a
+ * single argument, so it is not subject to any further quoting or splitting;
+ * values interpolated into it should be quoted with shell_quote().  The shell
+ * must support "set -o pipefail", so that a pipeline fails if any command
+ * within it does, and not only the last.
+ */
+fn shell_args(script: &str, opts: &Exec) -> Vec<String> {
+    vec![
+        opts.shell.as_deref().unwrap_or(DEFAULT_SHELL).to_string(),
+        "-c".to_string(),
+        format!("set -o pipefail\n{}", script),
+    ]
+}
+
+/**
+ * Run a shell script (e.g., a pipeline) which may alter the system, with the
+ * same options as for run_with().  In dry-run mode, the script is logged but
+ * not executed.
+ */
+pub fn run_shell(log: &Logger, script: &str, opts: &Exec) -> Result<()> {
+    let shell = opts.shell.as_deref().unwrap_or(DEFAULT_SHELL);
+    if dry_run_skip(log, Change::new(Action::Exec, script)
+        .detail(format!("with {}", shell)))
+    {
+        return Ok(());
+    }
+
+    query_with(log, &shell_args(script, opts), opts)
+}
+
+/**
+ * Run a shell script which only inspects the system, and return what it
+ * wrote to stdout.
+ */
+pub fn query_shell(log: &Logger, script: &str, opts: &Exec) -> Result<String> {
+    query_output(log, &shell_args(script, opts), opts)
+}
+
+/**
+ * Run a command which may alter the system.  In dry-run mode, the command is
+ * logged but not executed.
+ */
 pub fn run<S: AsRef<str>>(log: &Logger, args: &[S]) -> Result<()> {
+    run_with(log, args, &Exec::default())
+}
+
+pub fn run_with<S: AsRef<str>>(log: &Logger, args: &[S], opts: &Exec)
+    -> Result<()>
+{
+    let a: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+    let mut change = Change::new(Action::Exec, a.join(" "));
+    if let Some(user) = &opts.user {
+        change = change.detail(format!("as user {}", user));
+    }
+    if let Some(zone) = &opts.zone {
+        change = change.detail(format!("in zone {}", zone));
+    }
+    if let Some(root) = &opts.root {
+        change = change.detail(format!("in root {}", root.display()));
+    }
+    if dry_run_skip(log, change) {
+        return Ok(());
+    }
+
+    query_with(log, args, opts)
+}
+
+/**
+ * Run a command which only inspects the system, and which must therefore be
+ * executed even in dry-run mode.
+ */
+pub fn query<S: AsRef<str>>(log: &Logger, args: &[S]) -> Result<()> {
+    query_with(log, args, &Exec::default())
+}
+
+pub fn query_with<S: AsRef<str>>(log: &Logger, args: &[S], opts: &Exec)
+    -> Result<()>
+{
+    query_output(log, args, opts).map(|_| ())
+}
+
+/**
+ * Run a command as for query_with(), and return what it wrote to stdout.
+ */
+pub fn query_output<S: AsRef<str>>(log: &Logger, args: &[S], opts: &Exec)
+    -> Result<String>
+{
+    let out = exec(log, args, opts, true)?;
+    if !opts.succeeded(&out.status) {
+        return Err(Error::ExternalCommandFailed {
+            args: args.iter().map(|s| s.as_ref().to_string()).collect(),
+            status: out.status,
+            output: out.tail,
+        }.into());
+    }
+    Ok(out.stdout)
+}
+
+/**
+ * Run a command as for query(), but return its exit status, rather than
+ * failing if it is not zero.
+ */
+pub fn query_status<S: AsRef<str>>(log: &Logger, args: &[S])
+    -> Result<std::process::ExitStatus>
+{
+    query_status_with(log, args, &Exec::default())
+}
+
+pub fn query_status_with<S: AsRef<str>>(log: &Logger, args: &[S],
+    opts: &Exec)
+    -> Result<std::process::ExitStatus>
+{
+    Ok(exec(log, args, opts, false)?.status)
+}
+
+struct Output {
+    status: std::process::ExitStatus,
+    stdout: String,
+    /*
+     * The last lines of output, for an error message.
+     */
+    tail: Vec<String>,
+}
+
+fn tail_lines(tail: &Tail) -> Vec<String> {
+    tail.lock().unwrap().iter().cloned().collect()
+}
+
+/*
+ * Run a command, logging its output as it arrives, and return its exit status
+ * along with the last lines of that output.  If "capture" is set, stdout is
+ * also collected in full.
+ */
+fn exec<S: AsRef<str>>(log: &Logger, args: &[S], opts: &Exec, capture: bool)
+    -> Result<Output>
+{
     let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
 
-    let mut cmd = Command::new(&args[0]);
-    cmd.env_remove("LANG");
-    cmd.env_remove("LC_CTYPE");
-    cmd.env_remove("LC_NUMERIC");
-    cmd.env_remove("LC_TIME");
-    cmd.env_remove("LC_COLLATE");
-    cmd.env_remove("LC_MONETARY");
-    cmd.env_remove("LC_MESSAGES");
-    cmd.env_remove("LC_ALL");
+    /*
+     * zlogin(1) passes the command to a shell within the zone as a single
+     * string, so each argument must be quoted.  Variables for the command
+     * are assigned within that string, too.
+     */
+    let zcmd: String;
+    let argv: Vec<&str> = match &opts.zone {
+        Some(zone) => {
+            if !opts.secrets.is_empty() {
+                return Err(Error::validation(format!("exec {:?}", &args),
+                    format!("secrets cannot be passed into zone {}", zone))
+                    .into());
+            }
+            let quoted = opts.env.iter()
+                .map(|(k, v)| format!("{}={}", k, shell_quote(v)))
+                .chain(args.iter().map(|a| shell_quote(a)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            zcmd = match &opts.dir {
+                Some(dir) => format!("cd {} && {}",
+                    shell_quote(&dir.to_string_lossy()), quoted),
+                None => quoted,
+            };
+            let mut argv = vec![ZLOGIN];
+            if let Some(user) = &opts.user {
+                argv.push("-l");
+                argv.push(user);
+            }
+            argv.push(zone);
+            argv.push(&zcmd);
+            argv
+        }
+        None => args.clone(),
+    };
+
+    let mut cmd = Command::new(argv[0]);
+    if opts.inherit_env {
+        for v in LOCALE_VARS {
+            cmd.env_remove(v);
+        }
+    } else {
+        cmd.env_clear();
+        cmd.env("PATH", BASE_PATH);
+        for v in PASS_VARS {
+            if let Some(val) = std::env::var_os(v) {
+                cmd.env(v, val);
+            }
+        }
+    }
+    cmd.env("LANG", "C");
+    if opts.zone.is_none() {
+        for (k, v) in opts.env.iter().chain(opts.secrets.iter()) {
+            cmd.env(k, v);
+        }
+    }
+
+    if argv.len() > 1 {
+        cmd.args(&argv[1..]);
+    }
+
+    if opts.root.is_some() && opts.zone.is_some() {
+        return Err(Error::validation(format!("exec {:?}", &args),
+            "cannot use both a root and a zone").into());
+    }
+
+    let pty = if opts.pty {
+        if opts.zone.is_some() {
+            return Err(Error::validation(format!("exec {:?}", &args),
+                "a terminal cannot be allocated in a zone").into());
+        }
+        Some(sys::openpty()?)
+    } else {
+        None
+    };
+
+    match &pty {
+        Some(pty) => {
+            cmd.stdin(pty.slave.try_clone()?);
+            cmd.stdout(pty.slave.try_clone()?);
+            cmd.stderr(pty.slave.try_clone()?);
+        }
+        None => {
+            cmd.stdin(Stdio::null());
+            cmd.stdout(Stdio::piped());
+            cmd.stderr(Stdio::piped());
+        }
+    }
+
+    /*
+     * Put the command in a process group of its own, so that an interrupt
+     * from the terminal reaches us first; we then pass it on to the command
+     * (see below) and stop between steps.  With a pseudo-terminal, the
+     * command is instead in a session of its own, which also makes it the
+     * leader of a new process group.
+     */
+    match &pty {
+        Some(pty) => {
+            let name = pty.name.clone();
+            unsafe {
+                cmd.pre_exec(move || sys::set_controlling_tty(&name));
+            }
+        }
+        None => {
+            cmd.process_group(0);
+        }
+    }
 
-    if args.len() > 1 {
-        cmd.args(&args[1..]);
+    match (&opts.root, &opts.dir, &opts.zone) {
+        (Some(root), dir, None) => {
+            /*
+             * The working directory must be changed after the chroot, so it
+             * is done here, rather than with current_dir().
+             */
+            let root = CString::new(root.as_os_str().as_bytes())?;
+            let dir = CString::new(dir.as_deref().unwrap_or(Path::new("/"))
+                .as_os_str().as_bytes())?;
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::chroot(root.as_ptr()) != 0 ||
+                        libc::chdir(dir.as_ptr()) != 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        (None, Some(dir), None) => {
+            cmd.current_dir(dir);
+        }
+        _ => (),
     }
 
-    cmd.stdin(Stdio::null());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
+    /*
+     * A secret may also appear in the arguments; e.g., in a script which
+     * sets a password.  It is redacted from what we log of them, as from
+     * the output.
+     */
+    let secrets: Vec<String> = opts.secrets.iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(_, v)| v.to_string())
+        .collect();
+    let shown: Vec<String> = args.iter()
+        .map(|a| secrets.iter().fold(a.to_string(),
+            |a, s| a.replace(s.as_str(), REDACTED)))
+        .collect();
+
+    if let (Some(user), None) = (&opts.user, &opts.zone) {
+        let pw = match os_ops().passwd_by_name(user)? {
+            Some(pw) => pw,
+            None => return Err(anyhow::Error::new(Error::NotFound(
+                format!("user \"{}\"", user)))
+                .context(format!("exec {:?}", &args))),
+        };
+        let groups = os_ops().group_ids_for_user(user)?;
+        let basic = sys::PrivSet::basic()?;
+        let (uid, gid) = (pw.uid, pw.gid);
+
+        if let Some(dir) = &pw.dir {
+            cmd.env("HOME", dir);
+        }
+        cmd.env("USER", user);
+        cmd.env("LOGNAME", user);
+
+        /*
+         * Everything this closure uses has been looked up in advance, as it
+         * runs in the child between fork and exec.  The groups must be set
+         * while we still have the privilege to do so.
+         */
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setgroups(groups.len() as libc::c_int,
+                    groups.as_ptr()) != 0 ||
+                    libc::setgid(gid) != 0 ||
+                    libc::setuid(uid) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                basic.apply()
+            });
+        }
 
-    info!(log, "exec: {:?}", &args);
+        info!(log, "exec as {}: {:?}", user, &shown);
+    } else if let Some(zone) = &opts.zone {
+        info!(log, "exec in zone {}: {:?}", zone, &shown);
+    } else if let Some(root) = &opts.root {
+        info!(log, "exec in root {}: {:?}", root.display(), &shown);
+    } else {
+        info!(log, "exec: {:?}", &shown);
+    }
+    if !opts.env.is_empty() || !opts.secrets.is_empty() {
+        let vars = opts.env.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .chain(opts.secrets.iter()
+                .map(|(k, _)| format!("{}={}", k, REDACTED)))
+            .collect::<Vec<_>>();
+        debug!(log, "exec environment: {}", vars.join(" "));
+    }
 
     let mut child = cmd.spawn()?;
 
-    let readout = spawn_reader(log, "O", child.stdout.take());
-    let readerr = spawn_reader(log, "E", child.stderr.take());
+    /*
+     * Close our copies of the slave side of any terminal, so that we see EOF
+     * once the command has finished with it.
+     */
+    drop(cmd);
+    let master = pty.map(|pty| pty.master);
+
+    let tail: Tail = Arc::new(Mutex::new(VecDeque::new()));
+    let stdout = if capture {
+        Some(Arc::new(Mutex::new(Vec::new())))
+    } else {
+        None
+    };
+    let readout = match master {
+        Some(m) => spawn_reader(log, "T", Some(m), &tail, stdout.clone(),
+            &secrets, opts.show_output),
+        None => spawn_reader(log, "O", child.stdout.take(), &tail,
+            stdout.clone(), &secrets, opts.show_output),
+    };
+    let readerr = spawn_reader(log, "E", child.stderr.take(), &tail, None,
+        &secrets, opts.show_output);
+
+    /*
+     * The command is the leader of its own process group, so signals sent to
+     * the group also reach anything it has started.  As the command does not
+     * see an interrupt from the terminal, we pass on any signal that
+     * interrupts the run.
+     */
+    let pgid = child.id() as libc::pid_t;
+    let deadline = opts.timeout.map(|t| Instant::now() + t);
+    let mut forwarded = false;
+    let es = loop {
+        if let Some(es) = child.try_wait()? {
+            break es;
+        }
+        if let (Some(sig), false) = (interrupt_signal(), forwarded) {
+            warn!(log, "interrupted; signalling {:?}", &args);
+            unsafe { libc::killpg(pgid, sig) };
+            forwarded = true;
+        }
+        if let (Some(deadline), Some(timeout)) = (deadline, opts.timeout) {
+            if Instant::now() >= deadline {
+                unsafe { libc::killpg(pgid, libc::SIGKILL) };
+                child.wait()?;
+                for t in readout.into_iter().chain(readerr) {
+                    t.join().expect("join output thread");
+                }
+                return Err(Error::TimedOut {
+                    args: args.iter().map(|a| a.to_string()).collect(),
+                    timeout,
+                    output: tail_lines(&tail),
+                }.into());
+            }
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
 
     if let Some(t) = readout {
         t.join().expect("join stdout thread");
@@ -592,14 +1548,38 @@ pub fn run<S: AsRef<str>>(log: &Logger, args: &[S]) -> Result<()> {
         t.join().expect("join stderr thread");
     }
 
-    match child.wait() {
-        Err(e) => Err(e.into()),
-        Ok(es) => {
-            if !es.success() {
-                Err(anyhow!("exec {:?}: failed {:?}", &args, &es))
-            } else {
-                Ok(())
-            }
-        }
+    Ok(Output {
+        status: es,
+        stdout: stdout
+            .map(|s| String::from_utf8_lossy(&s.lock().unwrap()).to_string())
+            .unwrap_or_default(),
+        tail: tail_lines(&tail),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_value_lines() {
+        let log = Logger::root(slog::Discard, slog::o!());
+        let tf = super::super::common::temp_file("mpathd",
+            b"# comment\n#FAILBACK=yes\nFAILURE_DETECTION_TIME=10000\n\
+            TRACK_INTERFACES_ONLY_WITH_GROUPS=yes\n").unwrap();
+        let own = unsafe { Ownership::Ids(libc::getuid(), libc::getgid()) };
+        let settings = [
+            ("FAILURE_DETECTION_TIME", "5000"),
+            ("FAILBACK", "no"),
+            ("NEW", "1"),
+        ];
+
+        assert!(key_values(&log, tf.path(), &settings, &own, 0o644)
+            .unwrap());
+        assert_eq!(std::fs::read_to_string(tf.path()).unwrap(),
+            "# comment\nFAILBACK=no\nFAILURE_DETECTION_TIME=5000\n\
+            TRACK_INTERFACES_ONLY_WITH_GROUPS=yes\nNEW=1\n");
+        assert!(!key_values(&log, tf.path(), &settings, &own, 0o644)
+            .unwrap());
     }
 }
@@ -0,0 +1,145 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The kinds of failure in the OS and ensure layers which a program embedding
+ * confomat, or a test, may want to tell apart.  Functions still return
+ * anyhow::Result, as does the rest of confomat, and the kind of a failure is
+ * found by downcasting; e.g.,
+ *
+ *      match e.downcast_ref::<confomat::Error>() {
+ *          Some(confomat::Error::NotFound(what)) => ...,
+ *          Some(confomat::Error::ExternalCommandFailed { status, .. }) => ...,
+ *          _ => ...,
+ *      }
+ *
+ * Something which cannot be done on this platform at all fails with
+ * Unsupported instead (see the common module).  Failures of other kinds
+ * (e.g., errors from roles, or from parsing configuration) are not yet
+ * classified.
+ */
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use super::ensure::FileType;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /**
+     * A file, user, group, or other resource which must exist does not.
+     */
+    #[error("{0} does not exist")]
+    NotFound(String),
+    /**
+     * The operation (e.g., a system call) was not permitted.
+     */
+    #[error("{0}: permission denied")]
+    PermissionDenied(String),
+    /**
+     * A value or a request was checked and found to be invalid; e.g., an ID
+     * which is not a number, or options to a command which cannot be used
+     * together.
+     */
+    #[error("{what}: {reason}")]
+    ValidationFailed {
+        what: String,
+        reason: String,
+    },
+    /**
+     * A file exists, but is not of the expected type.
+     */
+    #[error("{} is a {found:?}, not a {expected:?}", .path.display())]
+    WrongType {
+        path: PathBuf,
+        expected: FileType,
+        found: FileType,
+    },
+    /**
+     * A command did not exit successfully.  The output is the last lines the
+     * command wrote, with any secrets redacted.
+     */
+    #[error("exec {args:?}: failed {status:?}{}", last_output(.output))]
+    ExternalCommandFailed {
+        args: Vec<String>,
+        status: ExitStatus,
+        output: Vec<String>,
+    },
+    /**
+     * A command ran for longer than its timeout, and was killed.
+     */
+    #[error("exec {args:?}: timed out after {}s{}", .timeout.as_secs_f64(),
+        last_output(.output))]
+    TimedOut {
+        args: Vec<String>,
+        timeout: Duration,
+        output: Vec<String>,
+    },
+    /**
+     * A system call failed for some other reason.
+     */
+    #[error("{call}: errno {errno}")]
+    SystemCall {
+        call: String,
+        errno: i32,
+    },
+}
+
+impl Error {
+    /**
+     * The error for a failed system call, "call", on "what" (e.g., a path),
+     * classified by its error number.
+     */
+    pub fn os(call: String, what: &str, errno: i32) -> Error {
+        match errno {
+            libc::ENOENT => Error::NotFound(what.to_string()),
+            libc::EACCES | libc::EPERM => Error::PermissionDenied(call),
+            _ => Error::SystemCall { call, errno },
+        }
+    }
+
+    /**
+     * The error for a failed I/O operation, "what" (e.g., "reading PATH"),
+     * on "path", classified where it is of a kind we distinguish.
+     */
+    pub fn io(what: &str, path: &Path, e: std::io::Error) -> anyhow::Error {
+        match e.kind() {
+            ErrorKind::NotFound => {
+                Error::NotFound(path.display().to_string()).into()
+            }
+            ErrorKind::PermissionDenied => {
+                Error::PermissionDenied(what.to_string()).into()
+            }
+            _ => anyhow::anyhow!("{}: {}", what, e),
+        }
+    }
+
+    pub fn validation<W: Into<String>, R: Into<String>>(what: W, reason: R)
+        -> Error
+    {
+        Error::ValidationFailed {
+            what: what.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/*
+ * Format the last lines of output from a command for an error message.
+ */
+fn last_output(lines: &[String]) -> String {
+    let mut out = String::new();
+    if !lines.is_empty() {
+        out.push_str("; last output:");
+        for l in lines.iter() {
+            out.push_str("\n    ");
+            out.push_str(l);
+        }
+    }
+    out
+}
@@ -0,0 +1,701 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Facts about the system, gathered once at startup, for roles to consult
+ * (see Context::facts()) and for the run report.  A fact which cannot be
+ * gathered is reported and left empty, rather than preventing the run.
+ *
+ * Sites can add their own facts with scripts, in "<dir>/facts.d" and in
+ * "/var/confomat/facts.d" (for facts particular to one machine).  Each
+ * executable file there is run, in order of name, and must write a JSON
+ * object to stdout.  The members of that object become facts, alongside (but
+ * not replacing) the built-in ones; e.g., {"rack": "a12"} would become
+ * "facts.rack" in templates.  A later script replaces the facts of an earlier
+ * one.
+ *
+ * The facts which are slow to gather, such as the package inventory, the
+ * hardware, and the disks (see hardware.rs), are kept in
+ * "/var/confomat/facts.json" for up to an hour, so that frequent runs (e.g.,
+ * from cron) need not gather them each time.  With "--refresh-facts", every
+ * fact is gathered afresh.
+ *
+ * To see what confomat knows about a host, "confomat facts" prints the facts
+ * (with "--json", as a JSON object) and exits without applying anything.
+ */
+
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::STATE_DIR;
+use super::ensure::{self, Exec};
+use super::hardware::{self, Disk, Hardware, Virtualization};
+use super::links::{self, Link};
+use super::osops::os_ops;
+use super::packages::{self, Manager};
+use super::OS;
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Uname {
+    pub sysname: String,
+    pub release: String,
+    pub version: String,
+    pub machine: String,
+}
+
+/**
+ * An IP address configured on a network interface.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InterfaceAddress {
+    pub interface: String,
+    pub address: IpAddr,
+    /**
+     * The length of the network prefix; e.g., 24 for a netmask of
+     * 255.255.255.0.
+     */
+    pub prefix: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Zpool {
+    pub name: String,
+    /**
+     * The total size of the pool, in bytes.
+     */
+    pub size: u64,
+    /**
+     * e.g., "ONLINE" or "DEGRADED".
+     */
+    pub health: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageSummary {
+    /**
+     * The package manager; e.g., "Ips" or "Apt".
+     */
+    pub manager: String,
+    pub installed: usize,
+}
+
+/**
+ * The release of the operating system.  The kernel version is in "uname".
+ */
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Release {
+    /**
+     * e.g., "OmniOS v11 r151048", from /etc/os-release (or /etc/release).
+     */
+    pub name: Option<String>,
+    /**
+     * e.g., "r151048" on OmniOS, or "22.04" on Ubuntu.
+     */
+    pub version: Option<String>,
+    /**
+     * On systems with IPS, the FMRI of the installed "entire" package.
+     */
+    pub entire: Option<String>,
+    /**
+     * On illumos, the boot environment which is active now.
+     */
+    pub boot_environment: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Facts {
+    pub os: String,
+    pub nodename: String,
+    pub zonename: String,
+    pub zoneid: i32,
+    pub uname: Uname,
+    pub release: Release,
+    /**
+     * Physical memory, in bytes.
+     */
+    pub memory: u64,
+    /**
+     * The number of CPUs online.
+     */
+    pub cpus: u32,
+    /**
+     * Every address but those on loopback interfaces.
+     */
+    pub addresses: Vec<InterfaceAddress>,
+    /**
+     * The address of each interface: the first IPv4 address on it, or if
+     * there are none, the first IPv6 address.
+     */
+    pub ipaddrs: BTreeMap<String, IpAddr>,
+    /**
+     * The network links, by name (see links.rs).
+     */
+    pub links: BTreeMap<String, Link>,
+    pub zpools: Vec<Zpool>,
+    pub datasets: Vec<String>,
+    pub packages: Option<PackageSummary>,
+    pub hardware: Option<Hardware>,
+    pub disks: Vec<Disk>,
+    pub virtualization: Virtualization,
+    /**
+     * The facts from fact scripts.
+     */
+    #[serde(flatten)]
+    pub custom: serde_json::Map<String, serde_json::Value>,
+}
+
+fn cstr(s: &[libc::c_char]) -> String {
+    unsafe { CStr::from_ptr(s.as_ptr()) }.to_string_lossy().to_string()
+}
+
+pub(crate) fn uname() -> Result<Uname> {
+    let mut un: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut un) } < 0 {
+        bail!("uname: {}", std::io::Error::last_os_error());
+    }
+
+    Ok(Uname {
+        sysname: cstr(&un.sysname),
+        release: cstr(&un.release),
+        version: cstr(&un.version),
+        machine: cstr(&un.machine),
+    })
+}
+
+fn sysconf(name: libc::c_int) -> u64 {
+    let v = unsafe { libc::sysconf(name) };
+    if v < 0 {
+        0
+    } else {
+        v as u64
+    }
+}
+
+fn addresses() -> Result<Vec<InterfaceAddress>> {
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        bail!("getifaddrs: {}", std::io::Error::last_os_error());
+    }
+
+    let mut out = Vec::new();
+    let mut ifa = ifap;
+    while !ifa.is_null() {
+        let i = unsafe { &*ifa };
+        ifa = i.ifa_next;
+
+        if i.ifa_addr.is_null() ||
+            i.ifa_flags & (libc::IFF_LOOPBACK as libc::c_uint) != 0
+        {
+            continue;
+        }
+
+        /*
+         * The netmask has the same family as the address.  Without one, the
+         * prefix is the whole address.
+         */
+        let mask = i.ifa_netmask;
+        let (address, prefix) = match unsafe { (*i.ifa_addr).sa_family }
+            as i32
+        {
+            libc::AF_INET => {
+                let sin = unsafe { &*(i.ifa_addr as *const libc::sockaddr_in) };
+                let prefix = if mask.is_null() {
+                    32
+                } else {
+                    unsafe { &*(mask as *const libc::sockaddr_in) }
+                        .sin_addr.s_addr.count_ones() as u8
+                };
+                (IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    sin.sin_addr.s_addr))), prefix)
+            }
+            libc::AF_INET6 => {
+                let sin6 =
+                    unsafe { &*(i.ifa_addr as *const libc::sockaddr_in6) };
+                let prefix = if mask.is_null() {
+                    128
+                } else {
+                    unsafe { &*(mask as *const libc::sockaddr_in6) }
+                        .sin6_addr.s6_addr.iter()
+                        .map(|b| b.count_ones() as u8)
+                        .sum()
+                };
+                (IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)), prefix)
+            }
+            _ => continue,
+        };
+
+        out.push(InterfaceAddress {
+            interface: unsafe { CStr::from_ptr(i.ifa_name) }
+                .to_string_lossy().to_string(),
+            address,
+            prefix,
+        });
+    }
+
+    unsafe { libc::freeifaddrs(ifap) };
+    Ok(out)
+}
+
+fn os_release() -> Result<BTreeMap<String, String>> {
+    let mut kv = BTreeMap::new();
+    match std::fs::read_to_string("/etc/os-release") {
+        Ok(s) => {
+            for l in s.lines() {
+                if let Some((k, v)) = l.split_once('=') {
+                    kv.insert(k.to_string(), v.trim_matches('"').to_string());
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => bail!("reading /etc/os-release: {}", e),
+    }
+    Ok(kv)
+}
+
+fn release(log: &Logger, os: &OS) -> Result<Release> {
+    let kv = os_release()?;
+    let mut r = Release {
+        name: kv.get("PRETTY_NAME").or_else(|| kv.get("NAME")).cloned(),
+        version: kv.get("VERSION_ID").cloned(),
+        ..Default::default()
+    };
+
+    match os {
+        OS::SmartOS | OS::OpenIndiana if r.name.is_none() => {
+            /*
+             * The first line of /etc/release names the release; e.g.,
+             * "SmartOS 20200813T030805Z x86_64".
+             */
+            if let Ok(s) = std::fs::read_to_string("/etc/release") {
+                r.name = s.lines().next().map(|l| l.trim().to_string());
+            }
+        }
+        OS::MacOS => {
+            r.name = Some("macOS".to_string());
+            r.version = Some(ensure::query_output(log,
+                &["/usr/bin/sw_vers", "-productVersion"], &Exec::default())?
+                .trim().to_string());
+        }
+        OS::OpenBSD => {
+            r.name = Some("OpenBSD".to_string());
+            r.version = Some(os_ops().uname()?.release);
+        }
+        _ => (),
+    }
+
+    if matches!(os, OS::OmniOS | OS::OpenIndiana) {
+        match ensure::query_output(log, &["/usr/bin/pkg", "list", "-H", "-v",
+            "entire"], &Exec::default())
+        {
+            Ok(out) => {
+                r.entire = out.split_whitespace().next()
+                    .map(|f| f.to_string());
+            }
+            Err(e) => warn!(log, "facts: entire: {}", e),
+        }
+    }
+    if os.is_illumos() && Path::new("/usr/sbin/beadm").exists() {
+        /*
+         * The boot environment active now has "N" in the active column; the
+         * one active on reboot has "R".
+         */
+        match super::list_boot_environments() {
+            Ok(bes) => {
+                r.boot_environment = bes.into_iter()
+                    .find(|be| be.active.contains('N'))
+                    .map(|be| be.name);
+            }
+            Err(e) => warn!(log, "facts: boot environment: {}", e),
+        }
+    }
+
+    Ok(r)
+}
+
+/**
+ * Find a command in the baseline PATH.  For example, the ZFS commands are in
+ * /usr/sbin on illumos and Linux, but in /sbin on FreeBSD.
+ */
+pub fn find_command(name: &str) -> Option<String> {
+    ensure::BASE_PATH.split(':')
+        .map(|dir| format!("{}/{}", dir, name))
+        .find(|p| std::path::Path::new(p).exists())
+}
+
+#[cfg(feature = "zfs")]
+fn zpools(log: &Logger, zpool: &str) -> Result<Vec<Zpool>> {
+    let out = ensure::query_output(log, &[zpool, "list", "-H", "-p", "-o",
+        "name,size,health"], &Exec::default())?;
+
+    let mut pools = Vec::new();
+    for l in out.lines() {
+        let f: Vec<&str> = l.split('\t').collect();
+        if f.len() != 3 {
+            bail!("unexpected zpool output: {:?}", l);
+        }
+        pools.push(Zpool {
+            name: f[0].to_string(),
+            size: f[1].parse()?,
+            health: f[2].to_string(),
+        });
+    }
+    Ok(pools)
+}
+
+#[cfg(feature = "zfs")]
+fn datasets(log: &Logger, zfs: &str) -> Result<Vec<String>> {
+    let out = ensure::query_output(log, &[zfs, "list", "-H", "-o", "name",
+        "-t", "filesystem,volume"], &Exec::default())?;
+
+    Ok(out.lines().map(|l| l.to_string()).collect())
+}
+
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CacheEntry {
+    /**
+     * When the fact was gathered, in seconds since the epoch.
+     */
+    gathered: u64,
+    value: serde_json::Value,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/**
+ * The cache of slow facts.  If "refresh" is set, no entry is used, but each
+ * is replaced as the fact is gathered.
+ */
+struct Cache {
+    entries: BTreeMap<String, CacheEntry>,
+    refresh: bool,
+    dirty: bool,
+}
+
+impl Cache {
+    fn path() -> PathBuf {
+        Path::new(STATE_DIR).join("facts.json")
+    }
+
+    fn load(log: &Logger, refresh: bool) -> Cache {
+        let p = Cache::path();
+
+        let entries = match std::fs::read_to_string(&p) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                warn!(log, "ignoring invalid fact cache {}: {}", p.display(),
+                    e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                BTreeMap::new()
+            }
+            Err(e) => {
+                warn!(log, "ignoring unreadable fact cache {}: {}",
+                    p.display(), e);
+                BTreeMap::new()
+            }
+        };
+
+        Cache {
+            entries,
+            refresh,
+            dirty: false,
+        }
+    }
+
+    /**
+     * Use the cached value of a fact if it is recent enough, or else gather
+     * it and cache it.  A fact which cannot be gathered is not cached.
+     */
+    fn get<T, F>(&mut self, log: &Logger, name: &str, gather: F) -> Result<T>
+        where T: Serialize + DeserializeOwned, F: FnOnce() -> Result<T>
+    {
+        if let (Some(e), false) = (self.entries.get(name), self.refresh) {
+            if now().saturating_sub(e.gathered) < CACHE_TTL.as_secs() {
+                if let Ok(v) = serde_json::from_value(e.value.clone()) {
+                    info!(log, "using cached fact {} (gathered {}s ago)",
+                        name, now().saturating_sub(e.gathered));
+                    return Ok(v);
+                }
+            }
+        }
+
+        let v = gather()?;
+        self.entries.insert(name.to_string(), CacheEntry {
+            gathered: now(),
+            value: serde_json::to_value(&v)?,
+        });
+        self.dirty = true;
+        Ok(v)
+    }
+
+    /**
+     * Write the cache back to disk, if anything changed.  A failure to save
+     * the cache is reported, but is not an error.
+     */
+    fn save(&self, log: &Logger) {
+        if !self.dirty {
+            return;
+        }
+
+        let p = Cache::path();
+        let tmp = p.with_extension("json.tmp");
+        let res = std::fs::create_dir_all(STATE_DIR)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(serde_json::to_string(&self.entries)?))
+            .and_then(|s| Ok(std::fs::write(&tmp, s)?))
+            .and_then(|_| Ok(std::fs::rename(&tmp, &p)?));
+        if let Err(e) = res {
+            warn!(log, "could not save fact cache {}: {}", p.display(), e);
+        }
+    }
+}
+
+/**
+ * The directories of fact scripts for a confomat data directory.
+ */
+pub fn script_dirs(dir: &Path) -> Vec<PathBuf> {
+    vec![dir.join("facts.d"), Path::new(STATE_DIR).join("facts.d")]
+}
+
+fn run_script(log: &Logger, path: &Path)
+    -> Result<serde_json::Map<String, serde_json::Value>>
+{
+    let out = ensure::query_output(log, &[path.to_str().unwrap()],
+        &Exec::default())?;
+    match serde_json::from_str(&out) {
+        Ok(serde_json::Value::Object(m)) => Ok(m),
+        Ok(_) => bail!("output is not a JSON object"),
+        Err(e) => bail!("output is not valid JSON: {}", e),
+    }
+}
+
+/**
+ * Run the fact scripts in these directories, which need not exist.  A
+ * script which fails, or whose output is not a JSON object, is reported and
+ * its facts are left out.
+ */
+fn scripts(log: &Logger, dirs: &[PathBuf])
+    -> serde_json::Map<String, serde_json::Value>
+{
+    let mut out = serde_json::Map::new();
+
+    for dir in dirs.iter() {
+        let mut paths: Vec<PathBuf> = match std::fs::read_dir(dir) {
+            Ok(rd) => rd.filter_map(|ent| ent.ok())
+                .map(|ent| ent.path())
+                .filter(|p| std::fs::metadata(p)
+                    .map(|md| md.is_file() && md.permissions().mode() & 0o111
+                        != 0)
+                    .unwrap_or(false))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                warn!(log, "facts: reading {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        paths.sort();
+
+        for p in paths.iter() {
+            info!(log, "running fact script {}", p.display());
+            match run_script(log, p) {
+                Ok(m) => out.extend(m),
+                Err(e) => warn!(log, "facts: script {}: {}", p.display(), e),
+            }
+        }
+    }
+
+    out
+}
+
+/**
+ * Gather the facts about this system, including those from the fact scripts
+ * in "dirs".  If "refresh" is set, cached facts are not used.  On systems
+ * without ZFS, or when built without the "zfs" feature, there are no pools
+ * or datasets.
+ */
+pub fn gather(log: &Logger, os: &OS, dirs: &[PathBuf], refresh: bool)
+    -> Facts
+{
+    let ops = os_ops();
+    let mut cache = Cache::load(log, refresh);
+
+    let uname = ops.uname().unwrap_or_else(|e| {
+        warn!(log, "facts: {}", e);
+        Uname {
+            sysname: String::new(),
+            release: String::new(),
+            version: String::new(),
+            machine: String::new(),
+        }
+    });
+
+    let release = release(log, os).unwrap_or_else(|e| {
+        warn!(log, "facts: release: {}", e);
+        Release::default()
+    });
+
+    let addresses = addresses().unwrap_or_else(|e| {
+        warn!(log, "facts: addresses: {}", e);
+        Vec::new()
+    });
+
+    let mut ipaddrs: BTreeMap<String, IpAddr> = BTreeMap::new();
+    for a in addresses.iter() {
+        let replace = match ipaddrs.get(&a.interface) {
+            Some(cur) => cur.is_ipv6() && a.address.is_ipv4(),
+            None => true,
+        };
+        if replace {
+            ipaddrs.insert(a.interface.clone(), a.address);
+        }
+    }
+
+    let links = links::gather(log, os, &addresses);
+
+    #[cfg(feature = "zfs")]
+    let (zpools, datasets) = match (find_command("zpool"),
+        find_command("zfs"))
+    {
+        (Some(zpool), Some(zfs)) => (
+            cache.get(log, "zpools", || zpools(log, &zpool))
+                .unwrap_or_else(|e| {
+                    warn!(log, "facts: pools: {}", e);
+                    Vec::new()
+                }),
+            cache.get(log, "datasets", || datasets(log, &zfs))
+                .unwrap_or_else(|e| {
+                    warn!(log, "facts: datasets: {}", e);
+                    Vec::new()
+                }),
+        ),
+        _ => (Vec::new(), Vec::new()),
+    };
+    #[cfg(not(feature = "zfs"))]
+    let (zpools, datasets) = (Vec::new(), Vec::new());
+
+    let packages = match Manager::for_os(os) {
+        Ok(m) => match cache.get(log, "packages", || {
+            Ok(PackageSummary {
+                manager: format!("{:?}", m),
+                installed: packages::count(log, m)?,
+            })
+        }) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!(log, "facts: packages: {}", e);
+                None
+            }
+        },
+        Err(_) => None,
+    };
+    let hardware = cache.get(log, "hardware", || {
+        Ok(hardware::gather(log, os))
+    }).ok();
+    let disks = cache.get(log, "disks", || hardware::disks(log, os))
+        .unwrap_or_else(|e| {
+            warn!(log, "facts: disks: {}", e);
+            Vec::new()
+        });
+    cache.save(log);
+
+    let virtualization = hardware::virtualization(log, os, ops.zoneid(),
+        &uname.version, hardware.as_ref());
+
+    let mut facts = Facts {
+        os: format!("{:?}", os),
+        nodename: ops.nodename(),
+        zonename: ops.zonename(),
+        zoneid: ops.zoneid(),
+        uname,
+        release,
+        memory: sysconf(libc::_SC_PHYS_PAGES) * sysconf(libc::_SC_PAGESIZE),
+        cpus: sysconf(libc::_SC_NPROCESSORS_ONLN) as u32,
+        addresses,
+        ipaddrs,
+        links,
+        zpools,
+        datasets,
+        packages,
+        hardware,
+        disks,
+        virtualization,
+        custom: serde_json::Map::new(),
+    };
+
+    /*
+     * A script cannot replace a built-in fact.
+     */
+    let builtin = serde_json::to_value(&facts).expect("facts serialise");
+    for (k, v) in scripts(log, dirs) {
+        if builtin.get(k.as_str()).is_some() {
+            warn!(log, "facts: ignoring script fact \"{}\", which is \
+                built in", k);
+            continue;
+        }
+        facts.custom.insert(k, v);
+    }
+
+    facts
+}
+
+fn flatten(name: &str, v: &serde_json::Value, out: &mut Vec<String>) {
+    let child = |k: &str| {
+        if name.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}.{}", name, k)
+        }
+    };
+
+    match v {
+        serde_json::Value::Object(m) if !m.is_empty() => {
+            for (k, v) in m.iter() {
+                flatten(&child(k), v, out);
+            }
+        }
+        serde_json::Value::Array(a) if !a.is_empty() => {
+            for (i, v) in a.iter().enumerate() {
+                flatten(&child(&i.to_string()), v, out);
+            }
+        }
+        serde_json::Value::String(s) => out.push(format!("{} = {}", name, s)),
+        v => out.push(format!("{} = {}", name, v)),
+    }
+}
+
+/**
+ * Print the facts for "confomat facts": as JSON, or as one line per fact,
+ * named as in templates; e.g., "uname.release = 5.11".
+ */
+pub fn print(facts: &Facts, json: bool) -> Result<()> {
+    let v = serde_json::to_value(facts)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&v)?);
+    } else {
+        let mut out = Vec::new();
+        flatten("", &v, &mut out);
+        for l in out.iter() {
+            println!("{}", l);
+        }
+    }
+
+    Ok(())
+}
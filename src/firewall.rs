@@ -0,0 +1,71 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use std::path::Path;
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, Ownership};
+use super::smf;
+
+pub const IPFILTER: &str = "svc:/network/ipfilter:default";
+const IPF_CONF: &str = "/etc/ipf/ipf.conf";
+const IPNAT_CONF: &str = "/etc/ipf/ipnat.conf";
+
+/*
+ * Check the syntax of a candidate rule set without loading it, using the "-n"
+ * flag of ipf(1M) or ipnat(1M).
+ */
+fn validate(log: &Logger, tool: &str, rules: &str) -> Result<()> {
+    let tmp = temp_file(&format!("{}.conf",
+        Path::new(tool).file_name().unwrap().to_string_lossy()),
+        rules.as_bytes())?;
+
+    let out = std::process::Command::new(tool)
+        .env_clear()
+        .arg("-n")
+        .arg("-f").arg(tmp.path())
+        .output()?;
+
+    if !out.status.success() {
+        bail!("{} rejected candidate rules: {}", tool, out.info());
+    }
+
+    info!(log, "{} accepted candidate rules", tool);
+    Ok(())
+}
+
+/**
+ * Install the ipf.conf(4) and (optionally) ipnat.conf(4) rule sets.  Each rule
+ * set is validated before it is installed, so that a rule set with a syntax
+ * error is never activated.  Returns true if the service should be refreshed
+ * to load new rules.
+ */
+pub fn rules(log: &Logger, ipf: &str, ipnat: Option<&str>) -> Result<bool> {
+    validate(log, "/usr/sbin/ipf", ipf)?;
+    if let Some(ipnat) = ipnat {
+        validate(log, "/usr/sbin/ipnat", ipnat)?;
+    }
+
+    let own = Ownership::Names("root", "sys");
+    let mut changed = ensure::contents(log, IPF_CONF, ipf.as_bytes(), &own,
+        0o644)?;
+    if let Some(ipnat) = ipnat {
+        changed |= ensure::contents(log, IPNAT_CONF, ipnat.as_bytes(), &own,
+            0o644)?;
+    }
+
+    /*
+     * Direct the service to use our rule set, rather than a policy generated
+     * from per-service firewall properties.
+     */
+    changed |= smf::ensure_property(log, IPFILTER,
+        "firewall_config_default/policy", "astring", &["custom"])?;
+    changed |= smf::ensure_property(log, IPFILTER,
+        "firewall_config_default/custom_policy_file", "astring",
+        &[IPF_CONF])?;
+
+    Ok(changed)
+}
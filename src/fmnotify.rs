@@ -0,0 +1,203 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Notification parameters for events on illumos (see smf(5) and
+ * svccfg(1M)): service state transitions (e.g., "to-maintenance"), and
+ * problems diagnosed by the fault manager (e.g., "problem-diagnosed").  The
+ * parameters are set with "svccfg setnotify" and read back with
+ * "svccfg listnotify"; email is sent by the smtp-notify service, and SNMP
+ * traps by snmp-notify.  The parameters for state transitions are set system
+ * wide (with "-g"), so that they apply to every service which does not have
+ * its own.
+ */
+
+use std::collections::BTreeMap;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::ensure::{self, Exec};
+
+pub const SMTP_NOTIFY: &str = "svc:/system/fm/smtp-notify:default";
+pub const SNMP_NOTIFY: &str = "svc:/system/fm/snmp-notify:default";
+const SVCCFG: &str = "/usr/sbin/svccfg";
+
+/**
+ * Where to send notifications of service maintenance and of hardware faults;
+ * e.g.,
+ *
+ *      EventNotify {
+ *          mailto: vec!["ops@example.com".into()],
+ *          ..Default::default()
+ *      }
+ *
+ * Notification parameters for events which are not listed are left as they
+ * are.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventNotify {
+    /**
+     * Email addresses to which notifications are sent.
+     */
+    pub mailto: Vec<String>,
+    /**
+     * Whether SNMP traps are sent.
+     */
+    pub snmp: bool,
+    /**
+     * Service state transitions; the default is "to-maintenance".
+     */
+    pub transitions: Vec<String>,
+    /**
+     * Fault management events; the default is "problem-diagnosed".  These
+     * are only set in the global zone, where the fault manager runs.
+     */
+    pub problems: Vec<String>,
+}
+
+impl Default for EventNotify {
+    fn default() -> EventNotify {
+        EventNotify {
+            mailto: Vec::new(),
+            snmp: false,
+            transitions: vec!["to-maintenance".to_string()],
+            problems: vec!["problem-diagnosed".to_string()],
+        }
+    }
+}
+
+impl EventNotify {
+    /**
+     * The parameters for "svccfg setnotify"; e.g., "mailto:ops@example.com".
+     */
+    pub fn params(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        if !self.mailto.is_empty() {
+            out.push(format!("mailto:{}", self.mailto.join(",")));
+        }
+        if self.snmp {
+            out.push("snmp:active".to_string());
+        }
+        out
+    }
+}
+
+/*
+ * The active notification types for an event, with their recipients, from
+ * the output of "svccfg listnotify"; e.g.,
+ *
+ *      Event: to-maintenance (source: svc:/system/svc/global:default)
+ *              Notification Type: smtp
+ *                      Active: true
+ *                      to: root@localhost
+ */
+fn current(log: &Logger, event: &str, global: bool)
+    -> Result<BTreeMap<String, Vec<String>>>
+{
+    let mut args = vec![SVCCFG, "listnotify"];
+    if global {
+        args.push("-g");
+    }
+    args.push(event);
+    let out = ensure::query_output(log, &args, &Exec::default())?;
+
+    let mut types: BTreeMap<String, (bool, Vec<String>)> = BTreeMap::new();
+    let mut cur: Option<String> = None;
+    for l in out.lines() {
+        let (k, v) = match l.split_once(':') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+        match (k, &cur) {
+            ("Notification Type", _) => {
+                types.insert(v.to_string(), (false, Vec::new()));
+                cur = Some(v.to_string());
+            }
+            ("Active", Some(t)) => {
+                types.get_mut(t).unwrap().0 = v == "true";
+            }
+            ("to", Some(t)) => {
+                types.get_mut(t).unwrap().1.extend(v
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|a| !a.is_empty())
+                    .map(|a| a.to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(types.into_iter()
+        .filter(|(_, (active, _))| *active)
+        .map(|(t, (_, to))| (t, to))
+        .collect())
+}
+
+fn ensure_event(log: &Logger, en: &EventNotify, event: &str, global: bool)
+    -> Result<bool>
+{
+    if event.is_empty() || event.contains(char::is_whitespace) {
+        bail!("invalid notification event \"{}\"", event);
+    }
+
+    let have = current(log, event, global)?;
+    let mut ok = true;
+    if !en.mailto.is_empty() {
+        let mut want = en.mailto.clone();
+        want.sort();
+        let mut to = have.get("smtp").cloned();
+        if let Some(to) = to.as_mut() {
+            to.sort();
+        }
+        ok &= to.as_ref() == Some(&want);
+    }
+    if en.snmp {
+        ok &= have.contains_key("snmp");
+    }
+    if ok {
+        info!(log, "notification for {} ok ({:?})", event, have);
+        return Ok(false);
+    }
+
+    info!(log, "notification for {} is {:?}, want {:?}", event, have,
+        en.params());
+    let mut args = vec![SVCCFG.to_string(), "setnotify".to_string()];
+    if global {
+        args.push("-g".to_string());
+    }
+    args.push(event.to_string());
+    args.extend(en.params());
+    ensure::run(log, &args)?;
+    Ok(true)
+}
+
+/**
+ * Ensure the notification parameters for service state transitions and, if
+ * "problems" is set, for fault management events.  Returns whether any
+ * parameter was changed.
+ */
+pub fn ensure(log: &Logger, en: &EventNotify, problems: bool)
+    -> Result<bool>
+{
+    if en.mailto.is_empty() && !en.snmp {
+        bail!("notifications need at least one email address, or SNMP");
+    }
+    if let Some(a) = en.mailto.iter()
+        .find(|a| !a.contains('@') || a.contains(|c: char| c == ','
+        || c.is_whitespace()))
+    {
+        bail!("invalid email address \"{}\"", a);
+    }
+
+    let mut did_work = false;
+    for t in en.transitions.iter() {
+        did_work |= ensure_event(log, en, t, true)?;
+    }
+    if problems {
+        for p in en.problems.iter() {
+            did_work |= ensure_event(log, en, p, false)?;
+        }
+    }
+    Ok(did_work)
+}
@@ -0,0 +1,41 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The FreeBSD counterpart of the illumos module.  Users and groups are looked
+ * up through nsswitch.conf(5), and are created and altered with pw(8).  Jails
+ * are not managed, so a jail is treated as though it were the global zone.
+ */
+
+use anyhow::Result;
+
+pub use super::unix::{get_group_ids_for_user, get_id_from_file, nodename,
+    openpty, set_controlling_tty, Pty};
+pub use super::nss::{get_group_by_id, get_group_by_name, get_passwd_by_id,
+    get_passwd_by_name, Group, Passwd};
+
+pub fn zoneid() -> i32 {
+    0
+}
+
+pub fn zonename() -> String {
+    "global".to_string()
+}
+
+/**
+ * FreeBSD has no privilege sets like those of illumos.  A process which
+ * changes from root to another user has no special privileges, so apply()
+ * does nothing.
+ */
+pub struct PrivSet;
+
+impl PrivSet {
+    pub fn basic() -> Result<PrivSet> {
+        Ok(PrivSet)
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The platform module for UNIX systems which confomat does not otherwise
+ * support (e.g., NetBSD), so that roles can at least be built and tested
+ * there, and applied in dry-run mode; e.g., to a tree of fixtures with
+ * "--root".  As elsewhere outside illumos, there are no zones and no
+ * privilege sets.
+ */
+
+use anyhow::Result;
+
+pub use super::unix::{get_group_ids_for_user, get_id_from_file, nodename,
+    openpty, set_controlling_tty, Pty};
+pub use super::nss::{get_group_by_id, get_group_by_name, get_passwd_by_id,
+    get_passwd_by_name, Group, Passwd};
+
+pub fn zoneid() -> i32 {
+    0
+}
+
+pub fn zonename() -> String {
+    "global".to_string()
+}
+
+pub struct PrivSet;
+
+impl PrivSet {
+    pub fn basic() -> Result<PrivSet> {
+        Ok(PrivSet)
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,618 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Hardware facts: the identity of the system and its memory modules, from
+ * SMBIOS, and the PCI devices.  On illumos, these come from smbios(1M) and
+ * prtconf(1M).  Elsewhere, SMBIOS is read with dmidecode(8), if it is
+ * installed, and on Linux the PCI devices are listed from sysfs.  Reading
+ * SMBIOS generally requires root; an unprivileged run has no hardware facts.
+ *
+ * The attached disks are also hardware facts, so that roles can lay out pools
+ * or choose scratch devices based on what is present.  They come from
+ * diskinfo(1M) and iostat(1M) on illumos, and from sysfs on Linux.
+ *
+ * From these and the zone, we also determine whether confomat is running on
+ * a physical machine, in a virtual machine, or in a zone, so that roles can
+ * skip steps which touch hardware (see Context::need_bare_metal()).
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use slog::{Logger, warn};
+use anyhow::{Result, bail};
+
+use super::ensure::{self, Exec};
+use super::facts::find_command;
+use super::OS;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryModule {
+    /**
+     * The slot in which the module is installed; e.g., "DIMMA1".
+     */
+    pub locator: String,
+    /**
+     * The size of the module, in bytes.
+     */
+    pub size: u64,
+    /**
+     * e.g., "DDR4".
+     */
+    pub kind: Option<String>,
+    /**
+     * e.g., "2400 MT/s".
+     */
+    pub speed: Option<String>,
+    pub manufacturer: Option<String>,
+    pub part: Option<String>,
+    pub serial: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PciDevice {
+    /**
+     * The vendor and device IDs, in hexadecimal; e.g., "8086" and "1533".
+     */
+    pub vendor_id: String,
+    pub device_id: String,
+    /**
+     * A description of the device, where the system has one; e.g., "Intel
+     * Corporation I210 Gigabit Network Connection".
+     */
+    pub description: Option<String>,
+    /**
+     * The driver attached to the device, if any.
+     */
+    pub driver: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hardware {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub memory_modules: Vec<MemoryModule>,
+    pub pci_devices: Vec<PciDevice>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Disk {
+    /**
+     * The device name; e.g., "c1t0d0" on illumos, or "sda" on Linux.
+     */
+    pub device: String,
+    /**
+     * The size of the disk, in bytes.
+     */
+    pub size: u64,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /**
+     * Whether the disk is solid state, if that is known.
+     */
+    pub ssd: Option<bool>,
+    pub removable: bool,
+}
+
+type Record = BTreeMap<String, String>;
+
+/*
+ * Split the output of smbios(1M) or dmidecode(8) into a record for each
+ * structure.  Each structure starts with an unindented header line (a row of
+ * "ID SIZE TYPE" for smbios, or "Handle ..." for dmidecode), followed by
+ * indented "Name: value" lines.
+ */
+fn records(out: &str) -> Vec<Record> {
+    let mut recs = Vec::new();
+    let mut cur: Option<Record> = None;
+
+    for l in out.lines() {
+        if l.starts_with("Handle ") ||
+            l.starts_with(|c: char| c.is_ascii_digit())
+        {
+            if let Some(r) = cur.take() {
+                recs.push(r);
+            }
+            cur = Some(Record::new());
+        } else if l.starts_with(char::is_whitespace) {
+            if let (Some(r), Some((k, v))) = (cur.as_mut(),
+                l.trim().split_once(": "))
+            {
+                r.entry(k.to_string())
+                    .or_insert_with(|| v.trim().to_string());
+            }
+        }
+    }
+    if let Some(r) = cur {
+        recs.push(r);
+    }
+
+    recs
+}
+
+/*
+ * The first of these fields which is present, and which is not a placeholder
+ * that firmware commonly leaves in unset fields.
+ */
+fn field(r: &Record, names: &[&str]) -> Option<String> {
+    names.iter()
+        .filter_map(|n| r.get(*n))
+        .find(|v| {
+            !v.is_empty() && !["Not Specified", "Unknown", "None",
+                "To Be Filled By O.E.M."].contains(&v.as_str())
+        })
+        .cloned()
+}
+
+/*
+ * A size such as "17179869184 bytes (16 GB)" (smbios) or "16 GB"
+ * (dmidecode).  An empty slot has no size.
+ */
+fn size(s: &str) -> Option<u64> {
+    let mut w = s.split_whitespace();
+    let n: u64 = w.next()?.parse().ok()?;
+    let mult: u64 = match w.next()? {
+        "bytes" => 1,
+        "kB" | "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        "TB" => 1 << 40,
+        _ => return None,
+    };
+    Some(n * mult).filter(|&n| n > 0)
+}
+
+/*
+ * smbios(1M) shows enumerated values as a number with a description; e.g.,
+ * "26 (DDR4)".
+ */
+fn described(s: String) -> String {
+    match (s.find('('), s.rfind(')')) {
+        (Some(a), Some(b)) if a < b => s[a + 1..b].to_string(),
+        _ => s,
+    }
+}
+
+fn memory_module(r: &Record) -> Option<MemoryModule> {
+    Some(MemoryModule {
+        size: size(r.get("Size")?)?,
+        locator: field(r, &["Device Locator", "Locator", "Location Tag"])
+            .unwrap_or_default(),
+        kind: field(r, &["Memory Type", "Type"]).map(described),
+        speed: field(r, &["Speed"]),
+        manufacturer: field(r, &["Manufacturer"]),
+        part: field(r, &["Part Number"]),
+        serial: field(r, &["Serial Number"]),
+    })
+}
+
+/*
+ * Read the system and memory device structures, with a command which takes
+ * the structure type as its last argument.
+ */
+fn smbios(log: &Logger, cmd: &[&str], system: &str, memdevice: &str)
+    -> Result<(Option<Record>, Vec<MemoryModule>)>
+{
+    let query = |t: &str| -> Result<Vec<Record>> {
+        let mut args = cmd.to_vec();
+        args.push(t);
+        Ok(records(&ensure::query_output(log, &args, &Exec::default())?))
+    };
+
+    let sys = query(system)?.into_iter().next();
+    let modules = query(memdevice)?.iter()
+        .filter_map(memory_module)
+        .collect();
+
+    Ok((sys, modules))
+}
+
+/*
+ * Parse a PCI device line from "prtconf -d"; e.g.,
+ *
+ *      pci15d9,1533 (pciex8086,1533) [Intel Corporation I210 Gigabit Network
+ *      Connection], instance #0 (driver name: igb)
+ *
+ * (all on one line).  The IDs in parentheses are those of the device itself;
+ * the node name may instead carry the subsystem IDs.
+ */
+fn prtconf_device(l: &str) -> Option<PciDevice> {
+    let l = l.trim();
+    let open = l.find(" (pci")?;
+    let ids = &l[open + 2..open + 2 + l[open + 2..].find(')')?];
+    let (vendor, device) = ids.trim_start_matches("pciex")
+        .trim_start_matches("pci")
+        .split_once(',')?;
+    let device = device.split('.').next()?;
+
+    let description = match (l.find(" ["), l.find("], ")) {
+        (Some(a), Some(b)) if a < b => Some(l[a + 2..b].to_string()),
+        _ => None,
+    };
+    let driver = l.find("(driver name: ")
+        .and_then(|a| {
+            let d = &l[a + 14..];
+            d.find(')').map(|b| d[..b].to_string())
+        });
+
+    Some(PciDevice {
+        vendor_id: vendor.to_string(),
+        device_id: device.to_string(),
+        description,
+        driver,
+    })
+}
+
+fn prtconf_devices(log: &Logger) -> Result<Vec<PciDevice>> {
+    let out = ensure::query_output(log, &["/usr/sbin/prtconf", "-d"],
+        &Exec::default())?;
+
+    Ok(out.lines().filter_map(prtconf_device).collect())
+}
+
+fn sysfs_devices() -> Result<Vec<PciDevice>> {
+    let id = |p: &Path, n: &str| -> Result<String> {
+        Ok(std::fs::read_to_string(p.join(n))?.trim()
+            .trim_start_matches("0x").to_string())
+    };
+
+    let mut devs = Vec::new();
+    let mut ents: Vec<_> = std::fs::read_dir("/sys/bus/pci/devices")?
+        .collect::<std::io::Result<_>>()?;
+    ents.sort_by_key(|e| e.file_name());
+    for ent in ents.iter() {
+        let p = ent.path();
+        devs.push(PciDevice {
+            vendor_id: id(&p, "vendor")?,
+            device_id: id(&p, "device")?,
+            description: None,
+            driver: std::fs::read_link(p.join("driver")).ok()
+                .and_then(|d| d.file_name()
+                    .map(|f| f.to_string_lossy().to_string())),
+        });
+    }
+    Ok(devs)
+}
+
+/**
+ * Gather the hardware facts.  Any which cannot be determined are reported, and
+ * left empty.
+ */
+pub fn gather(log: &Logger, os: &OS) -> Hardware {
+    let res = if os.is_illumos() {
+        smbios(log, &["/usr/sbin/smbios", "-t"],
+            "SMB_TYPE_SYSTEM", "SMB_TYPE_MEMDEVICE")
+    } else {
+        match find_command("dmidecode") {
+            Some(d) => smbios(log, &[&d, "-t"], "1", "17"),
+            None => Ok((None, Vec::new())),
+        }
+    };
+    let (sys, memory_modules) = res.unwrap_or_else(|e| {
+        warn!(log, "facts: SMBIOS: {}", e);
+        (None, Vec::new())
+    });
+
+    let pci = if os.is_illumos() {
+        prtconf_devices(log)
+    } else if os.is_linux() {
+        sysfs_devices()
+    } else {
+        Ok(Vec::new())
+    };
+    let pci_devices = pci.unwrap_or_else(|e| {
+        warn!(log, "facts: PCI devices: {}", e);
+        Vec::new()
+    });
+
+    let sys = sys.unwrap_or_default();
+    Hardware {
+        manufacturer: field(&sys, &["Manufacturer"]),
+        product: field(&sys, &["Product", "Product Name"]),
+        serial: field(&sys, &["Serial Number"]),
+        memory_modules,
+        pci_devices,
+    }
+}
+
+fn opt(s: &str) -> Option<String> {
+    let s = s.trim();
+    if s.is_empty() || s == "-" {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+/*
+ * The serial number of each disk, from "iostat -En", which shows a block for
+ * each device like:
+ *
+ *      c1t0d0           Soft Errors: 0 Hard Errors: 0 Transport Errors: 0
+ *      Vendor: ATA      Product: INTEL SSDSC2BB24 Revision: 0370 Serial No: X
+ */
+fn iostat_serials(log: &Logger) -> Result<BTreeMap<String, String>> {
+    let out = ensure::query_output(log, &["/usr/bin/iostat", "-En"],
+        &Exec::default())?;
+
+    let mut serials = BTreeMap::new();
+    let mut dev: Option<String> = None;
+    for l in out.lines() {
+        if !l.starts_with(char::is_whitespace) && l.contains("Soft Errors:") {
+            dev = l.split_whitespace().next().map(|d| d.to_string());
+        } else if let (Some(d), Some(i)) = (&dev, l.find("Serial No:")) {
+            if let Some(s) = opt(&l[i + 10..]) {
+                serials.insert(d.clone(), s);
+            }
+        }
+    }
+    Ok(serials)
+}
+
+/*
+ * The parseable output of "diskinfo -Hp" has tab-separated columns: TYPE,
+ * DISK, VID, PID, SIZE (in bytes), RMV, and SSD.
+ */
+fn diskinfo(log: &Logger) -> Result<Vec<Disk>> {
+    let out = ensure::query_output(log, &["/usr/bin/diskinfo", "-Hp"],
+        &Exec::default())?;
+    let serials = iostat_serials(log).unwrap_or_else(|e| {
+        warn!(log, "facts: disk serial numbers: {}", e);
+        BTreeMap::new()
+    });
+
+    let mut disks = Vec::new();
+    for l in out.lines() {
+        let f: Vec<&str> = l.split('\t').collect();
+        if f.len() != 7 {
+            bail!("unexpected diskinfo output: {:?}", l);
+        }
+        disks.push(Disk {
+            device: f[1].to_string(),
+            size: f[4].parse().unwrap_or(0),
+            vendor: opt(f[2]),
+            model: opt(f[3]),
+            serial: serials.get(f[1]).cloned(),
+            ssd: match f[6] {
+                "yes" => Some(true),
+                "no" => Some(false),
+                _ => None,
+            },
+            removable: f[5] == "yes",
+        });
+    }
+    Ok(disks)
+}
+
+/*
+ * Block devices in sysfs without a "device" link are virtual (e.g., loop,
+ * ram, or device-mapper devices), and are not disks.
+ */
+fn sysfs_disks() -> Result<Vec<Disk>> {
+    let read = |p: &Path| {
+        std::fs::read_to_string(p).ok().and_then(|s| opt(&s))
+    };
+
+    let mut disks = Vec::new();
+    let mut ents: Vec<_> = std::fs::read_dir("/sys/block")?
+        .collect::<std::io::Result<_>>()?;
+    ents.sort_by_key(|e| e.file_name());
+    for ent in ents.iter() {
+        let p = ent.path();
+        let dev = p.join("device");
+        if !dev.exists() {
+            continue;
+        }
+
+        /*
+         * The size is always in 512-byte sectors.
+         */
+        let sectors: u64 = read(&p.join("size"))
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0);
+        disks.push(Disk {
+            device: ent.file_name().to_string_lossy().to_string(),
+            size: sectors * 512,
+            vendor: read(&dev.join("vendor")),
+            model: read(&dev.join("model")),
+            serial: read(&dev.join("serial"))
+                .or_else(|| read(&dev.join("wwid"))),
+            ssd: read(&p.join("queue/rotational")).map(|r| r == "0"),
+            removable: read(&p.join("removable")).as_deref() == Some("1"),
+        });
+    }
+    Ok(disks)
+}
+
+/**
+ * Gather the attached disks.  On systems other than illumos and Linux, there
+ * are none.
+ */
+pub fn disks(log: &Logger, os: &OS) -> Result<Vec<Disk>> {
+    if os.is_illumos() {
+        diskinfo(log)
+    } else if os.is_linux() {
+        sysfs_disks()
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/**
+ * The context in which confomat is running.
+ */
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Virtualization {
+    /**
+     * "metal" for a physical machine, "vm" for a virtual machine, "zone" for
+     * an illumos non-global zone, or "lx" for an lx-branded zone.
+     */
+    pub context: String,
+    /**
+     * For a virtual machine, the hypervisor, where it can be told; e.g.,
+     * "bhyve", "kvm", "vmware", "hyperv", "xen", or "virtualbox".
+     */
+    pub hypervisor: Option<String>,
+    /**
+     * For a zone, the brand; e.g., "sparse", "lipkg", or "lx".
+     */
+    pub brand: Option<String>,
+}
+
+impl Virtualization {
+    pub fn is_virtual(&self) -> bool {
+        self.context != "metal"
+    }
+
+    /**
+     * A description of the context, for messages; e.g., "bhyve guest".
+     */
+    pub fn describe(&self) -> String {
+        match (self.context.as_str(), &self.hypervisor, &self.brand) {
+            ("vm", Some(h), _) => format!("{} guest", h),
+            ("vm", None, _) => "virtual machine".to_string(),
+            ("zone", _, Some(b)) => format!("{} zone", b),
+            ("lx", _, _) => "lx zone".to_string(),
+            (c, _, _) => c.to_string(),
+        }
+    }
+}
+
+/*
+ * Recognise a hypervisor from the SMBIOS system manufacturer and product.
+ */
+fn hypervisor_smbios(manufacturer: &str, product: &str)
+    -> Option<&'static str>
+{
+    let m = manufacturer.to_ascii_lowercase();
+    let p = product.to_ascii_lowercase();
+
+    if p.contains("bhyve") {
+        Some("bhyve")
+    } else if m.contains("vmware") || p.contains("vmware") {
+        Some("vmware")
+    } else if m.contains("microsoft") && p.contains("virtual machine") {
+        Some("hyperv")
+    } else if m.contains("xen") || p.contains("hvm domu") {
+        Some("xen")
+    } else if m.contains("innotek") || p.contains("virtualbox") {
+        Some("virtualbox")
+    } else if m.contains("parallels") || p.contains("parallels") {
+        Some("parallels")
+    } else if m.contains("qemu") || p.contains("kvm") ||
+        ["amazon ec2", "google", "digitalocean", "openstack"].iter()
+            .any(|v| m.starts_with(v))
+    {
+        Some("kvm")
+    } else {
+        None
+    }
+}
+
+/*
+ * Otherwise, recognise a hypervisor from the PCI vendors of the emulated or
+ * paravirtual devices it provides.  Both bhyve and KVM provide virtio
+ * devices.
+ */
+fn hypervisor_pci(devs: &[PciDevice]) -> Option<&'static str> {
+    devs.iter().find_map(|d| match d.vendor_id.as_str() {
+        "15ad" => Some("vmware"),
+        "1414" => Some("hyperv"),
+        "5853" => Some("xen"),
+        "80ee" => Some("virtualbox"),
+        "1ab8" => Some("parallels"),
+        "1af4" => Some("virtio"),
+        _ => None,
+    })
+}
+
+/**
+ * Determine the context in which confomat is running: in a zone (from the
+ * zone ID, or for lx, the kernel version), or in a virtual machine (from the
+ * SMBIOS system identity, or the PCI devices present).
+ */
+pub fn virtualization(log: &Logger, os: &OS, zoneid: i32, version: &str,
+    hw: Option<&Hardware>)
+    -> Virtualization
+{
+    if version.contains("BrandZ") {
+        return Virtualization {
+            context: "lx".to_string(),
+            hypervisor: None,
+            brand: Some("lx".to_string()),
+        };
+    }
+    if os.is_illumos() && zoneid != 0 {
+        /*
+         * Within a zone, "zoneadm list -p" shows only that zone; the sixth
+         * field is the brand.
+         */
+        let brand = match ensure::query_output(log,
+            &["/usr/sbin/zoneadm", "list", "-p"], &Exec::default())
+        {
+            Ok(out) => out.lines().next()
+                .and_then(|l| super::common::split_parseable(l).get(5)
+                    .cloned()),
+            Err(e) => {
+                warn!(log, "facts: zone brand: {}", e);
+                None
+            }
+        };
+        return Virtualization {
+            context: "zone".to_string(),
+            hypervisor: None,
+            brand,
+        };
+    }
+
+    /*
+     * On Linux, the system identity in sysfs is readable without privilege,
+     * even when SMBIOS itself is not.
+     */
+    let dmi = |n: &str| {
+        std::fs::read_to_string(format!("/sys/class/dmi/id/{}", n)).ok()
+            .map(|s| s.trim().to_string())
+    };
+    let (manufacturer, product) = match hw {
+        Some(hw) if hw.manufacturer.is_some() || hw.product.is_some() => {
+            (hw.manufacturer.clone(), hw.product.clone())
+        }
+        _ if os.is_linux() => (dmi("sys_vendor"), dmi("product_name")),
+        _ => (None, None),
+    };
+
+    let mut hypervisor = hypervisor_smbios(
+        manufacturer.as_deref().unwrap_or(""),
+        product.as_deref().unwrap_or(""))
+        .or_else(|| hw.and_then(|hw| hypervisor_pci(&hw.pci_devices)))
+        .map(|h| h.to_string());
+    let mut context = if hypervisor.is_some() { "vm" } else { "metal" };
+
+    /*
+     * Virtio devices alone do not tell bhyve from KVM.
+     */
+    if hypervisor.as_deref() == Some("virtio") {
+        hypervisor = None;
+    }
+    if context == "metal" && os.is_linux() {
+        let flagged = std::fs::read_to_string("/proc/cpuinfo")
+            .map(|c| c.lines()
+                .filter(|l| l.starts_with("flags"))
+                .any(|l| l.split_whitespace().any(|f| f == "hypervisor")))
+            .unwrap_or(false);
+        if flagged {
+            context = "vm";
+        }
+    }
+
+    Virtualization {
+        context: context.to_string(),
+        hypervisor,
+        brand: None,
+    }
+}
@@ -0,0 +1,348 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * A summary of each run is recorded in a small SQLite database,
+ * "/var/confomat/history.db", so that the history of a host can be examined
+ * on the host itself with "confomat history" (the most recent runs) or
+ * "confomat history RUN" (the roles of one run).  The database is written
+ * with sqlite3(1), and records only the most recent runs.  A failure to
+ * record a run is reported, but does not fail the run.
+ */
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde::Serialize;
+use slog::{Logger, debug, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::STATE_DIR;
+use super::ensure::{self, Exec};
+use super::facts::find_command;
+
+const DB: &str = "history.db";
+const KEEP_RUNS: u32 = 1000;
+
+/*
+ * The version of the schema, kept as the "user_version" of the database, so
+ * that the tables are created once, when the database is new.
+ */
+const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        started REAL NOT NULL,
+        finished REAL NOT NULL,
+        version TEXT NOT NULL,
+        dry_run INTEGER NOT NULL,
+        result TEXT NOT NULL,
+        error TEXT,
+        commit_id TEXT,
+        changed INTEGER NOT NULL,
+        failed INTEGER NOT NULL,
+        skipped INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS roles (
+        run INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        result TEXT NOT NULL,
+        duration_ms INTEGER NOT NULL
+    );
+";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Run {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /**
+     * When the run started and finished, in seconds since the epoch.
+     */
+    pub started: f64,
+    pub finished: f64,
+    pub version: String,
+    pub dry_run: bool,
+    /**
+     * "complete" or "failed".
+     */
+    pub result: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /**
+     * The git commit of the data directory, if it is a git repository.
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /**
+     * The number of top-level steps which changed something, failed, and
+     * were skipped.
+     */
+    pub changed: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub roles: Vec<RoleRun>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoleRun {
+    pub role: String,
+    pub result: String,
+    pub duration_ms: u64,
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn quote_opt(s: Option<&str>) -> String {
+    s.map(quote).unwrap_or_else(|| "NULL".to_string())
+}
+
+fn db() -> PathBuf {
+    Path::new(STATE_DIR).join(DB)
+}
+
+/*
+ * Run SQL against the database, returning the rows of any result, each a
+ * list of columns.  The ASCII unit and record separators delimit columns
+ * and rows, as they do not appear in the values we store.  The script is
+ * passed on stdin, rather than as an argument, so that it is neither logged
+ * nor visible to other users in ps(1).
+ */
+fn sql(log: &Logger, script: &str) -> Result<Vec<Vec<String>>> {
+    let sqlite = match find_command("sqlite3") {
+        Some(s) => s,
+        None => bail!("sqlite3 is not installed"),
+    };
+    let db = db();
+
+    debug!(log, "sqlite3 {}", db.display());
+    let mut child = Command::new(&sqlite)
+        .env_clear()
+        .args(["-batch", "-bail", "-ascii", "-noheader"])
+        .arg(&db)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut w = child.stdin.take().unwrap();
+    let wres = w.write_all(script.as_bytes());
+    drop(w);
+    let out = child.wait_with_output()?;
+    if !out.status.success() {
+        bail!("sqlite3 {}: {}", db.display(),
+            String::from_utf8_lossy(&out.stderr).trim());
+    }
+    wres?;
+
+    Ok(String::from_utf8(out.stdout)?.split('\x1e')
+        .filter(|r| !r.is_empty())
+        .map(|r| r.split('\x1f').map(|c| c.to_string()).collect())
+        .collect())
+}
+
+/*
+ * Create the tables, if the database is new.
+ */
+fn init(log: &Logger) -> Result<()> {
+    let rows = sql(log, "PRAGMA user_version;")?;
+    let version: u32 = match rows.first().and_then(|r| r.first()) {
+        Some(v) => v.trim().parse()?,
+        None => bail!("could not read the history schema version"),
+    };
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    info!(log, "creating history database {}", db().display());
+    sql(log, &format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;\n",
+        SCHEMA, SCHEMA_VERSION))?;
+    Ok(())
+}
+
+/**
+ * The commit checked out in the data directory, if it is a git repository.
+ */
+pub fn commit(log: &Logger, dir: &Path) -> Option<String> {
+    if !dir.join(".git").exists() {
+        return None;
+    }
+    ensure::query_output(log, &["git", "-C", dir.to_str()?, "rev-parse",
+        "HEAD"], &Exec::default()).ok().map(|s| s.trim().to_string())
+}
+
+pub fn record(log: &Logger, run: &Run) {
+    let error = run.error.as_deref()
+        .map(|e| e.replace(|c: char| c.is_control(), " "));
+
+    let mut s = format!("BEGIN;
+        INSERT INTO runs (started, finished, version, dry_run, result, error,
+            commit_id, changed, failed, skipped)
+        VALUES ({}, {}, {}, {}, {}, {}, {}, {}, {}, {});\n",
+        run.started, run.finished, quote(&run.version),
+        if run.dry_run { 1 } else { 0 }, quote(&run.result),
+        quote_opt(error.as_deref()), quote_opt(run.commit.as_deref()),
+        run.changed, run.failed, run.skipped);
+    for r in run.roles.iter() {
+        s.push_str(&format!("INSERT INTO roles (run, role, result, \
+            duration_ms) VALUES ((SELECT max(id) FROM runs), {}, {}, {});\n",
+            quote(&r.role), quote(&r.result), r.duration_ms));
+    }
+    s.push_str(&format!("DELETE FROM runs WHERE id <= \
+        (SELECT max(id) FROM runs) - {keep};
+        DELETE FROM roles WHERE run <= (SELECT max(id) FROM runs) - {keep};
+        COMMIT;\n", keep = KEEP_RUNS));
+
+    match init(log).and_then(|_| sql(log, &s)) {
+        Ok(_) => info!(log, "recorded run in history"),
+        Err(e) => warn!(log, "could not record run in history: {}", e),
+    }
+}
+
+fn parse_run(row: &[String]) -> Result<Run> {
+    if row.len() != 11 {
+        bail!("unexpected history row: {:?}", row);
+    }
+    let opt = |s: &str| if s.is_empty() { None } else { Some(s.to_string()) };
+
+    Ok(Run {
+        id: Some(row[0].parse()?),
+        started: row[1].parse()?,
+        finished: row[2].parse()?,
+        version: row[3].clone(),
+        dry_run: row[4] == "1",
+        result: row[5].clone(),
+        error: opt(&row[6]),
+        commit: opt(&row[7]),
+        changed: row[8].parse()?,
+        failed: row[9].parse()?,
+        skipped: row[10].parse()?,
+        roles: Vec::new(),
+    })
+}
+
+const RUN_COLUMNS: &str = "id, started, finished, version, dry_run, result, \
+    error, commit_id, changed, failed, skipped";
+
+/**
+ * The most recent runs, newest first, or, given a run ID, just that run with
+ * the results of its roles.
+ */
+pub fn query(log: &Logger, id: Option<u64>, limit: u32) -> Result<Vec<Run>> {
+    if !db().exists() {
+        match id {
+            Some(id) => bail!("there is no run {} in the history", id),
+            None => return Ok(Vec::new()),
+        }
+    }
+
+    let rows = match id {
+        Some(id) => sql(log, &format!("SELECT {} FROM runs WHERE id = {};",
+            RUN_COLUMNS, id))?,
+        None => sql(log, &format!("SELECT {} FROM runs ORDER BY id DESC \
+            LIMIT {};", RUN_COLUMNS, limit))?,
+    };
+    let mut runs = rows.iter()
+        .map(|r| parse_run(r))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(id) = id {
+        if runs.is_empty() {
+            bail!("there is no run {} in the history", id);
+        }
+        for r in sql(log, &format!("SELECT role, result, duration_ms FROM \
+            roles WHERE run = {} ORDER BY rowid;", id))?
+        {
+            if r.len() != 3 {
+                bail!("unexpected history row: {:?}", r);
+            }
+            runs[0].roles.push(RoleRun {
+                role: r[0].clone(),
+                result: r[1].clone(),
+                duration_ms: r[2].parse()?,
+            });
+        }
+    }
+
+    Ok(runs)
+}
+
+/**
+ * Print runs as a table, or as JSON.  Times are in seconds since the epoch.
+ */
+pub fn print(runs: &[Run], json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(runs)?);
+        return Ok(());
+    }
+
+    println!("   RUN     STARTED     SECS RESULT   CHANGED FAILED SKIPPED \
+        COMMIT");
+    for r in runs.iter() {
+        let result = if r.dry_run {
+            format!("{}*", r.result)
+        } else {
+            r.result.clone()
+        };
+        println!("{:>6} {:>11.0} {:>8.1} {:<8} {:>7} {:>6} {:>7} {}",
+            r.id.unwrap_or(0), r.started, r.finished - r.started,
+            result, r.changed, r.failed, r.skipped,
+            r.commit.as_deref().map(|c| &c[..c.len().min(12)]).unwrap_or("-"));
+        if let Some(e) = &r.error {
+            println!("       error: {}", e);
+        }
+        for rr in r.roles.iter() {
+            println!("       {:<30} {:<14} {:>8.1}", rr.role, rr.result,
+                rr.duration_ms as f64 / 1000.0);
+        }
+    }
+    if runs.iter().any(|r| r.dry_run) {
+        println!("(* a dry run)");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_rows() {
+        let run = parse_run(&row(&["7", "1602633600.5", "1602633630.25",
+            "0.1.0", "0", "failed", "role web failed", "", "3", "1", "2"]))
+            .unwrap();
+        assert_eq!(run, Run {
+            id: Some(7),
+            started: 1602633600.5,
+            finished: 1602633630.25,
+            version: "0.1.0".to_string(),
+            dry_run: false,
+            result: "failed".to_string(),
+            error: Some("role web failed".to_string()),
+            commit: None,
+            changed: 3,
+            failed: 1,
+            skipped: 2,
+            roles: Vec::new(),
+        });
+
+        assert!(parse_run(&row(&["7", "1602633600"])).is_err());
+        assert!(parse_run(&row(&["x", "1", "2", "0.1.0", "1", "complete",
+            "", "", "0", "0", "0"])).is_err());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(quote_opt(None), "NULL");
+        assert_eq!(quote_opt(Some("a")), "'a'");
+    }
+}
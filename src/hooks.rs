@@ -0,0 +1,193 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Hooks are site-specific commands, listed in "<dir>/hooks.toml", which run
+ * before and after the whole run, and before and after each role; e.g., to
+ * announce a run in chat, to drain a load balancer, or to silence and then
+ * re-enable monitoring:
+ *
+ *      [[before_run]]
+ *      command = [ "/opt/site/bin/announce", "confomat starting" ]
+ *      fatal = false
+ *
+ *      [[before_role]]
+ *      roles = [ "nginx" ]
+ *      command = [ "/opt/site/bin/lb", "drain" ]
+ *
+ *      [[after_role]]
+ *      roles = [ "nginx" ]
+ *      command = [ "/opt/site/bin/lb", "undrain" ]
+ *
+ * If a hook fails and is "fatal" (the default), a before hook prevents the run
+ * or role from going ahead, and an after hook fails the run or role.  Other
+ * failures are reported as warnings.  After hooks run whether or not the run
+ * or role succeeded.  Hooks do not run in dry-run mode unless they have
+ * "dry_run = true".
+ *
+ * Each hook has these environment variables, as well as any from confomat's
+ * own environment:
+ *
+ *      CONFOMAT_HOOK           "before_run", "after_role", etc.
+ *      CONFOMAT_NODE           the nodename
+ *      CONFOMAT_DRY_RUN        "true" or "false"
+ *      CONFOMAT_ROLE           for role hooks, the role name
+ *      CONFOMAT_INSTANCE       for role hooks, the instance name, if any
+ *      CONFOMAT_RESULT         for after hooks, the outcome; e.g., "changed"
+ */
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use serde::Deserialize;
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::{OutputExt, dry_run};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum When {
+    BeforeRun,
+    AfterRun,
+    BeforeRole,
+    AfterRole,
+}
+
+impl When {
+    fn name(&self) -> &'static str {
+        match self {
+            When::BeforeRun => "before_run",
+            When::AfterRun => "after_run",
+            When::BeforeRole => "before_role",
+            When::AfterRole => "after_role",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub command: Vec<String>,
+    /**
+     * For role hooks, the roles to which the hook applies.  If empty, the
+     * hook applies to every role.
+     */
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /**
+     * Whether a failure of this hook is an error.  The default is true.
+     */
+    pub fatal: Option<bool>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default)]
+    before_run: Vec<Hook>,
+    #[serde(default)]
+    after_run: Vec<Hook>,
+    #[serde(default)]
+    before_role: Vec<Hook>,
+    #[serde(default)]
+    after_role: Vec<Hook>,
+}
+
+impl Hooks {
+    /**
+     * Read "<dir>/hooks.toml".  If it does not exist, there are no hooks.
+     */
+    pub fn read(dir: &Path) -> Result<Hooks> {
+        let path = dir.join("hooks.toml");
+
+        let hooks: Hooks = match jmclib::toml::read_file(&path) {
+            Ok(Some(h)) => h,
+            Ok(None) => return Ok(Hooks::default()),
+            Err(e) => bail!("reading hooks {}: {}", path.display(), e),
+        };
+
+        for h in hooks.before_run.iter().chain(hooks.after_run.iter()) {
+            if !h.roles.is_empty() {
+                bail!("{}: run hooks cannot have roles", path.display());
+            }
+        }
+        for h in hooks.all() {
+            if h.command.is_empty() {
+                bail!("{}: hook command must not be empty", path.display());
+            }
+        }
+
+        Ok(hooks)
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Hook> {
+        self.before_run.iter()
+            .chain(self.after_run.iter())
+            .chain(self.before_role.iter())
+            .chain(self.after_role.iter())
+    }
+
+    /**
+     * Run the hooks for this point in the run.  For role hooks, "role" is the
+     * name of the role.  The "env" variables are passed to each hook, along
+     * with CONFOMAT_HOOK and CONFOMAT_DRY_RUN.
+     */
+    pub fn run(&self, log: &Logger, when: When, role: Option<&str>,
+        env: &[(&str, String)])
+        -> Result<()>
+    {
+        let hooks = match when {
+            When::BeforeRun => &self.before_run,
+            When::AfterRun => &self.after_run,
+            When::BeforeRole => &self.before_role,
+            When::AfterRole => &self.after_role,
+        };
+
+        for h in hooks.iter() {
+            if let Some(r) = role {
+                if !h.roles.is_empty() && !h.roles.iter().any(|hr| hr == r) {
+                    continue;
+                }
+            }
+            if dry_run() && !h.dry_run {
+                info!(log, "DRY RUN: would run {} hook {:?}", when.name(),
+                    h.command);
+                continue;
+            }
+
+            info!(log, "running {} hook {:?}", when.name(), h.command);
+            let out = Command::new(&h.command[0])
+                .args(&h.command[1..])
+                .env("CONFOMAT_HOOK", when.name())
+                .env("CONFOMAT_DRY_RUN", dry_run().to_string())
+                .envs(env.iter().map(|(k, v)| (k, v)))
+                .stdin(Stdio::null())
+                .output();
+
+            let err = match out {
+                Ok(out) => {
+                    let text = String::from_utf8_lossy(&out.stdout);
+                    for l in text.lines() {
+                        info!(log, "hook| {}", l);
+                    }
+                    if out.status.success() {
+                        continue;
+                    }
+                    out.info()
+                }
+                Err(e) => e.to_string(),
+            };
+
+            if h.fatal.unwrap_or(true) {
+                bail!("{} hook {:?} failed: {}", when.name(), h.command, err);
+            }
+            warn!(log, "{} hook {:?} failed: {}", when.name(), h.command,
+                err);
+        }
+
+        Ok(())
+    }
+}
@@ -6,25 +6,12 @@ use std::os::raw::{c_char, c_int};
 use std::process::exit;
 use std::ffi::{CString, CStr};
 use std::collections::HashMap;
-use anyhow::{Result, bail};
+use anyhow::Result;
 
-#[derive(Debug, PartialEq)]
-pub struct UserAttr {
-    pub name: String,
-    pub attr: HashMap<String, String>,
-}
+use super::error::Error;
 
-impl UserAttr {
-    pub fn profiles(&self) -> Vec<String> {
-        if let Some(p) = self.attr.get("profiles") {
-            p.split(',')
-                .map(|s| s.trim().to_string())
-                .collect::<Vec<_>>()
-        } else {
-            Vec::new()
-        }
-    }
-}
+pub use super::unix::{get_group_ids_for_user, get_id_from_file, nodename,
+    openpty, set_controlling_tty, Pty, UserAttr};
 
 #[repr(C)]
 struct Kv {
@@ -94,17 +81,6 @@ pub fn get_user_attr_by_name(name: &str) -> Result<Option<UserAttr>> {
     Ok(Some(out))
 }
 
-pub fn nodename() -> String {
-    unsafe {
-        let mut un: libc::utsname = std::mem::zeroed();
-        if libc::uname(&mut un) < 0 {
-            eprintln!("uname failure");
-            exit(100);
-        }
-        std::ffi::CStr::from_ptr(un.nodename.as_mut_ptr())
-    }.to_str().unwrap().to_string()
-}
-
 #[link(name = "c")]
 extern {
     fn getzoneid() -> i32;
@@ -236,7 +212,10 @@ pub fn get_passwd_by_id(uid: u32) -> Result<Option<Passwd>> {
         if e == 0 {
             Ok(None)
         } else {
-            bail!("getpwuid: errno {}", e);
+            Err(Error::SystemCall {
+                call: "getpwuid".to_string(),
+                errno: e,
+            }.into())
         }
     } else {
         Ok(Some(Passwd::from(p)?))
@@ -252,7 +231,10 @@ pub fn get_passwd_by_name(name: &str) -> Result<Option<Passwd>> {
         if e == 0 {
             Ok(None)
         } else {
-            bail!("getpwnam: errno {}", e);
+            Err(Error::SystemCall {
+                call: "getpwnam".to_string(),
+                errno: e,
+            }.into())
         }
     } else {
         Ok(Some(Passwd::from(p)?))
@@ -268,7 +250,10 @@ pub fn get_group_by_name(name: &str) -> Result<Option<Group>> {
         if e == 0 {
             Ok(None)
         } else {
-            bail!("getgrnam: errno {}", e);
+            Err(Error::SystemCall {
+                call: "getgrnam".to_string(),
+                errno: e,
+            }.into())
         }
     } else {
         Ok(Some(Group::from(g)?))
@@ -283,9 +268,80 @@ pub fn get_group_by_id(gid: u32) -> Result<Option<Group>> {
         if e == 0 {
             Ok(None)
         } else {
-            bail!("getgrgid: errno {}", e);
+            Err(Error::SystemCall {
+                call: "getgrgid".to_string(),
+                errno: e,
+            }.into())
         }
     } else {
         Ok(Some(Group::from(g)?))
     }
 }
+
+const PRIV_SET: c_int = 2; /* priv_op_t */
+const PRIV_PERMITTED: &[u8] = b"Permitted\0";
+const PRIV_INHERITABLE: &[u8] = b"Inheritable\0";
+const PRIV_LIMIT: &[u8] = b"Limit\0";
+
+#[link(name = "c")]
+extern {
+    fn priv_str_to_set(buf: *const c_char, sep: *const c_char,
+        endptr: *mut *const c_char) -> *mut libc::c_void;
+    fn priv_freeset(sp: *mut libc::c_void);
+    fn setppriv(op: c_int, which: *const c_char, set: *const libc::c_void)
+        -> c_int;
+}
+
+/**
+ * A privilege set, for use in a child process before exec.
+ */
+pub struct PrivSet(*mut libc::c_void);
+
+/*
+ * The set is only read once it has been created, so it may be shared with the
+ * closure that runs in the child.
+ */
+unsafe impl Send for PrivSet {}
+unsafe impl Sync for PrivSet {}
+
+impl Drop for PrivSet {
+    fn drop(&mut self) {
+        unsafe { priv_freeset(self.0) };
+    }
+}
+
+impl PrivSet {
+    /**
+     * The "basic" privilege set of an unprivileged process.
+     */
+    pub fn basic() -> Result<PrivSet> {
+        let p = unsafe {
+            priv_str_to_set(b"basic\0".as_ptr() as *const c_char,
+                b",\0".as_ptr() as *const c_char, std::ptr::null_mut())
+        };
+        if p.is_null() {
+            return Err(Error::SystemCall {
+                call: "priv_str_to_set".to_string(),
+                errno: errno(),
+            }.into());
+        }
+        Ok(PrivSet(p))
+    }
+
+    /**
+     * Reduce the permitted, inheritable, and limit sets of this process to
+     * this set.  This may be called between fork and exec: creating the set
+     * has already loaded the privilege data that setppriv() needs, so it makes
+     * only system calls.
+     */
+    pub fn apply(&self) -> std::io::Result<()> {
+        for which in [PRIV_PERMITTED, PRIV_INHERITABLE, PRIV_LIMIT] {
+            if unsafe {
+                setppriv(PRIV_SET, which.as_ptr() as *const c_char, self.0)
+            } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * When confomat is not built for illumos, this module stands in for the
+ * illumos one, with the same functions, so that roles which use them still
+ * build; e.g., on a developer's laptop.  Those which need illumos itself,
+ * such as the user_attr(4) database from libsecdb, find nothing.
+ */
+
+use anyhow::Result;
+
+pub use super::sys::{get_group_by_id, get_group_by_name,
+    get_group_ids_for_user, get_id_from_file, get_passwd_by_id,
+    get_passwd_by_name, nodename, openpty, set_controlling_tty, zoneid,
+    zonename, Group, Passwd, PrivSet, Pty};
+pub use super::unix::UserAttr;
+
+pub fn get_user_attr_by_name(_name: &str) -> Result<Option<UserAttr>> {
+    Ok(None)
+}
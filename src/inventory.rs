@@ -0,0 +1,68 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The host inventory, "<dir>/hosts.toml", maps each host to the roles that
+ * should be applied to it, and to any variables specific to that host, so
+ * that the same confomat directory (and the same command line) can be used
+ * on every machine.  Hosts are keyed by nodename, unless a different node ID
+ * is passed with "--node":
+ *
+ *      [hosts.gateway]
+ *      roles = [ "base", "ntp", "dhcp" ]
+ *      env = "production"
+ *      vars = { dhcp = { authoritative = true } }
+ *
+ *      [hosts.build0]
+ *      roles = [ "base", "buildzone:rust" ]
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+use anyhow::{Result, bail};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Host {
+    /**
+     * Role selectors, as would be passed on the command line; e.g., "ntp" or
+     * "buildzone:rust".
+     */
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /**
+     * Variables for this host, which override those in "vars.toml" but are
+     * themselves overridden by "vars/<nodename>.toml" and the command line.
+     */
+    pub vars: Option<serde_json::Value>,
+    /**
+     * The environment (see "--env") to which this host belongs, if any.
+     */
+    pub env: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Inventory {
+    #[serde(default)]
+    hosts: BTreeMap<String, Host>,
+}
+
+impl Inventory {
+    /**
+     * Read the inventory, returning None if the file does not exist.
+     */
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Inventory>> {
+        let path = path.as_ref();
+
+        match jmclib::toml::read_file(path) {
+            Ok(i) => Ok(i),
+            Err(e) => bail!("reading inventory {}: {}", path.display(), e),
+        }
+    }
+
+    pub fn host(&self, node: &str) -> Option<&Host> {
+        self.hosts.get(node)
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use anyhow::{Result, bail};
+
+use super::plan::Change;
+
+/**
+ * An entry in the JSON lines log.  Each line is a single object, with the
+ * "event" field describing which sort of entry it is: "run_start", "run_end",
+ * "role_start", "role_end", "step_start", or "step_end".  Fields that do not
+ * apply to a particular event are omitted.
+ */
+#[derive(Debug, Default, Serialize)]
+pub struct Event<'a> {
+    pub event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource: Option<&'a str>,
+    /**
+     * The outcome of a step or role: "changed", "unchanged", "skipped", or
+     * "failed"; or of the whole run: "complete" or "failed".  A step which
+     * failed, but which will be attempted again, is "retried".  Steps and
+     * roles which were not started because the run was interrupted or had
+     * already failed are "not-attempted".
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<&'a str>,
+    /**
+     * Why a step or role was skipped, or not attempted.
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<&'a [Change]>,
+}
+
+/*
+ * The form in which an event is written out, with the fields common to every
+ * line in the log.
+ */
+#[derive(Serialize)]
+struct Line<'a> {
+    time: f64,
+    host: &'a str,
+    dry_run: bool,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+pub struct Journal {
+    host: String,
+    dry_run: bool,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /**
+     * Open the log file for append, creating it if it does not exist, so that
+     * successive runs accumulate in the same file.
+     */
+    pub fn open<P: AsRef<Path>>(path: P, host: &str, dry_run: bool)
+        -> Result<Journal>
+    {
+        let path = path.as_ref();
+
+        let file = match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+        {
+            Ok(f) => f,
+            Err(e) => bail!("opening JSON log \"{}\": {}", path.display(), e),
+        };
+
+        Ok(Journal {
+            host: host.to_string(),
+            dry_run,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, event: &Event) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs_f64();
+
+        let mut line = serde_json::to_string(&Line {
+            time,
+            host: &self.host,
+            dry_run: self.dry_run,
+            event,
+        })?;
+        line.push('\n');
+
+        /*
+         * Write each line with a single call so that lines from concurrent
+         * writers are not interleaved.
+         */
+        let mut f = self.file.lock().unwrap();
+        f.write_all(line.as_bytes())?;
+        f.flush()?;
+
+        Ok(())
+    }
+}
+
+/**
+ * The value returned by a step, from which we determine whether or not the
+ * step changed anything.  Steps which return nothing are judged only by the
+ * changes they record.  A step that is skipped returns skipped().
+ */
+pub trait Outcome {
+    fn changed(&self) -> bool;
+    fn skipped() -> Self;
+}
+
+impl Outcome for bool {
+    fn changed(&self) -> bool {
+        *self
+    }
+
+    fn skipped() -> bool {
+        false
+    }
+}
+
+impl Outcome for () {
+    fn changed(&self) -> bool {
+        false
+    }
+
+    fn skipped() {}
+}
@@ -0,0 +1,117 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Services on macOS, which launchd(8) runs from the property lists in
+ * /Library/LaunchDaemons.  A daemon is "loaded" once it has been bootstrapped
+ * into the system domain, after which launchd starts it as the property list
+ * directs; e.g., at once, if it has "RunAtLoad" or "KeepAlive".
+ */
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, Ownership};
+
+const LAUNCHCTL: &str = "/bin/launchctl";
+
+/**
+ * The directory in which confomat installs daemon property lists.
+ */
+pub const DAEMON_DIR: &str = "/Library/LaunchDaemons";
+
+fn plist_path(label: &str) -> Result<String> {
+    if label.is_empty() || label.contains('/') {
+        bail!("invalid launchd label \"{}\"", label);
+    }
+    Ok(format!("{}/{}.plist", DAEMON_DIR, label))
+}
+
+/**
+ * Install the property list for a daemon with this label (e.g.,
+ * "org.nginx.nginx"), checking it with plutil(1) first.  Returns true if it
+ * changed, in which case the daemon must be loaded again for launchd to see
+ * the change.  The path is relative to the alternate root, if there is one.
+ */
+pub fn ensure_plist(log: &Logger, label: &str, contents: &str)
+    -> Result<bool>
+{
+    let path = rooted(plist_path(label)?)?;
+
+    let tmp = temp_file(&format!("{}.plist", label), contents.as_bytes())?;
+    if let Err(e) = ensure::query(log, &["/usr/bin/plutil", "-lint",
+        tmp.path().to_str().unwrap()])
+    {
+        bail!("property list for {} is not valid: {}", label, e);
+    }
+
+    ensure::contents(log, &path, contents.as_bytes(),
+        &Ownership::Names("root", "wheel"), 0o644)
+}
+
+pub fn loaded(log: &Logger, label: &str) -> Result<bool> {
+    Ok(ensure::query_status(log, &[LAUNCHCTL, "print",
+        &format!("system/{}", label)])?.success())
+}
+
+/**
+ * Has the daemon been disabled with "launchctl disable", which persists
+ * across reboots and prevents it from being loaded?
+ */
+pub fn disabled(log: &Logger, label: &str) -> Result<bool> {
+    let out = ensure::query_output(log, &[LAUNCHCTL, "print-disabled",
+        "system"], &ensure::Exec::default())?;
+
+    /*
+     * Each entry is of the form "label" => disabled (or, on older releases,
+     * => true).
+     */
+    let want = format!("\"{}\"", label);
+    Ok(out.lines().any(|l| {
+        let f: Vec<&str> = l.split("=>").map(|f| f.trim()).collect();
+        f.len() == 2 && f[0] == want && (f[1] == "disabled" || f[1] == "true")
+    }))
+}
+
+/**
+ * Ensure that a daemon is enabled and loaded, loading it again if it was
+ * already loaded and "need_restart" is set; e.g., because its property list
+ * changed.  Returns true if anything was done.
+ */
+pub fn ensure_loaded(log: &Logger, label: &str, need_restart: bool)
+    -> Result<bool>
+{
+    let path = plist_path(label)?;
+    let target = format!("system/{}", label);
+    let mut changed = false;
+
+    if !dry_run() && std::fs::metadata(&path).is_err() {
+        bail!("launchd daemon {} has no property list {}", label, path);
+    }
+
+    if disabled(log, label)? {
+        info!(log, "launchd daemon {}: disabled, enabling...", label);
+        ensure::run(log, &[LAUNCHCTL, "enable", &target])?;
+        changed = true;
+    }
+
+    if loaded(log, label)? {
+        if !need_restart {
+            return Ok(changed);
+        }
+        info!(log, "launchd daemon {}: reloading...", label);
+        ensure::run(log, &[LAUNCHCTL, "bootout", &target])?;
+    } else {
+        info!(log, "launchd daemon {}: loading...", label);
+    }
+    ensure::run(log, &[LAUNCHCTL, "bootstrap", "system", &path])?;
+
+    if !dry_run() && !loaded(log, label)? {
+        bail!("launchd daemon {} did not load", label);
+    }
+    info!(log, "launchd daemon {}: loaded!", label);
+
+    Ok(true)
+}
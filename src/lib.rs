@@ -8,20 +8,230 @@ use std::process::exit;
 use std::io::{Read, Write};
 use std::path::{PathBuf, Path};
 use std::fmt::Debug;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use slog::Logger;
 
 use anyhow::{Result, bail};
 
+#[cfg(target_os = "illumos")]
 pub mod illumos;
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(target_os = "freebsd")]
+pub mod freebsd;
+#[cfg(target_os = "macos")]
+pub mod macos;
+#[cfg(target_os = "openbsd")]
+pub mod openbsd;
+#[cfg(not(any(target_os = "illumos", target_os = "linux",
+    target_os = "freebsd", target_os = "macos", target_os = "openbsd")))]
+pub mod generic;
+mod unix;
+#[cfg(not(target_os = "illumos"))]
+mod nss;
 pub mod digitalocean;
 
+/*
+ * The module for the platform we are built for; each provides the same
+ * functions, e.g., for looking up users and groups.  Elsewhere than illumos,
+ * the "illumos" module is a stand-in built on that platform module, so that
+ * roles which use it can be built anywhere.
+ */
+#[cfg(target_os = "illumos")]
+use illumos as sys;
+#[cfg(target_os = "linux")]
+use linux as sys;
+#[cfg(target_os = "freebsd")]
+use freebsd as sys;
+#[cfg(target_os = "macos")]
+use macos as sys;
+#[cfg(target_os = "openbsd")]
+use openbsd as sys;
+#[cfg(not(any(target_os = "illumos", target_os = "linux",
+    target_os = "freebsd", target_os = "macos", target_os = "openbsd")))]
+use generic as sys;
+#[cfg(not(target_os = "illumos"))]
+#[path = "illumos_stub.rs"]
+pub mod illumos;
+
 mod common;
 use common::*;
+pub use common::shell_quote;
+
+mod error;
+pub use error::Error;
 
 mod ensure;
-pub use ensure::{Create, FileType, FileInfo, HashType};
+pub use ensure::{Create, Exec, FileType, FileInfo, HashType};
+use ensure::Ownership;
+
+/*
+ * The management of zones, SMF services, ZFS datasets, and the network (with
+ * the network services built on it) can each be left out of the build with
+ * the "zones", "smf", "zfs", and "net" features, for deployments which only
+ * need to manage files, users, and packages.  Each is enabled by default.
+ */
+#[cfg(feature = "zones")]
+mod zones;
+#[cfg(feature = "zones")]
+pub use zones::{BhyveDisk, BhyveVm, LxZone, Zone, ZoneFacts, ZoneLimits,
+    ZoneState};
+
+/*
+ * For now, only the network subsystems manage SMF properties.
+ */
+#[cfg(feature = "smf")]
+#[cfg_attr(not(feature = "net"), allow(dead_code))]
+mod smf;
+
+#[cfg(feature = "net")]
+mod net;
+#[cfg(feature = "net")]
+pub use net::{Address, DnsClient, IpmpGroup, Vnic};
+
+/*
+ * The time service is managed through SMF, so time synchronisation needs that
+ * subsystem as well as the network one.
+ */
+#[cfg(all(feature = "net", feature = "smf"))]
+mod ntp;
+#[cfg(all(feature = "net", feature = "smf"))]
+pub use ntp::TimeDaemon;
+
+#[cfg(feature = "net")]
+mod firewall;
+
+mod osops;
+use osops::os_ops;
+
+mod users;
+pub use users::User;
+
+mod rcd;
+
+mod coreadm;
+pub use coreadm::CoreConfig;
+
+mod drivers;
+pub use drivers::Driver;
+
+mod bootadm;
+mod eeprom;
+mod timezone;
+mod locale;
+
+mod project;
+pub use project::{Project, Rctl};
+
+mod certs;
+pub use certs::{Certificate, Challenge};
+
+mod manifest;
+
+mod policy;
+pub use policy::{LoginPolicy, PasswdPolicy, SuPolicy};
+
+mod sshd;
+
+mod pam;
+pub use pam::{PamEntry, PamPosition};
+
+#[cfg(feature = "zfs")]
+mod zfs;
+#[cfg(feature = "zfs")]
+pub use zfs::{EncryptedDataset, KeyFormat};
+
+#[cfg(feature = "smf")]
+mod audit;
+#[cfg(feature = "smf")]
+pub use audit::{AuditConfig, AuditPlugin};
+
+#[cfg(feature = "smf")]
+mod fmnotify;
+#[cfg(feature = "smf")]
+pub use fmnotify::EventNotify;
+
+mod systemd;
+
+mod launchd;
+
+mod packages;
+use packages::Manager;
+
+mod facts;
+pub use facts::{Facts, InterfaceAddress, PackageSummary, Release, Uname,
+    Zpool};
+
+mod hardware;
+pub use hardware::{Disk, Hardware, MemoryModule, PciDevice, Virtualization};
+
+mod links;
+pub use links::Link;
+
+#[cfg(feature = "net")]
+mod dhcp;
+#[cfg(feature = "net")]
+pub use dhcp::{DhcpConfig, DhcpHost, DhcpSubnet};
+
+mod plan;
+use plan::{Action, Change};
+
+mod journal;
+use journal::{Event, Journal, Outcome};
+
+mod syslog;
+use syslog::Syslog;
+
+mod audit_trail;
+use audit_trail::AuditTrail;
+
+mod display;
+use display::{Display, Style};
+
+mod vars;
+mod template;
+
+mod inventory;
+use inventory::Inventory;
+
+mod pull;
+
+mod lock;
+use lock::RunLock;
+
+mod cache;
+
+mod secrets;
+use secrets::Secrets;
+
+mod secret_source;
+use secret_source::SecretSource;
+
+mod push;
+use push::Push;
+
+mod progress;
+use progress::Progress;
+
+mod hooks;
+use hooks::{Hooks, When};
+
+mod report;
+use report::{Report, RoleReport, StepReport};
+
+mod metrics;
+use metrics::{RoleMetric, RunMetrics};
+
+mod history;
+
+mod notify;
+use notify::Notify;
 
 /*
  * Constants for commonly used User and Group names:
@@ -30,13 +240,120 @@ pub const ROOT: &str = "root";
 pub const BIN: &str = "bin";
 pub const SYS: &str = "sys";
 
+/*
+ * The number of steps to list, slowest first, in the summary at the end of a
+ * run.
+ */
+const SLOWEST_STEPS: usize = 10;
+
+/*
+ * The exit status of pkg(1) when there was nothing to do.
+ */
+const PKG_EXIT_NOP: i32 = 4;
+
+/*
+ * The exit status of a run started with Confomat::run().  A run that completes
+ * exits with EXIT_CHANGED if anything was changed (or, in a dry run, would have
+ * been), combined with EXIT_SKIPPED if any role or step was skipped; e.g., 6
+ * means both.  A run that completes without changing or skipping anything
+ * exits 0.  A run that fails for any reason exits with EXIT_FAILED.
+ */
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_CHANGED: i32 = 2;
+pub const EXIT_SKIPPED: i32 = 4;
+
 pub enum InstancePosture {
     Prohibited,
     Required,
 }
 
+/**
+ * A condition under which a role or a step is applied.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition<'a> {
+    /**
+     * The condition holds if the command exits zero.  The command is run
+     * even in dry-run mode, so it should not alter the system.
+     */
+    Command(&'a [&'a str]),
+    /**
+     * The condition holds if the expression, in the syntax of an "if" tag in
+     * a template, is true; e.g., "facts.zonename == global".  The role
+     * variables, registered values, and facts are available, as they are to
+     * templates.
+     */
+    Expr(&'a str),
+}
+
+/**
+ * How to retry an operation that may fail for transient reasons; e.g., a
+ * package repository fetch or a download.  The first retry waits for "delay",
+ * and each subsequent retry waits "backoff" times as long as the one before.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retry {
+    /**
+     * The total number of attempts, including the first.
+     */
+    pub attempts: u32,
+    pub delay: Duration,
+    /**
+     * A finite, non-negative factor; e.g., 2.0 to double each delay.
+     */
+    pub backoff: f64,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 3,
+            delay: Duration::from_secs(5),
+            backoff: 2.0,
+        }
+    }
+}
+
+impl Retry {
+    fn check(&self) -> Result<()> {
+        if self.attempts == 0 {
+            bail!("retry: at least one attempt is required");
+        }
+        if !self.backoff.is_finite() || self.backoff < 0.0 {
+            bail!("retry: backoff {} must be a non-negative number",
+                self.backoff);
+        }
+        Ok(())
+    }
+
+    /*
+     * The delay after the one given.  Once the delay can no longer be
+     * represented, it stops growing.
+     */
+    fn next_delay(&self, delay: Duration) -> Duration {
+        Duration::try_from_secs_f64(delay.as_secs_f64() * self.backoff)
+            .unwrap_or(delay)
+    }
+}
+
+impl std::fmt::Display for Condition<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Condition::Command(args) => write!(f, "command \"{}\"",
+                args.join(" ")),
+            Condition::Expr(e) => write!(f, "\"{}\"", e),
+        }
+    }
+}
+
 pub type RoleFunc = fn(c: &Context) -> Result<()>;
 
+/**
+ * A handler, declared by a role with Context::handler(), and run at the end of
+ * the role if any step that notified it changed something.
+ */
+pub type HandlerFunc = Box<dyn Fn(&Context) -> Result<()>>;
+
 pub struct RoleProvider {
     /**
      * The operator-visible name of this role.  This name will be used on the
@@ -58,11 +375,102 @@ pub struct RoleProvider {
     pub instance_posture: InstancePosture,
 }
 
+/**
+ * The ordering, dependencies, conditions and tags of a role, if it has any,
+ * for Confomat::register_with(); e.g.,
+ *
+ *      c.register_with(&RoleProvider {
+ *          name: "nginx",
+ *          func: nginx,
+ *          instance_posture: InstancePosture::Prohibited,
+ *      }, &RoleOptions {
+ *          requires: &["pkgsrc"],
+ *          tags: &["web"],
+ *          ..Default::default()
+ *      })?;
+ *
+ * More options may be added, so a literal should end with
+ * "..Default::default()".
+ */
+#[derive(Default)]
+pub struct RoleOptions {
+    /**
+     * The names of roles which, if they are also being applied in this run,
+     * must be complete before this role begins.  Roles without an ordering
+     * relationship may be applied concurrently when confomat is run with
+     * "-j".
+     */
+    pub after: &'static [&'static str],
+
+    /**
+     * The names of roles on which this role depends.  Whenever this role is
+     * applied, these roles are applied first, even if they were not
+     * selected on the command line.  A required role that needs an instance
+     * cannot be added automatically, so at least one instance of it must be
+     * selected explicitly.
+     */
+    pub requires: &'static [&'static str],
+
+    /**
+     * Conditions which must all hold for this role to be applied.  If one
+     * does not, the role is skipped, and the skip is reported.
+     */
+    pub only_if: &'static [Condition<'static>],
+
+    /**
+     * Conditions under which this role is skipped: if any of them holds, the
+     * role is not applied.
+     */
+    pub unless: &'static [Condition<'static>],
+
+    /**
+     * Tags for this role, and for every step within it; e.g., "network" or
+     * "packages".  With "--tags", only the steps which have one of the
+     * listed tags are applied; with "--skip-tags", roles and steps which
+     * have one of the listed tags are skipped.
+     */
+    pub tags: &'static [&'static str],
+}
+
 #[derive(Debug, PartialEq)]
 pub enum OS {
     OmniOS,
     OpenIndiana,
     SmartOS,
+    /*
+     * Linux distributions, identified by "ID" (or "ID_LIKE") in
+     * /etc/os-release.  RedHat includes its rebuilds; e.g., Rocky Linux.
+     */
+    Debian,
+    Ubuntu,
+    Fedora,
+    RedHat,
+    FreeBSD,
+    OpenBSD,
+    MacOS,
+}
+
+impl OS {
+    pub fn is_illumos(&self) -> bool {
+        matches!(self, OS::OmniOS | OS::OpenIndiana | OS::SmartOS)
+    }
+
+    pub fn is_linux(&self) -> bool {
+        matches!(self, OS::Debian | OS::Ubuntu | OS::Fedora | OS::RedHat)
+    }
+
+    /**
+     * The group which owns system configuration files in /etc.
+     */
+    pub fn root_group(&self) -> &'static str {
+        if self.is_illumos() {
+            "sys"
+        } else if self.is_linux() {
+            "root"
+        } else {
+            "wheel"
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -77,12 +485,60 @@ pub struct Context<'a> {
     role: &'a Role,
     instance: Option<String>,
     log: Logger,
+    /*
+     * If set by only_if() or unless(), the next step is skipped, for this
+     * reason.
+     */
+    skip: RefCell<Option<String>>,
+    /*
+     * The handlers declared by the role, in order; the handlers to notify if
+     * the next step changes something; and the handlers which have been
+     * notified.
+     */
+    handlers: RefCell<Vec<(String, Rc<HandlerFunc>)>>,
+    notify: RefCell<Vec<String>>,
+    notified: RefCell<Vec<String>>,
+    in_handler: Cell<bool>,
+    /*
+     * Set by retry() while a failure would be followed by another attempt.
+     */
+    will_retry: Cell<bool>,
+    /*
+     * Tags for the next step, set by tag().
+     */
+    tags: RefCell<Vec<String>>,
+    vars: Cow<'a, serde_json::Value>,
+    /*
+     * The roles which included this one, in turn, starting with the role
+     * which was applied; each as "role" or "role:instance".
+     */
+    included_by: Vec<String>,
 }
 
+/*
+ * Roles may include roles which include roles, but not without end.
+ */
+const MAX_INCLUDE_DEPTH: usize = 16;
+
 struct Role {
     name: String,
     func: RoleFunc,
     allow_instance: bool,
+    after: Vec<String>,
+    requires: Vec<String>,
+    only_if: Vec<Condition<'static>>,
+    unless: Vec<Condition<'static>>,
+    tags: Vec<String>,
+}
+
+impl Role {
+    /*
+     * A role named "name@env" replaces the role "name" in that environment,
+     * and shares its files and configuration.
+     */
+    fn base_name(&self) -> &str {
+        self.name.split('@').next().unwrap()
+    }
 }
 
 pub struct Confomat {
@@ -94,10 +550,295 @@ pub struct Confomat {
     log: Logger,
     roles: HashMap<String, Role>,
     freeargs: Vec<String>,
+    #[cfg(feature = "zones")]
+    zone_runs: Mutex<Vec<ZoneRun>>,
+    /*
+     * Facts about the non-global zones, for templates and conditions, as
+     * "facts.zones".  These are gathered the first time they are needed, and
+     * again after any step that made a change, as that may have created or
+     * altered a zone.
+     */
+    #[cfg(feature = "zones")]
+    zone_facts: Mutex<Option<serde_json::Value>>,
+    reboots: Mutex<Vec<Reboot>>,
+    /*
+     * Whether a role has asked for the boot archive to be updated.
+     */
+    boot_archive: Mutex<bool>,
+    plan: bool,
+    journal: Option<Journal>,
+    syslog: Option<Syslog>,
+    display: Option<Display>,
+    audit_trail: AuditTrail,
+    step_times: Mutex<Vec<StepTime>>,
+    role_times: Mutex<Vec<RoleTime>>,
+    jobs: usize,
+    vars: serde_json::Value,
+    tags: Vec<String>,
+    skip_tags: Vec<String>,
+    push: Option<Push>,
+    /*
+     * In push mode, the exit statuses of each remote run, combined.
+     */
+    push_status: i32,
+    verify_idempotent: bool,
+    /*
+     * The run lock is released when the Confomat is dropped.
+     */
+    _lock: Option<RunLock>,
+    secrets: Secrets,
+    /*
+     * The values from the secrets table of role variables, which must not
+     * appear in diffs.
+     */
+    secret_strings: Vec<String>,
+    secret_source: Option<Box<dyn SecretSource>>,
+    /*
+     * Values fetched from the secret source, which are fetched only once per
+     * run, and which must likewise not appear in diffs.
+     */
+    fetched_secrets: Mutex<HashMap<String, String>>,
+    env: Option<String>,
+    hooks: Hooks,
+    report: Option<PathBuf>,
+    report_url: Option<String>,
+    metrics: Option<PathBuf>,
+    notify: Notify,
+    only: Vec<String>,
+    start_at: Option<StepSel>,
+    /*
+     * Whether we have reached the step named by --start-at (or --resume).
+     */
+    started: Mutex<bool>,
+    /*
+     * The first step to fail, for --resume.
+     */
+    failed: Mutex<Option<Progress>>,
+    /*
+     * Values registered by roles during the run; e.g., from the output of a
+     * command.  See Context::register().
+     */
+    registered: Mutex<serde_json::Map<String, serde_json::Value>>,
+    facts: Facts,
+}
+
+/*
+ * A step named with --start-at or --only, as "STEP", "RESOURCE", or
+ * "STEP:RESOURCE"; e.g., "ensure_file:/etc/inet/ntp.conf".  For --resume, the
+ * role must also match.
+ */
+struct StepSel {
+    role: Option<String>,
+    name: String,
+    origin: &'static str,
+}
+
+impl StepSel {
+    fn matches(&self, role: &str, step: &str, resource: &str) -> bool {
+        if self.role.as_ref().map(|r| r != role).unwrap_or(false) {
+            return false;
+        }
+        self.name == step || self.name == resource ||
+            self.name == format!("{}:{}", step, resource)
+    }
+}
+
+/*
+ * The wall-clock time taken by a step, and its outcome, for the summary at the
+ * end of the run.  Steps run by other steps (e.g., ensure_online() within
+ * ensure_dns_client()) are nested, and are not counted separately in the
+ * totals.
+ */
+struct StepTime {
+    role: String,
+    step: String,
+    resource: String,
+    result: &'static str,
+    nested: bool,
+    duration: Duration,
+    reason: Option<String>,
+    error: Option<String>,
+    changes: Vec<Change>,
+}
+
+struct RoleTime {
+    role: String,
+    result: &'static str,
+    duration: Duration,
+}
+
+/*
+ * A record of a change that will not take full effect until either a zone or
+ * the whole system has been rebooted.
+ */
+struct Reboot {
+    zone: Option<String>,
+    reason: String,
+}
+
+/*
+ * A record of a set of roles applied within a non-global zone, so that we can
+ * report on the outcome at the end of the run in the global zone.
+ */
+#[cfg(feature = "zones")]
+struct ZoneRun {
+    zone: String,
+    roles: Vec<String>,
+    error: Option<String>,
 }
 
 impl Confomat {
+    /*
+     * Write an entry to the JSON log, if there is one, and to syslog, if
+     * requested.  A failure to write to the log is reported, but does not
+     * interrupt the run.
+     */
+    fn event(&self, event: &Event) {
+        if let Some(j) = &self.journal {
+            if let Err(e) = j.write(event) {
+                warn!(self.log, "could not write to JSON log: {}", e);
+            }
+        }
+        if let Some(s) = &self.syslog {
+            s.write(event);
+        }
+        if let Some(d) = &self.display {
+            d.event(event);
+        }
+    }
+
+    /*
+     * Update the boot archive, in the global zone on illumos, if the changes
+     * made in the run need it (see the bootadm module).
+     */
+    fn boot_archive(&self) -> Result<()> {
+        if !self.os.is_illumos() || self.zoneid != 0 {
+            return Ok(());
+        }
+
+        let requested = *self.boot_archive.lock().unwrap();
+        bootadm::update(&self.log, &plan::changes(), requested)?;
+        Ok(())
+    }
+
+    /*
+     * Report the time taken by each role and the slowest steps, along with
+     * the number of steps that changed something, or failed.
+     */
+    fn summary(&self, total: Duration) {
+        let log = &self.log;
+
+        for rt in self.role_times.lock().unwrap().iter() {
+            info!(log, "ROLE {} {} in {:.1}s", rt.role,
+                rt.result.to_uppercase(), rt.duration.as_secs_f64());
+        }
+
+        let steps = self.step_times.lock().unwrap();
+        let mut slowest: Vec<&StepTime> = steps.iter().collect();
+        slowest.sort_by_key(|st| std::cmp::Reverse(st.duration));
+        for st in slowest.iter().take(SLOWEST_STEPS) {
+            info!(log, "SLOW STEP {:.1}s: role {} {} {} ({})",
+                st.duration.as_secs_f64(), st.role, st.step, st.resource,
+                st.result);
+        }
+
+        let count = |r: &str| steps.iter()
+            .filter(|st| !st.nested && st.result == r)
+            .count();
+        info!(log, "STEPS: {} changed, {} unchanged, {} skipped, {} failed, \
+            {} not attempted; total time {:.1}s", count("changed"),
+            count("unchanged"), count("skipped"), count("failed"),
+            count("not-attempted"), total.as_secs_f64());
+    }
+
+    /*
+     * Check the tags of a role or step against --skip-tags and, for steps,
+     * --tags, returning the reason it should be skipped, if it should.
+     */
+    fn tag_skip(&self, tags: &[String], step: bool) -> Option<String> {
+        if let Some(t) = tags.iter().find(|t| self.skip_tags.contains(t)) {
+            Some(format!("tagged \"{}\", skipped by --skip-tags", t))
+        } else if step && !self.tags.is_empty() &&
+            !tags.iter().any(|t| self.tags.contains(t))
+        {
+            Some(format!("not tagged with any of {:?}", self.tags))
+        } else {
+            None
+        }
+    }
+
+    /*
+     * Check a step against --only and --start-at (or --resume), returning the
+     * reason it should be skipped, if it should.
+     */
+    fn target_skip(&self, role: &str, step: &str, resource: &str)
+        -> Option<String>
+    {
+        if !self.only.is_empty() &&
+            !self.only.iter().any(|n| StepSel {
+                role: None,
+                name: n.to_string(),
+                origin: "--only",
+            }.matches(role, step, resource))
+        {
+            return Some("not selected by --only".to_string());
+        }
+
+        if let Some(sel) = &self.start_at {
+            let mut started = self.started.lock().unwrap();
+            if !*started {
+                if !sel.matches(role, step, resource) {
+                    return Some(format!("before the {} step \"{}\"",
+                        sel.origin, sel.name));
+                }
+                info!(self.log, "reached the {} step \"{}\"", sel.origin,
+                    sel.name);
+                *started = true;
+            }
+        }
+
+        None
+    }
+
+    /*
+     * Once the roles have been applied, check that the step named by
+     * --start-at (or --resume) was reached.
+     */
+    fn check_started(&self) -> Result<()> {
+        match &self.start_at {
+            Some(sel) if !*self.started.lock().unwrap() => {
+                bail!("the {} step \"{}\" was not found", sel.origin,
+                    sel.name);
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /*
+     * The overlay directory for the selected environment, if any.
+     */
+    fn env_dir(&self) -> Option<PathBuf> {
+        self.env.as_ref().map(|e| self.dir.join("env").join(e))
+    }
+
+    /*
+     * Look up a role by name.  If a role has been registered for the
+     * selected environment (e.g., "ntp@lab" for "ntp"), it is used instead.
+     */
+    fn role(&self, name: &str) -> Option<&Role> {
+        self.env.as_ref()
+            .and_then(|e| self.roles.get(&format!("{}@{}", name, e)))
+            .or_else(|| self.roles.get(name))
+    }
+
     pub fn register(&mut self, provider: &RoleProvider) -> Result<()> {
+        self.register_with(provider, &RoleOptions::default())
+    }
+
+    pub fn register_with(&mut self, provider: &RoleProvider,
+        opts: &RoleOptions)
+        -> Result<()>
+    {
         if self.roles.contains_key(provider.name) {
             bail!("duplicate role name: {}", provider.name);
         }
@@ -111,348 +852,2293 @@ impl Confomat {
             name: provider.name.to_string(),
             func: provider.func,
             allow_instance,
+            after: opts.after.iter().map(|a| a.to_string()).collect(),
+            requires: opts.requires.iter().map(|r| r.to_string()).collect(),
+            only_if: opts.only_if.to_vec(),
+            unless: opts.unless.to_vec(),
+            tags: opts.tags.iter().map(|t| t.to_string()).collect(),
         });
 
         Ok(())
     }
 
-    pub fn apply(&mut self) -> Result<()> {
-        let log = &self.log;
+    /*
+     * Add a role to the list of roles to apply, preceded by any roles it
+     * requires (and, in turn, any roles they require).  The chain of roles
+     * that led us here is tracked so that we can report dependency cycles.
+     */
+    fn require<'a>(&'a self, selected: &[(&'a Role, Option<String>)],
+        order: &mut Vec<(&'a Role, Option<String>)>, role: &'a Role,
+        instance: Option<String>, chain: &mut Vec<String>)
+        -> Result<()>
+    {
+        if order.iter().any(|(r, i)| r.name == role.name && *i == instance) {
+            return Ok(());
+        }
 
-        for arg in self.freeargs.iter() {
-            /*
-             * Check for an instance name in the role selector:
-             */
-            let t: Vec<&str> = arg.splitn(2, ':').collect();
-            let rolename = t[0].to_string();
-            let instance = if t.len() == 2 {
-                info!(log, "PROCESSING ROLE {} INSTANCE {}", rolename, t[1]);
-                Some(t[1].to_string())
-            } else {
-                info!(log, "PROCESSING ROLE {}", rolename);
-                None
-            };
+        if chain.contains(&role.name) {
+            bail!("role dependency cycle: {} -> {}", chain.join(" -> "),
+                role.name);
+        }
+        chain.push(role.name.to_string());
 
-            let role = if let Some(role) = self.roles.get(&rolename) {
-                role
+        for req in role.requires.iter() {
+            let dep = if let Some(dep) = self.role(req) {
+                dep
             } else {
-                bail!("invalid role \"{}\"", rolename);
+                bail!("role \"{}\" requires unknown role \"{}\"", role.name,
+                    req);
             };
 
-            if !role.allow_instance && instance.is_some() {
-                bail!("role \"{}\" does not allow instances", rolename);
+            if dep.allow_instance {
+                /*
+                 * We cannot guess which instance is required, so one must
+                 * have been selected.  Ordering against it is taken care of
+                 * when the roles are applied.
+                 */
+                if !selected.iter().any(|(r, _)| r.name == dep.name) {
+                    bail!("role \"{}\" requires role \"{}\", which needs \
+                        an instance; please select one", role.name, dep.name);
+                }
+                continue;
             }
-            if role.allow_instance && instance.is_none() {
-                bail!("role \"{}\" requires an instance", rolename);
+
+            self.require(selected, order, dep, None, chain)?;
+        }
+
+        chain.pop();
+        order.push((role, instance));
+        Ok(())
+    }
+
+    /*
+     * Apply the selected roles.  A role is started once every other selected
+     * role that it must follow has completed.  Up to "jobs" roles are applied
+     * concurrently; with one job, roles without an ordering relationship are
+     * applied in the order they were selected.
+     */
+    fn apply_roles(&self, runs: &[(&Role, Option<String>)]) -> Result<()> {
+        let after: Vec<Vec<usize>> = runs.iter().enumerate()
+            .map(|(i, (role, _))| {
+                runs.iter().enumerate()
+                    .filter(|(j, (other, _))| {
+                        *j != i && (role.after.contains(&other.name) ||
+                            role.requires.contains(&other.name))
+                    })
+                    .map(|(j, _)| j)
+                    .collect()
+            })
+            .collect();
+
+        /*
+         * Make sure the ordering constraints can be satisfied before we apply
+         * anything.
+         */
+        let mut done = vec![false; runs.len()];
+        while let Some(i) = (0..runs.len())
+            .find(|&i| !done[i] && after[i].iter().all(|&j| done[j]))
+        {
+            done[i] = true;
+        }
+        if done.iter().any(|d| !d) {
+            let names: Vec<&str> = runs.iter().zip(done.iter())
+                .filter(|(_, d)| !**d)
+                .map(|((role, _), _)| role.name.as_str())
+                .collect();
+            bail!("role ordering cycle among: {}", names.join(", "));
+        }
+
+        struct Queue {
+            started: Vec<bool>,
+            done: Vec<bool>,
+            error: Option<anyhow::Error>,
+        }
+
+        let queue = Mutex::new(Queue {
+            started: vec![false; runs.len()],
+            done: vec![false; runs.len()],
+            error: None,
+        });
+        let cv = Condvar::new();
+
+        std::thread::scope(|s| {
+            for _ in 0..self.jobs.min(runs.len()) {
+                s.spawn(|| loop {
+                    let i = {
+                        let mut q = queue.lock().unwrap();
+                        loop {
+                            if q.error.is_some() ||
+                                q.started.iter().all(|s| *s)
+                            {
+                                /*
+                                 * Once a role has failed, we start no more.
+                                 */
+                                return;
+                            }
+
+                            if let Some(i) = (0..runs.len()).find(|&i| {
+                                !q.started[i] &&
+                                    after[i].iter().all(|&j| q.done[j])
+                            }) {
+                                q.started[i] = true;
+                                break i;
+                            }
+
+                            q = cv.wait(q).unwrap();
+                        }
+                    };
+
+                    let (role, instance) = &runs[i];
+                    let res = self.apply_role(role, instance.clone());
+
+                    let mut q = queue.lock().unwrap();
+                    q.done[i] = true;
+                    if let Err(e) = res {
+                        q.error.get_or_insert(e);
+                    }
+                    cv.notify_all();
+                });
+            }
+        });
+
+        /*
+         * If we stopped early, record the roles we did not get to.
+         */
+        let q = queue.into_inner().unwrap();
+        for (i, (role, instance)) in runs.iter().enumerate() {
+            if q.started[i] {
+                continue;
+            }
+            self.event(&Event {
+                event: "role_end",
+                role: Some(&role.name),
+                instance: instance.as_deref(),
+                result: Some("not-attempted"),
+                ..Default::default()
+            });
+            self.role_times.lock().unwrap().push(RoleTime {
+                role: match instance {
+                    Some(i) => format!("{}:{}", role.name, i),
+                    None => role.name.to_string(),
+                },
+                result: "not-attempted",
+                duration: Duration::from_secs(0),
+            });
+        }
+
+        match q.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * Apply the roles a second time, and fail if anything was changed by the
+     * second pass: a role which changes the system on every run is not doing
+     * what it should.  The timings of the second pass are discarded, so that
+     * the report and metrics describe each step once.
+     */
+    fn verify_idempotent(&self, runs: &[(&Role, Option<String>)])
+        -> Result<()>
+    {
+        let log = &self.log;
+        let before = plan::changes().len();
+        let roles_before = self.role_times.lock().unwrap().len();
+        let steps_before = self.step_times.lock().unwrap().len();
+
+        info!(log, "VERIFYING IDEMPOTENCY: applying roles again");
+        let res = self.apply_roles(runs);
+        self.role_times.lock().unwrap().truncate(roles_before);
+        self.step_times.lock().unwrap().truncate(steps_before);
+        res?;
+
+        let changes = plan::changes().split_off(before);
+        if changes.is_empty() {
+            info!(log, "IDEMPOTENCY VERIFIED: second pass made no changes");
+            return Ok(());
+        }
+
+        for c in changes.iter() {
+            error!(log, "SECOND PASS CHANGED: {}", c.summary());
+            for d in c.details.iter() {
+                error!(log, "    {}", d);
+            }
+        }
+        bail!("roles are not idempotent: the second pass made {} changes",
+            changes.len());
+    }
+
+    fn apply_role(&self, role: &Role, instance: Option<String>) -> Result<()> {
+        let log = &self.log;
+
+        let log0 = if let Some(i) = &instance {
+            info!(log, "PROCESSING ROLE {} INSTANCE {}", role.name, i);
+            log.new(o!("role" => role.name.to_string(),
+                "instance" => i.to_string()))
+        } else {
+            info!(log, "PROCESSING ROLE {}", role.name);
+            log.new(o!("role" => role.name.to_string()))
+        };
+
+        let ctx = Context::new(self, role, instance, log0,
+            Cow::Borrowed(&self.vars));
+
+        self.event(&Event {
+            event: "role_start",
+            role: Some(&role.name),
+            instance: ctx.instance.as_deref(),
+            ..Default::default()
+        });
+
+        let mut henv = vec![
+            ("CONFOMAT_NODE", self.nodename.to_string()),
+            ("CONFOMAT_ROLE", role.name.to_string()),
+        ];
+        if let Some(i) = &ctx.instance {
+            henv.push(("CONFOMAT_INSTANCE", i.to_string()));
+        }
+
+        let start = Instant::now();
+        plan::begin_role();
+        let (mut res, skipped) = match ctx.role_skip() {
+            Ok(Some(reason)) => {
+                debug!(log, "SKIPPING ROLE {}: {}", role.name, reason);
+                (Ok(()), Some(reason))
+            }
+            Ok(None) => (self.hooks.run(&ctx.log, When::BeforeRole,
+                Some(&role.name), &henv)
+                .and_then(|_| (role.func)(&ctx))
+                .and_then(|_| ctx.run_handlers()), None),
+            Err(e) => (Err(e), None),
+        };
+        let changed = !plan::end_role().is_empty();
+        let classify = |res: &Result<()>| match (res, changed, &skipped) {
+            (Err(_), _, _) => "failed",
+            (Ok(_), _, Some(_)) => "skipped",
+            (Ok(_), true, None) => "changed",
+            (Ok(_), false, None) => "unchanged",
+        };
+        let mut result = classify(&res);
+
+        if skipped.is_none() {
+            henv.push(("CONFOMAT_RESULT", result.to_string()));
+            match self.hooks.run(&ctx.log, When::AfterRole, Some(&role.name),
+                &henv)
+            {
+                Err(e) if res.is_ok() => {
+                    res = Err(e);
+                    result = classify(&res);
+                }
+                Err(e) => warn!(log, "{}", e),
+                Ok(()) => (),
+            }
+        }
+        let duration = start.elapsed();
+
+        self.event(&Event {
+            event: "role_end",
+            role: Some(&role.name),
+            instance: ctx.instance.as_deref(),
+            result: Some(result),
+            reason: skipped.as_deref(),
+            duration_ms: Some(duration.as_millis() as u64),
+            error: res.as_ref().err().map(|e| e.to_string()),
+            ..Default::default()
+        });
+
+        self.role_times.lock().unwrap().push(RoleTime {
+            role: match &ctx.instance {
+                Some(i) => format!("{}:{}", role.name, i),
+                None => role.name.to_string(),
+            },
+            result,
+            duration,
+        });
+
+        if let Err(e) = res {
+            error!(log, "PROCESSING ROLE {} FAILED", role.name);
+            bail!("role \"{}\" failed: {}", role.name, e);
+        }
+
+        info!(log, "PROCESSING ROLE {} COMPLETE", role.name);
+        Ok(())
+    }
+
+    /*
+     * A snapshot of what we know about the host, for the run report.
+     */
+    fn facts(&self) -> serde_json::Value {
+        let mut facts = serde_json::to_value(&self.facts)
+            .expect("facts serialise");
+        if let Some(f) = facts.as_object_mut() {
+            f.insert("env".to_string(), serde_json::json!(self.env));
+        }
+        facts
+    }
+
+    /*
+     * Write the run report, if one was requested, and send any
+     * notifications.
+     */
+    fn report(&self, started: SystemTime, duration: Duration,
+        res: &Result<()>)
+    {
+        let notify = !self.notify.is_empty() && !dry_run();
+        if self.report.is_none() && self.report_url.is_none() && !notify {
+            return;
+        }
+
+        let exit_status = match res {
+            Ok(()) => self.exit_status(),
+            Err(_) => EXIT_FAILED,
+        };
+        let roles = self.role_times.lock().unwrap();
+        let steps = self.step_times.lock().unwrap();
+
+        let report = Report {
+            version: env!("CARGO_PKG_VERSION"),
+            host: &self.facts.nodename,
+            started: started.duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+            duration_ms: duration.as_millis() as u64,
+            dry_run: dry_run(),
+            result: if res.is_ok() { "complete" } else { "failed" },
+            error: res.as_ref().err().map(|e| e.to_string()),
+            exit_status,
+            facts: self.facts(),
+            roles: roles.iter().map(|rt| RoleReport {
+                role: &rt.role,
+                result: rt.result,
+                duration_ms: rt.duration.as_millis() as u64,
+            }).collect(),
+            steps: steps.iter().map(|st| StepReport {
+                role: &st.role,
+                step: &st.step,
+                resource: &st.resource,
+                result: st.result,
+                nested: st.nested,
+                reason: st.reason.as_deref(),
+                error: st.error.as_deref(),
+                duration_ms: st.duration.as_millis() as u64,
+                changes: &st.changes,
+            }).collect(),
+        };
+
+        report::write(&self.log, self.report.as_deref(),
+            self.report_url.as_deref(), &report);
+
+        if notify {
+            match serde_json::to_string_pretty(&report) {
+                Ok(body) => self.notify.send(&self.log, &report, &body),
+                Err(e) => warn!(self.log, "could not serialize run report: {}",
+                    e),
             }
+        }
+    }
+
+    /*
+     * Write the metrics file, if one was requested.
+     */
+    fn metrics(&self, duration: Duration, res: &Result<()>) {
+        let path = match &self.metrics {
+            Some(path) => path,
+            None => return,
+        };
+        if dry_run() {
+            info!(self.log, "not writing metrics for a dry run");
+            return;
+        }
+
+        let roles = self.role_times.lock().unwrap();
+        let steps = self.step_times.lock().unwrap();
+
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for r in ["changed", "unchanged", "failed"].iter() {
+            counts.insert(r, 0);
+        }
+        for st in steps.iter().filter(|st| !st.nested) {
+            *counts.entry(st.result).or_insert(0) += 1;
+        }
+
+        let m = RunMetrics {
+            finished: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+            duration_secs: duration.as_secs_f64(),
+            success: res.is_ok(),
+            steps: counts.into_iter().collect(),
+            roles: roles.iter().map(|rt| RoleMetric {
+                role: &rt.role,
+                result: rt.result,
+                duration_secs: rt.duration.as_secs_f64(),
+            }).collect(),
+        };
+
+        metrics::write(&self.log, path, &m);
+    }
+
+    /*
+     * Record a summary of the run in the history database.
+     */
+    fn history(&self, started: SystemTime, res: &Result<()>) {
+        let secs = |t: SystemTime| t.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        let roles = self.role_times.lock().unwrap();
+        let steps = self.step_times.lock().unwrap();
+        let count = |r: &str| steps.iter()
+            .filter(|st| !st.nested && st.result == r)
+            .count() as u64;
+
+        let run = history::Run {
+            id: None,
+            started: secs(started),
+            finished: secs(SystemTime::now()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            dry_run: dry_run(),
+            result: if res.is_ok() { "complete" } else { "failed" }
+                .to_string(),
+            error: res.as_ref().err().map(|e| e.to_string()),
+            commit: history::commit(&self.log, &self.dir),
+            changed: count("changed"),
+            failed: count("failed"),
+            skipped: count("skipped"),
+            roles: roles.iter().map(|rt| history::RoleRun {
+                role: rt.role.clone(),
+                result: rt.result.to_string(),
+                duration_ms: rt.duration.as_millis() as u64,
+            }).collect(),
+        };
+
+        history::record(&self.log, &run);
+    }
+
+    pub fn apply(&mut self) -> Result<()> {
+        let log = &self.log;
+        let run_start = Instant::now();
+        let started = SystemTime::now();
+
+        if let Some(push) = &self.push {
+            self.push_status = push::push(log, push, &self.dir, self.jobs)?;
+            return Ok(());
+        }
+
+        self.event(&Event {
+            event: "run_start",
+            ..Default::default()
+        });
 
-            let log0 = if let Some(i) = &instance {
-                log.new(o!("role" => rolename,
-                    "instance" => i.to_string()))
+        /*
+         * Resolve each role selector before we apply any of them, so that a
+         * typo does not leave the system half configured.
+         */
+        let mut runs: Vec<(&Role, Option<String>)> = Vec::new();
+        for arg in self.freeargs.iter() {
+            /*
+             * Check for an instance name in the role selector:
+             */
+            let t: Vec<&str> = arg.splitn(2, ':').collect();
+            let rolename = t[0].to_string();
+            let instance = if t.len() == 2 {
+                Some(t[1].to_string())
             } else {
-                log.new(o!("role" => rolename))
+                None
             };
 
-            let ctx = Context {
-                confomat: self,
-                log: log0,
-                role,
-                instance,
+            let role = if let Some(role) = self.role(&rolename) {
+                role
+            } else {
+                bail!("invalid role \"{}\"", rolename);
             };
 
-            if let Err(e) = (role.func)(&ctx) {
-                bail!("role \"{}\" failed: {}", role.name, e);
+            if !role.allow_instance && instance.is_some() {
+                bail!("role \"{}\" does not allow instances", rolename);
+            }
+            if role.allow_instance && instance.is_none() {
+                bail!("role \"{}\" requires an instance", rolename);
+            }
+
+            runs.push((role, instance));
+        }
+
+        /*
+         * Add the roles that the selected roles depend on, ahead of the roles
+         * that need them, and drop any role selected more than once.
+         */
+        let mut order = Vec::new();
+        for (role, instance) in runs.iter() {
+            self.require(&runs, &mut order, role, instance.clone(),
+                &mut Vec::new())?;
+        }
+
+        let mut henv = vec![("CONFOMAT_NODE", self.nodename.to_string())];
+        let res = self.hooks.run(log, When::BeforeRun, None, &henv)
+            .and_then(|_| self.apply_roles(&order))
+            .and_then(|_| {
+                if self.verify_idempotent {
+                    self.verify_idempotent(&order)
+                } else {
+                    Ok(())
+                }
+            })
+            .and_then(|_| self.check_started());
+
+        /*
+         * The boot archive is updated even if the run failed, so that the
+         * changes which were made are those the system boots with.
+         */
+        let res = match (res, self.boot_archive()) {
+            (Ok(()), Err(e)) => Err(e),
+            (Err(e), Err(be)) => {
+                warn!(log, "{}", be);
+                Err(e)
+            }
+            (res, Ok(())) => res,
+        };
+
+        henv.push(("CONFOMAT_RESULT",
+            if res.is_ok() { "complete" } else { "failed" }.to_string()));
+        let res = match (res, self.hooks.run(log, When::AfterRun, None, &henv))
+        {
+            (Ok(()), Err(e)) => Err(e),
+            (Err(e), Err(he)) => {
+                warn!(log, "{}", he);
+                Err(e)
+            }
+            (res, Ok(())) => res,
+        };
+        if res.is_err() {
+            self.report(started, run_start.elapsed(), &res);
+            self.metrics(run_start.elapsed(), &res);
+            self.history(started, &res);
+        }
+        if let Err(e) = res {
+            self.summary(run_start.elapsed());
+            if !dry_run() {
+                cache::save(log);
+                match &*self.failed.lock().unwrap() {
+                    Some(pr) => progress::save(log, pr),
+                    None => progress::clear(log),
+                }
+            }
+            self.event(&Event {
+                event: "run_end",
+                result: Some("failed"),
+                duration_ms: Some(run_start.elapsed().as_millis() as u64),
+                error: Some(e.to_string()),
+                ..Default::default()
+            });
+            return Err(e);
+        }
+
+        #[cfg(feature = "zones")]
+        for zr in self.zone_runs.lock().unwrap().iter() {
+            if let Some(e) = &zr.error {
+                error!(log, "ZONE {} ROLES {:?} FAILED: {}", zr.zone, zr.roles,
+                    e);
+            } else {
+                info!(log, "ZONE {} ROLES {:?} COMPLETE", zr.zone, zr.roles);
+            }
+        }
+
+        let reboots = self.reboots.lock().unwrap();
+        for r in reboots.iter() {
+            if let Some(z) = &r.zone {
+                warn!(log, "REBOOT REQUIRED FOR ZONE {}: {}", z, r.reason);
+            } else {
+                warn!(log, "REBOOT REQUIRED: {}", r.reason);
+            }
+        }
+
+        if dry_run() {
+            let changes = plan::changes();
+            if self.plan {
+                print!("{}", plan::render(&changes));
             } else {
-                info!(log, "PROCESSING ROLE {} COMPLETE", role.name);
+                let (create, modify, remove, exec) = plan::counts(&changes);
+                info!(log, "DRY RUN: {} to create, {} to modify, {} to remove, \
+                    {} commands to run", create, modify, remove, exec);
             }
         }
 
+        self.summary(run_start.elapsed());
+        self.report(started, run_start.elapsed(), &Ok(()));
+        self.metrics(run_start.elapsed(), &Ok(()));
+        self.history(started, &Ok(()));
+        if !dry_run() {
+            cache::save(log);
+            progress::clear(log);
+        }
+
+        self.event(&Event {
+            event: "run_end",
+            result: Some("complete"),
+            duration_ms: Some(run_start.elapsed().as_millis() as u64),
+            ..Default::default()
+        });
+
         info!(log, "PROCESSING COMPLETE");
 
         Ok(())
     }
-}
 
-impl<'a> Context<'a> {
-    pub fn log(&self) -> &Logger {
-        &self.log
-    }
+    /**
+     * The exit status (EXIT_CHANGED, EXIT_SKIPPED, or both; or 0) for a run
+     * that has completed.
+     */
+    pub fn exit_status(&self) -> i32 {
+        if self.push.is_some() {
+            return self.push_status;
+        }
 
-    pub fn nodename(&self) -> &str {
-        &self.confomat.nodename
-    }
+        let roles = self.role_times.lock().unwrap();
+        let steps = self.step_times.lock().unwrap();
+        let any = |r: &str| {
+            roles.iter().any(|rt| rt.result == r) ||
+                steps.iter().any(|st| !st.nested && st.result == r)
+        };
 
-    pub fn os(&self) -> &OS {
-        &self.confomat.os
+        let mut status = 0;
+        if any("changed") || !plan::changes().is_empty() {
+            status |= EXIT_CHANGED;
+        }
+        if any("skipped") {
+            status |= EXIT_SKIPPED;
+        }
+        status
     }
 
-    pub fn config<C>(&self) -> Result<C>
-        where for<'de> C: serde::Deserialize<'de>
-    {
-        let mut r = self.confomat.dir.clone();
-        r.push("config");
-        r.push(&format!("{}.toml", self.role.name));
+    /**
+     * Apply the roles, as with apply(), and then exit with a status that
+     * reflects the outcome (see EXIT_CHANGED and friends), so that a wrapper
+     * run from cron or CI need not parse the output to know what happened.
+     */
+    pub fn run(mut self) -> ! {
+        let status = match self.apply() {
+            Ok(()) => self.exit_status(),
+            Err(e) => {
+                error!(self.log, "confomat failed: {:?}", e);
+                EXIT_FAILED
+            }
+        };
 
-        match jmclib::toml::read_file(&r) {
-            Ok(Some(c)) => Ok(c),
-            Ok(None) => bail!("config file {} not found", r.display()),
-            Err(e) => bail!("reading config {}: {}", r.display(), e),
-        }
+        /*
+         * exit() does not run destructors, so drop ourselves first to release
+         * the run lock.
+         */
+        drop(self);
+        exit(status);
     }
+}
 
-    pub fn file<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
-        match self.file_maybe(path.as_ref())?  {
-            Some(r) => Ok(r),
-            None => bail!("role file ({:?}, {}, {}) does not exist",
-                &self.instance, &self.role.name,
-                path.as_ref().display()),
+impl<'a> Context<'a> {
+    fn new(confomat: &'a Confomat, role: &'a Role, instance: Option<String>,
+        log: Logger, vars: Cow<'a, serde_json::Value>)
+        -> Context<'a>
+    {
+        Context {
+            confomat,
+            role,
+            instance,
+            log,
+            skip: RefCell::new(None),
+            handlers: RefCell::new(Vec::new()),
+            notify: RefCell::new(Vec::new()),
+            notified: RefCell::new(Vec::new()),
+            in_handler: Cell::new(false),
+            will_retry: Cell::new(false),
+            tags: RefCell::new(Vec::new()),
+            vars,
+            included_by: Vec::new(),
         }
     }
 
-    pub fn file_maybe<P: AsRef<Path>>(&self, path: P)
-        -> Result<Option<PathBuf>>
+    /*
+     * Run one step of a role, recording its outcome (and any changes it made)
+     * in the JSON log, if one was requested.
+     */
+    fn step<T, F>(&self, step: &str, resource: &str, func: F) -> Result<T>
+    where
+        T: Outcome,
+        F: FnOnce() -> Result<T>,
     {
-        let log = &self.log;
-        let p = path.as_ref();
+        let role = Some(self.role.name.as_str());
+        let instance = self.instance.as_deref();
+        let notify = std::mem::take(&mut *self.notify.borrow_mut());
+        let mut tags = std::mem::take(&mut *self.tags.borrow_mut());
+        tags.extend(self.role.tags.iter().cloned());
 
-        if let Some(i) = &self.instance {
-            /*
-             * First, try an instance-specific path:
-             */
-            let mut r = self.confomat.dir.clone();
-            r.push("files");
-            r.push(&self.role.name);
-            r.push("instances");
-            r.push(&i);
-            r.push(p);
+        /*
+         * Tags are checked only for outermost steps: a step run by another
+         * step is part of that step.  Handlers run whenever they have been
+         * notified.
+         */
+        let nested = plan::in_step();
+        let skip = match self.skip.borrow_mut().take() {
+            Some(reason) => Some(reason),
+            None if nested || self.in_handler.get() => None,
+            None => self.confomat.tag_skip(&tags, true).or_else(|| {
+                self.confomat.target_skip(&self.label(), step, resource)
+            }),
+        };
+
+        /*
+         * Once the run has been interrupted, we start no new steps; but a step
+         * already under way runs to completion, including any steps within
+         * it.
+         */
+        if let (Some(sig), false) = (interrupted(), nested) {
+            warn!(self.log, "NOT ATTEMPTING {} {}: interrupted by {}", step,
+                resource, sig);
+            let reason = format!("interrupted by {}", sig);
+            self.confomat.event(&Event {
+                event: "step_end",
+                role,
+                instance,
+                step: Some(step),
+                resource: Some(resource),
+                result: Some("not-attempted"),
+                reason: Some(&reason),
+                duration_ms: Some(0),
+                ..Default::default()
+            });
+            self.confomat.step_times.lock().unwrap().push(StepTime {
+                role: self.label(),
+                step: step.to_string(),
+                resource: resource.to_string(),
+                result: "not-attempted",
+                nested,
+                duration: Duration::from_secs(0),
+                reason: Some(reason.clone()),
+                error: None,
+                changes: Vec::new(),
+            });
+            bail!("{}", reason);
+        }
 
-            debug!(log, "check instance-level path: {}", r.display());
+        if let Some(reason) = skip {
+            debug!(self.log, "SKIPPING {} {}: {}", step, resource, reason);
+            self.confomat.event(&Event {
+                event: "step_end",
+                role,
+                instance,
+                step: Some(step),
+                resource: Some(resource),
+                result: Some("skipped"),
+                reason: Some(&reason),
+                duration_ms: Some(0),
+                ..Default::default()
+            });
+            self.confomat.step_times.lock().unwrap().push(StepTime {
+                role: self.label(),
+                step: step.to_string(),
+                resource: resource.to_string(),
+                result: "skipped",
+                nested,
+                duration: Duration::from_secs(0),
+                reason: Some(reason.clone()),
+                error: None,
+                changes: Vec::new(),
+            });
+            return Ok(T::skipped());
+        }
 
-            match ensure::check(&r)? {
-                Some(fi) if fi.filetype == FileType::File => return Ok(Some(r)),
-                Some(fi) => bail!("path {} is a {:?}, not a file",
-                    r.display(), fi.filetype),
-                None => (),
-            };
+        self.confomat.event(&Event {
+            event: "step_start",
+            role,
+            instance,
+            step: Some(step),
+            resource: Some(resource),
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+        let outer = if nested {
+            None
+        } else {
+            Some(set_step_name(Some(step.to_string())))
+        };
+        plan::begin_step();
+        let res = func();
+        let changes = plan::end_step();
+        if let Some(outer) = outer {
+            set_step_name(outer);
+        }
+        let duration = start.elapsed();
+
+        /*
+         * The changes made by a nested step are also those of the step which
+         * ran it, and are recorded in the audit trail with that step.
+         */
+        if !nested && !dry_run() && !changes.is_empty() {
+            if let Err(e) = self.confomat.audit_trail.record(&self.label(),
+                step, resource, &changes)
+            {
+                warn!(self.log, "could not write to audit trail: {}", e);
+            }
         }
 
-        /*
-         * Otherwise, fall back to the role-level path:
-         */
-        let mut r = self.confomat.dir.clone();
-        r.push("files");
-        r.push(&self.role.name);
-        r.push(p);
+        /*
+         * With "--unsupported=skip", a step which cannot be done on this
+         * platform is skipped rather than failing the run.  A step run by
+         * another step is part of that step, which is skipped as a whole.
+         */
+        let mut reason = None;
+        let res = match res {
+            Err(e) if !nested && skip_unsupported() &&
+                e.downcast_ref::<Unsupported>().is_some() =>
+            {
+                warn!(self.log, "SKIPPING {} {}: {}", step, resource, e);
+                reason = Some(e.to_string());
+                Ok(T::skipped())
+            }
+            res => res,
+        };
+
+        let (result, error) = match &res {
+            _ if reason.is_some() => ("skipped", None),
+            Ok(o) if o.changed() || !changes.is_empty() => ("changed", None),
+            Ok(_) => ("unchanged", None),
+            Err(e) if self.will_retry.get() && !nested => {
+                ("retried", Some(e.to_string()))
+            }
+            Err(e) => ("failed", Some(e.to_string())),
+        };
+        trace!(self.log, "{} {} {} in {:.3}s", step, resource,
+            result.to_uppercase(), duration.as_secs_f64());
+
+        if result == "failed" && !nested && !self.in_handler.get() {
+            self.confomat.failed.lock().unwrap().get_or_insert(Progress {
+                roles: self.confomat.freeargs.clone(),
+                role: self.label(),
+                step: step.to_string(),
+                resource: resource.to_string(),
+            });
+        }
+
+        if result == "changed" {
+            #[cfg(feature = "zones")]
+            self.confomat.zone_facts.lock().unwrap().take();
+
+            let mut notified = self.notified.borrow_mut();
+            for n in notify {
+                if !notified.contains(&n) {
+                    debug!(self.log, "{} {} notified handler {}", step,
+                        resource, n);
+                    notified.push(n);
+                }
+            }
+        }
+
+        self.confomat.event(&Event {
+            event: "step_end",
+            role,
+            instance,
+            step: Some(step),
+            resource: Some(resource),
+            result: Some(result),
+            reason: reason.as_deref(),
+            duration_ms: Some(duration.as_millis() as u64),
+            error: error.clone(),
+            changes: Some(&changes),
+        });
+
+        self.confomat.step_times.lock().unwrap().push(StepTime {
+            role: self.label(),
+            step: step.to_string(),
+            resource: resource.to_string(),
+            result,
+            nested,
+            duration,
+            reason,
+            error,
+            changes,
+        });
+
+        res
+    }
+
+    /*
+     * Determine whether a condition holds.
+     */
+    fn holds(&self, cond: &Condition) -> Result<bool> {
+        match cond {
+            Condition::Command(args) => {
+                if args.is_empty() {
+                    bail!("condition command must not be empty");
+                }
+
+                debug!(self.log, "check condition: {:?}", args);
+                match ensure::query_status_with(&self.log, args,
+                    &self.exec_opts(&Exec::default()))
+                {
+                    Ok(es) => Ok(es.success()),
+                    Err(e) => bail!("condition {}: {}", cond, e),
+                }
+            }
+            Condition::Expr(e) => {
+                match template::condition(e, &self.vars_registered()) {
+                    Ok(b) => Ok(b),
+                    Err(err) => bail!("condition \"{}\": {}", e, err),
+                }
+            }
+        }
+    }
+
+    /*
+     * The name of the role, with the instance, if there is one.
+     */
+    fn label(&self) -> String {
+        match &self.instance {
+            Some(i) => format!("{}:{}", self.role.name, i),
+            None => self.role.name.to_string(),
+        }
+    }
+
+    /*
+     * Check the guards on the role, returning the reason the role should be
+     * skipped, if it should.
+     */
+    fn role_skip(&self) -> Result<Option<String>> {
+        if let Some(reason) = self.confomat.tag_skip(&self.role.tags, false) {
+            return Ok(Some(reason));
+        }
+        for c in self.role.only_if.iter() {
+            if !self.holds(c)? {
+                return Ok(Some(format!("only_if {} does not hold", c)));
+            }
+        }
+        for c in self.role.unless.iter() {
+            if self.holds(c)? {
+                return Ok(Some(format!("unless {} holds", c)));
+            }
+        }
+        Ok(None)
+    }
+
+    /**
+     * Apply the next step only if the condition holds; e.g.,
+     *
+     *      c.only_if(Condition::Command(&["test", "-c", "/dev/vmm"]))?
+     *          .ensure_bhyve_vm("vm0", &vm)?;
+     *
+     * Otherwise, the step is skipped and the skip is reported.
+     */
+    pub fn only_if(&self, cond: Condition) -> Result<&Self> {
+        if !self.holds(&cond)? {
+            let reason = format!("only_if {} does not hold", cond);
+            self.skip.borrow_mut().get_or_insert(reason);
+        }
+        Ok(self)
+    }
+
+    /**
+     * Skip the next step if the condition holds.
+     */
+    pub fn unless(&self, cond: Condition) -> Result<&Self> {
+        if self.holds(&cond)? {
+            let reason = format!("unless {} holds", cond);
+            self.skip.borrow_mut().get_or_insert(reason);
+        }
+        Ok(self)
+    }
+
+    /*
+     * Run, in the order they were declared, each handler that was notified
+     * by a step that changed something.
+     */
+    fn run_handlers(&self) -> Result<()> {
+        let notified = self.notified.borrow().clone();
+        let handlers = self.handlers.borrow().clone();
+
+        for n in notified.iter() {
+            if !handlers.iter().any(|(name, _)| name == n) {
+                bail!("handler \"{}\" was notified but not declared", n);
+            }
+        }
+
+        self.in_handler.set(true);
+        let res = handlers.iter()
+            .filter(|(name, _)| notified.contains(name))
+            .try_for_each(|(name, func)| {
+                info!(self.log, "RUNNING HANDLER {}", name);
+                self.step("handler", name, || func(self))
+            });
+        self.in_handler.set(false);
+
+        res
+    }
+
+    /**
+     * Apply another role as a step of this one, with the given parameters
+     * added to (and overriding) the role variables; e.g.,
+     *
+     *      c.include("managed-service", Some("nginx"), serde_json::json!({
+     *          "user": "www",
+     *          "dataset": "data/www",
+     *      }))?;
+     *
+     * The included role locates its own files and configuration, and runs
+     * its own handlers when it is complete.  Its steps are reported as part
+     * of the "include" step, and the parameters are visible to roles that it
+     * includes in turn.
+     */
+    pub fn include(&self, name: &str, instance: Option<&str>,
+        params: serde_json::Value)
+        -> Result<()>
+    {
+        let role = match self.confomat.role(name) {
+            Some(role) => role,
+            None => bail!("role \"{}\" includes unknown role \"{}\"",
+                self.role.name, name),
+        };
+        if role.allow_instance && instance.is_none() {
+            bail!("role \"{}\" requires an instance", name);
+        }
+        if !role.allow_instance && instance.is_some() {
+            bail!("role \"{}\" does not allow instances", name);
+        }
+        if !params.is_object() && !params.is_null() {
+            bail!("parameters for role \"{}\" must be a table", name);
+        }
+
+        let what = match instance {
+            Some(i) => format!("{}:{}", name, i),
+            None => name.to_string(),
+        };
+
+        /*
+         * A role which is already being applied further up the chain would
+         * include itself again, and so on, forever.
+         */
+        let mut chain = self.included_by.clone();
+        chain.push(match &self.instance {
+            Some(i) => format!("{}:{}", self.role.name, i),
+            None => self.role.name.clone(),
+        });
+        if chain.contains(&what) {
+            bail!("role \"{}\" is included in a cycle: {} -> {}", what,
+                chain.join(" -> "), what);
+        }
+        if chain.len() > MAX_INCLUDE_DEPTH {
+            bail!("role \"{}\" is included more than {} roles deep: {}", what,
+                MAX_INCLUDE_DEPTH, chain.join(" -> "));
+        }
+
+        self.step("include", &what, || {
+            let mut vars = self.vars().clone();
+            if !params.is_null() {
+                vars::merge(&mut vars, params);
+            }
+
+            let log = match instance {
+                Some(i) => self.log.new(o!("include" => name.to_string(),
+                    "instance" => i.to_string())),
+                None => self.log.new(o!("include" => name.to_string())),
+            };
+            let mut ctx = Context::new(self.confomat, role,
+                instance.map(|i| i.to_string()), log, Cow::Owned(vars));
+            ctx.included_by = chain;
+
+            if let Some(reason) = ctx.role_skip()? {
+                info!(self.log, "SKIPPING INCLUDED ROLE {}: {}", what, reason);
+                return Ok(());
+            }
+
+            info!(self.log, "INCLUDING ROLE {}", what);
+            (role.func)(&ctx)?;
+            ctx.run_handlers()
+        })
+    }
+
+    /**
+     * Run an operation, usually one or more steps, retrying it if it fails;
+     * e.g.,
+     *
+     *      c.retry(&Retry::default(), || {
+     *          c.ensure_download(url, &path, hash, HashType::SHA256)
+     *      })?;
+     *
+     * Failed attempts before the last are reported as "retried" rather than
+     * "failed".
+     */
+    pub fn retry<T, F>(&self, retry: &Retry, mut func: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        retry.check()?;
+        let mut delay = retry.delay;
+        let mut attempt = 1;
+
+        loop {
+            let again = || attempt < retry.attempts && interrupted().is_none();
+            let outer = self.will_retry.replace(again());
+            let res = func();
+            self.will_retry.set(outer);
+
+            match res {
+                Err(e) if again() => {
+                    warn!(self.log, "attempt {}/{} failed: {}; retrying in \
+                        {:.1}s", attempt, retry.attempts, e,
+                        delay.as_secs_f64());
+                    std::thread::sleep(delay);
+                    delay = retry.next_delay(delay);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /**
+     * Tag the next step, so that it can be selected with --tags or skipped
+     * with --skip-tags.  Steps also carry the tags of their role.
+     */
+    pub fn tag(&self, tag: &str) -> &Self {
+        self.tags.borrow_mut().push(tag.to_string());
+        self
+    }
+
+    /**
+     * Declare a handler, which will run once at the end of the role if any
+     * step that notified it changed something; e.g.,
+     *
+     *      c.handler("restart-nginx", Box::new(|c| {
+     *          c.run(&["svcadm", "restart", "nginx"])
+     *      }));
+     *
+     * Handlers run in the order in which they were declared, regardless of
+     * the order in which they were notified.
+     */
+    pub fn handler(&self, name: &str, func: HandlerFunc) {
+        self.handlers.borrow_mut().push((name.to_string(), Rc::new(func)));
+    }
+
+    /**
+     * Notify the named handler if the next step changes something; e.g.,
+     *
+     *      c.notify("restart-nginx").ensure_file(...)?;
+     */
+    pub fn notify(&self, handler: &str) -> &Self {
+        self.notify.borrow_mut().push(handler.to_string());
+        self
+    }
+
+    pub fn log(&self) -> &Logger {
+        &self.log
+    }
+
+    pub fn nodename(&self) -> &str {
+        &self.confomat.nodename
+    }
+
+    pub fn os(&self) -> &OS {
+        &self.confomat.os
+    }
+
+    /**
+     * The facts about this system gathered at startup; e.g., the number of
+     * CPUs, or the IP addresses of each interface.
+     */
+    pub fn facts(&self) -> &Facts {
+        &self.confomat.facts
+    }
+
+    pub fn config<C>(&self) -> Result<C>
+        where for<'de> C: serde::Deserialize<'de>
+    {
+        let name = format!("{}.toml", self.role.base_name());
+        let mut r = self.confomat.dir.join("config").join(&name);
+        if let Some(env) = self.confomat.env_dir() {
+            /*
+             * The environment may override the whole configuration file:
+             */
+            let er = env.join("config").join(&name);
+            if er.is_file() {
+                r = er;
+            }
+        }
+
+        match jmclib::toml::read_file(&r) {
+            Ok(Some(c)) => Ok(c),
+            Ok(None) => bail!("config file {} not found", r.display()),
+            Err(e) => bail!("reading config {}: {}", r.display(), e),
+        }
+    }
+
+    /**
+     * Look up a role variable by its dotted path; e.g., "nginx.port".
+     * Returns None if the variable is not set, and an error if it is set but
+     * cannot be converted to the requested type.
+     */
+    pub fn var<T>(&self, name: &str) -> Result<Option<T>>
+        where T: serde::de::DeserializeOwned
+    {
+        let vars = self.vars_registered();
+        match vars::lookup(&vars, name) {
+            Some(v) => match serde_json::from_value(v.clone()) {
+                Ok(t) => Ok(Some(t)),
+                Err(e) => bail!("variable \"{}\": {}", name, e),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * All of the role variables, as a table.  For a role applied with
+     * include(), this includes the parameters passed by the including role.
+     */
+    pub fn vars(&self) -> &serde_json::Value {
+        &self.vars
+    }
+
+    /*
+     * The role variables, along with the values registered so far in the run
+     * as the table "registered", the facts about the system (and, in the
+     * global zone, about each non-global zone) as "facts", and the name and
+     * instance of this role as "role".  These three names are reserved: role
+     * variables of the same names are hidden.
+     */
+    fn vars_registered(&self) -> serde_json::Value {
+        let reg = self.confomat.registered.lock().unwrap();
+
+        let mut vars = self.vars().clone();
+        if let Some(t) = vars.as_object_mut() {
+            if !reg.is_empty() {
+                t.insert("registered".to_string(),
+                    serde_json::Value::Object(reg.clone()));
+            }
+            t.insert("facts".to_string(), self.confomat.facts());
+            #[cfg(feature = "zones")]
+            if let (Some(f), Some(zf)) = (t.get_mut("facts")
+                .and_then(|f| f.as_object_mut()), self.zone_facts_vars())
+            {
+                f.insert("zones".to_string(), zf);
+            }
+            t.insert("role".to_string(), serde_json::json!({
+                "name": self.role.name,
+                "instance": self.instance,
+            }));
+        }
+        vars
+    }
+
+    /**
+     * Render a template using the role variables, any registered values,
+     * and the facts about the system; e.g., "{{ facts.nodename }}".  See the
+     * template module for the syntax.
+     */
+    pub fn render(&self, text: &str) -> Result<String> {
+        template::render(text, &self.vars_registered(),
+            &|path| self.fetch_secret(path))
+    }
+
+    /*
+     * Fetch a value from the external secret source, if there is one.
+     */
+    fn fetch_secret(&self, path: &str) -> Result<String> {
+        let src = match &self.confomat.secret_source {
+            Some(src) => src,
+            None => bail!("no secret source is configured in \
+                secret_source.toml"),
+        };
+
+        let mut fetched = self.confomat.fetched_secrets.lock().unwrap();
+        if let Some(v) = fetched.get(path) {
+            return Ok(v.clone());
+        }
+        let v = src.fetch(&self.log, path)?;
+        fetched.insert(path.to_string(), v.clone());
+        Ok(v)
+    }
+
+    /*
+     * Whether rendered contents include any secret value, and so must not be
+     * shown in a diff.
+     */
+    fn contains_secret(&self, contents: &str) -> bool {
+        self.confomat.secret_strings.iter()
+            .any(|s| contents.contains(s.as_str())) ||
+            self.confomat.fetched_secrets.lock().unwrap().values()
+                .any(|s| !s.is_empty() && contents.contains(s.as_str()))
+    }
+
+    /**
+     * Register a value as the variable "registered.NAME", for use by the
+     * later steps and templates of every role in this run.  A value
+     * registered again under the same name replaces the earlier one.
+     */
+    pub fn register_value(&self, name: &str, value: serde_json::Value)
+        -> Result<()>
+    {
+        if name.is_empty() || name.contains('.') {
+            bail!("invalid name for registered value: \"{}\"", name);
+        }
+
+        debug!(self.log, "registered {} = {}", name, value);
+        self.confomat.registered.lock().unwrap()
+            .insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /**
+     * Run a command as with query_output(), even in dry-run mode, and
+     * register what it wrote to stdout, less any trailing newline, as
+     * "registered.NAME"; e.g., to find the first physical link:
+     *
+     *      let link = c.register("link", &["/usr/sbin/dladm", "show-phys",
+     *          "-p", "-o", "link"], &Exec::default())?;
+     *
+     * and then, in a template, "{{ registered.link }}".
+     */
+    pub fn register<S: AsRef<str>>(&self, name: &str, args: &[S],
+        opts: &Exec)
+        -> Result<String>
+    {
+        let out = self.query_output(args, opts)?;
+        let out = out.strip_suffix('\n').unwrap_or(&out).to_string();
+        self.register_value(name, serde_json::Value::String(out.clone()))?;
+        Ok(out)
+    }
+
+    /**
+     * Run a command as for register(), but parse what it wrote to stdout as
+     * JSON, and register the resulting value.
+     */
+    pub fn register_json<S: AsRef<str>>(&self, name: &str, args: &[S],
+        opts: &Exec)
+        -> Result<serde_json::Value>
+    {
+        let out = self.query_output(args, opts)?;
+        let value: serde_json::Value = match serde_json::from_str(&out) {
+            Ok(v) => v,
+            Err(e) => {
+                let args: Vec<&str> = args.iter().map(|a| a.as_ref())
+                    .collect();
+                bail!("register {}: output of {:?} is not JSON: {}", name,
+                    args, e);
+            }
+        };
+        self.register_value(name, value.clone())?;
+        Ok(value)
+    }
+
+    /**
+     * The environment (e.g., "production" or "lab") selected with "--env" or
+     * by the inventory, if any.
+     */
+    pub fn env(&self) -> Option<&str> {
+        self.confomat.env.as_deref()
+    }
+
+    /*
+     * The locations in which to look for a role file or directory, most
+     * specific first: the instance-level paths, and then the role-level
+     * paths, each first in the overlay for the environment (if any) and then
+     * in the data directory itself.
+     */
+    fn search_paths(&self, p: &Path) -> Vec<(&'static str, PathBuf)> {
+        let name = self.role.base_name();
+        let env = self.confomat.env_dir();
+        let dir = &self.confomat.dir;
+        let mut out = Vec::new();
+
+        if let Some(i) = &self.instance {
+            let inst = Path::new("files").join(name).join("instances").join(i)
+                .join(p);
+            if let Some(e) = &env {
+                out.push(("environment instance-level", e.join(&inst)));
+            }
+            out.push(("instance-level", dir.join(&inst)));
+        }
+
+        let role = Path::new("files").join(name).join(p);
+        if let Some(e) = &env {
+            out.push(("environment role-level", e.join(&role)));
+        }
+        out.push(("role-level", dir.join(&role)));
+        out
+    }
+
+    pub fn file<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        match self.file_maybe(path.as_ref())?  {
+            Some(r) => Ok(r),
+            None => bail!("role file ({:?}, {}, {}) does not exist",
+                &self.instance, &self.role.name,
+                path.as_ref().display()),
+        }
+    }
+
+    pub fn file_maybe<P: AsRef<Path>>(&self, path: P)
+        -> Result<Option<PathBuf>>
+    {
+        let log = &self.log;
+        let p = path.as_ref();
+
+        for (level, r) in self.search_paths(p) {
+            debug!(log, "check {} path: {}", level, r.display());
+
+            match ensure::check(&r)? {
+                Some(fi) if fi.filetype == FileType::File => return Ok(Some(r)),
+                Some(fi) => bail!("path {} is a {:?}, not a file",
+                    r.display(), fi.filetype),
+                None => (),
+            };
+        }
+
+        Ok(None)
+    }
+
+    pub fn files<P: AsRef<Path>>(&self, path: P) -> Result<Vec<PathBuf>> {
+        match self.files_maybe(path.as_ref())?  {
+            Some(r) => Ok(r),
+            None => bail!("role files directory ({:?}, {}, {}) \
+                does not exist", &self.instance, &self.role.name,
+                path.as_ref().display()),
+        }
+    }
+
+    pub fn files_maybe<P: AsRef<Path>>(&self, path: P)
+        -> Result<Option<Vec<PathBuf>>>
+    {
+        let log = &self.log;
+        let p = path.as_ref();
+
+        fn enum_files(r: &Path) -> Result<Option<Vec<PathBuf>>> {
+            let mut out = Vec::new();
+            let mut rd = std::fs::read_dir(&r)?;
+            while let Some(ent) = rd.next().transpose()? {
+                let path = ent.path();
+
+                if !ent.file_type()?.is_file() {
+                    bail!("resource path {} should contain just \
+                        files, but {} is of another type", r.display(),
+                        path.display());
+                }
+
+                out.push(path);
+            }
+            Ok(Some(out))
+        }
+
+        for (level, r) in self.search_paths(p) {
+            debug!(log, "check {} path: {}", level, r.display());
+
+            match ensure::check(&r)? {
+                Some(fi) if fi.filetype == FileType::Directory =>
+                    return enum_files(&r),
+                Some(fi) => bail!("path {} is a {:?}, not a dir",
+                    r.display(), fi.filetype),
+                None => (),
+            }
+        }
+
+        Ok(None)
+    }
+
+    #[cfg(feature = "smf")]
+    pub fn homedir(&self) -> Result<HomeDir> {
+        self.need_illumos("homedir")?;
+        let log = &self.log;
+
+        /*
+         * First, determine whether the automounter is online or disabled.
+         */
+        let autofs = match
+            instance_state("svc:/system/filesystem/autofs:default")?
+        {
+            (SMFState::Offline, None) => false,
+            (SMFState::Online, None) => true,
+            x => bail!("autofs not in stable state: {:?}", x),
+        };
+
+        if autofs {
+            /*
+             * Make sure the automounter is up-to-date with any changes to the
+             * map files.
+             */
+            self.run(&["/usr/sbin/automount"])?;
+        }
+
+        /*
+         * Determine whether /home is a ZFS dataset, or automounted.
+         */
+        let mnttab = self.read_lines("/etc/mnttab")?.expect("mnttab lines");
+        let x: Vec<Vec<_>> = mnttab.iter()
+            .map(|m| { m.split('\t').collect() })
+            .collect();
+        let homedir = if let Some(h) = x.iter().find(|x| x[1] == "/home") {
+            debug!(log, "/home mnttab entry: {:?}", h);
+            if h[2] == "zfs" {
+                HomeDir::ZFS(h[0].to_string())
+            } else if h[2] == "autofs" {
+                if !autofs {
+                    bail!("autofs disabled, but autofs /home detected");
+                }
+                HomeDir::NFS
+            } else {
+                bail!("unknown /home type: {:?}", h);
+            }
+        } else {
+            /*
+             * If neither a ZFS dataset nor the automounter are anchored at
+             * /home, treat this is a bare directory.
+             */
+            HomeDir::Bare
+        };
+        info!(log, "home directory type: {:?}", homedir);
+        Ok(homedir)
+    }
+
+    pub fn is_gz(&self) -> bool {
+        self.confomat.zoneid == 0
+    }
+
+    /**
+     * The error for something which this platform cannot do; see
+     * "--unsupported".
+     */
+    pub fn unsupported(&self, what: &str) -> anyhow::Error {
+        Unsupported::new(what, &format!("{:?}", self.os())).into()
+    }
+
+    fn need_illumos(&self, what: &str) -> Result<()> {
+        if !self.os().is_illumos() {
+            return Err(self.unsupported(what));
+        }
+        Ok(())
+    }
+
+    /**
+     * For steps which touch hardware (e.g., firmware settings or drivers),
+     * require that confomat be running on a physical machine, rather than in
+     * a virtual machine or a zone.  Elsewhere, the step is unsupported, and
+     * so may be skipped with "--unsupported skip".
+     */
+    pub fn need_bare_metal(&self, what: &str) -> Result<()> {
+        let v = &self.facts().virtualization;
+        if v.is_virtual() {
+            return Err(Unsupported::new(what, &v.describe()).into());
+        }
+        Ok(())
+    }
+
+    fn need_freebsd(&self, what: &str) -> Result<()> {
+        if *self.os() != OS::FreeBSD {
+            return Err(self.unsupported(what));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "zfs")]
+    pub fn data_dataset(&self) -> Result<String> {
+        match self.confomat.os {
+            OS::SmartOS => if self.is_gz() {
+                bail!("do not know where to put data in SmartOS GZ");
+            } else {
+                /*
+                 * Assume a delegated dataset is configured with the usual name
+                 * it would get under Triton, or using the "delegate_dataset"
+                 * property supported by vmadm(1M).
+                 */
+                Ok(format!("zones/{}/data", self.confomat.zonename))
+            }
+            OS::OmniOS | OS::OpenIndiana => if self.is_gz() {
+                Ok("rpool/data".to_string())
+            } else {
+                /*
+                 * XXX This is really a policy decision made for a specific set
+                 * of zones on a specific set of OmniOS hosts, but it will have
+                 * to do for now:
+                 */
+                Ok(format!("rpool/data/{}/data", self.confomat.zonename))
+            }
+            _ => Err(self.unsupported("data_dataset")),
+        }
+    }
+
+    pub fn check<P: AsRef<Path>>(&self, path: P) -> Result<Option<FileInfo>> {
+        ensure::check(rooted(path)?)
+    }
+
+    #[cfg(feature = "zfs")]
+    pub fn ensure_dataset(&self, dsname: &str, opts: &[&str]) -> Result<()> {
+        self.step("ensure_dataset", dsname, || {
+            /*
+             * XXX Assume failure here means we should try to create the
+             * dataset.
+             */
+            if ensure::query(&self.log, &["/usr/sbin/zfs", "list", "-H", "-o",
+                "name", &dsname]).is_ok()
+            {
+                info!(self.log, "dataset {} exists already", dsname);
+                return Ok(());
+            }
+
+            info!(self.log, "create dataset: {}", dsname);
+
+            let mut args: Vec<&str> = vec![
+                "/usr/sbin/zfs",
+                "create",
+            ];
+            for opt in opts.iter() {
+                args.push("-o");
+                args.push(opt);
+            }
+            args.push(dsname);
+
+            ensure::run(&self.log, &args)?;
+            Ok(())
+        })
+    }
+
+    /**
+     * Ensure that an encrypted dataset exists, that its key has been
+     * installed from an encrypted role file, and that the key is loaded and
+     * the dataset mounted.
+     */
+    #[cfg(feature = "zfs")]
+    pub fn ensure_encrypted_dataset(&self, ds: &EncryptedDataset)
+        -> Result<bool>
+    {
+        self.step("ensure_encrypted_dataset", &ds.name, || {
+            if let Some(root) = alt_root() {
+                bail!("datasets cannot be managed in alternate root {}",
+                    root.display());
+            }
+
+            let key = self.secret(&ds.key_secret)?;
+            if let Err(e) = ds.key_format.check(&key) {
+                bail!("key {} for dataset {}: {}", ds.key_secret.display(),
+                    ds.name, e);
+            }
+
+            let path = ds.key_path();
+            let mut did_work = false;
+            if ds.key_path.is_none() {
+                did_work |= ensure::directory(&self.log,
+                    path.parent().unwrap(), "root", self.os().root_group(),
+                    0o700)?;
+            }
+            did_work |= ensure::secret_contents(&self.log, &path, &key,
+                &Ownership::Names("root", self.os().root_group()), 0o400)?;
+
+            if zfs::encrypted(&self.log, ds)? {
+                did_work = true;
+                if dry_run() {
+                    /*
+                     * The dataset was not really created, so there is
+                     * nothing to unlock.
+                     */
+                    return Ok(true);
+                }
+            }
+            did_work |= zfs::unlocked(&self.log, &ds.name)?;
+            Ok(did_work)
+        })
+    }
+
+    /**
+     * Ensure that the key for an existing encrypted dataset is loaded from
+     * its keylocation, and that the dataset is mounted.
+     */
+    #[cfg(feature = "zfs")]
+    pub fn ensure_dataset_unlocked(&self, dsname: &str) -> Result<bool> {
+        self.step("ensure_dataset_unlocked", dsname, || {
+            if let Some(root) = alt_root() {
+                bail!("datasets cannot be managed in alternate root {}",
+                    root.display());
+            }
+
+            zfs::unlocked(&self.log, dsname)
+        })
+    }
+
+    #[cfg(feature = "zones")]
+    pub fn zone(&self, name: &str) -> Result<Option<Zone>> {
+        self.need_illumos("zone")?;
+        zones::zone(name)
+    }
+
+    #[cfg(feature = "zones")]
+    pub fn zones(&self) -> Result<Vec<Zone>> {
+        self.need_illumos("zones")?;
+        zones::zones()
+    }
+
+    /**
+     * Collect facts about each non-global zone on the system: brand, state,
+     * IP addresses, and delegated datasets.  This allows, e.g., a reverse
+     * proxy role to enumerate its backend zones.
+     */
+    #[cfg(feature = "zones")]
+    pub fn zone_facts(&self) -> Result<Vec<ZoneFacts>> {
+        self.need_illumos("zone_facts")?;
+        if !self.is_gz() {
+            bail!("zone facts are only available in the global zone");
+        }
+
+        zones::facts(&self.log)
+    }
+
+    /*
+     * The facts about each non-global zone, as the list "facts.zones" in the
+     * template variables.  These are only gathered in the global zone of an
+     * illumos system; a failure to gather them is reported, and leaves them
+     * out.
+     */
+    #[cfg(feature = "zones")]
+    fn zone_facts_vars(&self) -> Option<serde_json::Value> {
+        if !self.os().is_illumos() || !self.is_gz() {
+            return None;
+        }
+
+        let mut cache = self.confomat.zone_facts.lock().unwrap();
+        if cache.is_none() {
+            match zones::facts(&self.log) {
+                Ok(zf) => {
+                    *cache = Some(serde_json::Value::Array(zf.iter()
+                        .map(|z| serde_json::json!({
+                            "name": z.name,
+                            "brand": z.brand,
+                            "state": z.state.as_str(),
+                            "path": z.path,
+                            "addresses": z.addresses,
+                            "datasets": z.datasets,
+                        }))
+                        .collect()));
+                }
+                Err(e) => warn!(self.log, "facts: zones: {}", e),
+            }
+        }
+        cache.clone()
+    }
+
+    /**
+     * Ensure that a configured zone is installed and booted, waiting for the
+     * multi-user milestone within the zone to come online.  The step fails if
+     * the zone is not up within 15 minutes.
+     */
+    #[cfg(feature = "zones")]
+    pub fn ensure_zone_running(&self, name: &str) -> Result<bool> {
+        self.step("ensure_zone_running", name, || {
+            self.need_illumos("ensure_zone_running")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            zones::running(&self.log, name)
+        })
+    }
+
+    /**
+     * Apply a set of roles within a running non-global zone.  A copy of the
+     * running confomat binary and data directory is staged within the zone,
+     * and then executed there via zlogin(1).  The outcome is recorded so that
+     * it can be reported at the end of the global zone run.
+     */
+    #[cfg(feature = "zones")]
+    pub fn apply_in_zone(&self, name: &str, roles: &[&str]) -> Result<()> {
+        self.step("apply_in_zone", name, || {
+            self.need_illumos("apply_in_zone")?;
+            if !self.is_gz() {
+                bail!("roles may only be applied in zones from the global \
+                    zone");
+            }
+
+            let log = self.log.new(o!("zone" => name.to_string()));
+            info!(log, "applying roles {:?} in zone {}", roles, name);
+
+            let exe = std::env::current_exe()?;
+            let res = zones::apply_roles(&log, name, &exe, &self.confomat.dir,
+                roles);
+
+            self.confomat.zone_runs.lock().unwrap().push(ZoneRun {
+                zone: name.to_string(),
+                roles: roles.iter().map(|r| r.to_string()).collect(),
+                error: res.as_ref().err().map(|e| e.to_string()),
+            });
+
+            match res {
+                Ok(status) => Ok(status & EXIT_CHANGED != 0),
+                Err(e) => bail!("roles {:?} in zone {} failed: {}", roles,
+                    name, e),
+            }
+        }).map(|_| ())
+    }
+
+    /**
+     * Ensure the capped-memory, capped-cpu, and cpu-shares settings for a
+     * zone.  Changes are applied live where possible; any that cannot be are
+     * recorded as requiring a reboot of the zone.
+     */
+    #[cfg(feature = "zones")]
+    pub fn ensure_zone_limits(&self, name: &str, limits: &ZoneLimits)
+        -> Result<bool>
+    {
+        self.step("ensure_zone_limits", name, || {
+            self.need_illumos("ensure_zone_limits")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            let (did_work, reboot) = zones::limits(&self.log, name, limits)?;
+            for r in reboot {
+                self.zone_reboot_required(name, &r);
+            }
+
+            Ok(did_work)
+        })
+    }
+
+    /**
+     * Ensure that a bhyve-branded zone hosting a guest virtual machine is
+     * configured as described, with its disk volumes created, and that it is
+     * installed and running.
+     */
+    #[cfg(feature = "zones")]
+    pub fn ensure_bhyve_vm(&self, name: &str, vm: &BhyveVm) -> Result<bool> {
+        self.step("ensure_bhyve_vm", name, || {
+            self.need_illumos("ensure_bhyve_vm")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            let (did_work, reboot) = zones::bhyve(&self.log, name, vm)?;
+            if reboot {
+                self.zone_reboot_required(name, "bhyve configuration changed");
+            }
+
+            Ok(did_work)
+        })
+    }
+
+    /**
+     * Ensure that an lx-branded zone is configured as described, installed
+     * from the nominated image, and running.
+     */
+    #[cfg(feature = "zones")]
+    pub fn ensure_lx_zone(&self, name: &str, lz: &LxZone) -> Result<bool> {
+        self.step("ensure_lx_zone", name, || {
+            self.need_illumos("ensure_lx_zone")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            let (did_work, reboot) = zones::lx(&self.log, name, lz)?;
+            if reboot {
+                self.zone_reboot_required(name, "lx configuration changed");
+            }
+
+            Ok(did_work)
+        })
+    }
+
+    /**
+     * Ensure that a zone exists as a clone of the installed (and halted)
+     * source zone, rather than installing it from scratch.  The clone uses the
+     * configuration of the source as a template, with the provided zone path
+     * and any zonecfg(1M) subcommands in "overrides" applied; e.g.,
+     * "set autoboot=false".  The zone is left installed, but not booted.
+     */
+    #[cfg(feature = "zones")]
+    pub fn ensure_zone_cloned(&self, source: &str, name: &str, zonepath: &str,
+        overrides: &[&str])
+        -> Result<bool>
+    {
+        self.step("ensure_zone_cloned", name, || {
+            self.need_illumos("ensure_zone_cloned")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            zones::cloned(&self.log, source, name, zonepath, overrides)
+        })
+    }
+
+    /**
+     * Ensure a directory exists within the root of a zone.  The path is
+     * interpreted as it would be within the zone, and symbolic links within
+     * the zone are not permitted to escape the zone root.  The owner and group
+     * are resolved using the user database of the zone.
+     */
+    #[cfg(feature = "zones")]
+    pub fn ensure_zone_dir<P: AsRef<Path>>(&self, zone: &str, dir: P,
+        owner: &str, group: &str, perms: u32)
+        -> Result<bool>
+    {
+        let res = format!("{}:{}", zone, dir.as_ref().display());
+        self.step("ensure_zone_dir", &res, || {
+            self.need_illumos("ensure_zone_dir")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            zones::directory(&self.log, zone, dir.as_ref(), owner, group, perms)
+        })
+    }
+
+    /**
+     * Ensure a file exists within the root of a zone, with the same semantics
+     * as ensure_file().  As with ensure_zone_dir(), the destination path is
+     * resolved safely within the zone root.
+     */
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(feature = "zones")]
+    pub fn ensure_zone_file<S: AsRef<Path>, D: AsRef<Path>>(&self, zone: &str,
+        src: S, dst: D, owner: &str, group: &str, perms: u32,
+        create: Create)
+        -> Result<bool>
+    {
+        let res = format!("{}:{}", zone, dst.as_ref().display());
+        self.step("ensure_zone_file", &res, || {
+            self.need_illumos("ensure_zone_file")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            zones::file(&self.log, zone, src.as_ref(), dst.as_ref(), owner,
+                group, perms, create)
+        })
+    }
+
+    /**
+     * Ensure that a dataset exists, creating it with the provided properties
+     * if needed, and that it is delegated to the nominated zone.  If the zone
+     * is running, a reboot of the zone is recorded as required.
+     */
+    #[cfg(feature = "zones")]
+    pub fn ensure_delegated_dataset(&self, zone: &str, dsname: &str,
+        opts: &[&str])
+        -> Result<bool>
+    {
+        self.step("ensure_delegated_dataset", dsname, || {
+            self.need_illumos("ensure_delegated_dataset")?;
+            if !self.is_gz() {
+                bail!("zones may only be managed from the global zone");
+            }
+
+            self.ensure_dataset(dsname, opts)?;
+
+            let (did_work, reboot) = zones::delegate(&self.log, zone, dsname)?;
+            if reboot {
+                self.zone_reboot_required(zone,
+                    &format!("dataset {} delegated", dsname));
+            }
+
+            Ok(did_work)
+        })
+    }
+
+    /**
+     * Ask for the boot archive to be updated at the end of the run; e.g.,
+     * after a command which changed a file in it, which confomat cannot
+     * otherwise see.  Changes confomat makes itself to files in the archive
+     * are noticed without this.
+     */
+    pub fn update_boot_archive(&self) {
+        *self.confomat.boot_archive.lock().unwrap() = true;
+    }
+
+    /**
+     * Record that a change has been made which will not take full effect
+     * until the system is rebooted.  This is reported at the end of the run.
+     */
+    pub fn reboot_required(&self, reason: &str) {
+        warn!(self.log, "reboot required: {}", reason);
+        self.confomat.reboots.lock().unwrap().push(Reboot {
+            zone: None,
+            reason: reason.to_string(),
+        });
+    }
+
+    #[cfg(feature = "zones")]
+    fn zone_reboot_required(&self, zone: &str, reason: &str) {
+        warn!(self.log, "reboot of zone {} required: {}", zone, reason);
+        self.confomat.reboots.lock().unwrap().push(Reboot {
+            zone: Some(zone.to_string()),
+            reason: reason.to_string(),
+        });
+    }
 
-        debug!(log, "check role-level path: {}", r.display());
+    /**
+     * Ensure that an ipadm(1M) address object (e.g., "net0/v4") exists with
+     * the given type and value, creating the IP interface if required.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_address(&self, addrobj: &str, addr: &Address)
+        -> Result<bool>
+    {
+        self.step("ensure_address", addrobj, || {
+            self.need_illumos("ensure_address")?;
+            net::address(&self.log, addrobj, addr)
+        })
+    }
 
-        match ensure::check(&r)? {
-            Some(fi) if fi.filetype == FileType::File => Ok(Some(r)),
-            Some(fi) => bail!("path {} is a {:?}, not a file",
-                r.display(), fi.filetype),
-            None => Ok(None),
-        }
+    #[cfg(feature = "net")]
+    pub fn ensure_etherstub(&self, name: &str) -> Result<bool> {
+        self.step("ensure_etherstub", name, || {
+            self.need_illumos("ensure_etherstub")?;
+            net::etherstub(&self.log, name)
+        })
     }
 
-    pub fn files<P: AsRef<Path>>(&self, path: P) -> Result<Vec<PathBuf>> {
-        match self.files_maybe(path.as_ref())?  {
-            Some(r) => Ok(r),
-            None => bail!("role files directory ({:?}, {}, {}) \
-                does not exist", &self.instance, &self.role.name,
-                path.as_ref().display()),
-        }
+    /**
+     * Ensure that a VNIC exists over the nominated link, with the requested
+     * MAC address and VLAN ID.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_vnic(&self, name: &str, vnic: &Vnic) -> Result<bool> {
+        self.step("ensure_vnic", name, || {
+            self.need_illumos("ensure_vnic")?;
+            net::vnic(&self.log, name, vnic)
+        })
     }
 
-    pub fn files_maybe<P: AsRef<Path>>(&self, path: P)
-        -> Result<Option<Vec<PathBuf>>>
+    /**
+     * Ensure that a persistent static route exists to the destination via
+     * the nominated gateway, replacing any other persistent route to the same
+     * destination.  IPv6 routes are supported; e.g., ("default",
+     * "fe80::1%net0") for a default route via a link-local router.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_route(&self, destination: &str, gateway: &str)
+        -> Result<bool>
     {
-        let log = &self.log;
-        let p = path.as_ref();
-
-        fn enum_files(r: &Path) -> Result<Option<Vec<PathBuf>>> {
-            let mut out = Vec::new();
-            let mut rd = std::fs::read_dir(&r)?;
-            while let Some(ent) = rd.next().transpose()? {
-                let path = ent.path();
+        self.step("ensure_route", destination, || {
+            self.need_illumos("ensure_route")?;
+            net::route(&self.log, destination, gateway)
+        })
+    }
 
-                if !ent.file_type()?.is_file() {
-                    bail!("resource path {} should contain just \
-                        files, but {} is of another type", r.display(),
-                        path.display());
-                }
+    /**
+     * Ensure that a datalink property (e.g., "mtu") has the given value,
+     * applied persistently via dladm(1M).
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_link_prop(&self, link: &str, prop: &str, value: &str)
+        -> Result<bool>
+    {
+        self.step("ensure_link_prop", &format!("{}/{}", link, prop), || {
+            self.need_illumos("ensure_link_prop")?;
+            net::link_prop(&self.log, link, prop, value)
+        })
+    }
 
-                out.push(path);
-            }
-            Ok(Some(out))
-        }
+    /**
+     * Ensure that an IPMP group exists over the nominated interfaces, with
+     * its data and test addresses and failure detection settings.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_ipmp(&self, name: &str, group: &IpmpGroup) -> Result<bool> {
+        self.step("ensure_ipmp", name, || {
+            self.need_illumos("ensure_ipmp")?;
+            net::ipmp(&self.log, name, group)
+        })
+    }
 
-        if let Some(i) = &self.instance {
-            /*
-             * First, try an instance-specific path:
-             */
-            let mut r = self.confomat.dir.clone();
-            r.push("files");
-            r.push(&self.role.name);
-            r.push("instances");
-            r.push(i);
-            r.push(p);
+    /**
+     * Ensure the system nodename is set consistently in SMF, nodename(4), and
+     * the hosts(4) loopback entries, and that the running nodename matches.
+     * Note that nodename() continues to report the name the system had when
+     * confomat started.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_nodename(&self, name: &str) -> Result<bool> {
+        self.step("ensure_nodename", name, || {
+            self.need_illumos("ensure_nodename")?;
+            net::nodename(&self.log, name)
+        })
+    }
 
-            debug!(log, "check instance-level path: {}", r.display());
+    /**
+     * Ensure the DNS client configuration (nameservers, search domains) and
+     * name service switch databases are configured through SMF, rather than by
+     * writing resolv.conf(4) and nsswitch.conf(4), which nscfg(1M) would
+     * overwrite.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_dns_client(&self, dns: &DnsClient) -> Result<bool> {
+        let fmri = "svc:/network/dns/client:default";
+        self.step("ensure_dns_client", fmri, || {
+            self.need_illumos("ensure_dns_client")?;
+            let did_work = net::dns_client(&self.log, dns)?;
+            self.ensure_online(fmri, false)?;
+            Ok(did_work)
+        })
+    }
 
-            match ensure::check(&r)? {
-                Some(fi) if fi.filetype == FileType::Directory =>
-                    return enum_files(&r),
-                Some(fi) => bail!("path {} is a {:?}, not a dir",
-                    r.display(), fi.filetype),
-                None => (),
+    /**
+     * Configure time synchronisation with the given servers, using either
+     * NTP or chrony, and make sure the service is online.  The clock is then
+     * checked for synchronisation; this is reported, but is not fatal, as it
+     * may take some minutes after a restart.
+     */
+    #[cfg(all(feature = "net", feature = "smf"))]
+    pub fn ensure_time_sync(&self, daemon: TimeDaemon, servers: &[&str])
+        -> Result<bool>
+    {
+        self.step("ensure_time_sync", daemon.fmri(), || {
+            self.need_illumos("ensure_time_sync")?;
+            if servers.is_empty() {
+                bail!("at least one time server is required");
             }
-        }
-
-        /*
-         * Otherwise, fall back to the role-level path:
-         */
-        let mut r = self.confomat.dir.clone();
-        r.push("files");
-        r.push(&self.role.name);
-        r.push(p);
 
-        debug!(log, "check role-level path: {}", r.display());
+            let changed = ntp::configure(&self.log, daemon, servers)?;
+            self.ensure_online(daemon.fmri(), changed)?;
+            if !dry_run() {
+                ntp::verify(&self.log, daemon)?;
+            }
 
-        match ensure::check(&r)? {
-            Some(fi) if fi.filetype == FileType::Directory => enum_files(&r),
-            Some(fi) => bail!("path {} is a {:?}, not a dir",
-                r.display(), fi.filetype),
-            None => Ok(None),
-        }
+            Ok(changed)
+        })
     }
 
-    pub fn homedir(&self) -> Result<HomeDir> {
-        let log = &self.log;
+    /**
+     * Ensure that the time zone of the system is "tz", which must be in the
+     * zoneinfo database; e.g., "Australia/Sydney" or "UTC".  Processes which
+     * are already running keep the zone they started with: on illumos, where
+     * every service takes its zone from init(1M), a reboot is required.
+     */
+    pub fn ensure_timezone(&self, tz: &str) -> Result<bool> {
+        self.step("ensure_timezone", tz, || {
+            let os = self.os();
+            if !os.is_illumos() && !os.is_linux()
+                && !matches!(os, OS::FreeBSD | OS::OpenBSD)
+            {
+                return Err(self.unsupported("ensure_timezone"));
+            }
 
-        /*
-         * First, determine whether the automounter is online or disabled.
-         */
-        let autofs = match
-            instance_state("svc:/system/filesystem/autofs:default")?
-        {
-            (SMFState::Offline, None) => false,
-            (SMFState::Online, None) => true,
-            x => bail!("autofs not in stable state: {:?}", x),
-        };
+            let changed = timezone::ensure(&self.log, os, tz)?;
+            if changed && os.is_illumos() {
+                self.reboot_required(&format!("time zone changed to {}", tz));
+            } else if changed {
+                warn!(self.log, "time zone changed to {}; running services, \
+                    and users who are logged in, still use the old zone", tz);
+            }
 
-        if autofs {
-            /*
-             * Make sure the automounter is up-to-date with any changes to the
-             * map files.
-             */
-            self.run(&["/usr/sbin/automount"])?;
-        }
+            Ok(changed)
+        })
+    }
 
-        /*
-         * Determine whether /home is a ZFS dataset, or automounted.
-         */
-        let mnttab = self.read_lines("/etc/mnttab")?.expect("mnttab lines");
-        let x: Vec<Vec<_>> = mnttab.iter()
-            .map(|m| { m.split('\t').collect() })
-            .collect();
-        let homedir = if let Some(h) = x.iter().find(|x| x[1] == "/home") {
-            debug!(log, "/home mnttab entry: {:?}", h);
-            if h[2] == "zfs" {
-                HomeDir::ZFS(h[0].to_string())
-            } else if h[2] == "autofs" {
-                if !autofs {
-                    bail!("autofs disabled, but autofs /home detected");
-                }
-                HomeDir::NFS
-            } else {
-                bail!("unknown /home type: {:?}", h);
+    /**
+     * Ensure the default locale of the system, as (NAME, VALUE) for LANG and
+     * the LC_* variables; e.g., ("LANG", "en_US.UTF-8").  Each locale must be
+     * installed.  Other settings are left as they are.
+     */
+    pub fn ensure_locale(&self, settings: &[(&str, &str)]) -> Result<bool> {
+        self.step("ensure_locale", "locale", || {
+            let changed = locale::locale(&self.log, self.os(), settings)?;
+            if changed {
+                self.environment_changed("locale");
             }
-        } else {
-            /*
-             * If neither a ZFS dataset nor the automounter are anchored at
-             * /home, treat this is a bare directory.
-             */
-            HomeDir::Bare
-        };
-        info!(log, "home directory type: {:?}", homedir);
-        Ok(homedir)
+            Ok(changed)
+        })
     }
 
-    pub fn is_gz(&self) -> bool {
-        self.confomat.zoneid == 0
+    /**
+     * Ensure variables in the environment which every login starts with
+     * (/etc/environment on Linux).  On illumos, in /etc/default/init, only
+     * CMASK and the locale may be set.
+     */
+    pub fn ensure_environment(&self, vars: &[(&str, &str)]) -> Result<bool> {
+        self.step("ensure_environment", "environment", || {
+            let changed = locale::environment(&self.log, self.os(), vars)?;
+            if changed {
+                self.environment_changed("default environment");
+            }
+            Ok(changed)
+        })
     }
 
-    pub fn data_dataset(&self) -> Result<String> {
-        match self.confomat.os {
-            OS::SmartOS => if self.is_gz() {
-                bail!("do not know where to put data in SmartOS GZ");
-            } else {
-                /*
-                 * Assume a delegated dataset is configured with the usual name
-                 * it would get under Triton, or using the "delegate_dataset"
-                 * property supported by vmadm(1M).
-                 */
-                Ok(format!("zones/{}/data", self.confomat.zonename))
-            }
-            OS::OmniOS | OS::OpenIndiana => if self.is_gz() {
-                Ok("rpool/data".to_string())
-            } else {
-                /*
-                 * XXX This is really a policy decision made for a specific set
-                 * of zones on a specific set of OmniOS hosts, but it will have
-                 * to do for now:
-                 */
-                Ok(format!("rpool/data/{}/data", self.confomat.zonename))
-            }
+    /*
+     * On illumos, the defaults in /etc/default/init are only read at boot;
+     * elsewhere, they apply to each new login.
+     */
+    fn environment_changed(&self, what: &str) {
+        if self.os().is_illumos() {
+            self.reboot_required(&format!("{} changed", what));
+        } else {
+            warn!(self.log, "{} changed; users must log in again for it to \
+                take effect", what);
         }
     }
 
-    pub fn check<P: AsRef<Path>>(&self, path: P) -> Result<Option<FileInfo>> {
-        ensure::check(path)
-    }
+    /**
+     * Ensure the IP Filter rule sets are installed and active.  Each rule set
+     * is checked for syntax errors before it is installed, and the ipfilter
+     * service is only refreshed to load new rules once validation passes.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_ipfilter(&self, ipf: &str, ipnat: Option<&str>)
+        -> Result<bool>
+    {
+        self.step("ensure_ipfilter", firewall::IPFILTER, || {
+            self.need_illumos("ensure_ipfilter")?;
+            let changed = firewall::rules(&self.log, ipf, ipnat)?;
+            if changed {
+                smf::refresh(&self.log, firewall::IPFILTER)?;
+            }
+            self.ensure_online(firewall::IPFILTER, false)?;
 
-    pub fn ensure_dataset(&self, dsname: &str, opts: &[&str]) -> Result<()> {
-        /*
-         * XXX Assume failure here means we should try to create the dataset.
-         */
-        if self.run(&["/usr/sbin/zfs", "list", "-H", "-o", "name",
-            &dsname]).is_ok()
-        {
-            info!(self.log, "dataset {} exists already", dsname);
-            return Ok(());
-        }
+            Ok(changed)
+        })
+    }
 
-        info!(self.log, "create dataset: {}", dsname);
+    /**
+     * Render the ISC DHCP server configuration from the provided subnets and
+     * hosts, validate it, and install it, restarting the server if anything
+     * changed.  On SmartOS the server is expected to come from pkgsrc;
+     * elsewhere, from IPS.
+     */
+    #[cfg(feature = "net")]
+    pub fn ensure_dhcp_server(&self, cfg: &DhcpConfig) -> Result<bool> {
+        self.step("ensure_dhcp_server", "dhcpd", || {
+            self.need_illumos("ensure_dhcp_server")?;
+            let srv = match self.confomat.os {
+                OS::SmartOS => &dhcp::DHCPD_PKGSRC,
+                OS::OmniOS | OS::OpenIndiana => &dhcp::DHCPD_IPS,
+                _ => return Err(self.unsupported("ensure_dhcp_server")),
+            };
 
-        let mut args: Vec<&str> = vec![
-            "/usr/sbin/zfs",
-            "create",
-        ];
-        for opt in opts.iter() {
-            args.push("-o");
-            args.push(opt);
-        }
-        args.push(dsname);
+            let changed = dhcp::configure(&self.log, srv, cfg)?;
+            self.ensure_online(srv.fmri, changed)?;
 
-        ensure::run(&self.log, &args)?;
-        Ok(())
+            Ok(changed)
+        })
     }
 
     pub fn update_packages_ips(&self) -> Result<()> {
-        info!(self.log, "updating IPS publishers");
-        self.run(&["/usr/bin/pkg", "refresh"])?;
-
-        Ok(())
+        self.step("update_packages_ips", "ips", || {
+            self.need_illumos("update_packages_ips")?;
+            info!(self.log, "updating IPS publishers");
+            self.run_with(&["/usr/bin/pkg", "refresh"], &Exec {
+                ok_codes: vec![0, PKG_EXIT_NOP],
+                ..Default::default()
+            })?;
+
+            Ok(())
+        })
     }
 
     pub fn ensure_packages_ips(&self, names: &[&str]) -> Result<()> {
+        self.step("ensure_packages_ips", &names.join(" "), || {
+            self.need_illumos("ensure_packages_ips")?;
+            self.packages_ips(names)
+        })
+    }
+
+    fn packages_ips(&self, names: &[&str]) -> Result<()> {
         let install: Vec<&str> = names.iter().filter(|name| {
             /*
-             * The "install" command appears to fail if no update was required
-             * to the image, so first check to see if the package is already
-             * installed.
+             * The "install" command exits with PKG_EXIT_NOP if no update was
+             * required to the image.  Check each package first anyway, so
+             * that we know which are to be installed.
              */
-            match self.run(&["/usr/bin/pkg", "info", "-q", name]) {
+            match self.query(&["/usr/bin/pkg", "info", "-q", name]) {
                 Ok(_) => {
                     info!(self.log, "IPS package {} already installed", name);
                     false
@@ -474,80 +3160,267 @@ impl<'a> Context<'a> {
             args.push(i);
         }
 
-        self.run(&args)?;
+        self.run_with(&args, &Exec {
+            ok_codes: vec![0, PKG_EXIT_NOP],
+            ..Default::default()
+        })?;
         Ok(())
     }
 
     pub fn update_packages(&self) -> Result<()> {
-        info!(self.log, "updating pkgsrc database");
-        run_pkgsrc(&self.log, &["update"])?;
+        self.step("update_packages", "pkgsrc", || {
+            info!(self.log, "updating pkgsrc database");
+            run_pkgsrc(&self.log, &["update"])?;
 
-        info!(self.log, "updating pkgsrc packages");
-        run_pkgsrc(&self.log, &["full-upgrade"])?;
+            info!(self.log, "updating pkgsrc packages");
+            run_pkgsrc(&self.log, &["full-upgrade"])?;
 
-        Ok(())
+            Ok(())
+        })
     }
 
     pub fn ensure_packages(&self, names: &[&str]) -> Result<()> {
-        let install: Vec<&str> = names.iter().filter(|name| {
-            match ensure::run(&self.log,
-                &["/opt/local/sbin/pkg_admin", "-q", "check", name])
-            {
-                Ok(_) => {
-                    info!(self.log, "pkgsrc package {} already installed",
-                        name);
-                    false
-                }
-                Err(_) => {
-                    info!(self.log, "pkgsrc package {} must be installed",
-                        name);
-                    true
+        self.step("ensure_packages", &names.join(" "), || {
+            let install: Vec<&str> = names.iter().filter(|name| {
+                match ensure::query(&self.log,
+                    &["/opt/local/sbin/pkg_admin", "-q", "check", name])
+                {
+                    Ok(_) => {
+                        info!(self.log, "pkgsrc package {} already installed",
+                            name);
+                        false
+                    }
+                    Err(_) => {
+                        info!(self.log, "pkgsrc package {} must be installed",
+                            name);
+                        true
+                    }
                 }
+            }).copied().collect();
+
+            if install.is_empty() {
+                return Ok(());
             }
-        }).copied().collect();
 
-        if install.is_empty() {
-            return Ok(());
-        }
+            info!(self.log, "updating pkgsrc database");
+            run_pkgsrc(&self.log, &["update"])?;
+
+            info!(self.log, "updating pkgsrc packages");
+            run_pkgsrc(&self.log, &["full-upgrade"])?;
 
-        info!(self.log, "updating pkgsrc database");
-        run_pkgsrc(&self.log, &["update"])?;
+            info!(self.log, "installing pkgsrc packages: {:?}", install);
+            let mut args: Vec<&str> = vec!["install"];
+            for i in &install {
+                args.push(i);
+            }
+            run_pkgsrc(&self.log, &args)?;
 
-        info!(self.log, "updating pkgsrc packages");
-        run_pkgsrc(&self.log, &["full-upgrade"])?;
+            Ok(())
+        })
+    }
 
-        info!(self.log, "installing pkgsrc packages: {:?}", install);
-        let mut args: Vec<&str> = vec!["install"];
-        for i in &install {
-            args.push(i);
+    /**
+     * Refresh the list of available packages, or on illumos, update the
+     * publishers or pkgsrc, with the package manager for this system.
+     */
+    pub fn update_os_packages(&self) -> Result<()> {
+        let m = match Manager::for_os(self.os()) {
+            Ok(m) => m,
+            Err(e) => {
+                return self.step("update_os_packages", "packages", || Err(e));
+            }
+        };
+        match m {
+            Manager::Ips => self.update_packages_ips(),
+            Manager::Pkgsrc => self.update_packages(),
+            Manager::FreeBSD => self.update_packages_freebsd(),
+            /*
+             * pkg_add(1) consults the mirror each time, with no catalogue to
+             * refresh.
+             */
+            Manager::OpenBSD => Ok(()),
+            m => self.step("update_os_packages", &format!("{:?}", m), || {
+                packages::update(&self.log, m,
+                    &self.exec_opts(&Exec::default()))
+            }),
+        }
+    }
+
+    /**
+     * Ensure that these packages are installed, with the package manager for
+     * this system; e.g., IPS on OmniOS, or apt on Debian.  On Debian and
+     * RedHat family systems, a package may be pinned as "NAME=VERSION"; see
+     * the packages module.
+     */
+    pub fn ensure_os_packages(&self, names: &[&str]) -> Result<()> {
+        let m = match Manager::for_os(self.os()) {
+            Ok(m) => m,
+            Err(e) => {
+                return self.step("ensure_os_packages", &names.join(" "),
+                    || Err(e));
+            }
+        };
+        match m {
+            Manager::Ips => self.ensure_packages_ips(names),
+            Manager::Pkgsrc => self.ensure_packages(names),
+            Manager::FreeBSD => self.ensure_packages_freebsd(names),
+            m => self.step("ensure_os_packages", &names.join(" "), || {
+                packages::ensure(&self.log, m, names,
+                    &self.exec_opts(&Exec::default()))
+            }).map(|_| ()),
         }
-        run_pkgsrc(&self.log, &args)?;
+    }
+
+    /**
+     * Ensure that none of these packages are installed, with the package
+     * manager for this system.
+     */
+    pub fn ensure_os_packages_removed(&self, names: &[&str]) -> Result<bool> {
+        self.step("ensure_os_packages_removed", &names.join(" "), || {
+            packages::remove(&self.log, Manager::for_os(self.os())?, names,
+                &self.exec_opts(&Exec::default()))
+        })
+    }
+
+    pub fn update_packages_freebsd(&self) -> Result<()> {
+        self.step("update_packages_freebsd", "pkg", || {
+            self.need_freebsd("update_packages_freebsd")?;
+            info!(self.log, "updating FreeBSD package repositories");
+            self.run_with(&["/usr/sbin/pkg", "update"], &pkg_bootstrap())?;
+
+            Ok(())
+        })
+    }
+
+    /**
+     * Ensure that these FreeBSD packages are installed, with pkg(8).
+     */
+    pub fn ensure_packages_freebsd(&self, names: &[&str]) -> Result<()> {
+        self.step("ensure_packages_freebsd", &names.join(" "), || {
+            self.need_freebsd("ensure_packages_freebsd")?;
+            let install: Vec<&str> = names.iter().filter(|name| {
+                match self.query(&["/usr/sbin/pkg", "info", "-e", name]) {
+                    Ok(_) => {
+                        info!(self.log, "package {} already installed", name);
+                        false
+                    }
+                    Err(_) => {
+                        info!(self.log, "package {} must be installed", name);
+                        true
+                    }
+                }
+            }).copied().collect();
+
+            if install.is_empty() {
+                return Ok(());
+            }
+
+            info!(self.log, "installing packages: {:?}", install);
+            let mut args: Vec<&str> = vec!["/usr/sbin/pkg", "install", "-y"];
+            for i in &install {
+                args.push(i);
+            }
+            self.run_with(&args, &pkg_bootstrap())?;
+
+            Ok(())
+        })
+    }
+
+    /**
+     * Ensure that these OpenBSD packages are installed, with pkg_add(1).
+     * OpenBSD has no catalogue of packages to update first.
+     */
+    pub fn ensure_packages_openbsd(&self, names: &[&str]) -> Result<()> {
+        self.step("ensure_packages_openbsd", &names.join(" "), || {
+            if *self.os() != OS::OpenBSD {
+                return Err(self.unsupported("ensure_packages_openbsd"));
+            }
+            packages::ensure(&self.log, Manager::OpenBSD, names,
+                &self.exec_opts(&Exec::default()))
+        }).map(|_| ())
+    }
+
+    /**
+     * Ensure that a FreeBSD or OpenBSD rc.d service is enabled and running,
+     * restarting it if "need_restart" is set; e.g., after changing its
+     * configuration.
+     */
+    pub fn ensure_rc_service(&self, name: &str, need_restart: bool)
+        -> Result<bool>
+    {
+        self.step("ensure_rc_service", name, || {
+            let rc = match self.os() {
+                OS::FreeBSD => rcd::Rc::Service,
+                OS::OpenBSD => rcd::Rc::Rcctl,
+                _ => return Err(self.unsupported("ensure_rc_service")),
+            };
+            rcd::ensure_running(&self.log, rc, name, need_restart)
+        })
+    }
+
+    /**
+     * Ensure that a local user exists with the given attributes, using the
+     * account management commands of this platform; e.g.,
+     *
+     *      c.ensure_user(&User {
+     *          name: "build".to_string(),
+     *          uid: Some(2000),
+     *          group: Some("staff".to_string()),
+     *          home: Some("/home/build".to_string()),
+     *          ..Default::default()
+     *      })?;
+     */
+    pub fn ensure_user(&self, user: &User) -> Result<bool> {
+        self.step("ensure_user", &user.name, || {
+            if let Some(root) = alt_root() {
+                bail!("users cannot yet be managed in alternate root {}",
+                    root.display());
+            }
+            users::ensure_user(&self.log, users::tools(self.os())?, user)
+        })
+    }
 
-        Ok(())
+    pub fn ensure_group(&self, name: &str, gid: Option<u32>) -> Result<bool> {
+        self.step("ensure_group", name, || {
+            if let Some(root) = alt_root() {
+                bail!("groups cannot yet be managed in alternate root {}",
+                    root.display());
+            }
+            users::ensure_group(&self.log, users::tools(self.os())?, name, gid)
+        })
     }
 
     pub fn ensure_removed<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        ensure::removed(&self.log, path)
+        self.step("ensure_removed", &path.as_ref().display().to_string(), || {
+            ensure::removed(&self.log, rooted(path)?)
+        })
     }
 
     pub fn ensure_download<P: AsRef<Path>>(&self, url: &str, path: P,
         hash: &str, hashtype: HashType) -> Result<()>
     {
-        ensure::download_file(&self.log, url, path, hash, hashtype)
+        self.step("ensure_download", &path.as_ref().display().to_string(), || {
+            ensure::download_file(&self.log, url, rooted(path)?, hash,
+                hashtype)
+        })
     }
 
     pub fn ensure_dir<P: AsRef<Path>>(&self, dir: P,
         owner: &str, group: &str, perms: u32)
         -> Result<bool>
     {
-        ensure::directory(&self.log, dir, owner, group, perms)
+        self.step("ensure_dir", &dir.as_ref().display().to_string(), || {
+            ensure::directory(&self.log, rooted(dir)?, owner, group, perms)
+        })
     }
 
     pub fn ensure_symlink<L: AsRef<Path>, T: AsRef<Path>>(&self,
         link: L, target: T, owner: &str, group: &str)
         -> Result<bool>
     {
-        ensure::symlink(&self.log, link, target, owner, group)
+        self.step("ensure_symlink", &link.as_ref().display().to_string(), || {
+            ensure::symlink(&self.log, rooted(link)?, target, owner, group)
+        })
     }
 
     pub fn ensure_file<S: AsRef<Path>, D: AsRef<Path>>(&self,
@@ -555,23 +3428,266 @@ impl<'a> Context<'a> {
         create: Create)
         -> Result<bool>
     {
-        ensure::file(&self.log, src, dst, owner, group, perms, create)
+        self.step("ensure_file", &dst.as_ref().display().to_string(), || {
+            ensure::file(&self.log, src, rooted(dst)?, owner, group, perms,
+                create)
+        })
+    }
+
+    /**
+     * Ensure that a file exists with exactly the provided contents, and the
+     * specified ownership and permissions.
+     */
+    pub fn ensure_file_contents<D: AsRef<Path>, C: AsRef<[u8]>>(&self,
+        dst: D, contents: C, owner: &str, group: &str, perms: u32)
+        -> Result<bool>
+    {
+        let res = dst.as_ref().display().to_string();
+        self.step("ensure_file_contents", &res, || {
+            ensure::contents(&self.log, rooted(dst)?, contents.as_ref(),
+                &Ownership::Names(owner, group), perms)
+        })
+    }
+
+    /**
+     * Ensure that a file contains the result of rendering the template at
+     * "src" (usually located with file()) with the role variables, and has
+     * the specified ownership and permissions.
+     */
+    pub fn ensure_template<S: AsRef<Path>, D: AsRef<Path>>(&self,
+        src: S, dst: D, owner: &str, group: &str, perms: u32)
+        -> Result<bool>
+    {
+        let res = dst.as_ref().display().to_string();
+        self.step("ensure_template", &res, || {
+            let src = src.as_ref();
+
+            /*
+             * A template may itself be encrypted.
+             */
+            let encrypted = src.extension().and_then(|e| e.to_str())
+                .map(|e| secrets::EXTENSIONS.contains(&e))
+                .unwrap_or(false);
+            let text = if encrypted {
+                String::from_utf8(self.confomat.secrets.decrypt(src)?)?
+            } else {
+                match read_file(src)? {
+                    Some(text) => text,
+                    None => bail!("template {} does not exist",
+                        src.display()),
+                }
+            };
+
+            let contents = match self.render(&text) {
+                Ok(contents) => contents,
+                Err(e) => bail!("template {}: {}", src.display(), e),
+            };
+
+            let own = Ownership::Names(owner, group);
+            let dst = rooted(dst)?;
+            if encrypted || self.contains_secret(&contents) {
+                ensure::secret_contents(&self.log, dst, contents.as_bytes(),
+                    &own, perms)
+            } else {
+                ensure::contents(&self.log, dst, contents.as_bytes(), &own,
+                    perms)
+            }
+        })
+    }
+
+    /**
+     * Locate an encrypted role file (see file()), given its name without the
+     * ".age" or ".gpg" suffix, and decrypt it in memory.
+     */
+    pub fn secret<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let p = path.as_ref();
+
+        for ext in secrets::EXTENSIONS.iter() {
+            if let Some(f) = self.file_maybe(secrets::with_extension(p, ext))? {
+                return self.confomat.secrets.decrypt(&f);
+            }
+        }
+
+        bail!("secret role file ({:?}, {}, {}) does not exist", &self.instance,
+            &self.role.name, p.display());
+    }
+
+    /**
+     * Ensure that a file contains the decrypted contents of an encrypted role
+     * file (see secret()), with the specified ownership and permissions.
+     */
+    pub fn ensure_secret_file<S: AsRef<Path>, D: AsRef<Path>>(&self,
+        src: S, dst: D, owner: &str, group: &str, perms: u32)
+        -> Result<bool>
+    {
+        let res = dst.as_ref().display().to_string();
+        self.step("ensure_secret_file", &res, || {
+            let data = self.secret(src)?;
+            ensure::secret_contents(&self.log, rooted(dst)?, &data,
+                &Ownership::Names(owner, group), perms)
+        })
+    }
+
+    /**
+     * Check the files listed in a manifest of SHA-256 digests (a role file,
+     * located with file(); see the manifest module) against those on the
+     * system.  Any file which is missing or altered is reported, and, if
+     * "fail" is set, fails the step.
+     */
+    pub fn verify_manifest<P: AsRef<Path>>(&self, src: P, fail: bool)
+        -> Result<()>
+    {
+        let res = src.as_ref().display().to_string();
+        self.step("verify_manifest", &res, || {
+            let text = match read_file(self.file(src)?)? {
+                Some(text) => text,
+                None => bail!("manifest {} does not exist", res),
+            };
+
+            let bad = manifest::verify(&self.log, &text)?;
+            for m in bad.iter() {
+                warn!(self.log, "manifest {}: {}", res, m);
+            }
+            if fail && !bad.is_empty() {
+                bail!("{} of the files in manifest {} do not match",
+                    bad.len(), res);
+            }
+            Ok(())
+        })
     }
 
     pub fn ensure_perms<P: AsRef<Path>>(&self, path: P,
         owner: &str, group: &str, perms: u32)
         -> Result<bool>
     {
-        ensure::perms(&self.log, path, owner, group, perms)
+        self.step("ensure_perms", &path.as_ref().display().to_string(), || {
+            ensure::perms(&self.log, rooted(path)?, owner, group, perms)
+        })
     }
 
+    /**
+     * Run a command which may alter the system.  In dry-run mode, the command
+     * is logged but not executed; roles should use query() for commands that
+     * merely inspect the system.
+     */
     pub fn run<S: AsRef<str>>(&self, args: &[S]) -> Result<()> {
-        ensure::run(&self.log, args)
+        self.run_with(args, &Exec::default())
+    }
+
+    /**
+     * Run a command, as with run(), with options; e.g.,
+     *
+     *      c.run_with(&["/opt/vendor/install.sh"], &Exec {
+     *          timeout: Some(Duration::from_secs(600)),
+     *          ..Default::default()
+     *      })?;
+     */
+    pub fn run_with<S: AsRef<str>>(&self, args: &[S], opts: &Exec)
+        -> Result<()>
+    {
+        let res: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+        self.step("run", &res.join(" "), || {
+            ensure::run_with(&self.log, args, &self.exec_opts(opts))
+        })
+    }
+
+    /**
+     * Run a shell script, such as a pipeline, through the shell given in the
+     * options (by default, /bin/sh) with "pipefail" set; e.g.,
+     *
+     *      c.run_shell(&format!("gzcat {} | tar -xf -",
+     *          shell_quote(&tarball)), &Exec {
+     *              dir: Some(PathBuf::from("/opt/app")),
+     *              ..Default::default()
+     *          })?;
+     *
+     * Values interpolated into the script should be quoted with
+     * shell_quote().
+     */
+    pub fn run_shell(&self, script: &str, opts: &Exec) -> Result<()> {
+        self.step("run_shell", script, || {
+            ensure::run_shell(&self.log, script, &self.exec_opts(opts))
+        })
     }
 
+    /**
+     * Run a shell script which does not alter the system, even in dry-run
+     * mode, and return what it wrote to stdout.
+     */
+    pub fn query_shell(&self, script: &str, opts: &Exec) -> Result<String> {
+        ensure::query_shell(&self.log, script, &self.exec_opts(opts))
+    }
+
+    /**
+     * Run a command which does not alter the system, even in dry-run mode.
+     */
+    pub fn query<S: AsRef<str>>(&self, args: &[S]) -> Result<()> {
+        self.query_with(args, &Exec::default())
+    }
+
+    pub fn query_with<S: AsRef<str>>(&self, args: &[S], opts: &Exec)
+        -> Result<()>
+    {
+        ensure::query_with(&self.log, args, &self.exec_opts(opts))
+    }
+
+    /**
+     * Run a command as with query_with(), and return what it wrote to stdout;
+     * e.g., to inspect a zone:
+     *
+     *      let out = c.query_output(&["/usr/bin/svcs", "-H", "nginx"],
+     *          &Exec {
+     *              zone: Some("web0".to_string()),
+     *              ..Default::default()
+     *          })?;
+     */
+    pub fn query_output<S: AsRef<str>>(&self, args: &[S], opts: &Exec)
+        -> Result<String>
+    {
+        ensure::query_output(&self.log, args, &self.exec_opts(opts))
+    }
+
+    /*
+     * Commands run by roles are chrooted to the alternate root, if there is
+     * one, unless they are to run in a zone or have a root of their own.
+     */
+    fn exec_opts(&self, opts: &Exec) -> Exec {
+        let mut opts = opts.clone();
+        if opts.root.is_none() && opts.zone.is_none() {
+            opts.root = alt_root();
+        }
+        opts
+    }
+
+    /**
+     * The alternate root (see "--root"), if the system being configured is
+     * not the one on which we are running.
+     */
+    pub fn alt_root(&self) -> Option<PathBuf> {
+        alt_root()
+    }
+
+    /**
+     * Are we reporting changes rather than making them?  Roles which alter
+     * the system through means other than the ensure primitives and run()
+     * should check this first.
+     */
+    pub fn dry_run(&self) -> bool {
+        dry_run()
+    }
+
+    #[cfg(feature = "smf")]
     pub fn ensure_online(&self, fmri: &str, need_restart: bool)
         -> Result<()>
     {
+        self.step("ensure_online", fmri, || {
+            self.need_illumos("ensure_online")?;
+            self.online(fmri, need_restart)
+        })
+    }
+
+    #[cfg(feature = "smf")]
+    fn online(&self, fmri: &str, need_restart: bool) -> Result<()> {
         if need_restart {
             /*
              * Restarts are posted, and merely have no effect in the event that
@@ -580,6 +3696,32 @@ impl<'a> Context<'a> {
             self.run(&["/usr/sbin/svcadm", "restart", fmri])?;
         }
 
+        /*
+         * In dry-run mode, we cannot wait for the instance to come online, as
+         * we are not going to enable it.  The instance may not yet exist at
+         * all if the package that delivers it would have been installed.
+         */
+        match instance_state(fmri) {
+            Ok((SMFState::Online, None)) => {
+                info!(self.log, "smf instance {}: online!", fmri);
+                return Ok(());
+            }
+            Ok(x) if dry_run_skip(&self.log, Change::new(Action::Modify,
+                format!("smf instance {}", fmri))
+                .detail(format!("state {:?} -> online", x))) =>
+            {
+                return Ok(());
+            }
+            Err(e) if dry_run_skip(&self.log, Change::new(Action::Modify,
+                format!("smf instance {}", fmri))
+                .detail(format!("bring online ({})", e))) =>
+            {
+                return Ok(());
+            }
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        }
+
         loop {
             match instance_state(fmri)? {
                 (SMFState::Online, None) => {
@@ -606,8 +3748,95 @@ impl<'a> Context<'a> {
         }
     }
 
+    /*
+     * The systemd primitives, like those for SMF, rely on the service
+     * manager having booted the system, or on an alternate root (in which
+     * systemctl only edits the configuration).
+     */
+    fn need_systemd(&self) -> Result<()> {
+        if !self.os().is_linux() || (!systemd::booted() && alt_root().is_none())
+        {
+            return Err(Unsupported::new("systemd",
+                "systemd is not managing this system").into());
+        }
+        Ok(())
+    }
+
+    /**
+     * Ensure that a systemd unit file (e.g., "nginx.service") in
+     * "/etc/systemd/system" has these contents, reloading the systemd
+     * configuration if it changed.
+     */
+    pub fn ensure_unit_file(&self, unit: &str, contents: &str) -> Result<bool> {
+        self.step("ensure_unit_file", unit, || {
+            self.need_systemd()?;
+            systemd::ensure_unit_file(&self.log, unit, contents,
+                &self.exec_opts(&Exec::default()))
+        })
+    }
+
+    pub fn daemon_reload(&self) -> Result<()> {
+        self.step("daemon_reload", "systemd", || {
+            self.need_systemd()?;
+            systemd::daemon_reload(&self.log, &self.exec_opts(&Exec::default()))
+        })
+    }
+
+    /**
+     * Ensure that a systemd unit is enabled and active, as ensure_online()
+     * does for an SMF instance, restarting it if "need_restart" is set.
+     */
+    pub fn ensure_active(&self, unit: &str, need_restart: bool)
+        -> Result<bool>
+    {
+        self.step("ensure_active", unit, || {
+            self.need_systemd()?;
+            systemd::ensure_active(&self.log, unit, need_restart,
+                &self.exec_opts(&Exec::default()))
+        })
+    }
+
+    fn need_launchd(&self) -> Result<()> {
+        if *self.os() != OS::MacOS {
+            return Err(self.unsupported("launchd"));
+        }
+        Ok(())
+    }
+
+    /**
+     * Ensure that the property list for a launchd daemon with this label
+     * (e.g., "org.nginx.nginx") in "/Library/LaunchDaemons" has these
+     * contents.  If it changed, the daemon should be loaded again with
+     * ensure_launchd_daemon().
+     */
+    pub fn ensure_launchd_plist(&self, label: &str, contents: &str)
+        -> Result<bool>
+    {
+        self.step("ensure_launchd_plist", label, || {
+            self.need_launchd()?;
+            launchd::ensure_plist(&self.log, label, contents)
+        })
+    }
+
+    /**
+     * Ensure that a launchd daemon is enabled and loaded, as ensure_online()
+     * does for an SMF instance, loading it again if "need_restart" is set.
+     */
+    pub fn ensure_launchd_daemon(&self, label: &str, need_restart: bool)
+        -> Result<bool>
+    {
+        self.step("ensure_launchd_daemon", label, || {
+            self.need_launchd()?;
+            if let Some(root) = alt_root() {
+                bail!("daemons cannot be loaded in alternate root {}",
+                    root.display());
+            }
+            launchd::ensure_loaded(&self.log, label, need_restart)
+        })
+    }
+
     pub fn exists_file<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
-        let p = path.as_ref();
+        let p = &rooted(path)?;
 
         debug!(self.log, "exists_file({})", p.display());
         let f = match std::fs::metadata(p) {
@@ -624,7 +3853,7 @@ impl<'a> Context<'a> {
     }
 
     pub fn dir_empty<P: AsRef<Path>>(&self, p: P) -> Result<bool> {
-        let p = p.as_ref();
+        let p = &rooted(p)?;
         let mut rd = std::fs::read_dir(p)?;
         if let Some(_ent) = rd.next().transpose()? {
             info!(self.log, "directory {} is NOT empty", p.display());
@@ -634,51 +3863,258 @@ impl<'a> Context<'a> {
         Ok(true)
     }
 
-    pub fn beadm_list(&self) -> Result<Vec<BootEnvironment>> {
-        let out = std::process::Command::new("/usr/sbin/beadm")
-            .env_clear()
-            .arg("list")
-            .arg("-H")
-            .output()?;
-        if !out.status.success() {
-            bail!("beadm list failed: {}", out.info());
+    /**
+     * Ensure that a TLS certificate from an ACME CA (see certs.rs) is
+     * installed, obtaining a new one if there is none yet, if the names have
+     * changed, or if it is due for renewal.  To restart the services which
+     * use the certificate on renewal, notify a handler; e.g.,
+     *
+     *      c.notify("restart-nginx").ensure_certificate(&cert)?;
+     */
+    pub fn ensure_certificate(&self, cert: &Certificate) -> Result<bool> {
+        self.step("ensure_certificate", &cert.hostname, || {
+            if let Some(root) = alt_root() {
+                bail!("certificates cannot be obtained for alternate root {}",
+                    root.display());
+            }
+            certs::ensure(&self.log, cert)
+        })
+    }
+
+    /**
+     * Ensure that sshd_config has these contents (e.g., as rendered from a
+     * template with render()).  The candidate is checked with "sshd -t"
+     * first, and is neither installed nor is sshd restarted unless it passes.
+     */
+    pub fn ensure_sshd_config(&self, contents: &str) -> Result<bool> {
+        self.step("ensure_sshd_config", sshd::CONFIG, || {
+            let changed = sshd::config(&self.log, self.os(), contents)?;
+            self.restart_sshd(changed)?;
+            Ok(changed)
+        })
+    }
+
+    /**
+     * As for ensure_sshd_config(), but for a drop-in fragment,
+     * "/etc/ssh/sshd_config.d/NAME.conf", which is checked along with the
+     * rest of the configuration.
+     */
+    pub fn ensure_sshd_fragment(&self, name: &str, contents: &str)
+        -> Result<bool>
+    {
+        self.step("ensure_sshd_fragment", name, || {
+            let changed = sshd::fragment(&self.log, self.os(), name,
+                contents)?;
+            self.restart_sshd(changed)?;
+            Ok(changed)
+        })
+    }
+
+    /*
+     * Restart sshd to load a new configuration.  Existing sessions survive.
+     * On macOS, sshd is started by launchd for each connection, and reads
+     * its configuration each time.
+     */
+    fn restart_sshd(&self, changed: bool) -> Result<()> {
+        if !changed || alt_root().is_some() {
+            return Ok(());
         }
-        let val = String::from_utf8(out.stdout)?;
-        let lines: Vec<&str> = val.lines().collect();
-        if lines.is_empty() {
-            bail!("unexpected output: {:?}", lines);
+
+        let os = self.os();
+        if os.is_illumos() {
+            self.run(&["/usr/sbin/svcadm", "restart", sshd::SSH_FMRI])?;
+        } else if os.is_linux() {
+            self.ensure_active(sshd::service(os), true)?;
+        } else if matches!(os, OS::FreeBSD | OS::OpenBSD) {
+            self.ensure_rc_service(sshd::service(os), true)?;
         }
+        Ok(())
+    }
 
-        let mut bes = Vec::new();
+    /**
+     * Ensure that a module line is present in the PAM stack for a service,
+     * at the given position among the lines of its module type.  Other lines
+     * are left as they are.
+     */
+    pub fn ensure_pam_entry(&self, e: &PamEntry) -> Result<bool> {
+        let what = format!("{} {} {}", e.service, e.module_type, e.module);
+        self.step("ensure_pam_entry", &what, || {
+            pam::ensure(&self.log, self.os(), e)
+        })
+    }
 
-        for l in lines.iter() {
-            let t: Vec<&str> = l.split(';').collect();
+    /**
+     * Ensure the password policy settings in /etc/default/passwd.  Only the
+     * keys which are set in the policy are changed.
+     */
+    pub fn ensure_passwd_policy(&self, p: &PasswdPolicy) -> Result<bool> {
+        self.step("ensure_passwd_policy", policy::PASSWD_DEFAULTS, || {
+            self.need_illumos("ensure_passwd_policy")?;
+            policy::passwd(&self.log, p)
+        })
+    }
 
-            if t.len() < 7 {
-                bail!("unexpected line: {:?}", t);
+    /**
+     * Ensure the login policy settings in /etc/default/login; e.g., that
+     * root may log in only on the console.
+     */
+    pub fn ensure_login_policy(&self, p: &LoginPolicy) -> Result<bool> {
+        self.step("ensure_login_policy", policy::LOGIN_DEFAULTS, || {
+            self.need_illumos("ensure_login_policy")?;
+            policy::login(&self.log, p)
+        })
+    }
+
+    /**
+     * Ensure the su(1M) policy settings in /etc/default/su.
+     */
+    pub fn ensure_su_policy(&self, p: &SuPolicy) -> Result<bool> {
+        self.step("ensure_su_policy", policy::SU_DEFAULTS, || {
+            self.need_illumos("ensure_su_policy")?;
+            policy::su(&self.log, p)
+        })
+    }
+
+    /**
+     * Ensure the core file settings (see coreadm(1M)): the patterns for
+     * global and per-process core files, their content, and which kinds of
+     * core dump are enabled.  Settings which are None are left alone.
+     */
+    pub fn ensure_coreadm(&self, cc: &CoreConfig) -> Result<bool> {
+        self.step("ensure_coreadm", "core files", || {
+            self.need_illumos("ensure_coreadm")?;
+            if let Some(root) = alt_root() {
+                bail!("core file settings cannot be changed in alternate \
+                    root {}", root.display());
             }
+            coreadm::ensure(&self.log, cc)
+        })
+    }
 
-            let mountpoint = if t[3] == "-" {
-                None
-            } else {
-                Some(t[3].to_string())
-            };
+    /**
+     * Ensure that a device driver is added (see add_drv(1M)), with the given
+     * aliases, minor node permissions, and driver.conf(4) properties.  As
+     * most drivers only read their properties when they attach, a change to
+     * the properties of a driver already added requires a reboot.
+     */
+    pub fn ensure_driver(&self, d: &Driver) -> Result<bool> {
+        self.step("ensure_driver", &d.name, || {
+            self.need_illumos("ensure_driver")?;
+            if !self.is_gz() {
+                bail!("drivers may only be managed from the global zone");
+            }
 
-            bes.push(BootEnvironment {
-                name: t[0].to_string(),
-                uuid: t[1].to_string(),
-                active: t[2].to_string(),
-                mountpoint,
-                space: t[4].parse()?,
-                policy: t[5].to_string(),
-                created: t[6].parse()?,
-            });
-        }
+            let (did_work, reboot) = drivers::ensure(&self.log, d)?;
+            if reboot {
+                self.reboot_required(&format!("properties of driver {} \
+                    changed", d.name));
+            }
+
+            Ok(did_work)
+        })
+    }
+
+    /**
+     * Ensure that a project exists in the project database (see project(4)),
+     * with the given resource controls; e.g., to raise the limit on open
+     * files for a user:
+     *
+     *      let mut p = Project::user("build");
+     *      p.rctls.push(Rctl::new("process.max-file-descriptor", 65536));
+     *      c.ensure_project(&p)?;
+     *
+     * A changed resource control is applied to the processes already in the
+     * project, where possible.
+     */
+    pub fn ensure_project(&self, p: &Project) -> Result<bool> {
+        self.step("ensure_project", &p.name, || {
+            self.need_illumos("ensure_project")?;
+            project::ensure(&self.log, p)
+        })
+    }
+
+    /**
+     * Ensure that a boot parameter (see eeprom(1M)) has this value; e.g.,
+     * "console" or "ttya-mode".  A change takes effect at the next boot.
+     */
+    pub fn ensure_eeprom(&self, name: &str, value: &str) -> Result<bool> {
+        self.step("ensure_eeprom", name, || {
+            self.need_illumos("ensure_eeprom")?;
+            if !self.is_gz() {
+                bail!("boot parameters may only be set in the global zone");
+            }
+            if let Some(root) = alt_root() {
+                bail!("boot parameters cannot be set in alternate root {}",
+                    root.display());
+            }
+
+            let changed = eeprom::ensure(&self.log, name, value)?;
+            if changed {
+                self.reboot_required(&format!("boot parameter {} changed",
+                    name));
+            }
+
+            Ok(changed)
+        })
+    }
+
+    /**
+     * Ensure the audit policy, flags, and plugins (see auditconfig(1M)), and
+     * that auditing is on.  If the configuration changed, auditd is
+     * refreshed so that it takes effect.
+     */
+    #[cfg(feature = "smf")]
+    pub fn ensure_audit(&self, ac: &AuditConfig) -> Result<bool> {
+        self.step("ensure_audit", audit::AUDITD, || {
+            self.need_illumos("ensure_audit")?;
+            if let Some(root) = alt_root() {
+                bail!("the audit configuration cannot be changed in \
+                    alternate root {}", root.display());
+            }
+
+            let changed = audit::configure(&self.log, ac)?;
+            self.ensure_online(audit::AUDITD, false)?;
+            if changed {
+                self.run(&["/usr/sbin/audit", "-s"])?;
+            }
+            Ok(changed)
+        })
+    }
+
+    /**
+     * Ensure that notifications are sent when a service enters maintenance,
+     * and when the fault manager diagnoses a problem (see EventNotify), and
+     * that the services which send them are online.  In a non-global zone
+     * only the service notifications are set, as the fault manager runs in
+     * the global zone.
+     */
+    #[cfg(feature = "smf")]
+    pub fn ensure_event_notify(&self, en: &EventNotify) -> Result<bool> {
+        self.step("ensure_event_notify", &en.params().join(" "), || {
+            self.need_illumos("ensure_event_notify")?;
+            if let Some(root) = alt_root() {
+                bail!("notification parameters cannot be set in alternate \
+                    root {}", root.display());
+            }
+
+            let changed = fmnotify::ensure(&self.log, en, self.is_gz())?;
+            if !en.mailto.is_empty() {
+                self.online(fmnotify::SMTP_NOTIFY, false)?;
+            }
+            if en.snmp {
+                self.online(fmnotify::SNMP_NOTIFY, false)?;
+            }
+            Ok(changed)
+        })
+    }
 
-        Ok(bes)
+    pub fn beadm_list(&self) -> Result<Vec<BootEnvironment>> {
+        self.need_illumos("beadm_list")?;
+        list_boot_environments()
     }
 
     pub fn pkg_publishers(&self) -> Result<Vec<PkgPublisher>> {
+        self.need_illumos("pkg_publishers")?;
         let out = std::process::Command::new("/usr/bin/pkg")
             .env_clear()
             .arg("publisher")
@@ -730,10 +4166,12 @@ impl<'a> Context<'a> {
     pub fn read_lines<P: AsRef<Path>>(&self, path: P)
         -> Result<Option<Vec<String>>>
     {
-        read_lines(path)
+        read_lines(rooted(path)?)
     }
 
+    #[cfg(feature = "smf")]
     pub fn svcprop(&self, fmri: &str, propval: &str) -> Result<String> {
+        self.need_illumos("svcprop")?;
         let out = std::process::Command::new("/usr/bin/svcprop")
             .env_clear()
             .arg("-p").arg(propval)
@@ -753,6 +4191,12 @@ impl<'a> Context<'a> {
     pub fn ensure_cron(&self, user: &str, name: &str, script: &str)
         -> Result<()>
     {
+        self.step("ensure_cron", &format!("{}:{}", user, name), || {
+            self.cron(user, name, script)
+        })
+    }
+
+    fn cron(&self, user: &str, name: &str, script: &str) -> Result<()> {
         info!(self.log, "cron script \"{}\" for user \"{}\"", script, user);
 
         /*
@@ -857,6 +4301,13 @@ impl<'a> Context<'a> {
             ct.push('\n');
         }
 
+        if dry_run() && dry_run_skip(&self.log, Change::new(Action::Modify,
+            format!("crontab for user {}", user))
+            .details(plan::diff(&val, &ct)))
+        {
+            return Ok(());
+        }
+
         /*
          * Run crontab as the target user to install the crontab.  Note that
          * this may fail, e.g., if the syntax is not valid or the disk is full.
@@ -926,20 +4377,52 @@ fn read_lines<P: AsRef<Path>>(path: P) -> Result<Option<Vec<String>>> {
     }))
 }
 
+/*
+ * macOS has no /etc/os-release, but every release has this.
+ */
+const MACOS_VERSION: &str = "/System/Library/CoreServices/SystemVersion.plist";
+
 fn which_os(log: &Logger) -> Result<OS> {
+    /*
+     * Nor has OpenBSD, but as its kernel and userland are released together,
+     * the name of the kernel will do.
+     */
+    if std::env::consts::OS == "openbsd" {
+        return Ok(OS::OpenBSD);
+    }
+
     if let Some(data) = read_lines("/etc/os-release")? {
         let kv: Vec<Vec<&str>> = data.iter()
             .map(|s| s.split('=').collect())
             .collect();
 
-        if let Some(id) = kv.iter().find(|kve| kve[0] == "ID") {
-            if id[1] == "omnios" {
-                return Ok(OS::OmniOS);
+        let val = |k: &str| kv.iter()
+            .find(|kve| kve.len() == 2 && kve[0] == k)
+            .map(|kve| kve[1].trim_matches('"'))
+            .unwrap_or("");
+
+        match val("ID") {
+            "omnios" => return Ok(OS::OmniOS),
+            "freebsd" => return Ok(OS::FreeBSD),
+            "debian" => return Ok(OS::Debian),
+            "ubuntu" => return Ok(OS::Ubuntu),
+            "fedora" => return Ok(OS::Fedora),
+            "rhel" | "centos" | "rocky" | "almalinux" => {
+                return Ok(OS::RedHat);
             }
+            _ => (),
+        }
+        let like: Vec<&str> = val("ID_LIKE").split(' ').collect();
+        if like.contains(&"rhel") || like.contains(&"fedora") {
+            return Ok(OS::RedHat);
+        } else if like.contains(&"debian") {
+            return Ok(OS::Debian);
         }
 
         error!(log, "unknown OS from /etc/os-release: {:?}", data);
 
+    } else if Path::new(MACOS_VERSION).exists() {
+        return Ok(OS::MacOS);
     } else if let Some(data) = read_lines("/etc/release")? {
         if !data.is_empty() {
             if data[0].contains("SmartOS") {
@@ -956,6 +4439,17 @@ fn which_os(log: &Logger) -> Result<OS> {
     bail!("OS detection failure");
 }
 
+/*
+ * Until pkg(8) has been installed on FreeBSD, /usr/sbin/pkg is a shim which
+ * asks before installing it, unless told not to.
+ */
+fn pkg_bootstrap() -> Exec {
+    Exec {
+        env: vec![("ASSUME_ALWAYS_YES".to_string(), "yes".to_string())],
+        ..Default::default()
+    }
+}
+
 fn run_pkgsrc(log: &Logger, cmd: &[&str]) -> Result<()> {
     let mut args = vec!["/opt/local/bin/pkgin", "-y"];
     for c in cmd {
@@ -978,6 +4472,52 @@ pub struct BootEnvironment {
     pub created: u64,
 }
 
+/*
+ * The boot environments, as for beadm_list(); also used for the facts.
+ */
+fn list_boot_environments() -> Result<Vec<BootEnvironment>> {
+    let out = std::process::Command::new("/usr/sbin/beadm")
+        .env_clear()
+        .arg("list")
+        .arg("-H")
+        .output()?;
+    if !out.status.success() {
+        bail!("beadm list failed: {}", out.info());
+    }
+    let val = String::from_utf8(out.stdout)?;
+    let lines: Vec<&str> = val.lines().collect();
+    if lines.is_empty() {
+        bail!("unexpected output: {:?}", lines);
+    }
+
+    let mut bes = Vec::new();
+
+    for l in lines.iter() {
+        let t: Vec<&str> = l.split(';').collect();
+
+        if t.len() < 7 {
+            bail!("unexpected line: {:?}", t);
+        }
+
+        let mountpoint = if t[3] == "-" {
+            None
+        } else {
+            Some(t[3].to_string())
+        };
+
+        bes.push(BootEnvironment {
+            name: t[0].to_string(),
+            uuid: t[1].to_string(),
+            active: t[2].to_string(),
+            mountpoint,
+            space: t[4].parse()?,
+            policy: t[5].to_string(),
+            created: t[6].parse()?,
+        });
+    }
+
+    Ok(bes)
+}
 
 #[derive(Debug, PartialEq)]
 pub struct PkgPublisher {
@@ -991,6 +4531,7 @@ pub struct PkgPublisher {
     pub proxy: String,
 }
 
+#[cfg(feature = "smf")]
 #[derive(Debug, PartialEq)]
 enum SMFState {
     Disabled,
@@ -1001,6 +4542,7 @@ enum SMFState {
     Other(String),
 }
 
+#[cfg(feature = "smf")]
 impl SMFState {
     fn from_str(val: &str) -> Option<SMFState> {
         match val {
@@ -1015,6 +4557,7 @@ impl SMFState {
     }
 }
 
+#[cfg(feature = "smf")]
 fn instance_state(fmri: &str) -> Result<(SMFState, Option<SMFState>)> {
     let out = std::process::Command::new("/usr/bin/svcs")
         .env_clear()
@@ -1034,13 +4577,22 @@ fn instance_state(fmri: &str) -> Result<(SMFState, Option<SMFState>)> {
         SMFState::from_str(&terms[1])))
 }
 
+fn split_tags(args: &[String]) -> Vec<String> {
+    args.iter()
+        .flat_map(|a| a.split(','))
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
 /**
  * A confomat wrapper should call this entrypoint to get things rolling.  This
  * will process arguments, locate the confomat directory and read any base
  * configuration.
  *
  * The `Confomat` instance returned should be configured with any additional
- * roles that the wrapper wishes to include, and then apply() should be called.
+ * roles that the wrapper wishes to include, and then apply() should be called;
+ * or run(), to exit with a status that reflects the outcome.
  */
 pub fn start() -> Result<Confomat> {
     let args: Vec<String> = std::env::args().collect();
@@ -1048,6 +4600,62 @@ pub fn start() -> Result<Confomat> {
     let mut opts = getopts::Options::new();
 
     opts.optopt("d", "", "confomat data directory", "DIRECTORY");
+    opts.optflag("n", "dry-run", "report changes without making them");
+    opts.optflag("p", "plan", "as for --dry-run, then list each change");
+    opts.optopt("j", "jobs", "apply up to JOBS unordered roles at once",
+        "JOBS");
+    opts.optopt("", "json-log", "also log each step as JSON lines to FILE",
+        "FILE");
+    opts.optflag("", "syslog", "also log the run, role results, and failures \
+        to syslog");
+    opts.optflag("q", "quiet", "report only the steps which change \
+        something or fail, and warnings");
+    opts.optflagmulti("v", "verbose", "also log skipped steps and the output \
+        of commands; twice, also diffs and timing");
+    opts.optflag("", "no-progress", "on a terminal, write the full log rather \
+        than the progress of each role");
+    opts.optmulti("", "vars", "read role variables from a TOML file", "FILE");
+    opts.optmulti("e", "", "set a role variable", "NAME=VALUE");
+    opts.optmulti("", "tags", "apply only steps with these (comma-separated) \
+        tags", "TAGS");
+    opts.optmulti("", "skip-tags", "skip roles and steps with these \
+        (comma-separated) tags", "TAGS");
+    opts.optopt("", "pull", "fetch the data directory from a git repository \
+        or HTTPS tarball first", "SOURCE");
+    opts.optopt("", "pin", "with --pull, require this git commit or tarball \
+        SHA-256", "PIN");
+    opts.optflag("", "verify-commit", "with --pull, check the signature on \
+        the git commit");
+    opts.optflag("", "verify-idempotent", "apply the roles twice, and fail if \
+        the second pass changes anything");
+    opts.optflag("", "no-cache", "read every file in full, rather than \
+        trusting the cache of unchanged files");
+    opts.optopt("", "secrets-key", "decrypt .age secrets with the identity in \
+        FILE", "FILE");
+    opts.optopt("", "env", "apply the overlay for this environment (e.g., \
+        \"production\")", "ENV");
+    opts.optflag("", "wait", "if another run holds the lock, wait for it to \
+        finish");
+    opts.optopt("", "report", "write a JSON report of the run to FILE",
+        "FILE");
+    opts.optopt("", "report-url", "send a JSON report of the run to URL",
+        "URL");
+    opts.optopt("", "metrics", "write Prometheus metrics for the run to FILE",
+        "FILE");
+    opts.optflag("", "resume", "skip the steps before the one at which the \
+        last run failed");
+    opts.optopt("", "start-at", "skip the steps before this one", "STEP");
+    opts.optmulti("", "only", "apply only these steps", "STEP");
+    opts.optopt("", "root", "make file changes and run commands beneath \
+        this alternate root, such as a mounted boot environment or an image",
+        "DIR");
+    opts.optopt("", "node", "look up this node ID in the host inventory \
+        instead of the nodename", "ID");
+    opts.optflag("", "json", "with \"facts\" or \"history\", print JSON");
+    opts.optflag("", "refresh-facts", "gather every fact afresh, rather than \
+        using those cached by an earlier run");
+    opts.optopt("", "unsupported", "for steps this platform cannot do, \
+        \"fail\" (the default) or \"skip\"", "ACTION");
 
     let p = match opts.parse(&args[1..]) {
         Ok(p) => p,
@@ -1058,24 +4666,457 @@ pub fn start() -> Result<Confomat> {
         }
     };
 
-    let log = init_log();
+    let jobs = match p.opt_str("j").map(|j| j.parse::<usize>()) {
+        None => 1,
+        Some(Ok(j)) if j > 0 => j,
+        Some(_) => {
+            eprintln!("ERROR: -j requires a positive number of jobs");
+            exit(1);
+        }
+    };
+
+    /*
+     * The "facts" subcommand prints the facts and exits, as does "history"
+     * with the run history.  The log goes to stderr, so that the facts (or
+     * the history) alone appear on stdout.
+     */
+    let facts_cmd = p.free.first().map(|a| a == "facts").unwrap_or(false);
+    let history_cmd = p.free.first().map(|a| a == "history")
+        .unwrap_or(false);
+
+    let push_cmd = p.free.first().map(|a| a == "push").unwrap_or(false);
+
+    let verbosity = match (p.opt_present("q"), p.opt_count("v")) {
+        (false, 0) => Verbosity::Normal,
+        (false, 1) => Verbosity::Verbose,
+        (false, _) => Verbosity::Debug,
+        (true, 0) => Verbosity::Quiet,
+        (true, _) => {
+            eprintln!("ERROR: -q and -v cannot be used together");
+            exit(1);
+        }
+    };
+
+    /*
+     * When roles are applied one at a time on a terminal, the progress of
+     * each role is shown in place of the log, of which only warnings and
+     * errors are written.  With "-q", the steps which change something or
+     * fail are listed in place of the log.  A push passes the verbosity on
+     * to each remote run, and shows what that run reports.
+     */
+    let style = if facts_cmd || history_cmd || push_cmd {
+        None
+    } else if verbosity == Verbosity::Quiet {
+        Some(Style::Quiet)
+    } else if verbosity == Verbosity::Normal && jobs == 1
+        && !p.opt_present("no-progress")
+        && atty::is(atty::Stream::Stdout)
+    {
+        Some(Style::Progress)
+    } else {
+        None
+    };
+    let level = if push_cmd {
+        verbosity.max(Verbosity::Normal).level()
+    } else if style == Some(Style::Progress) {
+        Verbosity::Quiet.level()
+    } else {
+        verbosity.level()
+    };
+
+    let log = init_log(jobs > 1, facts_cmd || history_cmd, level);
+    catch_signals();
+
+    /*
+     * In push mode, the remaining arguments are the hosts to push to.  The
+     * options which affect what is done are passed on to each remote run,
+     * where the roles are chosen from the inventory.
+     */
+    let push = if push_cmd {
+        if p.free.len() < 2 {
+            bail!("usage: push HOST...");
+        }
+        for o in ["vars", "pull", "node", "json-log", "root"].iter() {
+            if p.opt_present(o) {
+                bail!("--{} cannot be used with push", o);
+            }
+        }
+
+        let mut args = Vec::new();
+        if p.opt_present("p") {
+            args.push("--plan".to_string());
+        } else if p.opt_present("n") {
+            args.push("--dry-run".to_string());
+        }
+        if p.opt_present("verify-idempotent") {
+            args.push("--verify-idempotent".to_string());
+        }
+        for t in p.opt_strs("tags").iter() {
+            args.push(format!("--tags={}", t));
+        }
+        for t in p.opt_strs("skip-tags").iter() {
+            args.push(format!("--skip-tags={}", t));
+        }
+        if let Some(e) = p.opt_str("env") {
+            args.push(format!("--env={}", e));
+        }
+        if p.opt_present("resume") {
+            args.push("--resume".to_string());
+        }
+        if p.opt_present("refresh-facts") {
+            args.push("--refresh-facts".to_string());
+        }
+        match verbosity {
+            Verbosity::Quiet => args.push("-q".to_string()),
+            Verbosity::Normal => (),
+            Verbosity::Verbose => args.push("-v".to_string()),
+            Verbosity::Debug => args.push("-vv".to_string()),
+        }
+        if p.opt_present("syslog") {
+            args.push("--syslog".to_string());
+        }
+        if let Some(r) = p.opt_str("report") {
+            args.push(format!("--report={}", r));
+        }
+        if let Some(u) = p.opt_str("report-url") {
+            args.push(format!("--report-url={}", u));
+        }
+        if let Some(m) = p.opt_str("metrics") {
+            args.push(format!("--metrics={}", m));
+        }
+        if let Some(s) = p.opt_str("start-at") {
+            args.push(format!("--start-at={}", s));
+        }
+        for s in p.opt_strs("only").iter() {
+            args.push(format!("--only={}", s));
+        }
+        if let Some(u) = p.opt_str("unsupported") {
+            args.push(format!("--unsupported={}", u));
+        }
+        for e in p.opt_strs("e").iter() {
+            args.push("-e".to_string());
+            args.push(e.to_string());
+        }
+
+        Some(Push {
+            hosts: p.free[1..].to_vec(),
+            args,
+        })
+    } else {
+        None
+    };
+
+    let source = match p.opt_str("pull") {
+        Some(s) => Some(pull::Source::parse(&s)?),
+        None => None,
+    };
+    if source.is_none() && (p.opt_present("pin") ||
+        p.opt_present("verify-commit"))
+    {
+        bail!("--pin and --verify-commit require --pull");
+    }
 
-    let dir = match p.opt_str("d") {
-        Some(d) => std::path::PathBuf::from(d),
-        None => rootdir()?,
+    let dir = match (p.opt_str("d"), &source) {
+        (Some(d), _) => std::path::PathBuf::from(d),
+        (None, Some(_)) => std::path::PathBuf::from(pull::DEFAULT_DIR),
+        (None, None) => rootdir()?,
     };
     info!(log, "confomat starting, dir: {}", dir.display());
+
+    if facts_cmd {
+        if p.free.len() > 1 {
+            bail!("usage: facts [--json]");
+        }
+        for o in ["pull", "root"].iter() {
+            if p.opt_present(o) {
+                bail!("--{} cannot be used with facts", o);
+            }
+        }
+
+        let os = which_os(&log)?;
+        let facts = facts::gather(&log, &os, &facts::script_dirs(&dir),
+            p.opt_present("refresh-facts"));
+        facts::print(&facts, p.opt_present("json"))?;
+        exit(0);
+    }
+    if history_cmd {
+        let id = match p.free.get(1).map(|s| s.parse::<u64>()) {
+            _ if p.free.len() > 2 => None,
+            Some(Ok(id)) => Some(Some(id)),
+            Some(Err(_)) => None,
+            None => Some(None),
+        };
+        let id = match id {
+            Some(id) => id,
+            None => bail!("usage: history [RUN] [--json]"),
+        };
+
+        let runs = history::query(&log, id, 20)?;
+        history::print(&runs, p.opt_present("json"))?;
+        exit(0);
+    }
+    if p.opt_present("json") {
+        bail!("--json can only be used with facts or history");
+    }
+
+    /*
+     * Take the run lock before we fetch or read anything, so that a
+     * concurrent run cannot replace the data directory underneath us.  In
+     * push mode, the lock is taken on each remote host instead.
+     */
+    let lock = if push.is_none() {
+        Some(lock::acquire(&log, p.opt_present("wait"))?)
+    } else {
+        None
+    };
+
+    if let Some(source) = &source {
+        pull::pull(&log, source, p.opt_str("pin").as_deref(),
+            p.opt_present("verify-commit"), &dir)?;
+    }
+    if lock.is_some() && !p.opt_present("no-cache") {
+        cache::load(&log);
+    }
+
+    if p.opt_present("resume") && p.opt_present("start-at") {
+        bail!("--resume and --start-at cannot be used together");
+    }
+    if (p.opt_present("resume") || p.opt_present("start-at")) && jobs > 1 {
+        bail!("--resume and --start-at cannot be used with -j");
+    }
+
+    let plan = p.opt_present("p");
+    let verify_idempotent = p.opt_present("verify-idempotent");
+    if verify_idempotent && (plan || p.opt_present("n")) {
+        bail!("--verify-idempotent cannot be used with --dry-run or --plan");
+    }
+    if plan || p.opt_present("n") {
+        set_dry_run(true);
+        warn!(log, "DRY RUN: no changes will be made");
+    }
+    if let Some(root) = p.opt_str("root") {
+        let root = PathBuf::from(root);
+        if !root.is_absolute() || !root.is_dir() {
+            bail!("--root {}: must be an absolute path to a directory",
+                root.display());
+        }
+        warn!(log, "applying to alternate root {}", root.display());
+        set_alt_root(Some(root));
+    }
+    match p.opt_str("unsupported").as_deref() {
+        None | Some("fail") => (),
+        Some("skip") => set_skip_unsupported(true),
+        Some(u) => bail!("--unsupported {}: must be \"fail\" or \"skip\"", u),
+    }
     let os = which_os(&log)?;
 
+    let nodename = os_ops().nodename();
+    let node = p.opt_str("node").unwrap_or_else(|| nodename.clone());
+
+    /*
+     * If no roles were selected on the command line, apply the roles listed
+     * for this host in the inventory.
+     */
+    let inventory = Inventory::read(dir.join("hosts.toml"))?;
+    let host = inventory.as_ref().and_then(|inv| inv.host(&node).cloned());
+    match (&inventory, &host) {
+        (None, _) if p.opt_present("node") => {
+            bail!("--node requires an inventory (hosts.toml)");
+        }
+        (Some(_), None) if p.opt_present("node") => {
+            bail!("node \"{}\" is not in the inventory", node);
+        }
+        (Some(_), None) if push.is_none() => {
+            warn!(log, "node \"{}\" is not in the inventory", node);
+        }
+        _ => (),
+    }
+    let freeargs = match &host {
+        _ if push.is_some() => Vec::new(),
+        Some(h) if p.free.is_empty() => {
+            info!(log, "roles for node \"{}\" from inventory: {:?}", node,
+                h.roles);
+            h.roles.clone()
+        }
+        _ => p.free.clone(),
+    };
+
+    /*
+     * To resume a failed run, start at the step that failed.
+     */
+    let start_at = if p.opt_present("resume") && push.is_none() {
+        match progress::load()? {
+            Some(pr) if pr.roles != freeargs => {
+                bail!("the failed run applied roles {:?}, not {:?}; resume \
+                    with the same roles", pr.roles, freeargs);
+            }
+            Some(pr) => {
+                info!(log, "resuming at role {} step {} {}", pr.role,
+                    pr.step, pr.resource);
+                Some(StepSel {
+                    role: Some(pr.role),
+                    name: format!("{}:{}", pr.step, pr.resource),
+                    origin: "--resume",
+                })
+            }
+            None => {
+                info!(log, "no failed run to resume; applying all steps");
+                None
+            }
+        }
+    } else {
+        p.opt_str("start-at").map(|name| StepSel {
+            role: None,
+            name,
+            origin: "--start-at",
+        })
+    };
+
+    /*
+     * The environment may be selected on the command line, or by the
+     * inventory.
+     */
+    let env = p.opt_str("env")
+        .or_else(|| host.as_ref().and_then(|h| h.env.clone()));
+    if let Some(e) = &env {
+        if e.is_empty() || e.contains('/') || e.starts_with('.') {
+            bail!("invalid environment name \"{}\"", e);
+        }
+        let ed = dir.join("env").join(e);
+        if push.is_none() && !ed.is_dir() {
+            bail!("environment \"{}\" has no directory {}", e,
+                ed.display());
+        }
+        info!(log, "environment: {}", e);
+    }
+
+    /*
+     * Collect the role variables, with each source overriding those before
+     * it.
+     */
+    let mut v = vars::empty();
+    if let Some(f) = vars::read(dir.join("vars.toml"))? {
+        vars::merge(&mut v, f);
+    }
+    if let Some(e) = &env {
+        let envvars = dir.join("env").join(e).join("vars.toml");
+        if let Some(f) = vars::read(&envvars)? {
+            info!(log, "reading variables from {}", envvars.display());
+            vars::merge(&mut v, f);
+        }
+    }
+    if let Some(f) = host.and_then(|h| h.vars) {
+        vars::merge(&mut v, f);
+    }
+    let hostvars = dir.join("vars").join(format!("{}.toml", node));
+    if let Some(f) = vars::read(&hostvars)? {
+        info!(log, "reading variables from {}", hostvars.display());
+        vars::merge(&mut v, f);
+    }
+    for path in p.opt_strs("vars").iter() {
+        match vars::read(path)? {
+            Some(f) => vars::merge(&mut v, f),
+            None => bail!("vars file {} not found", path),
+        }
+    }
+    for arg in p.opt_strs("e").iter() {
+        vars::merge(&mut v, vars::assignment(arg)?);
+    }
+
+    let secrets = Secrets::new(p.opt_str("secrets-key").map(PathBuf::from));
+    let mut secret_strings = Vec::new();
+    if push.is_none() {
+        if let Some(s) = secrets.load_vars(&dir)? {
+            info!(log, "loaded secrets for templates");
+            secrets::strings(&s, &mut secret_strings);
+            let mut m = serde_json::Map::new();
+            m.insert("secrets".to_string(), s);
+            vars::merge(&mut v, serde_json::Value::Object(m));
+        }
+    }
+
+    let secret_source = if push.is_none() {
+        secret_source::read(&dir)?
+    } else {
+        None
+    };
+    if let Some(src) = &secret_source {
+        info!(log, "fetching template secrets from {}", src.describe());
+    }
+
+    let hooks = if push.is_none() {
+        Hooks::read(&dir)?
+    } else {
+        Hooks::default()
+    };
+    let notify = if push.is_none() {
+        Notify::read(&dir)?
+    } else {
+        Notify::default()
+    };
+
+    let facts = facts::gather(&log, &os, &facts::script_dirs(&dir),
+        p.opt_present("refresh-facts"));
+
+    let journal = if let Some(path) = p.opt_str("json-log") {
+        info!(log, "logging JSON lines to {}", path);
+        Some(Journal::open(&path, &nodename, dry_run())?)
+    } else {
+        None
+    };
+    let syslog = if p.opt_present("syslog") && push.is_none() {
+        Some(Syslog::open(dry_run()))
+    } else {
+        None
+    };
+    let audit_trail = AuditTrail::new(&nodename);
+
     let c = Confomat {
         log,
         dir,
         os,
-        nodename: illumos::nodename(),
-        zoneid: illumos::zoneid(),
-        zonename: illumos::zonename(),
-        freeargs: p.free,
+        nodename,
+        zoneid: os_ops().zoneid(),
+        zonename: os_ops().zonename(),
+        freeargs,
+        #[cfg(feature = "zones")]
+        zone_runs: Mutex::new(Vec::new()),
+        #[cfg(feature = "zones")]
+        zone_facts: Mutex::new(None),
+        reboots: Mutex::new(Vec::new()),
+        boot_archive: Mutex::new(false),
         roles: HashMap::new(),
+        plan,
+        journal,
+        syslog,
+        display: style.map(Display::new),
+        audit_trail,
+        step_times: Mutex::new(Vec::new()),
+        role_times: Mutex::new(Vec::new()),
+        jobs,
+        vars: v,
+        tags: split_tags(&p.opt_strs("tags")),
+        skip_tags: split_tags(&p.opt_strs("skip-tags")),
+        push,
+        push_status: 0,
+        verify_idempotent,
+        _lock: lock,
+        secrets,
+        secret_strings,
+        secret_source,
+        fetched_secrets: Mutex::new(HashMap::new()),
+        env,
+        hooks,
+        report: p.opt_str("report").map(PathBuf::from),
+        report_url: p.opt_str("report-url"),
+        metrics: p.opt_str("metrics").map(PathBuf::from),
+        notify,
+        only: p.opt_strs("only"),
+        start_at,
+        started: Mutex::new(false),
+        failed: Mutex::new(None),
+        registered: Mutex::new(serde_json::Map::new()),
+        facts,
     };
 
     info!(c.log, "operating system: {:?}", c.os);
@@ -1085,3 +5126,538 @@ pub fn start() -> Result<Confomat> {
 
     Ok(c)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * A Confomat as start() would make it, with nothing requested on the
+     * command line, and no roles.
+     */
+    fn confomat() -> Confomat {
+        let log = Logger::root(slog::Discard, o!());
+        Confomat {
+            log,
+            dir: PathBuf::from("/nonexistent"),
+            os: OS::OmniOS,
+            nodename: "test".to_string(),
+            zoneid: 0,
+            zonename: "global".to_string(),
+            freeargs: Vec::new(),
+            #[cfg(feature = "zones")]
+            zone_runs: Mutex::new(Vec::new()),
+            #[cfg(feature = "zones")]
+            zone_facts: Mutex::new(None),
+            reboots: Mutex::new(Vec::new()),
+            boot_archive: Mutex::new(false),
+            roles: HashMap::new(),
+            plan: false,
+            journal: None,
+            syslog: None,
+            display: None,
+            audit_trail: AuditTrail::new("test"),
+            step_times: Mutex::new(Vec::new()),
+            role_times: Mutex::new(Vec::new()),
+            jobs: 1,
+            vars: vars::empty(),
+            tags: Vec::new(),
+            skip_tags: Vec::new(),
+            push: None,
+            push_status: 0,
+            verify_idempotent: false,
+            _lock: None,
+            secrets: Secrets::new(None),
+            secret_strings: Vec::new(),
+            secret_source: None,
+            fetched_secrets: Mutex::new(HashMap::new()),
+            env: None,
+            hooks: Hooks::default(),
+            report: None,
+            report_url: None,
+            metrics: None,
+            notify: Notify::default(),
+            only: Vec::new(),
+            start_at: None,
+            started: Mutex::new(false),
+            failed: Mutex::new(None),
+            registered: Mutex::new(serde_json::Map::new()),
+            facts: Facts::default(),
+        }
+    }
+
+    fn role(name: &str, func: RoleFunc) -> Role {
+        Role {
+            name: name.to_string(),
+            func,
+            allow_instance: false,
+            after: Vec::new(),
+            requires: Vec::new(),
+            only_if: Vec::new(),
+            unless: Vec::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    /*
+     * The name of each step recorded in the run, in the order in which they
+     * finished, and whether each was nested.
+     */
+    fn steps(c: &Confomat) -> Vec<(String, &'static str, bool)> {
+        c.step_times.lock().unwrap().iter()
+            .map(|st| (st.step.to_string(), st.result, st.nested))
+            .collect()
+    }
+
+    fn nested_steps(c: &Context) -> Result<()> {
+        c.step("outer", "a", || c.step("inner", "b", || Ok(())))?;
+        c.step("after", "c", || Ok(()))
+    }
+
+    #[test]
+    fn step_nesting() {
+        let c = confomat();
+        c.apply_role(&role("nested", nested_steps), None).unwrap();
+
+        assert_eq!(steps(&c), vec![
+            ("inner".to_string(), "unchanged", true),
+            ("outer".to_string(), "unchanged", false),
+            ("after".to_string(), "unchanged", false),
+        ]);
+        assert!(!plan::in_step());
+    }
+
+    fn start_at(name: &str) -> Option<StepSel> {
+        Some(StepSel {
+            role: None,
+            name: name.to_string(),
+            origin: "--start-at",
+        })
+    }
+
+    #[test]
+    fn start_at_step() {
+        let mut c = confomat();
+        c.start_at = start_at("after:c");
+        c.apply_role(&role("nested", nested_steps), None).unwrap();
+        c.check_started().unwrap();
+
+        assert_eq!(steps(&c), vec![
+            ("outer".to_string(), "skipped", false),
+            ("after".to_string(), "unchanged", false),
+        ]);
+    }
+
+    #[test]
+    fn start_at_missing_step() {
+        let mut c = confomat();
+        c.start_at = start_at("nonexistent");
+        c.apply_role(&role("nested", nested_steps), None).unwrap();
+
+        assert!(c.check_started().is_err());
+        assert!(steps(&c).iter().all(|(_, result, _)| *result == "skipped"));
+    }
+
+    fn failing_step(c: &Context) -> Result<()> {
+        c.step("outer", "a", || c.step("inner", "b", || -> Result<()> {
+            bail!("failed")
+        }))
+    }
+
+    #[test]
+    fn resume_at_failed_step() {
+        let c = confomat();
+        assert!(c.apply_role(&role("failing", failing_step), None).is_err());
+
+        let failed = c.failed.lock().unwrap();
+        let pr = failed.as_ref().unwrap();
+        assert_eq!((pr.role.as_str(), pr.step.as_str(), pr.resource.as_str()),
+            ("failing", "outer", "a"));
+    }
+
+    fn changing_step(c: &Context) -> Result<()> {
+        c.step("outer", "a", || c.step("inner", "b", || {
+            plan::record(Change::new(Action::Create, "/tmp/b"));
+            Ok(())
+        }))?;
+        c.step("after", "c", || Ok(()))
+    }
+
+    #[test]
+    fn audit_changed_step() {
+        let path = std::env::temp_dir().join(format!("confomat.audit.{}",
+            std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut c = confomat();
+        c.audit_trail = AuditTrail::at("test", path.clone());
+        c.apply_role(&role("changing", changing_step), None).unwrap();
+
+        let trail = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        /*
+         * The change is recorded once, with the outermost step.
+         */
+        let lines: Vec<&str> = trail.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("\"step\":\"outer\""));
+        assert!(lines[0].contains("\"what\":\"/tmp/b\""));
+        assert_eq!(steps(&c)[1], ("outer".to_string(), "changed", false));
+    }
+
+    fn unsupported_step(c: &Context) -> Result<()> {
+        c.step("outer", "a", || c.step("inner", "b", || -> Result<()> {
+            Err(Unsupported::new("inner", "test").into())
+        }))?;
+        c.step("after", "c", || Ok(()))
+    }
+
+    #[test]
+    fn skip_unsupported_step() {
+        /*
+         * No other test fails with Unsupported, so setting this for the
+         * whole process does not affect them.
+         */
+        set_skip_unsupported(true);
+        let c = confomat();
+        c.apply_role(&role("unsupported", unsupported_step), None).unwrap();
+
+        assert_eq!(steps(&c), vec![
+            ("inner".to_string(), "failed", true),
+            ("outer".to_string(), "skipped", false),
+            ("after".to_string(), "unchanged", false),
+        ]);
+    }
+
+    fn flaky_step(c: &Context) -> Result<()> {
+        let attempts = Cell::new(0);
+        let retry = Retry {
+            attempts: 3,
+            delay: Duration::from_secs(0),
+            backoff: 2.0,
+        };
+        c.retry(&retry, || c.step("flaky", "a", || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                bail!("attempt {} failed", attempts.get());
+            }
+            Ok(true)
+        }))?;
+        Ok(())
+    }
+
+    #[test]
+    fn retry_step() {
+        let c = confomat();
+        c.apply_role(&role("flaky", flaky_step), None).unwrap();
+
+        assert_eq!(steps(&c), vec![
+            ("flaky".to_string(), "retried", false),
+            ("flaky".to_string(), "retried", false),
+            ("flaky".to_string(), "changed", false),
+        ]);
+    }
+
+    #[test]
+    fn retry_options() {
+        let retry = |attempts, backoff| Retry {
+            attempts,
+            delay: Duration::from_secs(1),
+            backoff,
+        };
+        assert!(retry(3, 2.0).check().is_ok());
+        assert!(retry(0, 2.0).check().is_err());
+        assert!(retry(3, -1.0).check().is_err());
+        assert!(retry(3, f64::NAN).check().is_err());
+        assert!(retry(3, f64::INFINITY).check().is_err());
+
+        let r = retry(3, 1e300);
+        assert_eq!(r.next_delay(Duration::from_secs(1)),
+            Duration::from_secs(1));
+        assert_eq!(retry(3, 2.0).next_delay(Duration::from_secs(1)),
+            Duration::from_secs(2));
+    }
+
+    fn tagged_steps(c: &Context) -> Result<()> {
+        c.tag("net").step("outer", "a", || c.step("inner", "b", || Ok(())))?;
+        c.step("after", "c", || Ok(()))
+    }
+
+    #[test]
+    fn tags() {
+        let mut c = confomat();
+        c.tags = vec!["net".to_string()];
+        c.apply_role(&role("tagged", tagged_steps), None).unwrap();
+
+        assert_eq!(steps(&c), vec![
+            ("inner".to_string(), "unchanged", true),
+            ("outer".to_string(), "unchanged", false),
+            ("after".to_string(), "skipped", false),
+        ]);
+    }
+
+    #[test]
+    fn skip_tags() {
+        let mut c = confomat();
+        c.skip_tags = vec!["net".to_string()];
+        c.apply_role(&role("tagged", tagged_steps), None).unwrap();
+
+        assert_eq!(steps(&c), vec![
+            ("outer".to_string(), "skipped", false),
+            ("after".to_string(), "unchanged", false),
+        ]);
+    }
+
+    fn named_steps(c: &Context) -> Result<()> {
+        c.step("outer", "a", || c.step("inner", "b", || {
+            match step_name() {
+                Some(n) if n == "outer" => Ok(()),
+                n => bail!("step name is {:?} within the inner step", n),
+            }
+        }))?;
+        match step_name() {
+            None => Ok(()),
+            n => bail!("step name is {:?} between steps", n),
+        }
+    }
+
+    #[test]
+    fn step_name_outermost() {
+        let c = confomat();
+        c.apply_role(&role("named", named_steps), None).unwrap();
+    }
+
+    #[test]
+    fn only_step() {
+        let mut c = confomat();
+        c.only = vec!["outer".to_string()];
+        c.apply_role(&role("nested", nested_steps), None).unwrap();
+
+        assert_eq!(steps(&c), vec![
+            ("inner".to_string(), "unchanged", true),
+            ("outer".to_string(), "unchanged", false),
+            ("after".to_string(), "skipped", false),
+        ]);
+    }
+
+    #[test]
+    fn temp_file_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tf = common::temp_file("test.conf", b"contents\n").unwrap();
+        let dir = tf.path().parent().unwrap().to_path_buf();
+        let mode = |p: &Path| {
+            std::fs::metadata(p).unwrap().permissions().mode() & 0o777
+        };
+        assert_eq!(std::fs::read(tf.path()).unwrap(), b"contents\n");
+        assert_eq!(mode(tf.path()), 0o600);
+        assert_eq!(mode(&dir), 0o700);
+
+        drop(tf);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn resolve_within_root() {
+        let root = std::env::temp_dir().join(format!("confomat.root.{}",
+            std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("real")).unwrap();
+        std::os::unix::fs::symlink("/real", root.join("abs")).unwrap();
+        std::os::unix::fs::symlink("../../real", root.join("rel")).unwrap();
+
+        let r = |p: &str| common::resolve(&root, Path::new(p)).unwrap();
+        assert_eq!(r("/abs/passwd"), root.join("real/passwd"));
+        assert_eq!(r("/rel/passwd"), root.join("real/passwd"));
+        assert_eq!(r("/../../etc/passwd"), root.join("etc/passwd"));
+        assert_eq!(r("/abs"), root.join("abs"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /*
+     * An imaginary system on which a user and a group have our own IDs, so
+     * that the files we create belong to them.  The implementation of OsOps
+     * is shared by the whole process, so every test which relies on it
+     * installs this same one.
+     */
+    fn mock_os() {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let mut os = osops::MockOs::new("mock");
+        os.add_group("mockgrp", gid, &["mockusr"]);
+        os.add_user("mockusr", uid, gid, "/home/mockusr");
+        osops::set_os_ops(std::sync::Arc::new(os));
+    }
+
+    #[test]
+    fn check_owner_names() {
+        mock_os();
+        let tf = temp_file("owned", b"").unwrap();
+
+        let fi = ensure::check(tf.path()).unwrap().unwrap();
+        assert_eq!(fi.owner, ensure::Id::Name("mockusr".to_string()));
+        assert_eq!(fi.group, ensure::Id::Name("mockgrp".to_string()));
+    }
+
+    #[test]
+    fn perms_owner_names() {
+        mock_os();
+        let log = Logger::root(slog::Discard, o!());
+        let tf = temp_file("owned", b"").unwrap();
+
+        /*
+         * The names are those of the owner already, so only the mode is
+         * changed.
+         */
+        assert!(!ensure::perms(&log, tf.path(), "mockusr", "mockgrp", 0o600)
+            .unwrap());
+        assert!(ensure::perms(&log, tf.path(), "mockusr", "mockgrp", 0o640)
+            .unwrap());
+        assert!(ensure::perms(&log, tf.path(), "nosuch", "mockgrp", 0o640)
+            .is_err());
+    }
+
+    #[test]
+    fn exec_unknown_user() {
+        mock_os();
+        let log = Logger::root(slog::Discard, o!());
+
+        let e = ensure::query_with(&log, &["/bin/true"], &ensure::Exec {
+            user: Some("nosuch".to_string()),
+            ..Default::default()
+        }).unwrap_err();
+        assert!(format!("{:#}", e).contains("nosuch"));
+    }
+
+    #[test]
+    fn user_attr_profiles() {
+        let ua = illumos::UserAttr {
+            name: "build".to_string(),
+            attr: vec![("profiles".to_string(),
+                "Software Installation, Service Management".to_string())]
+                .into_iter().collect(),
+        };
+        assert_eq!(ua.profiles(), vec!["Software Installation",
+            "Service Management"]);
+
+        let ua = illumos::UserAttr {
+            name: "build".to_string(),
+            attr: HashMap::new(),
+        };
+        assert!(ua.profiles().is_empty());
+    }
+
+    /*
+     * Elsewhere than illumos, there is one zone, the global zone, and the
+     * stand-in illumos module finds nothing in user_attr(4).
+     */
+    #[cfg(not(target_os = "illumos"))]
+    #[test]
+    fn stand_in_illumos() {
+        assert_eq!(sys::zoneid(), 0);
+        assert_eq!(sys::zonename(), "global");
+        assert!(illumos::get_user_attr_by_name("root").unwrap().is_none());
+        assert!(sys::PrivSet::basic().unwrap().apply().is_ok());
+    }
+
+    #[test]
+    fn email_date() {
+        let at = |s| std::time::UNIX_EPOCH + std::time::Duration::from_secs(s);
+        assert_eq!(notify::date(at(0)), "Thu, 1 Jan 1970 00:00:00 +0000");
+        assert_eq!(notify::date(at(951782400)),
+            "Tue, 29 Feb 2000 00:00:00 +0000");
+        assert_eq!(notify::date(at(1791981296)),
+            "Wed, 14 Oct 2026 12:34:56 +0000");
+    }
+
+    fn include_ping(c: &Context) -> Result<()> {
+        c.include("pong", None, serde_json::Value::Null)
+    }
+
+    fn include_pong(c: &Context) -> Result<()> {
+        c.include("ping", None, serde_json::Value::Null)
+    }
+
+    #[test]
+    fn include_cycle() {
+        let mut c = confomat();
+        c.roles.insert("ping".to_string(), role("ping", include_ping));
+        c.roles.insert("pong".to_string(), role("pong", include_pong));
+
+        let e = c.apply_role(c.role("ping").unwrap(), None).unwrap_err();
+        assert!(format!("{:#}", e).contains("ping -> pong -> ping"));
+    }
+
+    #[test]
+    fn register_roles() {
+        let mut c = confomat();
+        let plain = RoleProvider {
+            name: "plain",
+            func: nested_steps,
+            instance_posture: InstancePosture::Prohibited,
+        };
+        c.register(&plain).unwrap();
+        c.register_with(&RoleProvider {
+            name: "web",
+            func: nested_steps,
+            instance_posture: InstancePosture::Required,
+        }, &RoleOptions {
+            requires: &["plain"],
+            tags: &["web"],
+            ..Default::default()
+        }).unwrap();
+
+        let r = c.role("plain").unwrap();
+        assert!(!r.allow_instance);
+        assert!(r.requires.is_empty() && r.tags.is_empty());
+        let r = c.role("web").unwrap();
+        assert!(r.allow_instance);
+        assert_eq!(r.requires, vec!["plain"]);
+        assert_eq!(r.tags, vec!["web"]);
+
+        assert!(c.register(&plain).is_err());
+    }
+
+    fn ran_step(c: &Context) -> Result<()> {
+        c.step("ran", "a", || Ok(()))
+    }
+
+    /*
+     * The role of each step recorded in the run.
+     */
+    fn step_roles(c: &Confomat) -> Vec<String> {
+        c.step_times.lock().unwrap().iter()
+            .map(|st| st.role.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn only_if_facts() {
+        let mut c = confomat();
+        c.facts.zonename = "global".to_string();
+
+        let mut r = role("gz", ran_step);
+        r.only_if = vec![Condition::Expr("facts.zonename == global")];
+        c.apply_role(&r, None).unwrap();
+        let mut r = role("ngz", ran_step);
+        r.unless = vec![Condition::Expr("facts.zonename == global")];
+        c.apply_role(&r, None).unwrap();
+
+        assert_eq!(step_roles(&c), vec!["gz".to_string()]);
+    }
+
+    #[test]
+    fn only_if_command() {
+        let c = confomat();
+
+        let mut r = role("dir", ran_step);
+        r.only_if = vec![Condition::Command(&["test", "-d", "/"])];
+        c.apply_role(&r, None).unwrap();
+        let mut r = role("file", ran_step);
+        r.only_if = vec![Condition::Command(&["test", "-f", "/"])];
+        c.apply_role(&r, None).unwrap();
+
+        assert_eq!(step_roles(&c), vec!["dir".to_string()]);
+    }
+}
@@ -0,0 +1,168 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Network link facts: the datalinks on the system (physical links, VNICs,
+ * aggregations, and so on), with their MAC addresses, MTUs, and current IP
+ * addresses, so that templates can refer to the actual interface
+ * configuration; e.g., "facts.links.net0.mtu".  On illumos these come from
+ * dladm(1M), and on Linux from sysfs.  On other systems, only the links with
+ * IP addresses are known, and have no other details.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Serialize;
+use slog::{Logger, warn};
+use anyhow::Result;
+
+use super::common::parseable;
+use super::facts::InterfaceAddress;
+use super::OS;
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Link {
+    /**
+     * The kind of link; e.g., "phys", "vnic", "aggr", or "etherstub" on
+     * illumos, or "phys", "vlan", "bond", or "bridge" on Linux.
+     */
+    pub class: Option<String>,
+    /**
+     * For a VNIC, VLAN, or aggregation, the link (or links) over which it is
+     * built.
+     */
+    pub over: Option<String>,
+    pub mac: Option<String>,
+    pub mtu: Option<u32>,
+    /**
+     * e.g., "up" or "down".
+     */
+    pub state: Option<String>,
+    /**
+     * The IP addresses on the link, as "address/prefix", including those on
+     * logical interfaces (e.g., "net0:1") over it.
+     */
+    pub addresses: Vec<String>,
+}
+
+fn nonempty(s: &str) -> Option<String> {
+    if s.is_empty() || s == "--" {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+fn dladm(links: &mut BTreeMap<String, Link>) -> Result<()> {
+    for f in parseable(&["/usr/sbin/dladm", "show-link", "-p", "-o",
+        "link,class,mtu,state,over"])?
+    {
+        if f.len() != 5 {
+            continue;
+        }
+        let l = links.entry(f[0].clone()).or_default();
+        l.class = nonempty(&f[1]);
+        l.mtu = f[2].parse().ok();
+        l.state = nonempty(&f[3]);
+        l.over = nonempty(&f[4]);
+    }
+
+    /*
+     * A physical link may have more than one MAC address slot; the first is
+     * the primary address.
+     */
+    let phys = parseable(&["/usr/sbin/dladm", "show-phys", "-m", "-p", "-o",
+        "link,address"])?;
+    let vnics = parseable(&["/usr/sbin/dladm", "show-vnic", "-p", "-o",
+        "link,macaddress"])?;
+    for f in phys.iter().chain(vnics.iter()) {
+        if let (2, Some(l)) = (f.len(), links.get_mut(&f[0])) {
+            if l.mac.is_none() {
+                l.mac = nonempty(&f[1]);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn sysfs(links: &mut BTreeMap<String, Link>) -> Result<()> {
+    let read = |p: &Path, n: &str| {
+        std::fs::read_to_string(p.join(n)).ok()
+            .and_then(|s| nonempty(s.trim()))
+    };
+
+    for ent in std::fs::read_dir("/sys/class/net")? {
+        let ent = ent?;
+        let p = ent.path();
+        let name = ent.file_name().to_string_lossy().to_string();
+
+        /*
+         * ARPHRD_LOOPBACK:
+         */
+        if read(&p, "type").as_deref() == Some("772") {
+            continue;
+        }
+
+        let devtype = read(&p, "uevent").and_then(|u| {
+            u.lines()
+                .find_map(|l| l.strip_prefix("DEVTYPE="))
+                .map(|t| t.to_string())
+        });
+        let class = match devtype {
+            Some(t) if t == "bond" || t == "bridge" || t == "vlan" => Some(t),
+            _ if p.join("device").exists() => Some("phys".to_string()),
+            t => t,
+        };
+
+        let mut over: Vec<String> = std::fs::read_dir(&p)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_string_lossy()
+                .strip_prefix("lower_").map(|l| l.to_string()))
+            .collect();
+        over.sort();
+
+        let l = links.entry(name).or_default();
+        l.class = class;
+        l.over = nonempty(&over.join(" "));
+        l.mac = read(&p, "address");
+        l.mtu = read(&p, "mtu").and_then(|m| m.parse().ok());
+        l.state = read(&p, "operstate");
+    }
+
+    Ok(())
+}
+
+/**
+ * Gather the links, with the addresses on each.  If the link details cannot
+ * be determined, that is reported, and only the links with addresses are
+ * known.
+ */
+pub fn gather(log: &Logger, os: &OS, addresses: &[InterfaceAddress])
+    -> BTreeMap<String, Link>
+{
+    let mut links = BTreeMap::new();
+
+    let res = if os.is_illumos() {
+        dladm(&mut links)
+    } else if os.is_linux() {
+        sysfs(&mut links)
+    } else {
+        Ok(())
+    };
+    if let Err(e) = res {
+        warn!(log, "facts: links: {}", e);
+        links.clear();
+    }
+
+    for a in addresses.iter() {
+        let name = a.interface.split(':').next().unwrap_or(&a.interface);
+        links.entry(name.to_string())
+            .or_default()
+            .addresses.push(format!("{}/{}", a.address, a.prefix));
+    }
+
+    links
+}
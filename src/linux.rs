@@ -0,0 +1,53 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The Linux counterpart of the illumos module, providing the same functions
+ * so that confomat (and the roles built on it) can be built for either.
+ * Users and groups are looked up through the name service switch, as with
+ * getent(1), and are created and altered with the shadow-utils commands
+ * (useradd(8), groupadd(8), and so on; see the users module).  Linux has no
+ * zones, so the host is treated as though it were the global
+ * zone.
+ */
+
+use anyhow::Result;
+
+pub use super::unix::{get_group_ids_for_user, get_id_from_file, nodename,
+    openpty, set_controlling_tty, Pty};
+pub use super::nss::{get_group_by_id, get_group_by_name, get_passwd_by_id,
+    get_passwd_by_name, Group, Passwd};
+
+pub fn zoneid() -> i32 {
+    0
+}
+
+pub fn zonename() -> String {
+    "global".to_string()
+}
+
+/**
+ * Is this system managed by systemd?  This is the test made by sd_booted(3):
+ * whether systemd has created its runtime directory.
+ */
+pub fn has_systemd() -> bool {
+    std::path::Path::new("/run/systemd/system").is_dir()
+}
+
+/**
+ * Linux has no privilege sets.  A process which changes from root to another
+ * user loses its capabilities, so there is nothing more to give up, and
+ * apply() does nothing.
+ */
+pub struct PrivSet;
+
+impl PrivSet {
+    pub fn basic() -> Result<PrivSet> {
+        Ok(PrivSet)
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
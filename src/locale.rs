@@ -0,0 +1,156 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The default locale of the system, and the environment which every process
+ * (or every login) starts with.
+ *
+ * On illumos, both live in /etc/default/init, which init(1M) reads at boot and
+ * passes on to every service and login; it only allows TZ (see the timezone
+ * module), CMASK, LANG, and the LC_* variables.  On Linux, the locale is in
+ * /etc/default/locale (Debian and Ubuntu) or /etc/locale.conf (Fedora and
+ * RedHat), and other variables are in /etc/environment, which pam_env(8)
+ * reads at each login.
+ *
+ * The value of LANG and of each LC_* variable must be a locale the system
+ * has, as listed by "locale -a"; e.g., "en_US.UTF-8" or "C".
+ */
+
+use slog::Logger;
+use anyhow::{Result, bail};
+
+use super::common::{Unsupported, alt_root, rooted};
+use super::ensure::{self, Exec, Ownership};
+use super::OS;
+
+const INIT_DEFAULTS: &str = "/etc/default/init";
+const DEBIAN_LOCALE: &str = "/etc/default/locale";
+const LOCALE_CONF: &str = "/etc/locale.conf";
+const ENVIRONMENT: &str = "/etc/environment";
+
+const INIT_VARS: &[&str] = &["CMASK", "LANG"];
+
+fn is_locale_var(name: &str) -> bool {
+    name == "LANG" || name.starts_with("LC_")
+}
+
+/*
+ * Locale names are compared without regard to the spelling of the codeset,
+ * as glibc lists "en_US.utf8" for "en_US.UTF-8".
+ */
+fn normalise(locale: &str) -> String {
+    let (name, modifier) = match locale.split_once('@') {
+        Some((n, m)) => (n, Some(m)),
+        None => (locale, None),
+    };
+    let mut out = match name.split_once('.') {
+        Some((l, cs)) => {
+            format!("{}.{}", l, cs.to_lowercase().replace('-', ""))
+        }
+        None => name.to_string(),
+    };
+    if let Some(m) = modifier {
+        out.push('@');
+        out.push_str(m);
+    }
+    out
+}
+
+/*
+ * Check that the system (beneath the alternate root, if there is one) has
+ * each locale named in these settings.
+ */
+fn check_locales(log: &Logger, settings: &[(&str, &str)]) -> Result<()> {
+    let wanted: Vec<&str> = settings.iter()
+        .filter(|(k, _)| is_locale_var(k))
+        .map(|(_, v)| *v)
+        .filter(|v| *v != "C" && *v != "POSIX")
+        .collect();
+    if wanted.is_empty() {
+        return Ok(());
+    }
+
+    let out = ensure::query_output(log, &["locale", "-a"], &Exec {
+        root: alt_root(),
+        ..Default::default()
+    })?;
+    let have: Vec<String> = out.lines().map(|l| normalise(l.trim())).collect();
+    for w in wanted {
+        if !have.contains(&normalise(w)) {
+            bail!("locale \"{}\" is not installed", w);
+        }
+    }
+    Ok(())
+}
+
+fn check_settings(settings: &[(&str, &str)]) -> Result<()> {
+    for (k, v) in settings.iter() {
+        if k.is_empty() || !k.chars().all(|c| c.is_ascii_alphanumeric()
+            || c == '_')
+        {
+            bail!("invalid environment variable name \"{}\"", k);
+        }
+        if v.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+            bail!("the value of {} must not contain spaces or quotes", k);
+        }
+    }
+    Ok(())
+}
+
+fn apply(log: &Logger, os: &OS, path: &str, settings: &[(&str, &str)])
+    -> Result<bool>
+{
+    ensure::key_values(log, rooted(path)?, settings,
+        &Ownership::Names("root", os.root_group()), 0o644)
+}
+
+/**
+ * Ensure the default locale of the system; e.g., LANG, or LC_COLLATE.
+ */
+pub fn locale(log: &Logger, os: &OS, settings: &[(&str, &str)])
+    -> Result<bool>
+{
+    check_settings(settings)?;
+    if let Some((k, _)) = settings.iter().find(|(k, _)| !is_locale_var(k)) {
+        bail!("{} is not a locale setting", k);
+    }
+    check_locales(log, settings)?;
+
+    let path = match os {
+        os if os.is_illumos() => INIT_DEFAULTS,
+        OS::Debian | OS::Ubuntu => DEBIAN_LOCALE,
+        OS::Fedora | OS::RedHat => LOCALE_CONF,
+        _ => {
+            return Err(Unsupported::new("system locale",
+                &format!("{:?}", os)).into());
+        }
+    };
+    apply(log, os, path, settings)
+}
+
+/**
+ * Ensure variables in the default environment.
+ */
+pub fn environment(log: &Logger, os: &OS, vars: &[(&str, &str)])
+    -> Result<bool>
+{
+    check_settings(vars)?;
+    check_locales(log, vars)?;
+
+    let path = if os.is_illumos() {
+        if let Some((k, _)) = vars.iter()
+            .find(|(k, _)| !is_locale_var(k) && !INIT_VARS.contains(k))
+        {
+            bail!("{} cannot be set in {}: only CMASK, LANG, and LC_* may \
+                be", k, INIT_DEFAULTS);
+        }
+        INIT_DEFAULTS
+    } else if os.is_linux() {
+        ENVIRONMENT
+    } else {
+        return Err(Unsupported::new("default environment",
+            &format!("{:?}", os)).into());
+    };
+    apply(log, os, path, vars)
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * A lock file ensures that only one confomat run at a time alters the system.
+ * The run holds an exclusive flock(3C) on the file, which the kernel releases
+ * when the process exits, however that happens; the file itself stays in
+ * place, so there is never a stale lock to break.  While the lock is held,
+ * the file contains the process ID of the holder and the time at which it
+ * started, as seconds since the epoch, for the benefit of anyone waiting:
+ *
+ *      pid 1234 started 1602633600
+ */
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::{STATE_DIR, errno, interrupted};
+
+const POLL: Duration = Duration::from_secs(5);
+
+pub struct RunLock {
+    file: File,
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        /*
+         * Clear the holder from the file; the lock itself is released when
+         * the file is closed.
+         */
+        self.file.set_len(0).ok();
+    }
+}
+
+pub fn path() -> PathBuf {
+    Path::new(STATE_DIR).join("lock")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/*
+ * Parse the contents of a lock file, returning the process ID and start time
+ * of the holder.
+ */
+fn parse(contents: &str) -> Option<(i32, u64)> {
+    let w: Vec<&str> = contents.split_whitespace().collect();
+    match w.as_slice() {
+        ["pid", pid, "started", started] => {
+            Some((pid.parse().ok()?, started.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/*
+ * Try to take an exclusive lock on the file without blocking, returning
+ * whether we got it.
+ */
+fn try_lock(f: &File) -> std::io::Result<bool> {
+    loop {
+        if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }
+            == 0
+        {
+            return Ok(true);
+        }
+        match errno() {
+            libc::EINTR => continue,
+            libc::EWOULDBLOCK => return Ok(false),
+            _ => return Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+/*
+ * Describe the holder of the lock, if it has recorded itself in the file.
+ */
+fn holder(mut f: &File) -> String {
+    let mut contents = String::new();
+    if f.rewind().is_err() || f.read_to_string(&mut contents).is_err() {
+        return "another confomat run".to_string();
+    }
+    match parse(&contents) {
+        Some((pid, started)) => {
+            format!("another confomat run (pid {}, started {}s ago)", pid,
+                now().saturating_sub(started))
+        }
+        None => "another confomat run".to_string(),
+    }
+}
+
+/**
+ * Take the run lock.  If another run holds it, either wait for it to be
+ * released or fail, depending on "wait".
+ */
+pub fn acquire(log: &Logger, wait: bool) -> Result<RunLock> {
+    let path = path();
+    std::fs::create_dir_all(STATE_DIR)?;
+
+    let mut f = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o644)
+        .open(&path)
+    {
+        Ok(f) => f,
+        Err(e) => bail!("opening lock {}: {}", path.display(), e),
+    };
+
+    let mut waiting = false;
+    loop {
+        match try_lock(&f) {
+            Ok(true) => break,
+            Ok(false) => (),
+            Err(e) => bail!("locking {}: {}", path.display(), e),
+        }
+
+        if !wait {
+            bail!("{} holds the lock {}; use --wait to wait for it",
+                holder(&f), path.display());
+        }
+        if !waiting {
+            info!(log, "waiting for run lock held by {}", holder(&f));
+            waiting = true;
+        }
+
+        std::thread::sleep(POLL);
+        if let Some(sig) = interrupted() {
+            bail!("interrupted by {} while waiting for the run lock", sig);
+        }
+    }
+
+    f.set_len(0)?;
+    f.rewind()?;
+    f.write_all(format!("pid {} started {}\n", std::process::id(), now())
+        .as_bytes())?;
+    f.flush()?;
+    info!(log, "acquired run lock {}", path.display());
+    Ok(RunLock { file: f })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_holder() {
+        assert_eq!(parse("pid 1234 started 1602633600\n"),
+            Some((1234, 1602633600)));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("pid x started 1602633600"), None);
+        assert_eq!(parse("pid 1234"), None);
+    }
+
+    #[test]
+    fn lock_exclusive() {
+        let tf = super::super::common::temp_file("lock", b"").unwrap();
+        let a = File::open(tf.path()).unwrap();
+        let b = File::open(tf.path()).unwrap();
+
+        assert!(try_lock(&a).unwrap());
+        assert!(!try_lock(&b).unwrap());
+        drop(a);
+        assert!(try_lock(&b).unwrap());
+    }
+}
@@ -0,0 +1,89 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The macOS counterpart of the illumos module.  Users and groups are kept by
+ * Directory Services rather than in /etc/passwd and /etc/group, which macOS
+ * reads only in single-user mode.  They can be looked up through the usual
+ * getpwnam(3) routines, but group membership must be found with dscl(1), and
+ * accounts are created and altered with dscl(1) and dseditgroup(8) (see the
+ * users module).  There are no zones, so a Mac is treated as though it were
+ * the global zone.
+ */
+
+use std::process::Command;
+
+use anyhow::Result;
+
+use super::error::Error;
+
+pub use super::unix::{get_id_from_file, nodename, openpty,
+    set_controlling_tty, Pty};
+pub use super::nss::{get_group_by_id, get_group_by_name, get_passwd_by_id,
+    get_passwd_by_name, Group, Passwd};
+
+const DSCL: &str = "/usr/bin/dscl";
+
+pub fn zoneid() -> i32 {
+    0
+}
+
+pub fn zonename() -> String {
+    "global".to_string()
+}
+
+/**
+ * The IDs of the groups in the local directory which list this user as a
+ * member.
+ */
+pub fn get_group_ids_for_user(name: &str) -> Result<Vec<u32>> {
+    let out = Command::new(DSCL)
+        .args([".", "-list", "/Groups", "GroupMembership"])
+        .output()?;
+    if !out.status.success() {
+        return Err(Error::ExternalCommandFailed {
+            args: vec![DSCL.to_string(), ".".to_string(), "-list".to_string(),
+                "/Groups".to_string(), "GroupMembership".to_string()],
+            status: out.status,
+            output: String::from_utf8_lossy(&out.stderr).lines()
+                .map(|l| l.to_string())
+                .collect(),
+        }.into());
+    }
+
+    /*
+     * Each line is a group name followed by the names of its members.
+     */
+    let mut gids = Vec::new();
+    for l in String::from_utf8(out.stdout)?.lines() {
+        let mut f = l.split_whitespace();
+        let group = match f.next() {
+            Some(g) => g,
+            None => continue,
+        };
+        if !f.any(|m| m == name) {
+            continue;
+        }
+        if let Some(gr) = get_group_by_name(group)? {
+            gids.push(gr.gid);
+        }
+    }
+
+    Ok(gids)
+}
+
+/**
+ * macOS has no privilege sets like those of illumos, so apply() does nothing.
+ */
+pub struct PrivSet;
+
+impl PrivSet {
+    pub fn basic() -> Result<PrivSet> {
+        Ok(PrivSet)
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
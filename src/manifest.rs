@@ -0,0 +1,112 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Manifests of the files expected on a system, with their SHA-256 digests,
+ * for the detection of files which have been altered between runs.  A
+ * manifest is in the format written by sha256sum(1) (or "digest -a sha256"
+ * with some help), one file per line:
+ *
+ *    9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  /bin/ls
+ *
+ * Blank lines and lines beginning with "#" are ignored.  Paths must be
+ * absolute, and are checked beneath the alternate root, if there is one.
+ */
+
+use std::fmt;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::rooted;
+use super::ensure::{self, FileType, HashType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    Missing(String),
+    NotFile(String),
+    Digest {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing(p) => write!(f, "{}: missing", p),
+            Mismatch::NotFile(p) => write!(f, "{}: not a file", p),
+            Mismatch::Digest { path, expected, actual } => {
+                write!(f, "{}: SHA-256 {}, expected {}", path, actual,
+                    expected)
+            }
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Vec<(String, String)>> {
+    let mut out = Vec::new();
+
+    for (i, l) in text.lines().enumerate() {
+        let l = l.trim();
+        if l.is_empty() || l.starts_with('#') {
+            continue;
+        }
+
+        /*
+         * sha256sum(1) separates the digest and the path with either two
+         * spaces, or a space and a "*" for a binary file.
+         */
+        let (digest, path) = match l.split_once(char::is_whitespace) {
+            Some((d, p)) => (d, p.trim_start().trim_start_matches('*')),
+            None => bail!("line {}: expected \"DIGEST PATH\"", i + 1),
+        };
+        if digest.len() != 64 ||
+            !digest.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            bail!("line {}: invalid SHA-256 digest \"{}\"", i + 1, digest);
+        }
+        if !path.starts_with('/') {
+            bail!("line {}: path \"{}\" is not absolute", i + 1, path);
+        }
+        out.push((digest.to_ascii_lowercase(), path.to_string()));
+    }
+
+    Ok(out)
+}
+
+/**
+ * Check each file in a manifest, returning every file which is missing or
+ * does not have the expected digest.
+ */
+pub fn verify(log: &Logger, text: &str) -> Result<Vec<Mismatch>> {
+    let entries = parse(text)?;
+    let mut bad = Vec::new();
+
+    for (expected, path) in entries.iter() {
+        let p = rooted(path)?;
+        let m = match ensure::check(&p)? {
+            None => Mismatch::Missing(path.clone()),
+            Some(fi) if fi.filetype != FileType::File => {
+                Mismatch::NotFile(path.clone())
+            }
+            Some(_) => {
+                let actual = ensure::hash_file(&p, &HashType::SHA256)?;
+                if &actual == expected {
+                    continue;
+                }
+                Mismatch::Digest {
+                    path: path.clone(),
+                    expected: expected.clone(),
+                    actual,
+                }
+            }
+        };
+        bad.push(m);
+    }
+
+    info!(log, "verified {} files: {} mismatched", entries.len(), bad.len());
+    Ok(bad)
+}
@@ -0,0 +1,120 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * With "--metrics FILE", metrics about each run are written at the end of the
+ * run in the Prometheus text format, for the textfile collector of
+ * node_exporter; e.g., to "/var/lib/node_exporter/confomat.prom".  The file
+ * is replaced atomically, so that the collector never sees part of it.  The
+ * time of the last successful run is carried over from the previous file
+ * when a run fails, so that an alert can fire on hosts which have not been
+ * configured successfully for some time.  Dry runs do not write metrics.
+ */
+
+use std::fmt::Write;
+use std::path::Path;
+
+use slog::{Logger, info, warn};
+
+pub struct RoleMetric<'a> {
+    pub role: &'a str,
+    pub result: &'a str,
+    pub duration_secs: f64,
+}
+
+pub struct RunMetrics<'a> {
+    /**
+     * When the run finished, in seconds since the epoch.
+     */
+    pub finished: f64,
+    pub duration_secs: f64,
+    pub success: bool,
+    /**
+     * The number of top-level steps with each result; e.g., ("changed", 3).
+     */
+    pub steps: Vec<(&'a str, usize)>,
+    pub roles: Vec<RoleMetric<'a>>,
+}
+
+const LAST_SUCCESS: &str = "confomat_last_success_timestamp_seconds";
+
+fn label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/*
+ * The time of the last successful run, from the previous metrics file.
+ */
+fn last_success(path: &Path) -> Option<f64> {
+    std::fs::read_to_string(path).ok()?
+        .lines()
+        .find_map(|l| l.strip_prefix(LAST_SUCCESS)
+            .and_then(|v| v.trim().parse().ok()))
+}
+
+fn render(path: &Path, m: &RunMetrics) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str,
+        values: &[(String, f64)]|
+    {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for (labels, v) in values.iter() {
+            writeln!(out, "{}{} {}", name, labels, v).unwrap();
+        }
+    };
+
+    metric("confomat_last_run_timestamp_seconds", "gauge",
+        "When the last run finished.",
+        &[(String::new(), m.finished)]);
+    metric("confomat_last_run_duration_seconds", "gauge",
+        "How long the last run took.",
+        &[(String::new(), m.duration_secs)]);
+    metric("confomat_last_run_success", "gauge",
+        "Whether the last run succeeded.",
+        &[(String::new(), if m.success { 1.0 } else { 0.0 })]);
+
+    let prev = if m.success { Some(m.finished) } else { last_success(path) };
+    if let Some(t) = prev {
+        metric(LAST_SUCCESS, "gauge", "When the last successful run finished.",
+            &[(String::new(), t)]);
+    }
+
+    metric("confomat_last_run_steps", "gauge",
+        "The number of steps in the last run, by result.",
+        &m.steps.iter()
+            .map(|(r, n)| (format!("{{result=\"{}\"}}", label(r)), *n as f64))
+            .collect::<Vec<_>>());
+    metric("confomat_role_success", "gauge",
+        "Whether each role succeeded in the last run.",
+        &m.roles.iter()
+            .map(|r| (format!("{{role=\"{}\",result=\"{}\"}}", label(r.role),
+                label(r.result)),
+                if r.result == "failed" { 0.0 } else { 1.0 }))
+            .collect::<Vec<_>>());
+    metric("confomat_role_duration_seconds", "gauge",
+        "How long each role took in the last run.",
+        &m.roles.iter()
+            .map(|r| (format!("{{role=\"{}\"}}", label(r.role)),
+                r.duration_secs))
+            .collect::<Vec<_>>());
+
+    out
+}
+
+/**
+ * Write the metrics file.  A failure is reported, but does not fail the run.
+ */
+pub fn write(log: &Logger, path: &Path, m: &RunMetrics) {
+    let body = render(path, m);
+
+    let tmp = path.with_extension("tmp");
+    let res = std::fs::write(&tmp, body)
+        .and_then(|_| std::fs::rename(&tmp, path));
+    match res {
+        Ok(()) => info!(log, "wrote metrics to {}", path.display()),
+        Err(e) => warn!(log, "could not write metrics {}: {}", path.display(),
+            e),
+    }
+}
@@ -0,0 +1,673 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, Ownership};
+use super::smf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    /**
+     * A static address, with prefix length; e.g., "10.1.1.5/24" or
+     * "fd00:1::5/64".
+     */
+    Static(String),
+    Dhcp,
+    /**
+     * An IPv6 link-local address and stateless autoconfiguration.
+     */
+    Addrconf,
+}
+
+impl Address {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Address::Static(_) => "static",
+            Address::Dhcp => "dhcp",
+            Address::Addrconf => "addrconf",
+        }
+    }
+}
+
+/*
+ * Compare two addresses with prefix length (e.g., "fd00::5/64"), accounting
+ * for the several ways in which an IPv6 address may be written.
+ */
+fn addr_eq(a: &str, b: &str) -> bool {
+    fn parse(s: &str) -> Option<(IpAddr, Option<u8>)> {
+        let t: Vec<&str> = s.splitn(2, '/').collect();
+        let addr: IpAddr = t[0].parse().ok()?;
+        let prefix = match t.get(1) {
+            Some(p) => Some(p.parse().ok()?),
+            None => None,
+        };
+        Some((addr, prefix))
+    }
+
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn interface_exists(ifname: &str) -> Result<bool> {
+    Ok(parseable(&["/usr/sbin/ipadm", "show-if", "-p", "-o", "ifname"])?
+        .iter()
+        .any(|t| t[0] == ifname))
+}
+
+fn ensure_interface(log: &Logger, ifname: &str) -> Result<bool> {
+    if interface_exists(ifname)? {
+        return Ok(false);
+    }
+
+    info!(log, "creating IP interface {}", ifname);
+    ensure::run(log, &["/usr/sbin/ipadm", "create-if", ifname])?;
+    Ok(true)
+}
+
+/**
+ * Ensure that an address object exists with the given type and, for static
+ * addresses, value.  The IP interface is created if it does not exist.  An
+ * existing address object of the wrong type or value is deleted and
+ * recreated.
+ */
+pub fn address(log: &Logger, addrobj: &str, addr: &Address) -> Result<bool> {
+    let t: Vec<&str> = addrobj.splitn(2, '/').collect();
+    if t.len() != 2 || t[0].is_empty() || t[1].is_empty() {
+        bail!("invalid address object name \"{}\"", addrobj);
+    }
+    let ifname = t[0];
+
+    let mut did_work = ensure_interface(log, ifname)?;
+
+    let existing = parseable(&["/usr/sbin/ipadm", "show-addr", "-p", "-o",
+        "addrobj,type,addr"])?
+        .into_iter()
+        .find(|t| t.len() == 3 && t[0] == addrobj);
+
+    if let Some(e) = existing {
+        let ok = match addr {
+            Address::Static(want) => e[1] == "static" && addr_eq(&e[2], want),
+            a => e[1] == a.type_name(),
+        };
+
+        if ok {
+            info!(log, "address {} ok ({} {})", addrobj, e[1], e[2]);
+            return Ok(did_work);
+        }
+
+        info!(log, "address {} is {} {}, want {:?}; deleting", addrobj,
+            e[1], e[2], addr);
+        ensure::run(log, &["/usr/sbin/ipadm", "delete-addr", addrobj])?;
+    }
+
+    did_work = true;
+    info!(log, "creating address {} ({:?})", addrobj, addr);
+    let mut args = vec!["/usr/sbin/ipadm", "create-addr", "-T",
+        addr.type_name()];
+    if let Address::Static(a) = addr {
+        args.push("-a");
+        args.push(a);
+    }
+    args.push(addrobj);
+    ensure::run(log, &args)?;
+
+    Ok(did_work)
+}
+
+/**
+ * Ensure that an etherstub (a virtual switch with no physical link) exists.
+ */
+pub fn etherstub(log: &Logger, name: &str) -> Result<bool> {
+    let exists = parseable(&["/usr/sbin/dladm", "show-etherstub", "-p", "-o",
+        "link"])?
+        .iter()
+        .any(|t| t[0] == name);
+
+    if exists {
+        info!(log, "etherstub {} exists already", name);
+        return Ok(false);
+    }
+
+    info!(log, "creating etherstub {}", name);
+    ensure::run(log, &["/usr/sbin/dladm", "create-etherstub", name])?;
+    Ok(true)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vnic {
+    /**
+     * The link (physical, aggregation, or etherstub) over which to create
+     * the VNIC.
+     */
+    pub over: String,
+    /**
+     * A fixed MAC address; if None, a random address is assigned at creation
+     * time and never changed.
+     */
+    pub mac: Option<String>,
+    pub vlan: Option<u16>,
+}
+
+/*
+ * dladm(1M) renders MAC addresses without leading zeroes in each octet, so
+ * compare them numerically.
+ */
+fn mac_eq(a: &str, b: &str) -> bool {
+    fn octets(m: &str) -> Option<Vec<u8>> {
+        m.split(':').map(|o| u8::from_str_radix(o, 16).ok()).collect()
+    }
+
+    match (octets(a), octets(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/**
+ * Ensure that a VNIC exists over the nominated link, with the requested MAC
+ * address and VLAN ID.  A VNIC over the wrong link or with the wrong VLAN is
+ * deleted and recreated; a wrong MAC address is corrected in place.
+ */
+pub fn vnic(log: &Logger, name: &str, vnic: &Vnic) -> Result<bool> {
+    let existing = parseable(&["/usr/sbin/dladm", "show-vnic", "-p", "-o",
+        "link,over,macaddress,vid"])?
+        .into_iter()
+        .find(|t| t.len() == 4 && t[0] == name);
+
+    if let Some(e) = existing {
+        let vid: u16 = e[3].parse().unwrap_or(0);
+
+        if e[1] == vnic.over && vid == vnic.vlan.unwrap_or(0) {
+            match &vnic.mac {
+                Some(mac) if !mac_eq(mac, &e[2]) => {
+                    info!(log, "vnic {} MAC is {}, want {}", name, e[2], mac);
+                    ensure::run(log, &["/usr/sbin/dladm", "modify-vnic",
+                        "-m", mac, name])?;
+                    return Ok(true);
+                }
+                _ => {
+                    info!(log, "vnic {} ok (over {}, vid {})", name, e[1],
+                        vid);
+                    return Ok(false);
+                }
+            }
+        }
+
+        info!(log, "vnic {} is over {} vid {}, want {:?}; deleting", name,
+            e[1], vid, vnic);
+        ensure::run(log, &["/usr/sbin/dladm", "delete-vnic", name])?;
+    }
+
+    info!(log, "creating vnic {} ({:?})", name, vnic);
+    let vid = vnic.vlan.map(|v| v.to_string());
+    let mut args = vec!["/usr/sbin/dladm", "create-vnic", "-l", &vnic.over];
+    if let Some(mac) = &vnic.mac {
+        args.push("-m");
+        args.push(mac);
+    }
+    if let Some(vid) = &vid {
+        args.push("-v");
+        args.push(vid);
+    }
+    args.push(name);
+    ensure::run(log, &args)?;
+
+    Ok(true)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PersistentRoute {
+    args: Vec<String>,
+    inet6: bool,
+    destination: String,
+    gateway: String,
+    interface: Option<String>,
+}
+
+/*
+ * List persistent routes.  The output of "route -p show" includes lines of
+ * the form:
+ *
+ *      persistent: route add -net 10.2.0.0/16 10.0.0.1
+ *
+ * where the arguments are those that were originally passed to route(1M).
+ */
+fn persistent_routes() -> Result<Vec<PersistentRoute>> {
+    let out = std::process::Command::new("/usr/sbin/route")
+        .env_clear()
+        .arg("-p").arg("show")
+        .output()?;
+    if !out.status.success() {
+        bail!("route -p show failed: {}", out.info());
+    }
+    let val = String::from_utf8(out.stdout)?;
+
+    let mut routes = Vec::new();
+    for l in val.lines() {
+        let l = if let Some(l) = l.trim().strip_prefix("persistent: route add") {
+            l
+        } else {
+            continue;
+        };
+
+        let args: Vec<String> = l.split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+
+        /*
+         * Separate the positional arguments from the flags, some of which
+         * (e.g., "-ifp net0") take a value.
+         */
+        let mut pos = Vec::new();
+        let mut interface = None;
+        let mut inet6 = false;
+        let mut iter = args.iter();
+        while let Some(a) = iter.next() {
+            match a.as_str() {
+                "-inet6" => inet6 = true,
+                "-ifp" | "-ifa" | "-netmask" => {
+                    let v = iter.next();
+                    if a == "-ifp" {
+                        interface = v.cloned();
+                    }
+                }
+                a if a.starts_with('-') => (),
+                a => pos.push(a.to_string()),
+            }
+        }
+        if pos.len() < 2 {
+            continue;
+        }
+
+        routes.push(PersistentRoute {
+            destination: pos[0].to_string(),
+            gateway: pos[1].to_string(),
+            inet6,
+            interface,
+            args,
+        });
+    }
+
+    Ok(routes)
+}
+
+/**
+ * Ensure that a persistent route exists to the destination (e.g., "default"
+ * or "10.2.0.0/16") via the nominated gateway.  Any other persistent route to
+ * the same destination in the same address family is removed.
+ *
+ * IPv6 routes are detected from the presence of a colon in either the
+ * destination or gateway.  A link-local IPv6 gateway must nominate the
+ * interface through which it is reached, with the form "fe80::1%net0".
+ */
+pub fn route(log: &Logger, destination: &str, gateway: &str) -> Result<bool> {
+    let mut did_work = false;
+    let mut found = false;
+
+    let inet6 = destination.contains(':') || gateway.contains(':');
+    let (gateway, interface) = match gateway.find('%') {
+        Some(i) if inet6 => (&gateway[..i], Some(&gateway[i + 1..])),
+        Some(_) => bail!("interface scope only valid for IPv6 gateways"),
+        None => (gateway, None),
+    };
+
+    for r in persistent_routes()? {
+        if r.destination != destination || r.inet6 != inet6 {
+            continue;
+        }
+
+        if r.gateway == gateway && r.interface.as_deref() == interface &&
+            !found
+        {
+            info!(log, "route to {} via {} ok", destination, gateway);
+            found = true;
+            continue;
+        }
+
+        info!(log, "removing route to {} via {}", r.destination, r.gateway);
+        let mut args = vec!["/usr/sbin/route", "-p", "delete"];
+        args.extend(r.args.iter().map(|a| a.as_str()));
+        ensure::run(log, &args)?;
+        did_work = true;
+    }
+
+    if !found {
+        info!(log, "adding route to {} via {}", destination, gateway);
+        let mut args = vec!["/usr/sbin/route", "-p", "add"];
+        if inet6 {
+            args.push("-inet6");
+        }
+        args.push(destination);
+        args.push(gateway);
+        if let Some(interface) = interface {
+            args.push("-ifp");
+            args.push(interface);
+        }
+        ensure::run(log, &args)?;
+        did_work = true;
+    }
+
+    Ok(did_work)
+}
+
+const DNS_CLIENT: &str = "svc:/network/dns/client:default";
+const NAME_SERVICE_SWITCH: &str = "svc:/system/name-service/switch:default";
+
+/**
+ * DNS resolver configuration.  This is stored in the properties of the DNS
+ * client and name service switch SMF services, from which nscfg(1M) generates
+ * resolv.conf(4) and nsswitch.conf(4).
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsClient {
+    pub nameservers: Vec<String>,
+    pub search: Vec<String>,
+    pub domain: Option<String>,
+    /**
+     * Sources for name service switch databases; e.g., "host" and "ipnodes"
+     * mapped to "files dns".  Databases which are not listed are left as
+     * they are.
+     */
+    pub switch: BTreeMap<String, String>,
+}
+
+/**
+ * Ensure the DNS client and name service switch properties, refreshing each
+ * service that was changed.
+ */
+pub fn dns_client(log: &Logger, dns: &DnsClient) -> Result<bool> {
+    if dns.nameservers.is_empty() {
+        bail!("at least one nameserver is required");
+    }
+
+    let mut dns_changed = smf::ensure_property(log, DNS_CLIENT,
+        "config/nameserver", "net_address", &dns.nameservers)?;
+    if !dns.search.is_empty() {
+        dns_changed |= smf::ensure_property(log, DNS_CLIENT, "config/search",
+            "astring", &dns.search)?;
+    }
+    if let Some(domain) = &dns.domain {
+        dns_changed |= smf::ensure_property(log, DNS_CLIENT, "config/domain",
+            "astring", &[domain])?;
+    }
+
+    let mut switch_changed = false;
+    for (db, sources) in dns.switch.iter() {
+        if db.is_empty() || db.contains(|c: char| c == '/' ||
+            c.is_whitespace())
+        {
+            bail!("invalid name service switch database \"{}\"", db);
+        }
+        if sources.trim().is_empty() {
+            bail!("no sources for name service switch database {}", db);
+        }
+        switch_changed |= smf::ensure_property(log, NAME_SERVICE_SWITCH,
+            &format!("config/{}", db), "astring", &[sources.trim()])?;
+    }
+
+    if dns_changed {
+        smf::refresh(log, DNS_CLIENT)?;
+    }
+    if switch_changed {
+        smf::refresh(log, NAME_SERVICE_SWITCH)?;
+    }
+
+    Ok(dns_changed || switch_changed)
+}
+
+/**
+ * Ensure that a datalink property (e.g., "mtu", "allowed-ips", or
+ * "protection") has the given value.  The property is set persistently, and
+ * only when the current value differs.
+ */
+pub fn link_prop(log: &Logger, link: &str, prop: &str, value: &str)
+    -> Result<bool>
+{
+    let out = std::process::Command::new("/usr/sbin/dladm")
+        .env_clear()
+        .arg("show-linkprop")
+        .arg("-c").arg("-o").arg("value")
+        .arg("-p").arg(prop)
+        .arg(link)
+        .output()?;
+    if !out.status.success() {
+        bail!("dladm show-linkprop {} {} failed: {}", link, prop,
+            out.info());
+    }
+    let have = String::from_utf8(out.stdout)?.trim().replace("\\:", ":");
+
+    if have == value {
+        info!(log, "link {} {} ok ({})", link, prop, value);
+        return Ok(false);
+    }
+
+    info!(log, "link {} {} is \"{}\", want \"{}\"", link, prop, have, value);
+    ensure::run(log, &["/usr/sbin/dladm", "set-linkprop", "-p",
+        &format!("{}={}", prop, value), link])?;
+    Ok(true)
+}
+
+const IDENTITY_NODE: &str = "svc:/system/identity:node";
+
+/*
+ * Rewrite the loopback entries in hosts(4) so that they include the nodename,
+ * removing any previous nodename.
+ */
+fn hosts_loopback(hosts: &str, old: &str, name: &str) -> String {
+    let mut out = String::new();
+
+    for l in hosts.lines() {
+        let t: Vec<&str> = l.split_whitespace().collect();
+        if t.is_empty() || t[0].starts_with('#') ||
+            (t[0] != "127.0.0.1" && t[0] != "::1")
+        {
+            out.push_str(l);
+            out.push('\n');
+            continue;
+        }
+
+        let mut names: Vec<&str> = t[1..].iter()
+            .take_while(|n| !n.starts_with('#'))
+            .filter(|n| **n != old && **n != name)
+            .copied()
+            .collect();
+        names.insert(0, name);
+        if !names.contains(&"localhost") {
+            names.push("localhost");
+        }
+
+        out.push_str(&format!("{}\t{}\n", t[0], names.join(" ")));
+    }
+
+    out
+}
+
+/**
+ * Set the system nodename consistently in each place it is stored: the
+ * identity:node SMF service, nodename(4), and the loopback entries in
+ * hosts(4).  The running nodename is updated if it differs.
+ */
+pub fn nodename(log: &Logger, name: &str) -> Result<bool> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        bail!("invalid nodename \"{}\"", name);
+    }
+
+    let current = super::osops::os_ops().nodename();
+    let own = Ownership::Names("root", "sys");
+
+    let mut changed = false;
+    if smf::ensure_property(log, IDENTITY_NODE, "config/nodename", "astring",
+        &[name])?
+    {
+        smf::refresh(log, IDENTITY_NODE)?;
+        changed = true;
+    }
+
+    changed |= ensure::contents(log, rooted("/etc/nodename")?,
+        format!("{}\n", name).as_bytes(), &own, 0o644)?;
+
+    let hosts = rooted("/etc/inet/hosts")?;
+    let text = super::read_file(&hosts)?.unwrap_or_default();
+    changed |= ensure::contents(log, &hosts,
+        hosts_loopback(&text, &current, name).as_bytes(), &own, 0o644)?;
+
+    if current != name {
+        info!(log, "running nodename is {}, want {}", current, name);
+        ensure::run(log, &["/usr/bin/hostname", name])?;
+        changed = true;
+    }
+
+    Ok(changed)
+}
+
+/**
+ * The configuration of an IPMP group.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpmpGroup {
+    /**
+     * The underlying interfaces which are members of the group.
+     */
+    pub interfaces: Vec<String>,
+    /**
+     * Data addresses, hosted on the IPMP interface; e.g., "10.1.1.5/24".
+     */
+    pub addresses: Vec<String>,
+    /**
+     * Test addresses for probe-based failure detection, as pairs of
+     * underlying interface and address.  If empty, only link-based failure
+     * detection is used.
+     */
+    pub test_addresses: Vec<(String, String)>,
+    /**
+     * The in.mpathd(1M) failure detection time, in milliseconds.
+     */
+    pub failure_detection_time: Option<u32>,
+    pub failback: Option<bool>,
+}
+
+const MPATHD_DEFAULTS: &str = "/etc/default/mpathd";
+
+/*
+ * Return the class of an IP interface ("ip" or "ipmp") and, for IPMP
+ * interfaces, the underlying interfaces, or None if it does not exist.
+ */
+fn interface_info(ifname: &str) -> Result<Option<(String, Vec<String>)>> {
+    Ok(parseable(&["/usr/sbin/ipadm", "show-if", "-p", "-o",
+        "ifname,class,over"])?
+        .into_iter()
+        .find(|t| t.len() == 3 && t[0] == ifname)
+        .map(|t| (t[1].to_string(), t[2].split_whitespace()
+            .map(|s| s.to_string())
+            .collect())))
+}
+
+/**
+ * Ensure that an IPMP interface exists with the given members, data
+ * addresses, and test addresses, and that the failure detection settings for
+ * in.mpathd(1M) are as requested.  Members not listed are removed from the
+ * group, and addresses not listed (on the IPMP interface, or as the test
+ * address of a member or former member) are deleted.
+ */
+pub fn ipmp(log: &Logger, name: &str, group: &IpmpGroup) -> Result<bool> {
+    if group.interfaces.is_empty() {
+        bail!("IPMP group {} requires at least one interface", name);
+    }
+
+    let mut did_work = false;
+
+    let over = match interface_info(name)? {
+        Some((class, over)) if class == "ipmp" => over,
+        Some((class, _)) => bail!("interface {} exists with class {}, not \
+            ipmp", name, class),
+        None => {
+            info!(log, "creating IPMP interface {}", name);
+            ensure::run(log, &["/usr/sbin/ipadm", "create-ipmp", name])?;
+            did_work = true;
+            Vec::new()
+        }
+    };
+
+    for i in group.interfaces.iter() {
+        if over.contains(i) {
+            continue;
+        }
+
+        ensure_interface(log, i)?;
+        info!(log, "adding {} to IPMP group {}", i, name);
+        ensure::run(log, &["/usr/sbin/ipadm", "add-ipmp", "-i", i, name])?;
+        did_work = true;
+    }
+    for i in over.iter() {
+        if !group.interfaces.contains(i) {
+            info!(log, "removing {} from IPMP group {}", i, name);
+            ensure::run(log, &["/usr/sbin/ipadm", "remove-ipmp", "-i", i,
+                name])?;
+            did_work = true;
+        }
+    }
+
+    /*
+     * Stale addresses are deleted first, so that an address which has moved
+     * from one address object to another can be created again.
+     */
+    let mut want: Vec<String> = (0..group.addresses.len())
+        .map(|n| format!("{}/data{}", name, n))
+        .collect();
+    want.extend(group.test_addresses.iter()
+        .map(|(i, _)| format!("{}/test", i)));
+    for t in parseable(&["/usr/sbin/ipadm", "show-addr", "-p", "-o",
+        "addrobj"])?
+    {
+        let obj = &t[0];
+        let ours = match obj.split_once('/') {
+            Some((i, _)) if i == name => true,
+            Some((i, "test")) => group.interfaces.iter().chain(over.iter())
+                .any(|m| m == i),
+            _ => false,
+        };
+        if ours && !want.contains(obj) {
+            info!(log, "deleting stale address {}", obj);
+            ensure::run(log, &["/usr/sbin/ipadm", "delete-addr", obj])?;
+            did_work = true;
+        }
+    }
+
+    for (n, a) in group.addresses.iter().enumerate() {
+        did_work |= address(log, &format!("{}/data{}", name, n),
+            &Address::Static(a.to_string()))?;
+    }
+    for (i, a) in group.test_addresses.iter() {
+        did_work |= address(log, &format!("{}/test", i),
+            &Address::Static(a.to_string()))?;
+    }
+
+    let mut settings = Vec::new();
+    let fdt = group.failure_detection_time.map(|t| t.to_string());
+    if let Some(fdt) = &fdt {
+        settings.push(("FAILURE_DETECTION_TIME", fdt.as_str()));
+    }
+    if let Some(fb) = group.failback {
+        settings.push(("FAILBACK", if fb { "yes" } else { "no" }));
+    }
+    if !settings.is_empty() && ensure::key_values(log, MPATHD_DEFAULTS,
+        &settings, &Ownership::Names("root", "sys"), 0o644)?
+    {
+        /*
+         * in.mpathd(1M) rereads its configuration file on SIGHUP.
+         */
+        info!(log, "signalling in.mpathd to reread configuration");
+        ensure::run(log, &["/usr/bin/pkill", "-HUP", "-x", "in.mpathd"])?;
+        did_work = true;
+    }
+
+    Ok(did_work)
+}
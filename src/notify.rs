@@ -0,0 +1,295 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Notifications at the end of a run, configured in "<dir>/notify.toml": the
+ * run report (see the report module) can be sent in a POST request to one or
+ * more webhooks, and a summary can be sent by email through an SMTP relay:
+ *
+ *      [[webhook]]
+ *      url = "https://hooks.example.com/confomat"
+ *      failures_only = true
+ *
+ *      [email]
+ *      relay = "mail.example.com:25"
+ *      from = "confomat@example.com"
+ *      to = [ "oncall@example.com" ]
+ *      failures_only = true
+ *
+ * With "failures_only", a notification is only sent for a run which failed.
+ * The relay must accept mail from the host without authentication.  If the
+ * relay offers STARTTLS, it is used, and the certificate of the relay must be
+ * valid for its name; with "require_tls", mail is not sent to a relay which
+ * does not offer it.  Notifications are not sent for dry runs.  A failure to
+ * send a notification is reported, but does not fail the run.
+ */
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use openssl::ssl::{SslConnector, SslMethod};
+use serde::Deserialize;
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::report::{self, Report};
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default)]
+    pub failures_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Email {
+    /**
+     * The relay, as "HOST" or "HOST:PORT"; the default port is 25.
+     */
+    pub relay: String,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub failures_only: bool,
+    /**
+     * Fail, rather than send the message in the clear, if the relay does
+     * not offer STARTTLS.
+     */
+    #[serde(default)]
+    pub require_tls: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Notify {
+    #[serde(default)]
+    webhook: Vec<Webhook>,
+    #[serde(default)]
+    email: Option<Email>,
+}
+
+impl Notify {
+    /**
+     * Read "<dir>/notify.toml".  If it does not exist, there are no
+     * notifications.
+     */
+    pub fn read(dir: &Path) -> Result<Notify> {
+        let path = dir.join("notify.toml");
+
+        let n: Notify = match jmclib::toml::read_file(&path) {
+            Ok(Some(n)) => n,
+            Ok(None) => return Ok(Notify::default()),
+            Err(e) => bail!("reading notifications {}: {}", path.display(),
+                e),
+        };
+
+        if let Some(e) = &n.email {
+            if e.to.is_empty() {
+                bail!("{}: email must have at least one recipient",
+                    path.display());
+            }
+        }
+
+        Ok(n)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhook.is_empty() && self.email.is_none()
+    }
+
+    pub fn send(&self, log: &Logger, report: &Report, body: &str) {
+        let failed = report.result != "complete";
+
+        for w in self.webhook.iter().filter(|w| failed || !w.failures_only) {
+            match report::post(&w.url, body.to_string()) {
+                Ok(()) => info!(log, "notified webhook {}", w.url),
+                Err(e) => warn!(log, "could not notify webhook {}: {}",
+                    w.url, e),
+            }
+        }
+
+        if let Some(e) = self.email.as_ref()
+            .filter(|e| failed || !e.failures_only)
+        {
+            match email(e, report) {
+                Ok(()) => info!(log, "sent run summary to {}",
+                    e.to.join(", ")),
+                Err(err) => warn!(log, "could not send run summary via {}: {}",
+                    e.relay, err),
+            }
+        }
+    }
+}
+
+fn summary(report: &Report) -> (String, String) {
+    let subject = format!("confomat on {}: {}", report.host, report.result);
+
+    let mut text = format!("Host: {}\nResult: {}\nDuration: {:.1}s\n",
+        report.host, report.result, report.duration_ms as f64 / 1000.0);
+    if let Some(e) = &report.error {
+        text.push_str(&format!("Error: {}\n", e));
+    }
+
+    text.push_str("\nRoles:\n");
+    for r in report.roles.iter() {
+        text.push_str(&format!("    {:<30} {}\n", r.role, r.result));
+    }
+
+    let failed: Vec<_> = report.steps.iter()
+        .filter(|s| s.result == "failed")
+        .collect();
+    if !failed.is_empty() {
+        text.push_str("\nFailed steps:\n");
+        for s in failed {
+            text.push_str(&format!("    {} {} {}: {}\n", s.role, s.step,
+                s.resource, s.error.unwrap_or("")));
+        }
+    }
+
+    (subject, text)
+}
+
+/**
+ * A time as in the Date: header of a message (see RFC 5322); e.g.,
+ * "Wed, 14 Oct 2026 12:34:56 +0000".
+ */
+pub(crate) fn date(t: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+        "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    /*
+     * The civil date from the number of days since the epoch, counting in
+     * 400 year eras of 146097 days which begin on the first of March.
+     */
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    format!("{}, {} {} {} {:02}:{:02}:{:02} +0000", DAYS[(days % 7) as usize],
+        d, MONTHS[m as usize - 1], y, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+struct Smtp<S: Read + Write> {
+    s: BufReader<S>,
+}
+
+impl<S: Read + Write> Smtp<S> {
+    /*
+     * Read an SMTP reply, which may span several lines ("250-..." then
+     * "250 ..."), check its code, and return the text of each line.
+     */
+    fn reply(&mut self, want: &str) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut l = String::new();
+            if self.s.read_line(&mut l)? == 0 {
+                bail!("connection closed");
+            }
+            if !l.starts_with(want) {
+                bail!("unexpected reply: {}", l.trim());
+            }
+            lines.push(l.get(4..).unwrap_or("").trim().to_string());
+            if l.as_bytes().get(3) != Some(&b'-') {
+                return Ok(lines);
+            }
+        }
+    }
+
+    fn cmd(&mut self, line: &str, want: &str) -> Result<Vec<String>> {
+        let w = self.s.get_mut();
+        w.write_all(format!("{}\r\n", line).as_bytes())?;
+        w.flush()?;
+        self.reply(want)
+    }
+
+    /*
+     * Send the message, once the session has been established.
+     */
+    fn send(&mut self, e: &Email, host: &str, subject: &str, text: &str)
+        -> Result<()>
+    {
+        self.cmd(&format!("MAIL FROM:<{}>", e.from), "250")?;
+        for to in e.to.iter() {
+            self.cmd(&format!("RCPT TO:<{}>", to), "25")?;
+        }
+        self.cmd("DATA", "354")?;
+
+        let now = SystemTime::now();
+        let id = now.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut msg = format!("Date: {}\r\nMessage-ID: <{}.{:09}.{}@{}>\r\n\
+            From: {}\r\nTo: {}\r\nSubject: {}\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\r\n", date(now),
+            id.as_secs(), id.subsec_nanos(), std::process::id(), host,
+            e.from, e.to.join(", "), subject);
+        for l in text.lines() {
+            /*
+             * A line beginning with "." must have another prepended, so that
+             * it is not taken for the end of the message.
+             */
+            if l.starts_with('.') {
+                msg.push('.');
+            }
+            msg.push_str(l);
+            msg.push_str("\r\n");
+        }
+        msg.push('.');
+        self.cmd(&msg, "250")?;
+        self.cmd("QUIT", "221")?;
+
+        Ok(())
+    }
+}
+
+fn email(e: &Email, report: &Report) -> Result<()> {
+    let (subject, text) = summary(report);
+
+    let (name, addr) = match e.relay.split_once(':') {
+        Some((name, _)) => (name, e.relay.clone()),
+        None => (e.relay.as_str(), format!("{}:25", e.relay)),
+    };
+    let s = TcpStream::connect(&addr)?;
+    s.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    s.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut smtp = Smtp { s: BufReader::new(s) };
+
+    smtp.reply("220")?;
+    let ehlo = format!("EHLO {}", report.host);
+    let ext = smtp.cmd(&ehlo, "250")?;
+    if !ext.iter().any(|x| x.eq_ignore_ascii_case("STARTTLS")) {
+        if e.require_tls {
+            bail!("relay does not offer STARTTLS");
+        }
+        return smtp.send(e, report.host, &subject, &text);
+    }
+
+    /*
+     * The relay sends nothing more until the TLS handshake, so no input is
+     * lost with the buffer.  The session starts again within TLS.
+     */
+    smtp.cmd("STARTTLS", "220")?;
+    let tls = SslConnector::builder(SslMethod::tls())?.build()
+        .connect(name, smtp.s.into_inner())?;
+    let mut smtp = Smtp { s: BufReader::new(tls) };
+    smtp.cmd(&ehlo, "250")?;
+    smtp.send(e, report.host, &subject, &text)
+}
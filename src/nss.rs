@@ -0,0 +1,178 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Users and groups, looked up through the name service switch, on systems
+ * other than illumos; the platform modules re-export these.
+ */
+
+use std::os::raw::c_char;
+use std::ffi::{CString, CStr};
+use anyhow::Result;
+
+use super::common::errno;
+use super::error::Error;
+
+fn clear_errno() {
+    #[cfg(target_os = "linux")]
+    let enp = unsafe { libc::__errno_location() };
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    let enp = unsafe { libc::__error() };
+    #[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+    let enp = unsafe { libc::__errno() };
+
+    unsafe { *enp = 0 };
+}
+
+fn cs(lpsz: *const c_char) -> Result<Option<String>> {
+    if lpsz.is_null() {
+        Ok(None)
+    } else {
+        let cstr = unsafe { CStr::from_ptr(lpsz) };
+        Ok(Some(cstr.to_str()?.to_string()))
+    }
+}
+
+/**
+ * A passwd entry, with the same fields as on illumos.  Other systems have
+ * neither "age" nor "comment", so those are always None.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passwd {
+    pub name: Option<String>,
+    pub passwd: Option<String>,
+    pub uid: u32,
+    pub gid: u32,
+    pub age: Option<String>,
+    pub comment: Option<String>,
+    pub gecos: Option<String>,
+    pub dir: Option<String>,
+    pub shell: Option<String>,
+}
+
+impl Passwd {
+    fn from(p: *const libc::passwd) -> Result<Passwd> {
+        Ok(Passwd {
+            name: cs(unsafe { (*p).pw_name })?,
+            passwd: cs(unsafe { (*p).pw_passwd })?,
+            uid: unsafe { (*p).pw_uid },
+            gid: unsafe { (*p).pw_gid },
+            age: None,
+            comment: None,
+            gecos: cs(unsafe { (*p).pw_gecos })?,
+            dir: cs(unsafe { (*p).pw_dir })?,
+            shell: cs(unsafe { (*p).pw_shell })?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: Option<String>,
+    pub passwd: Option<String>,
+    pub gid: u32,
+    pub members: Option<Vec<String>>,
+}
+
+impl Group {
+    fn from(g: *mut libc::group) -> Result<Group> {
+        let mut mems = unsafe { (*g).gr_mem };
+        let members: Option<Vec<String>> = if !mems.is_null() {
+            let mut members = Vec::new();
+            while !unsafe { *mems }.is_null() {
+                members.push(cs(unsafe { *mems })?.unwrap());
+                mems = unsafe { mems.offset(1) };
+            }
+            Some(members)
+        } else {
+            None
+        };
+
+        Ok(Group {
+            name: cs(unsafe { (*g).gr_name })?,
+            passwd: cs(unsafe { (*g).gr_passwd })?,
+            gid: unsafe { (*g).gr_gid },
+            members,
+        })
+    }
+}
+
+/*
+ * The lookup functions return NULL both when there is no such entry and on
+ * error; only errno tells them apart.
+ */
+pub fn get_passwd_by_id(uid: u32) -> Result<Option<Passwd>> {
+    clear_errno();
+    let p = unsafe { libc::getpwuid(uid) };
+    let e = errno();
+    if p.is_null() {
+        if e == 0 {
+            Ok(None)
+        } else {
+            Err(Error::SystemCall {
+                call: "getpwuid".to_string(),
+                errno: e,
+            }.into())
+        }
+    } else {
+        Ok(Some(Passwd::from(p)?))
+    }
+}
+
+pub fn get_passwd_by_name(name: &str) -> Result<Option<Passwd>> {
+    clear_errno();
+    let name = CString::new(name.to_owned())?;
+    let p = unsafe { libc::getpwnam(name.as_ptr()) };
+    let e = errno();
+    if p.is_null() {
+        if e == 0 {
+            Ok(None)
+        } else {
+            Err(Error::SystemCall {
+                call: "getpwnam".to_string(),
+                errno: e,
+            }.into())
+        }
+    } else {
+        Ok(Some(Passwd::from(p)?))
+    }
+}
+
+pub fn get_group_by_name(name: &str) -> Result<Option<Group>> {
+    clear_errno();
+    let name = CString::new(name.to_owned())?;
+    let g = unsafe { libc::getgrnam(name.as_ptr()) };
+    let e = errno();
+    if g.is_null() {
+        if e == 0 {
+            Ok(None)
+        } else {
+            Err(Error::SystemCall {
+                call: "getgrnam".to_string(),
+                errno: e,
+            }.into())
+        }
+    } else {
+        Ok(Some(Group::from(g)?))
+    }
+}
+
+pub fn get_group_by_id(gid: u32) -> Result<Option<Group>> {
+    clear_errno();
+    let g = unsafe { libc::getgrgid(gid) };
+    let e = errno();
+    if g.is_null() {
+        if e == 0 {
+            Ok(None)
+        } else {
+            Err(Error::SystemCall {
+                call: "getgrgid".to_string(),
+                errno: e,
+            }.into())
+        }
+    } else {
+        Ok(Some(Group::from(g)?))
+    }
+}
+
@@ -0,0 +1,127 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use slog::{Logger, info, warn};
+use anyhow::Result;
+
+use super::common::*;
+use super::ensure::{self, Ownership};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeDaemon {
+    Ntp,
+    Chrony,
+}
+
+impl TimeDaemon {
+    pub fn fmri(&self) -> &'static str {
+        match self {
+            TimeDaemon::Ntp => "svc:/network/ntp:default",
+            TimeDaemon::Chrony => "svc:/network/chrony:default",
+        }
+    }
+
+    fn config_path(&self) -> &'static str {
+        match self {
+            TimeDaemon::Ntp => "/etc/inet/ntp.conf",
+            TimeDaemon::Chrony => "/etc/inet/chrony.conf",
+        }
+    }
+}
+
+fn config(daemon: TimeDaemon, servers: &[&str]) -> String {
+    let mut out = String::new();
+    out.push_str("#\n# This file is managed by confomat.\n#\n");
+
+    match daemon {
+        TimeDaemon::Ntp => {
+            out.push_str("driftfile /var/ntp/ntp.drift\n");
+            out.push_str("restrict default nomodify nopeer noquery limited \
+                kod\n");
+            out.push_str("restrict 127.0.0.1\n");
+            out.push_str("restrict ::1\n");
+            for s in servers {
+                out.push_str(&format!("server {} iburst\n", s));
+            }
+        }
+        TimeDaemon::Chrony => {
+            out.push_str("driftfile /var/lib/chrony/drift\n");
+            out.push_str("makestep 1.0 3\n");
+            for s in servers {
+                out.push_str(&format!("server {} iburst\n", s));
+            }
+        }
+    }
+
+    out
+}
+
+/**
+ * Check whether the clock is currently synchronised to one of the configured
+ * servers.
+ */
+pub fn synced(daemon: TimeDaemon) -> Result<bool> {
+    match daemon {
+        TimeDaemon::Ntp => {
+            /*
+             * The peer selected for synchronisation is marked with an
+             * asterisk in the "ntpq -pn" peer list.
+             */
+            let out = std::process::Command::new("/usr/sbin/ntpq")
+                .env_clear()
+                .arg("-pn")
+                .output()?;
+            if !out.status.success() {
+                return Ok(false);
+            }
+            let val = String::from_utf8(out.stdout)?;
+            Ok(val.lines().any(|l| l.starts_with('*')))
+        }
+        TimeDaemon::Chrony => {
+            let out = std::process::Command::new("/usr/bin/chronyc")
+                .env_clear()
+                .arg("-n").arg("tracking")
+                .output()?;
+            if !out.status.success() {
+                return Ok(false);
+            }
+            let val = String::from_utf8(out.stdout)?;
+            Ok(val.lines().any(|l| {
+                let t: Vec<&str> = l.splitn(2, ':').map(|s| s.trim())
+                    .collect();
+                t.len() == 2 && t[0] == "Leap status" && t[1] == "Normal"
+            }))
+        }
+    }
+}
+
+/**
+ * Write the configuration file for the time daemon.  Returns true if the file
+ * was changed, in which case the service must be restarted.
+ */
+pub fn configure(log: &Logger, daemon: TimeDaemon, servers: &[&str])
+    -> Result<bool>
+{
+    ensure::contents(log, daemon.config_path(),
+        config(daemon, servers).as_bytes(),
+        &Ownership::Names("root", "sys"), 0o644)
+}
+
+/**
+ * Wait a short time for the clock to synchronise.  Failure to synchronise is
+ * not fatal, as it may take some minutes after the daemon starts, but we
+ * report it.
+ */
+pub fn verify(log: &Logger, daemon: TimeDaemon) -> Result<bool> {
+    for _ in 0..30 {
+        if synced(daemon)? {
+            info!(log, "clock synchronised ({:?})", daemon);
+            return Ok(true);
+        }
+        sleep(2);
+    }
+
+    warn!(log, "clock not yet synchronised ({:?})", daemon);
+    Ok(false)
+}
@@ -0,0 +1,43 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The OpenBSD counterpart of the illumos module.  Users and groups are looked
+ * up through getpwnam(3) and the like, and are created and altered with
+ * user(8) and group(8).  There are no zones, so the host is treated as though
+ * it were the global zone.
+ */
+
+use anyhow::Result;
+
+pub use super::unix::{get_group_ids_for_user, get_id_from_file, nodename,
+    openpty, set_controlling_tty, Pty};
+pub use super::nss::{get_group_by_id, get_group_by_name, get_passwd_by_id,
+    get_passwd_by_name, Group, Passwd};
+
+pub fn zoneid() -> i32 {
+    0
+}
+
+pub fn zonename() -> String {
+    "global".to_string()
+}
+
+/**
+ * OpenBSD has no privilege sets like those of illumos; pledge(2) and
+ * unveil(2) are for a program to restrict itself, not for us to impose on
+ * the commands we run.  A process which changes from root to another user
+ * has no special privileges, so apply() does nothing.
+ */
+pub struct PrivSet;
+
+impl PrivSet {
+    pub fn basic() -> Result<PrivSet> {
+        Ok(PrivSet)
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The operations on the running system which the ensure primitives use to
+ * look things up (users, groups, the nodename, the zone, and uname(2)) are
+ * made through the OsOps trait, rather than by calling the platform module
+ * directly, so that the logic of those primitives can be exercised in tests
+ * against MockOs without root or a particular system.  Like dry-run mode, the
+ * implementation in use is a property of the whole process; RealOs is used
+ * unless a test sets another.
+ */
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+pub use super::sys::{Group, Passwd};
+pub use super::facts::Uname;
+use super::sys;
+use super::facts;
+
+pub trait OsOps: Send + Sync {
+    fn nodename(&self) -> String;
+    fn zoneid(&self) -> i32;
+    fn zonename(&self) -> String;
+    fn passwd_by_name(&self, name: &str) -> Result<Option<Passwd>>;
+    fn passwd_by_id(&self, uid: u32) -> Result<Option<Passwd>>;
+    fn group_by_name(&self, name: &str) -> Result<Option<Group>>;
+    fn group_by_id(&self, gid: u32) -> Result<Option<Group>>;
+    /**
+     * The IDs of the supplementary groups of which this user is a member.
+     */
+    fn group_ids_for_user(&self, name: &str) -> Result<Vec<u32>>;
+    fn uname(&self) -> Result<Uname>;
+}
+
+/**
+ * The running system, through the platform module.
+ */
+pub struct RealOs;
+
+impl OsOps for RealOs {
+    fn nodename(&self) -> String {
+        sys::nodename()
+    }
+
+    fn zoneid(&self) -> i32 {
+        sys::zoneid()
+    }
+
+    fn zonename(&self) -> String {
+        sys::zonename()
+    }
+
+    fn passwd_by_name(&self, name: &str) -> Result<Option<Passwd>> {
+        sys::get_passwd_by_name(name)
+    }
+
+    fn passwd_by_id(&self, uid: u32) -> Result<Option<Passwd>> {
+        sys::get_passwd_by_id(uid)
+    }
+
+    fn group_by_name(&self, name: &str) -> Result<Option<Group>> {
+        sys::get_group_by_name(name)
+    }
+
+    fn group_by_id(&self, gid: u32) -> Result<Option<Group>> {
+        sys::get_group_by_id(gid)
+    }
+
+    fn group_ids_for_user(&self, name: &str) -> Result<Vec<u32>> {
+        sys::get_group_ids_for_user(name)
+    }
+
+    fn uname(&self) -> Result<Uname> {
+        facts::uname()
+    }
+}
+
+/**
+ * An imaginary system, with the users and groups it is given; e.g.,
+ *
+ *      let mut os = MockOs::new("test0");
+ *      os.add_group("staff", 10, &["build"]);
+ *      os.add_user("build", 2000, 10, "/home/build");
+ *      set_os_ops(Arc::new(os));
+ */
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MockOs {
+    pub nodename: String,
+    pub zoneid: i32,
+    pub zonename: String,
+    pub uname: Uname,
+    pub users: Vec<Passwd>,
+    pub groups: Vec<Group>,
+}
+
+#[cfg(test)]
+impl MockOs {
+    /**
+     * A global zone with this nodename, and no users or groups.
+     */
+    pub fn new(nodename: &str) -> MockOs {
+        MockOs {
+            nodename: nodename.to_string(),
+            zoneid: 0,
+            zonename: "global".to_string(),
+            uname: Uname {
+                sysname: "SunOS".to_string(),
+                release: "5.11".to_string(),
+                version: "mock".to_string(),
+                machine: "i86pc".to_string(),
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn add_user(&mut self, name: &str, uid: u32, gid: u32, home: &str) {
+        self.users.push(Passwd {
+            name: Some(name.to_string()),
+            passwd: Some("x".to_string()),
+            uid,
+            gid,
+            age: None,
+            comment: None,
+            gecos: None,
+            dir: Some(home.to_string()),
+            shell: Some("/bin/sh".to_string()),
+        });
+    }
+
+    pub fn add_group(&mut self, name: &str, gid: u32, members: &[&str]) {
+        self.groups.push(Group {
+            name: Some(name.to_string()),
+            passwd: None,
+            gid,
+            members: Some(members.iter().map(|m| m.to_string()).collect()),
+        });
+    }
+}
+
+#[cfg(test)]
+impl OsOps for MockOs {
+    fn nodename(&self) -> String {
+        self.nodename.clone()
+    }
+
+    fn zoneid(&self) -> i32 {
+        self.zoneid
+    }
+
+    fn zonename(&self) -> String {
+        self.zonename.clone()
+    }
+
+    fn passwd_by_name(&self, name: &str) -> Result<Option<Passwd>> {
+        Ok(self.users.iter().find(|u| u.name.as_deref() == Some(name))
+            .cloned())
+    }
+
+    fn passwd_by_id(&self, uid: u32) -> Result<Option<Passwd>> {
+        Ok(self.users.iter().find(|u| u.uid == uid).cloned())
+    }
+
+    fn group_by_name(&self, name: &str) -> Result<Option<Group>> {
+        Ok(self.groups.iter().find(|g| g.name.as_deref() == Some(name))
+            .cloned())
+    }
+
+    fn group_by_id(&self, gid: u32) -> Result<Option<Group>> {
+        Ok(self.groups.iter().find(|g| g.gid == gid).cloned())
+    }
+
+    fn group_ids_for_user(&self, name: &str) -> Result<Vec<u32>> {
+        Ok(self.groups.iter()
+            .filter(|g| g.members.iter().flatten().any(|m| m == name))
+            .map(|g| g.gid)
+            .collect())
+    }
+
+    fn uname(&self) -> Result<Uname> {
+        Ok(self.uname.clone())
+    }
+}
+
+static OPS: Mutex<Option<Arc<dyn OsOps>>> = Mutex::new(None);
+
+/**
+ * Use this implementation of OsOps, rather than the running system, from now
+ * on, in this test process.
+ */
+#[cfg(test)]
+pub(crate) fn set_os_ops(ops: Arc<dyn OsOps>) {
+    *OPS.lock().unwrap() = Some(ops);
+}
+
+pub fn os_ops() -> Arc<dyn OsOps> {
+    match &*OPS.lock().unwrap() {
+        Some(ops) => Arc::clone(ops),
+        None => Arc::new(RealOs),
+    }
+}
@@ -0,0 +1,264 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Packages on Debian and RedHat family Linux systems, with apt and dnf, and on
+ * OpenBSD, with pkg_add(1); and the removal of packages with the package
+ * manager of any system.  The
+ * package manager is chosen from the detected OS, so that one list of
+ * packages can be applied everywhere (see Context::ensure_os_packages()).
+ *
+ * On apt and dnf systems, a package may be given as "NAME=VERSION", which
+ * pins it: exactly that version is installed, and it is then held there, with
+ * "apt-mark hold" or "dnf versionlock", so that a later upgrade leaves it be.
+ * The versionlock plugin for dnf must be installed to pin packages.
+ */
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::Unsupported;
+use super::ensure::{self, Exec};
+use super::OS;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Manager {
+    Ips,
+    Pkgsrc,
+    FreeBSD,
+    OpenBSD,
+    Apt,
+    Dnf,
+}
+
+impl Manager {
+    pub fn for_os(os: &OS) -> Result<Manager> {
+        Ok(match os {
+            OS::OmniOS | OS::OpenIndiana => Manager::Ips,
+            OS::SmartOS => Manager::Pkgsrc,
+            OS::FreeBSD => Manager::FreeBSD,
+            OS::OpenBSD => Manager::OpenBSD,
+            OS::Debian | OS::Ubuntu => Manager::Apt,
+            OS::Fedora | OS::RedHat => Manager::Dnf,
+            /*
+             * Homebrew and its kin are meant to be run by a user, not root.
+             */
+            OS::MacOS => {
+                return Err(Unsupported::new("system packages",
+                    &format!("{:?}", os)).into());
+            }
+        })
+    }
+}
+
+struct Spec<'a> {
+    name: &'a str,
+    version: Option<&'a str>,
+}
+
+fn parse(s: &str) -> Spec<'_> {
+    match s.find('=') {
+        Some(i) => Spec { name: &s[..i], version: Some(&s[i + 1..]) },
+        None => Spec { name: s, version: None },
+    }
+}
+
+/*
+ * apt must not stop to ask questions; e.g., about configuration files.
+ */
+fn apt_opts(opts: &Exec) -> Exec {
+    let mut opts = opts.clone();
+    opts.env.push(("DEBIAN_FRONTEND".to_string(),
+        "noninteractive".to_string()));
+    opts
+}
+
+/**
+ * The installed version of a package, if it is installed.
+ */
+fn installed(log: &Logger, m: Manager, name: &str, opts: &Exec)
+    -> Result<Option<String>>
+{
+    let stem = format!("{}-*", name);
+    let (args, ok): (Vec<&str>, &str) = match m {
+        Manager::Apt => (vec!["/usr/bin/dpkg-query", "-W", "-f",
+            "${Status}\t${Version}", name], "install ok installed\t"),
+        Manager::Dnf => (vec!["/usr/bin/rpm", "-q", "--qf",
+            "%{VERSION}-%{RELEASE}", name], ""),
+        Manager::Ips => (vec!["/usr/bin/pkg", "info", "-q", name], ""),
+        Manager::Pkgsrc => (vec!["/opt/local/sbin/pkg_admin", "-q", "check",
+            name], ""),
+        Manager::FreeBSD => (vec!["/usr/sbin/pkg", "info", "-e", name], ""),
+        Manager::OpenBSD => (vec!["/usr/sbin/pkg_info", "-q", "-e", &stem],
+            ""),
+    };
+
+    /*
+     * Each of these commands fails if the package is not installed.
+     */
+    match ensure::query_output(log, &args, opts) {
+        Ok(out) => Ok(out.trim().strip_prefix(ok).map(|v| v.to_string())),
+        Err(_) => Ok(None),
+    }
+}
+
+/**
+ * The number of packages installed.
+ */
+pub fn count(log: &Logger, m: Manager) -> Result<usize> {
+    let args: &[&str] = match m {
+        Manager::Apt => &["/usr/bin/dpkg-query", "-W", "-f",
+            "${db:Status-Abbrev}\n"],
+        Manager::Dnf => &["/usr/bin/rpm", "-qa"],
+        Manager::Ips => &["/usr/bin/pkg", "list", "-H"],
+        Manager::Pkgsrc => &["/opt/local/sbin/pkg_info", "-q"],
+        Manager::FreeBSD => &["/usr/sbin/pkg", "query", "%n"],
+        Manager::OpenBSD => &["/usr/sbin/pkg_info", "-q"],
+    };
+
+    let out = ensure::query_output(log, args, &Exec::default())?;
+    Ok(out.lines()
+        .filter(|l| m != Manager::Apt || l.starts_with("ii"))
+        .count())
+}
+
+/*
+ * Versions from dnf include the release, which a pin may leave out.
+ */
+fn version_matches(m: Manager, have: &str, want: &str) -> bool {
+    have == want ||
+        (m == Manager::Dnf && have.starts_with(&format!("{}-", want)))
+}
+
+fn held(log: &Logger, m: Manager, name: &str, opts: &Exec) -> Result<bool> {
+    match m {
+        Manager::Apt => {
+            let out = ensure::query_output(log,
+                &["/usr/bin/apt-mark", "showhold"], opts)?;
+            Ok(out.lines().any(|l| l.trim() == name))
+        }
+        Manager::Dnf => {
+            let out = ensure::query_output(log,
+                &["/usr/bin/dnf", "-q", "versionlock", "list"], opts)?;
+            Ok(out.lines().any(|l| l.starts_with(&format!("{}-", name))))
+        }
+        _ => bail!("cannot pin packages with {:?}", m),
+    }
+}
+
+/**
+ * Ensure that these apt, dnf, or OpenBSD packages are installed, and any
+ * pinned ones are at their version and held.  OpenBSD packages cannot be
+ * pinned.  Returns true if anything was done.
+ */
+pub fn ensure(log: &Logger, m: Manager, names: &[&str], opts: &Exec)
+    -> Result<bool>
+{
+    let opts = &apt_opts(opts);
+    let mut install = Vec::new();
+    let mut hold = Vec::new();
+
+    for s in names.iter().map(|n| parse(n)) {
+        let have = installed(log, m, s.name, opts)?;
+        match (&have, s.version) {
+            (None, None) => {
+                info!(log, "package {} must be installed", s.name);
+                install.push(s.name.to_string());
+            }
+            (Some(_), None) => {
+                info!(log, "package {} already installed", s.name);
+            }
+            (have, Some(v)) => {
+                if have.as_deref().map(|h| version_matches(m, h, v))
+                    .unwrap_or(false)
+                {
+                    info!(log, "package {} already installed at {}", s.name,
+                        v);
+                } else {
+                    info!(log, "package {} must be installed at {} (not {})",
+                        s.name, v, have.as_deref().unwrap_or("installed"));
+                    install.push(match m {
+                        Manager::Apt => format!("{}={}", s.name, v),
+                        _ => format!("{}-{}", s.name, v),
+                    });
+                }
+                if have.is_none() || !held(log, m, s.name, opts)? {
+                    hold.push(s);
+                }
+            }
+        }
+    }
+
+    if !install.is_empty() {
+        info!(log, "installing packages: {:?}", install);
+        let mut args: Vec<&str> = match m {
+            Manager::Apt => vec!["/usr/bin/apt-get", "install", "-y", "-q",
+                "--allow-downgrades"],
+            Manager::Dnf => vec!["/usr/bin/dnf", "install", "-y", "-q"],
+            Manager::OpenBSD => vec!["/usr/sbin/pkg_add", "-I"],
+            _ => bail!("packages cannot be installed with {:?} here", m),
+        };
+        args.extend(install.iter().map(|s| s.as_str()));
+        ensure::run_with(log, &args, opts)?;
+    }
+
+    for s in hold.iter() {
+        info!(log, "holding package {} at {}", s.name, s.version.unwrap());
+        match m {
+            Manager::Apt => ensure::run_with(log,
+                &["/usr/bin/apt-mark", "hold", s.name], opts)?,
+            Manager::Dnf => ensure::run_with(log,
+                &["/usr/bin/dnf", "-q", "versionlock", "add",
+                &format!("{}-{}", s.name, s.version.unwrap())], opts)?,
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(!install.is_empty() || !hold.is_empty())
+}
+
+/**
+ * Ensure that these packages are not installed.  Returns true if any were
+ * removed.
+ */
+pub fn remove(log: &Logger, m: Manager, names: &[&str], opts: &Exec)
+    -> Result<bool>
+{
+    let opts = &apt_opts(opts);
+    let mut remove = Vec::new();
+    for name in names.iter() {
+        if installed(log, m, name, opts)?.is_some() {
+            info!(log, "package {} must be removed", name);
+            remove.push(*name);
+        }
+    }
+    if remove.is_empty() {
+        return Ok(false);
+    }
+
+    let mut args: Vec<&str> = match m {
+        Manager::Ips => vec!["/usr/bin/pkg", "uninstall"],
+        Manager::Pkgsrc => vec!["/opt/local/bin/pkgin", "-y", "remove"],
+        Manager::FreeBSD => vec!["/usr/sbin/pkg", "delete", "-y"],
+        Manager::OpenBSD => vec!["/usr/sbin/pkg_delete", "-I"],
+        Manager::Apt => vec!["/usr/bin/apt-get", "remove", "-y", "-q"],
+        Manager::Dnf => vec!["/usr/bin/dnf", "remove", "-y", "-q"],
+    };
+    args.extend(remove.iter());
+    ensure::run_with(log, &args, opts)?;
+    Ok(true)
+}
+
+/**
+ * Refresh the list of available apt or dnf packages.
+ */
+pub fn update(log: &Logger, m: Manager, opts: &Exec) -> Result<()> {
+    match m {
+        Manager::Apt => ensure::run_with(log,
+            &["/usr/bin/apt-get", "update", "-q"], &apt_opts(opts)),
+        Manager::Dnf => ensure::run_with(log,
+            &["/usr/bin/dnf", "makecache", "-q"], opts),
+        _ => bail!("packages cannot be updated with {:?} here", m),
+    }
+}
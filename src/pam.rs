@@ -0,0 +1,249 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * PAM configuration, managed an entry at a time rather than by templating
+ * whole files.  Each service has a stack of modules for each module type
+ * (auth, account, session, and password).  The entries for a service are in
+ * "/etc/pam.d/SERVICE" if that file exists, as on Linux:
+ *
+ *      auth    required    pam_faillock.so preauth
+ *
+ * or otherwise, on illumos, in "/etc/pam.conf", where each line begins with
+ * the service name:
+ *
+ *      sshd    auth    required    pam_unix_auth.so.1
+ *
+ * Lines other than the entry being ensured are left exactly as they are.
+ */
+
+use std::path::PathBuf;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::rooted;
+use super::ensure::{self, Ownership};
+use super::OS;
+
+const PAM_CONF: &str = "/etc/pam.conf";
+const PAM_DIR: &str = "/etc/pam.d";
+
+/**
+ * Where an entry belongs within the stack for its service and module type.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PamPosition {
+    First,
+    Last,
+    /**
+     * Directly before, or directly after, the entry for this module; e.g.,
+     * "pam_unix.so".
+     */
+    Before(String),
+    After(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PamEntry {
+    /**
+     * e.g., "sshd", "login", or "other".
+     */
+    pub service: String,
+    /**
+     * "auth", "account", "session", or "password".
+     */
+    pub module_type: String,
+    /**
+     * e.g., "required", "sufficient", or on Linux, "[default=die]".
+     */
+    pub control: String,
+    /**
+     * e.g., "pam_unix_auth.so.1", or a full path.
+     */
+    pub module: String,
+    pub args: Vec<String>,
+    pub position: PamPosition,
+}
+
+/*
+ * The fields of an entry line, without the service name: type, control,
+ * module, and arguments.  A Linux control value in brackets may contain
+ * spaces.  A leading "-" on the type (Linux) is ignored for matching.
+ */
+fn fields(line: &str) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    let mut bracket = false;
+
+    for w in line.split_whitespace() {
+        if bracket {
+            let last = out.last_mut().unwrap();
+            last.push(' ');
+            last.push_str(w);
+            bracket = !w.ends_with(']');
+        } else {
+            bracket = out.len() == 1 && w.starts_with('[') && !w.ends_with(']');
+            out.push(w.to_string());
+        }
+    }
+
+    out
+}
+
+fn basename(m: &str) -> &str {
+    m.rsplit('/').next().unwrap_or(m)
+}
+
+struct Stack {
+    path: PathBuf,
+    /**
+     * Whether lines begin with the service name (pam.conf).
+     */
+    conf: bool,
+}
+
+impl Stack {
+    fn for_service(os: &OS, service: &str) -> Result<Stack> {
+        let dfile = rooted(PAM_DIR)?.join(service);
+        if dfile.exists() {
+            Ok(Stack { path: dfile, conf: false })
+        } else if os.is_illumos() {
+            Ok(Stack { path: rooted(PAM_CONF)?, conf: true })
+        } else {
+            bail!("PAM service \"{}\" has no file in {}", service, PAM_DIR);
+        }
+    }
+
+    /*
+     * The fields of a line, without the service, if it is an entry for this
+     * service.
+     */
+    fn entry(&self, line: &str, service: &str) -> Option<Vec<String>> {
+        let l = line.trim();
+        if l.is_empty() || l.starts_with('#') {
+            return None;
+        }
+        if self.conf {
+            let (svc, rest) = l.split_once(char::is_whitespace)?;
+            if svc != service {
+                return None;
+            }
+            Some(fields(rest))
+        } else {
+            Some(fields(l))
+        }
+    }
+}
+
+fn same_type(f: &[String], t: &str) -> bool {
+    f.first().map(|ft| ft.trim_start_matches('-') == t).unwrap_or(false)
+}
+
+/**
+ * Ensure that an entry is present, with this control and these arguments, at
+ * its position in the stack.
+ */
+pub fn ensure(log: &Logger, os: &OS, e: &PamEntry) -> Result<bool> {
+    if e.service.is_empty() || e.service.contains('/') ||
+        e.module.is_empty()
+    {
+        bail!("invalid PAM entry {:?}", e);
+    }
+    if !["auth", "account", "session", "password"]
+        .contains(&e.module_type.as_str())
+    {
+        bail!("invalid PAM module type \"{}\"", e.module_type);
+    }
+
+    let stack = Stack::for_service(os, &e.service)?;
+    let orig = match std::fs::read_to_string(&stack.path) {
+        Ok(s) => s,
+        Err(err) => bail!("reading {}: {}", stack.path.display(), err),
+    };
+    let mut lines: Vec<String> = orig.lines().map(|l| l.to_string())
+        .collect();
+
+    let mut want = vec![e.module_type.clone(), e.control.clone(),
+        e.module.clone()];
+    want.extend(e.args.iter().cloned());
+
+    /*
+     * Take out the existing entry for this module, if any, keeping its text
+     * if it is already as it should be.
+     */
+    let is_ours = |f: &[String]| {
+        same_type(f, &e.module_type) &&
+            f.get(2).map(|m| basename(m) == basename(&e.module))
+                .unwrap_or(false)
+    };
+    let existing = lines.iter().position(|l| {
+        stack.entry(l, &e.service).map(|f| is_ours(&f)).unwrap_or(false)
+    });
+    let text = match existing {
+        Some(i) => {
+            let l = lines.remove(i);
+            let f = stack.entry(&l, &e.service).unwrap();
+            if f[1..] == want[1..] {
+                Some(l)
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+    let text = text.unwrap_or_else(|| {
+        let body = want.join("\t");
+        if stack.conf {
+            format!("{}\t{}", e.service, body)
+        } else {
+            body
+        }
+    });
+
+    /*
+     * Find the place for it among the remaining entries of this type.
+     */
+    let typed: Vec<usize> = lines.iter().enumerate()
+        .filter(|(_, l)| stack.entry(l, &e.service)
+            .map(|f| same_type(&f, &e.module_type))
+            .unwrap_or(false))
+        .map(|(i, _)| i)
+        .collect();
+    let anchor = |m: &str| -> Result<usize> {
+        match typed.iter().find(|&&i| {
+            stack.entry(&lines[i], &e.service)
+                .and_then(|f| f.get(2).map(|fm| basename(fm) == basename(m)))
+                .unwrap_or(false)
+        }) {
+            Some(&i) => Ok(i),
+            None => bail!("PAM {} {} stack has no entry for {}", e.service,
+                e.module_type, m),
+        }
+    };
+    let at = match (&e.position, typed.first(), typed.last()) {
+        (PamPosition::Before(m), _, _) => anchor(m)?,
+        (PamPosition::After(m), _, _) => anchor(m)? + 1,
+        (PamPosition::First, Some(&first), _) => first,
+        (PamPosition::Last, _, Some(&last)) => last + 1,
+        /*
+         * The first entry of this type goes after the last of this service,
+         * or at the end.
+         */
+        _ => lines.iter()
+            .rposition(|l| stack.entry(l, &e.service).is_some())
+            .map(|i| i + 1)
+            .unwrap_or(lines.len()),
+    };
+    lines.insert(at, text);
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+    if out == orig {
+        info!(log, "PAM {} {} {} ok", e.service, e.module_type, e.module);
+        return Ok(false);
+    }
+
+    ensure::contents(log, &stack.path, out.as_bytes(),
+        &Ownership::Names("root", os.root_group()), 0o644)
+}
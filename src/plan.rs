@@ -0,0 +1,419 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Modify,
+    Remove,
+    Exec,
+}
+
+impl Action {
+    fn verb(&self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Modify => "modify",
+            Action::Remove => "remove",
+            Action::Exec => "exec",
+        }
+    }
+
+    fn symbol(&self) -> char {
+        match self {
+            Action::Create => '+',
+            Action::Modify => '~',
+            Action::Remove => '-',
+            Action::Exec => '!',
+        }
+    }
+}
+
+/**
+ * A change made to the system, or which would have been made had we not been
+ * in dry-run mode.  The details are free-form lines which describe the change;
+ * e.g., "mode 755 -> 700", or the lines of a unified diff.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub action: Action,
+    pub what: String,
+    pub details: Vec<String>,
+    /**
+     * The state of the resource before and after the change, for the audit
+     * log; e.g., the SHA-256 hash of the contents of a file, or the value of
+     * a property.
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+impl Change {
+    pub fn new<S: Into<String>>(action: Action, what: S) -> Change {
+        Change {
+            action,
+            what: what.into(),
+            details: Vec::new(),
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn before<S: Into<String>>(mut self, before: S) -> Change {
+        self.before = Some(before.into());
+        self
+    }
+
+    pub fn after<S: Into<String>>(mut self, after: S) -> Change {
+        self.after = Some(after.into());
+        self
+    }
+
+    pub fn detail<S: Into<String>>(mut self, detail: S) -> Change {
+        self.details.push(detail.into());
+        self
+    }
+
+    pub fn details(mut self, details: Vec<String>) -> Change {
+        self.details.extend(details);
+        self
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} {}", self.action.verb(), self.what)
+    }
+}
+
+static CHANGES: Mutex<Vec<Change>> = Mutex::new(Vec::new());
+
+/*
+ * The changes collected for a role or a step in progress.  Roles collect the
+ * changes made by their steps, but are not themselves steps: a step run
+ * directly by a role is not nested.
+ */
+struct Frame {
+    step: bool,
+    changes: Vec<Change>,
+}
+
+thread_local! {
+    /*
+     * The roles and steps in progress on this thread, innermost last.
+     */
+    static FRAMES: RefCell<Vec<Frame>> = const {
+        RefCell::new(Vec::new())
+    };
+}
+
+pub fn record(change: Change) {
+    FRAMES.with(|f| {
+        if let Some(frame) = f.borrow_mut().last_mut() {
+            frame.changes.push(change.clone());
+        }
+    });
+    CHANGES.lock().unwrap().push(change);
+}
+
+/**
+ * Are we within a step on this thread?
+ */
+pub fn in_step() -> bool {
+    FRAMES.with(|f| f.borrow().iter().any(|frame| frame.step))
+}
+
+fn begin(step: bool) {
+    FRAMES.with(|f| f.borrow_mut().push(Frame {
+        step,
+        changes: Vec::new(),
+    }));
+}
+
+fn end() -> Vec<Change> {
+    FRAMES.with(|f| {
+        let mut f = f.borrow_mut();
+        let changes = f.pop().map(|frame| frame.changes).unwrap_or_default();
+        if let Some(outer) = f.last_mut() {
+            outer.changes.extend(changes.iter().cloned());
+        }
+        changes
+    })
+}
+
+/**
+ * Begin collecting the changes made by the steps of a role.
+ */
+pub fn begin_role() {
+    begin(false);
+}
+
+/**
+ * Finish the innermost role, returning the changes its steps made.
+ */
+pub fn end_role() -> Vec<Change> {
+    end()
+}
+
+/**
+ * Begin collecting the changes made by a step.  Steps may be nested, in which
+ * case the changes made by the inner step are also attributed to the outer
+ * step.
+ */
+pub fn begin_step() {
+    begin(true);
+}
+
+/**
+ * Finish the innermost step, returning the changes it made.
+ */
+pub fn end_step() -> Vec<Change> {
+    end()
+}
+
+pub fn changes() -> Vec<Change> {
+    CHANGES.lock().unwrap().clone()
+}
+
+/**
+ * Count the recorded changes of each kind, in the order: create, modify,
+ * remove, exec.
+ */
+pub fn counts(changes: &[Change]) -> (usize, usize, usize, usize) {
+    let n = |a: Action| changes.iter().filter(|c| c.action == a).count();
+    (n(Action::Create), n(Action::Modify), n(Action::Remove), n(Action::Exec))
+}
+
+/**
+ * Render the plan: each pending change, followed by its details, and then a
+ * summary count.
+ */
+pub fn render(changes: &[Change]) -> String {
+    let mut out = String::new();
+
+    for c in changes.iter() {
+        out.push_str(&format!("{} {}\n", c.action.symbol(), c.summary()));
+        for d in c.details.iter() {
+            out.push_str(&format!("    {}\n", d));
+        }
+    }
+
+    let (create, modify, remove, exec) = counts(changes);
+    out.push_str(&format!("\nPlan: {} to create, {} to modify, {} to remove, \
+        {} commands to run.\n", create, modify, remove, exec));
+
+    out
+}
+
+/*
+ * The number of unchanged lines to include around each hunk of a diff.
+ */
+const DIFF_CONTEXT: usize = 3;
+
+/*
+ * Above this many cells in the table of common subsequence lengths, we do not
+ * attempt a line-by-line comparison and instead show the whole of the old and
+ * new text.
+ */
+const DIFF_MAX_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Same(usize, usize),
+    Del(usize),
+    Ins(usize),
+}
+
+fn diff_ops(a: &[&str], b: &[&str]) -> Vec<Op> {
+    /*
+     * Lines common to the start and end of both files are trimmed before we
+     * compute the longest common subsequence of the remainder.
+     */
+    let mut pre = 0;
+    while pre < a.len() && pre < b.len() && a[pre] == b[pre] {
+        pre += 1;
+    }
+    let mut suf = 0;
+    while suf < a.len() - pre && suf < b.len() - pre &&
+        a[a.len() - 1 - suf] == b[b.len() - 1 - suf]
+    {
+        suf += 1;
+    }
+
+    let ma = &a[pre..a.len() - suf];
+    let mb = &b[pre..b.len() - suf];
+    let mut ops: Vec<Op> = (0..pre).map(|i| Op::Same(i, i)).collect();
+
+    if (ma.len() + 1) * (mb.len() + 1) > DIFF_MAX_CELLS {
+        ops.extend((0..ma.len()).map(|i| Op::Del(pre + i)));
+        ops.extend((0..mb.len()).map(|j| Op::Ins(pre + j)));
+    } else {
+        let w = mb.len() + 1;
+        let mut lcs = vec![0usize; (ma.len() + 1) * w];
+        for i in (0..ma.len()).rev() {
+            for j in (0..mb.len()).rev() {
+                lcs[i * w + j] = if ma[i] == mb[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < ma.len() || j < mb.len() {
+            if i < ma.len() && j < mb.len() && ma[i] == mb[j] {
+                ops.push(Op::Same(pre + i, pre + j));
+                i += 1;
+                j += 1;
+            } else if i < ma.len() &&
+                (j == mb.len() || lcs[(i + 1) * w + j] >= lcs[i * w + j + 1])
+            {
+                ops.push(Op::Del(pre + i));
+                i += 1;
+            } else {
+                ops.push(Op::Ins(pre + j));
+                j += 1;
+            }
+        }
+    }
+
+    ops.extend((0..suf).map(|k| Op::Same(a.len() - suf + k,
+        b.len() - suf + k)));
+    ops
+}
+
+/**
+ * Produce the lines of a unified diff between the old and new contents of a
+ * file.  Returns an empty list if the contents are the same.
+ */
+pub fn diff(old: &str, new: &str) -> Vec<String> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let ops = diff_ops(&a, &b);
+
+    let mut out = Vec::new();
+    let changed: Vec<usize> = ops.iter().enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Same(_, _)))
+        .map(|(i, _)| i)
+        .collect();
+    if changed.is_empty() {
+        return out;
+    }
+
+    out.push("--- current".to_string());
+    out.push("+++ planned".to_string());
+
+    /*
+     * Group the changed operations into hunks, merging any that are close
+     * enough that their context would overlap.
+     */
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(DIFF_CONTEXT);
+        let mut end = changed[k];
+        while k + 1 < changed.len() &&
+            changed[k + 1] <= end + 2 * DIFF_CONTEXT + 1
+        {
+            k += 1;
+            end = changed[k];
+        }
+        let end = (end + DIFF_CONTEXT + 1).min(ops.len());
+        k += 1;
+
+        /*
+         * Determine the starting line in each file, and the number of lines
+         * the hunk covers in each.
+         */
+        let (mut astart, mut bstart) = (None, None);
+        let (mut alen, mut blen) = (0, 0);
+        let mut lines = Vec::new();
+        for op in ops[start..end].iter() {
+            match *op {
+                Op::Same(i, j) => {
+                    astart.get_or_insert(i);
+                    bstart.get_or_insert(j);
+                    alen += 1;
+                    blen += 1;
+                    lines.push(format!(" {}", a[i]));
+                }
+                Op::Del(i) => {
+                    astart.get_or_insert(i);
+                    alen += 1;
+                    lines.push(format!("-{}", a[i]));
+                }
+                Op::Ins(j) => {
+                    bstart.get_or_insert(j);
+                    blen += 1;
+                    lines.push(format!("+{}", b[j]));
+                }
+            }
+        }
+
+        /*
+         * A hunk which adds lines to an empty file, or removes every line,
+         * starts at line zero in the empty side.
+         */
+        let astart = astart.map(|i| i + 1).unwrap_or(0);
+        let bstart = bstart.map(|j| j + 1).unwrap_or(0);
+        out.push(format!("@@ -{},{} +{},{} @@", astart, alen, bstart, blen));
+        out.extend(lines);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(n: usize, change: &[usize]) -> String {
+        (1..=n).map(|i| if change.contains(&i) {
+            format!("changed {}\n", i)
+        } else {
+            format!("line {}\n", i)
+        }).collect()
+    }
+
+    #[test]
+    fn diff_same() {
+        assert!(diff("a\nb\n", "a\nb\n").is_empty());
+        assert!(diff("", "").is_empty());
+    }
+
+    #[test]
+    fn diff_hunks() {
+        assert_eq!(diff(&numbered(10, &[]), &numbered(10, &[5])), vec![
+            "--- current", "+++ planned", "@@ -2,7 +2,7 @@",
+            " line 2", " line 3", " line 4", "-line 5", "+changed 5",
+            " line 6", " line 7", " line 8",
+        ]);
+
+        /*
+         * Changes far enough apart that their context does not overlap are
+         * shown in separate hunks.
+         */
+        let d = diff(&numbered(20, &[]), &numbered(20, &[2, 18]));
+        let hunks: Vec<&String> = d.iter()
+            .filter(|l| l.starts_with("@@"))
+            .collect();
+        assert_eq!(hunks, vec!["@@ -1,5 +1,5 @@", "@@ -15,6 +15,6 @@"]);
+    }
+
+    #[test]
+    fn diff_empty_side() {
+        assert_eq!(diff("", "a\nb\n"), vec![
+            "--- current", "+++ planned", "@@ -0,0 +1,2 @@", "+a", "+b",
+        ]);
+        assert_eq!(diff("a\n", ""), vec![
+            "--- current", "+++ planned", "@@ -1,1 +0,0 @@", "-a",
+        ]);
+    }
+}
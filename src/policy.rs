@@ -0,0 +1,271 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Account policy settings in /etc/default: each policy edits only the keys it
+ * knows about, in place, and leaves the rest of the vendor file (including
+ * its comments) alone.  Settings which are None are not changed.
+ */
+
+use slog::Logger;
+use anyhow::{Result, bail};
+
+use super::common::rooted;
+use super::ensure::{self, Ownership};
+
+pub const PASSWD_DEFAULTS: &str = "/etc/default/passwd";
+pub const LOGIN_DEFAULTS: &str = "/etc/default/login";
+pub const SU_DEFAULTS: &str = "/etc/default/su";
+
+fn yes_no(b: bool) -> String {
+    if b { "YES" } else { "NO" }.to_string()
+}
+
+/*
+ * Apply settings to a file in /etc/default (beneath the alternate root, if
+ * there is one).
+ */
+fn apply(log: &Logger, path: &str, settings: &[(&str, String)])
+    -> Result<bool>
+{
+    if settings.is_empty() {
+        return Ok(false);
+    }
+
+    let settings: Vec<(&str, &str)> = settings.iter()
+        .map(|(k, v)| (*k, v.as_str()))
+        .collect();
+    ensure::key_values(log, rooted(path)?, &settings,
+        &Ownership::Names("root", "sys"), 0o644)
+}
+
+/**
+ * The password policy in /etc/default/passwd; see passwd(1).
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PasswdPolicy {
+    /**
+     * The minimum length of a password.
+     */
+    pub minlen: Option<u32>,
+    /**
+     * The number of previous passwords which may not be reused (at most
+     * 26).
+     */
+    pub history: Option<u32>,
+    /**
+     * Password ageing, in weeks: the longest a password may be used, the
+     * shortest time between changes, and how long before expiry to warn.
+     */
+    pub maxweeks: Option<u32>,
+    pub minweeks: Option<u32>,
+    pub warnweeks: Option<u32>,
+    /**
+     * Complexity: the minimum number of characters which must differ from
+     * the old password, and the minimum numbers of each kind of character.
+     */
+    pub mindiff: Option<u32>,
+    pub minalpha: Option<u32>,
+    pub minnonalpha: Option<u32>,
+    pub minupper: Option<u32>,
+    pub minlower: Option<u32>,
+    pub mindigit: Option<u32>,
+    pub minspecial: Option<u32>,
+    /**
+     * The most times a character may be repeated consecutively.
+     */
+    pub maxrepeats: Option<u32>,
+    /**
+     * Whether a password may not contain the login name.
+     */
+    pub namecheck: Option<bool>,
+    /**
+     * Whether a password may contain white space.
+     */
+    pub whitespace: Option<bool>,
+}
+
+impl PasswdPolicy {
+    fn settings(&self) -> Result<Vec<(&'static str, String)>> {
+        if self.history.map(|h| h > 26).unwrap_or(false) {
+            bail!("password HISTORY may be at most 26");
+        }
+        if let (Some(min), Some(max)) = (self.minweeks, self.maxweeks) {
+            if min > max {
+                bail!("password MINWEEKS ({}) exceeds MAXWEEKS ({})", min,
+                    max);
+            }
+        }
+
+        let mut s = Vec::new();
+        for (k, v) in [
+            ("MINLEN", self.minlen),
+            ("HISTORY", self.history),
+            ("MAXWEEKS", self.maxweeks),
+            ("MINWEEKS", self.minweeks),
+            ("WARNWEEKS", self.warnweeks),
+            ("MINDIFF", self.mindiff),
+            ("MINALPHA", self.minalpha),
+            ("MINNONALPHA", self.minnonalpha),
+            ("MINUPPER", self.minupper),
+            ("MINLOWER", self.minlower),
+            ("MINDIGIT", self.mindigit),
+            ("MINSPECIAL", self.minspecial),
+            ("MAXREPEATS", self.maxrepeats),
+        ].iter() {
+            if let Some(v) = v {
+                s.push((*k, v.to_string()));
+            }
+        }
+        if let Some(b) = self.namecheck {
+            s.push(("NAMECHECK", yes_no(b)));
+        }
+        if let Some(b) = self.whitespace {
+            s.push(("WHITESPACE", yes_no(b)));
+        }
+        Ok(s)
+    }
+}
+
+pub fn passwd(log: &Logger, p: &PasswdPolicy) -> Result<bool> {
+    apply(log, PASSWD_DEFAULTS, &p.settings()?)
+}
+
+fn strings(s: &mut Vec<(&'static str, String)>,
+    values: &[(&'static str, &Option<String>)])
+{
+    for (k, v) in values.iter() {
+        if let Some(v) = v {
+            s.push((*k, v.to_string()));
+        }
+    }
+}
+
+/**
+ * The login policy in /etc/default/login; see login(1).
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LoginPolicy {
+    /**
+     * The only device on which root may log in; e.g., "/dev/console".
+     */
+    pub console: Option<String>,
+    /**
+     * Whether every account must have a password.
+     */
+    pub passreq: Option<bool>,
+    /**
+     * The initial PATH for users, and for root.
+     */
+    pub path: Option<String>,
+    pub supath: Option<String>,
+    /**
+     * The number of seconds to wait for a login before giving up.
+     */
+    pub timeout: Option<u32>,
+    pub umask: Option<u32>,
+    /**
+     * Whether root logins, and repeated failed logins, are logged.
+     */
+    pub syslog: Option<bool>,
+    /**
+     * The number of failed attempts after which a failed login is logged.
+     */
+    pub syslog_failed_logins: Option<u32>,
+    /**
+     * The number of failed attempts allowed before the connection is
+     * closed, the seconds to wait after each (at most 5), and the seconds
+     * to wait after the last.
+     */
+    pub retries: Option<u32>,
+    pub sleeptime: Option<u32>,
+    pub disabletime: Option<u32>,
+}
+
+impl LoginPolicy {
+    fn settings(&self) -> Result<Vec<(&'static str, String)>> {
+        if self.sleeptime.map(|t| t > 5).unwrap_or(false) {
+            bail!("login SLEEPTIME may be at most 5");
+        }
+        if self.umask.map(|u| u > 0o777).unwrap_or(false) {
+            bail!("login UMASK must be at most 777 (octal)");
+        }
+
+        let mut s = Vec::new();
+        strings(&mut s, &[
+            ("CONSOLE", &self.console),
+            ("PATH", &self.path),
+            ("SUPATH", &self.supath),
+        ]);
+        if let Some(b) = self.passreq {
+            s.push(("PASSREQ", yes_no(b)));
+        }
+        if let Some(b) = self.syslog {
+            s.push(("SYSLOG", yes_no(b)));
+        }
+        if let Some(u) = self.umask {
+            s.push(("UMASK", format!("{:03o}", u)));
+        }
+        for (k, v) in [
+            ("TIMEOUT", self.timeout),
+            ("SYSLOG_FAILED_LOGINS", self.syslog_failed_logins),
+            ("RETRIES", self.retries),
+            ("SLEEPTIME", self.sleeptime),
+            ("DISABLETIME", self.disabletime),
+        ].iter() {
+            if let Some(v) = v {
+                s.push((*k, v.to_string()));
+            }
+        }
+        Ok(s)
+    }
+}
+
+pub fn login(log: &Logger, p: &LoginPolicy) -> Result<bool> {
+    apply(log, LOGIN_DEFAULTS, &p.settings()?)
+}
+
+/**
+ * The su(1M) policy in /etc/default/su.
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SuPolicy {
+    /**
+     * The file in which every use of su is logged; e.g., "/var/adm/sulog".
+     */
+    pub sulog: Option<String>,
+    /**
+     * A device on which to report each use of su to root.
+     */
+    pub console: Option<String>,
+    /**
+     * The PATH after su to a user, and to root.
+     */
+    pub path: Option<String>,
+    pub supath: Option<String>,
+    /**
+     * Whether every use of su is logged with syslog.
+     */
+    pub syslog: Option<bool>,
+}
+
+impl SuPolicy {
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut s = Vec::new();
+        strings(&mut s, &[
+            ("SULOG", &self.sulog),
+            ("CONSOLE", &self.console),
+            ("PATH", &self.path),
+            ("SUPATH", &self.supath),
+        ]);
+        if let Some(b) = self.syslog {
+            s.push(("SYSLOG", yes_no(b)));
+        }
+        s
+    }
+}
+
+pub fn su(log: &Logger, p: &SuPolicy) -> Result<bool> {
+    apply(log, SU_DEFAULTS, &p.settings())
+}
@@ -0,0 +1,83 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * When a run fails at a step, that step is recorded in
+ * "/var/confomat/progress.json", so that a later run with "--resume" can skip
+ * the steps before it, which have already been applied, and start again from
+ * the step that failed.  A run which completes removes the record.
+ */
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::STATE_DIR;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /**
+     * The role selectors for the run that failed, which a resumed run must
+     * also use.
+     */
+    pub roles: Vec<String>,
+    /**
+     * The role (and instance, as "role:instance") of the step that failed.
+     */
+    pub role: String,
+    pub step: String,
+    pub resource: String,
+}
+
+pub fn path() -> PathBuf {
+    Path::new(STATE_DIR).join("progress.json")
+}
+
+/**
+ * Read the record of the last failed run, if there is one.
+ */
+pub fn load() -> Result<Option<Progress>> {
+    let p = path();
+
+    match std::fs::read_to_string(&p) {
+        Ok(s) => match serde_json::from_str(&s) {
+            Ok(pr) => Ok(Some(pr)),
+            Err(e) => bail!("invalid progress record {}: {}", p.display(), e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => bail!("reading progress record {}: {}", p.display(), e),
+    }
+}
+
+/**
+ * Record the step at which this run failed.  A failure to save the record is
+ * reported, but is not an error.
+ */
+pub fn save(log: &Logger, pr: &Progress) {
+    let p = path();
+    let tmp = p.with_extension("json.tmp");
+    let res = serde_json::to_string(pr)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| Ok(std::fs::write(&tmp, s)?))
+        .and_then(|_| Ok(std::fs::rename(&tmp, &p)?));
+    match res {
+        Ok(()) => info!(log, "recorded failed step for --resume in {}",
+            p.display()),
+        Err(e) => warn!(log, "could not save progress {}: {}", p.display(), e),
+    }
+}
+
+/**
+ * Remove the record of a failed run, if there is one.
+ */
+pub fn clear(log: &Logger) {
+    let p = path();
+    match std::fs::remove_file(&p) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => warn!(log, "could not remove {}: {}", p.display(), e),
+    }
+}
@@ -0,0 +1,413 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Projects on illumos, as kept in the project database, /etc/project (see
+ * project(4)), and the resource controls (see resource_controls(5)) which are
+ * attributes of a project; e.g.,
+ *
+ *      Project {
+ *          name: "db".into(),
+ *          users: vec!["postgres".into()],
+ *          rctls: vec![Rctl::new("project.max-shm-memory", 8 << 30),
+ *              Rctl::new("process.max-file-descriptor", 65536)],
+ *          ..Default::default()
+ *      }
+ *
+ * Each user is in the project "user.NAME", if there is one, unless another
+ * default project is given to it; see Project::user().  A change to a
+ * resource control is written to the database with projmod(1M), which new
+ * tasks in the project will see, and is applied with prctl(1) to the
+ * processes already in the project where that can be done.
+ */
+
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::{alt_root, dry_run, rooted};
+use super::ensure::{self, Exec};
+
+const PROJADD: &str = "/usr/sbin/projadd";
+const PROJMOD: &str = "/usr/sbin/projmod";
+const PRCTL: &str = "/usr/bin/prctl";
+const PGREP: &str = "/usr/bin/pgrep";
+const PROJECT: &str = "/etc/project";
+
+/**
+ * One value of a resource control.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rctl {
+    /**
+     * The name of the resource control; e.g., "project.max-shm-memory".
+     */
+    pub name: String,
+    pub value: u64,
+    /**
+     * "basic" or "privileged"; the default is "privileged".
+     */
+    pub privilege: Option<String>,
+    /**
+     * "deny", "none", or a signal; e.g., "signal=TERM".  The default is
+     * "deny".
+     */
+    pub action: Option<String>,
+}
+
+impl Rctl {
+    pub fn new(name: &str, value: u64) -> Rctl {
+        Rctl {
+            name: name.to_string(),
+            value,
+            privilege: None,
+            action: None,
+        }
+    }
+
+    fn privilege(&self) -> &str {
+        self.privilege.as_deref().unwrap_or("privileged")
+    }
+
+    fn action(&self) -> &str {
+        self.action.as_deref().unwrap_or("deny")
+    }
+
+    /*
+     * The value as it appears in /etc/project; e.g., "(privileged,1024,deny)".
+     */
+    fn attr_value(&self) -> String {
+        format!("({},{},{})", self.privilege(), self.value, self.action())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Project {
+    pub name: String,
+    /**
+     * If the project already exists with a different ID, that is an error
+     * rather than something to be changed, as running tasks would be left in
+     * the old project.
+     */
+    pub id: Option<u32>,
+    pub comment: Option<String>,
+    /**
+     * Users and groups which may join the project.  Those which are not
+     * listed are left as they are.
+     */
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    /**
+     * Resource controls with one value each.  Resource controls which are
+     * not listed are left as they are.
+     */
+    pub rctls: Vec<Rctl>,
+}
+
+impl Project {
+    /**
+     * The default project for a user, which holds the resource controls for
+     * the processes of that user.
+     */
+    pub fn user(user: &str) -> Project {
+        Project {
+            name: format!("user.{}", user),
+            ..Default::default()
+        }
+    }
+}
+
+/*
+ * An entry in /etc/project, of the form:
+ *
+ *      NAME:ID:COMMENT:USERS:GROUPS:ATTRIBUTES
+ */
+struct Entry {
+    id: u32,
+    comment: String,
+    users: Vec<String>,
+    groups: Vec<String>,
+    attrs: Vec<(String, String)>,
+}
+
+fn list(val: &str) -> Vec<String> {
+    val.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn entry(name: &str) -> Result<Option<Entry>> {
+    let path = rooted(PROJECT)?;
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!("reading {}: {}", path.display(), e),
+    };
+
+    for l in data.lines() {
+        let t: Vec<&str> = l.splitn(6, ':').collect();
+        if t.len() != 6 || t[0] != name {
+            continue;
+        }
+
+        let id = match t[1].parse() {
+            Ok(id) => id,
+            Err(_) => bail!("{}: invalid ID for project {}: {:?}",
+                path.display(), name, t[1]),
+        };
+        let attrs = t[5].split(';')
+            .filter(|a| !a.trim().is_empty())
+            .map(|a| match a.split_once('=') {
+                Some((k, v)) => (k.trim().to_string(), v.trim().to_string()),
+                None => (a.trim().to_string(), String::new()),
+            })
+            .collect();
+
+        return Ok(Some(Entry {
+            id,
+            comment: t[2].to_string(),
+            users: list(t[3]),
+            groups: list(t[4]),
+            attrs,
+        }));
+    }
+
+    Ok(None)
+}
+
+/*
+ * A value in /etc/project may have a unit; e.g., "8GB" or "10K".
+ */
+fn parse_value(val: &str) -> Option<u64> {
+    let val = val.trim();
+    let val = val.strip_suffix(|c| c == 'B' || c == 'b').unwrap_or(val);
+    let shift = match val.chars().last()? {
+        'K' | 'k' => 10,
+        'M' | 'm' => 20,
+        'G' | 'g' => 30,
+        'T' | 't' => 40,
+        'P' | 'p' => 50,
+        'E' | 'e' => 60,
+        _ => return val.parse().ok(),
+    };
+    val[..val.len() - 1].parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn same_privilege(a: &str, b: &str) -> bool {
+    let norm = |p: &str| if p == "priv" { "privileged".to_string() } else {
+        p.to_lowercase()
+    };
+    norm(a) == norm(b)
+}
+
+/*
+ * Does an attribute value (e.g., "(privileged,1024,deny)") hold just this
+ * value of the resource control?
+ */
+fn rctl_matches(have: &str, want: &Rctl) -> bool {
+    let vals: Vec<&str> = have.split("),")
+        .map(|v| v.trim().trim_start_matches('(').trim_end_matches(')'))
+        .collect();
+    if vals.len() != 1 {
+        return false;
+    }
+
+    let t: Vec<&str> = vals[0].splitn(3, ',').map(|s| s.trim()).collect();
+    t.len() == 3
+        && same_privilege(t[0], want.privilege())
+        && parse_value(t[1]) == Some(want.value)
+        && t[2].eq_ignore_ascii_case(want.action())
+}
+
+fn check(p: &Project) -> Result<()> {
+    if !p.name.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !p.name.chars().all(|c| c.is_ascii_alphanumeric()
+        || c == '_' || c == '-' || c == '.')
+    {
+        bail!("invalid project name \"{}\"", p.name);
+    }
+    if let Some(c) = &p.comment {
+        if c.contains([':', '\n']) {
+            bail!("project {}: comment must not contain ':'", p.name);
+        }
+    }
+    for r in p.rctls.iter() {
+        if !r.name.contains('.') || !r.name.chars().all(|c|
+            c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+        {
+            bail!("project {}: invalid resource control \"{}\"", p.name,
+                r.name);
+        }
+        if !matches!(r.privilege(), "basic" | "privileged") {
+            bail!("project {}: {} privilege must be \"basic\" or \
+                \"privileged\"", p.name, r.name);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * The arguments for projadd(1M) or projmod(1M), with the project file beneath
+ * the alternate root, if there is one.
+ */
+fn proj_cmd(cmd: &str) -> Result<Vec<String>> {
+    let mut args = vec![cmd.to_string()];
+    if alt_root().is_some() {
+        args.push("-f".to_string());
+        args.push(rooted(PROJECT)?.display().to_string());
+    }
+    Ok(args)
+}
+
+fn rctl_arg(r: &Rctl) -> String {
+    format!("{}={}", r.name, r.attr_value())
+}
+
+/*
+ * Apply a resource control to the processes already in the project.  A
+ * process-scoped control is set on each process, and a project-scoped one on
+ * the project; task-scoped controls are left for new tasks.
+ */
+fn apply_live(log: &Logger, project: &str, r: &Rctl) -> Result<()> {
+    let es = ensure::query_status(log, &[PGREP, "-J", project])?;
+    if !es.success() {
+        info!(log, "no processes in project {}", project);
+        return Ok(());
+    }
+
+    let value = r.value.to_string();
+    let base = [PRCTL, "-n", r.name.as_str(), "-t", r.privilege(), "-r",
+        "-v", value.as_str()];
+    if r.name.starts_with("process.") {
+        let out = ensure::query_output(log, &[PGREP, "-J", project],
+            &Exec::default())?;
+        for pid in out.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let mut args = base.to_vec();
+            args.extend_from_slice(&["-i", "process", pid]);
+            if let Err(e) = ensure::run(log, &args) {
+                /*
+                 * The process may well have exited in the meantime.
+                 */
+                warn!(log, "could not set {} on process {}: {}", r.name, pid,
+                    e);
+            }
+        }
+        Ok(())
+    } else if r.name.starts_with("project.") {
+        let mut args = base.to_vec();
+        args.extend_from_slice(&["-i", "project", project]);
+        ensure::run(log, &args)
+    } else {
+        info!(log, "{} will apply to new tasks in project {}", r.name,
+            project);
+        Ok(())
+    }
+}
+
+/**
+ * Ensure that the project exists, with its users, groups, and resource
+ * controls.
+ */
+pub fn ensure(log: &Logger, p: &Project) -> Result<bool> {
+    check(p)?;
+
+    let e = if let Some(e) = entry(&p.name)? {
+        e
+    } else {
+        info!(log, "adding project {}", p.name);
+        let mut args = proj_cmd(PROJADD)?;
+        if let Some(id) = p.id {
+            args.push("-p".to_string());
+            args.push(id.to_string());
+        }
+        if let Some(c) = &p.comment {
+            args.push("-c".to_string());
+            args.push(c.to_string());
+        }
+        if !p.users.is_empty() {
+            args.push("-U".to_string());
+            args.push(p.users.join(","));
+        }
+        if !p.groups.is_empty() {
+            args.push("-G".to_string());
+            args.push(p.groups.join(","));
+        }
+        for r in p.rctls.iter() {
+            args.push("-K".to_string());
+            args.push(rctl_arg(r));
+        }
+        args.push(p.name.to_string());
+        ensure::run(log, &args)?;
+        return Ok(true);
+    };
+
+    if let Some(id) = p.id {
+        if id != e.id {
+            bail!("project {} has ID {}, not {}", p.name, e.id, id);
+        }
+    }
+
+    let mut did_work = false;
+
+    if let Some(c) = &p.comment {
+        if *c != e.comment {
+            info!(log, "project {} comment is {:?}, want {:?}", p.name,
+                e.comment, c);
+            let mut args = proj_cmd(PROJMOD)?;
+            args.extend_from_slice(&["-c".to_string(), c.to_string(),
+                p.name.to_string()]);
+            ensure::run(log, &args)?;
+            did_work = true;
+        }
+    }
+
+    for (flag, what, want, have) in [("-U", "users", &p.users, &e.users),
+        ("-G", "groups", &p.groups, &e.groups)].iter()
+    {
+        let missing: Vec<&str> = want.iter()
+            .filter(|w| !have.contains(w))
+            .map(|w| w.as_str())
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        info!(log, "adding {} to project {}: {:?}", what, p.name, missing);
+        let mut args = proj_cmd(PROJMOD)?;
+        args.extend_from_slice(&["-a".to_string(), flag.to_string(),
+            missing.join(","), p.name.to_string()]);
+        ensure::run(log, &args)?;
+        did_work = true;
+    }
+
+    for r in p.rctls.iter() {
+        let have = e.attrs.iter()
+            .find(|(k, _)| *k == r.name)
+            .map(|(_, v)| v.as_str());
+        match have {
+            Some(have) if rctl_matches(have, r) => continue,
+            Some(have) => info!(log, "project {} {} is {}, want {}", p.name,
+                r.name, have, r.attr_value()),
+            None => info!(log, "project {} {} not set, want {}", p.name,
+                r.name, r.attr_value()),
+        }
+
+        let mut args = proj_cmd(PROJMOD)?;
+        args.extend_from_slice(&["-s".to_string(), "-K".to_string(),
+            rctl_arg(r), p.name.to_string()]);
+        ensure::run(log, &args)?;
+        did_work = true;
+
+        if alt_root().is_none() && !dry_run() {
+            if let Err(e) = apply_live(log, &p.name, r) {
+                warn!(log, "could not apply {} live to project {}: {}",
+                    r.name, p.name, e);
+            }
+        }
+    }
+
+    if !did_work {
+        info!(log, "project {} ok", p.name);
+    }
+    Ok(did_work)
+}
@@ -0,0 +1,209 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * In pull mode, confomat fetches its data directory (roles, files, and
+ * configuration) from a remote source before applying it.  The source is
+ * either a git repository or an HTTPS tarball:
+ *
+ *      --pull https://example.com/confomat.git     (or "git+https://...")
+ *      --pull https://example.com/confomat.tar.gz
+ *
+ * The fetched tree may be pinned with "--pin": for a git repository, to a
+ * particular commit, and for a tarball, to the SHA-256 hash of the archive.
+ * With "--verify-commit", the signature on the checked out git commit is also
+ * checked with git-verify-commit(1).
+ */
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, HashType};
+
+pub const DEFAULT_DIR: &str = "/var/confomat/data";
+
+const GIT: &str = "git";
+const TAR: &str = "/usr/bin/gtar";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Git(String),
+    Tarball(String),
+}
+
+impl Source {
+    pub fn parse(source: &str) -> Result<Source> {
+        if let Some(url) = source.strip_prefix("git+") {
+            return Ok(Source::Git(url.to_string()));
+        }
+        if source.ends_with(".git") {
+            return Ok(Source::Git(source.to_string()));
+        }
+        if !source.starts_with("https://") {
+            bail!("pull source \"{}\" must be a git repository or an \
+                HTTPS URL", source);
+        }
+        if source.ends_with(".tar.gz") || source.ends_with(".tgz") {
+            return Ok(Source::Tarball(source.to_string()));
+        }
+        bail!("pull source \"{}\" must end in .git, .tar.gz, or .tgz",
+            source);
+    }
+}
+
+/*
+ * Run a command and return its standard output, trimmed.
+ */
+fn output(args: &[&str]) -> Result<String> {
+    let out = Command::new(args[0])
+        .args(&args[1..])
+        .output()?;
+
+    if !out.status.success() {
+        bail!("exec {:?}: failed: {}", args, out.info());
+    }
+
+    Ok(String::from_utf8(out.stdout)?.trim().to_string())
+}
+
+fn with_suffix(dir: &Path, suffix: &str) -> PathBuf {
+    let mut s = dir.as_os_str().to_os_string();
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+fn git(log: &Logger, url: &str, pin: Option<&str>, verify: bool, dir: &Path)
+    -> Result<()>
+{
+    let d = dir.to_str().unwrap();
+
+    if dir.join(".git").is_dir() {
+        info!(log, "fetching {} into {}", url, d);
+        ensure::query(log, &[GIT, "-C", d, "remote", "set-url", "origin",
+            url])?;
+        ensure::query(log, &[GIT, "-C", d, "fetch", "--quiet", "--prune",
+            "origin"])?;
+    } else {
+        if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+            bail!("{} exists, but is not a git repository", d);
+        }
+        info!(log, "cloning {} into {}", url, d);
+        ensure::query(log, &[GIT, "clone", "--quiet", "--no-checkout", url,
+            d])?;
+    }
+
+    /*
+     * Without a pin, we track the default branch of the remote.
+     */
+    let target = pin.unwrap_or("origin/HEAD");
+    ensure::query(log, &[GIT, "-C", d, "checkout", "--quiet", "--force",
+        "--detach", target])?;
+    ensure::query(log, &[GIT, "-C", d, "clean", "--quiet", "-fd"])?;
+
+    let head = output(&[GIT, "-C", d, "rev-parse", "HEAD"])?;
+    if let Some(pin) = pin {
+        if pin.len() < 7 || !head.starts_with(&pin.to_lowercase()) {
+            bail!("checked out commit {} does not match pin {}", head, pin);
+        }
+    }
+    if verify {
+        ensure::query(log, &[GIT, "-C", d, "verify-commit", "HEAD"])?;
+    }
+
+    info!(log, "role tree at commit {}", head);
+    Ok(())
+}
+
+fn tarball(log: &Logger, url: &str, pin: Option<&str>, dir: &Path)
+    -> Result<()>
+{
+    let archive = with_suffix(dir, ".tar.gz");
+    let staging = with_suffix(dir, ".new");
+    let old = with_suffix(dir, ".old");
+
+    info!(log, "downloading {}", url);
+    let mut res = reqwest::blocking::get(url)?;
+    if !res.status().is_success() {
+        bail!("download {}: HTTP {:?}", url, res.status());
+    }
+    let mut f = std::fs::File::create(&archive)?;
+    std::io::copy(&mut res, &mut f)?;
+    drop(f);
+
+    let hash = ensure::hash_file(&archive, &HashType::SHA256)?;
+    info!(log, "archive SHA-256 {}", hash);
+    if let Some(pin) = pin {
+        if hash != pin.to_lowercase() {
+            std::fs::remove_file(&archive)?;
+            bail!("archive SHA-256 {} does not match pin {}", hash, pin);
+        }
+    }
+
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir(&staging)?;
+    ensure::query(log, &[TAR, "-x", "-z", "-f", archive.to_str().unwrap(),
+        "-C", staging.to_str().unwrap()])?;
+    std::fs::remove_file(&archive)?;
+
+    /*
+     * Archives generated by source hosting services generally contain a
+     * single top-level directory.  If so, that directory is the role tree.
+     */
+    let entries = std::fs::read_dir(&staging)?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let root = match entries.as_slice() {
+        [e] if e.file_type()?.is_dir() => e.path(),
+        _ => staging.clone(),
+    };
+
+    /*
+     * Replace the existing tree only once the new one is complete, so that
+     * a failed pull leaves the previous tree in place.
+     */
+    if old.exists() {
+        std::fs::remove_dir_all(&old)?;
+    }
+    if dir.exists() {
+        std::fs::rename(dir, &old)?;
+    }
+    std::fs::rename(&root, dir)?;
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    if old.exists() {
+        std::fs::remove_dir_all(&old)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Fetch the role tree from the source into the directory, replacing any
+ * tree fetched previously.  This happens even in dry-run mode, as we need the
+ * tree to determine what would be done.
+ */
+pub fn pull(log: &Logger, source: &Source, pin: Option<&str>, verify: bool,
+    dir: &Path)
+    -> Result<()>
+{
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    match source {
+        Source::Git(url) => git(log, url, pin, verify, dir),
+        Source::Tarball(url) => {
+            if verify {
+                bail!("--verify-commit applies only to git sources");
+            }
+            tarball(log, url, pin, dir)
+        }
+    }
+}
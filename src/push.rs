@@ -0,0 +1,160 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * In push mode, "confomat push HOST...", this program and its data directory
+ * are copied to each remote host over SSH and run there.  Each remote run
+ * applies the roles listed for that host in the inventory (see the inventory
+ * module), with the same dry-run, tag, and variable options as were passed
+ * locally.  The remote hosts must run the same operating system and
+ * architecture as the local host, and the SSH user is usually root.
+ *
+ * Up to JOBS hosts (from "-j") are pushed to at once.  The exit status of
+ * each remote run is passed back, so that the local run exits with
+ * EXIT_CHANGED if any host was changed, and so on.
+ */
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use slog::{Logger, info, warn, error, o};
+use anyhow::{Result, bail};
+
+use super::common::{interrupted, shell_quote};
+use super::ensure::{self, Exec};
+use super::{EXIT_CHANGED, EXIT_SKIPPED};
+
+const SSH: &str = "/usr/bin/ssh";
+const SCP: &str = "/usr/bin/scp";
+
+pub struct Push {
+    pub hosts: Vec<String>,
+    /**
+     * Arguments to pass to the remote confomat, after "-d".
+     */
+    pub args: Vec<String>,
+}
+
+fn ssh_output(log: &Logger, host: &str, cmd: &str) -> Result<String> {
+    let out = ensure::query_output(log, &[SSH, "-o", "BatchMode=yes", host,
+        cmd], &Exec::default())?;
+    Ok(out.trim().to_string())
+}
+
+/*
+ * Describe the exit status of a completed run.
+ */
+fn describe(status: i32) -> &'static str {
+    match (status & EXIT_CHANGED != 0, status & EXIT_SKIPPED != 0) {
+        (true, true) => "changed, with skipped steps",
+        (true, false) => "changed",
+        (false, true) => "unchanged, with skipped steps",
+        (false, false) => "unchanged",
+    }
+}
+
+fn push_one(log: &Logger, host: &str, exe: &Path, dir: &Path, args: &[String])
+    -> Result<i32>
+{
+    let tmp = ssh_output(log, host, "mktemp -d /var/tmp/confomat.XXXXXX")?;
+    if !tmp.starts_with("/var/tmp/confomat.") {
+        bail!("unexpected temporary directory on {}: {:?}", host, tmp);
+    }
+    info!(log, "copying confomat to {}:{}", host, tmp);
+
+    let run = || -> Result<i32> {
+        ensure::query(log, &[SCP, "-q", "-o", "BatchMode=yes",
+            exe.to_str().unwrap(), &format!("{}:{}/confomat", host, tmp)])?;
+        ensure::query(log, &[SCP, "-q", "-r", "-o", "BatchMode=yes",
+            dir.to_str().unwrap(), &format!("{}:{}/data", host, tmp)])?;
+
+        let mut cmd = format!("{}/confomat -d {}/data", tmp, tmp);
+        for a in args.iter() {
+            cmd.push(' ');
+            cmd.push_str(&shell_quote(a));
+        }
+        let es = ensure::query_status_with(log,
+            &[SSH, "-o", "BatchMode=yes", host, &cmd], &Exec {
+                show_output: true,
+                ..Default::default()
+            })?;
+        match es.code() {
+            Some(c) if c >= 0 && c & !(EXIT_CHANGED | EXIT_SKIPPED) == 0 => {
+                Ok(c)
+            }
+            _ => bail!("remote confomat failed: {:?}", es),
+        }
+    };
+    let res = run();
+
+    if let Err(e) = ssh_output(log, host,
+        &format!("rm -rf {}", shell_quote(&tmp))) {
+        warn!(log, "could not remove {}:{}: {}", host, tmp, e);
+    }
+
+    res
+}
+
+/**
+ * Push to each host, and report on the outcome for each.  Fails if the run
+ * failed on any host; otherwise, returns the exit statuses of the remote runs,
+ * combined.  If we are interrupted, runs already under way on remote hosts
+ * are left to finish, but no more are started.
+ */
+pub fn push(log: &Logger, push: &Push, dir: &Path, jobs: usize) -> Result<i32> {
+    let exe: PathBuf = std::env::current_exe()?;
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<(String, Result<i32, String>)>> =
+        Mutex::new(Vec::new());
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs.min(push.hosts.len()) {
+            s.spawn(|| {
+                while let Some(host) = push.hosts.get(next.fetch_add(1,
+                    Ordering::SeqCst))
+                {
+                    if let Some(sig) = interrupted() {
+                        results.lock().unwrap().push((host.to_string(),
+                            Err(format!("not attempted: interrupted by {}",
+                            sig))));
+                        continue;
+                    }
+                    let hlog = log.new(o!("host" => host.to_string()));
+                    info!(hlog, "PUSHING TO HOST {}", host);
+                    let res = push_one(&hlog, host, &exe, dir, &push.args);
+                    results.lock().unwrap().push((host.to_string(),
+                        res.map_err(|e| e.to_string())));
+                }
+            });
+        }
+    });
+
+    /*
+     * Report in the order the hosts were listed, rather than the order in
+     * which they finished.
+     */
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(h, _)| push.hosts.iter().position(|x| x == h));
+
+    let mut failed = 0;
+    let mut status = 0;
+    for (host, res) in results.iter() {
+        match res {
+            Err(e) => {
+                error!(log, "HOST {} FAILED: {}", host, e);
+                failed += 1;
+            }
+            Ok(s) => {
+                info!(log, "HOST {} COMPLETE ({})", host, describe(*s));
+                status |= s;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("push failed on {} of {} hosts", failed, push.hosts.len());
+    }
+    Ok(status)
+}
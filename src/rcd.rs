@@ -0,0 +1,81 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Services on FreeBSD and OpenBSD, which rc(8) starts at boot from the
+ * scripts in /etc/rc.d (and, on FreeBSD, /usr/local/etc/rc.d) if they are
+ * enabled.  On FreeBSD, services are enabled in rc.conf(5) with sysrc(8),
+ * and started with service(8); on OpenBSD, rcctl(8) does both.
+ */
+
+use slog::{Logger, info};
+use anyhow::Result;
+
+use super::ensure;
+
+const SERVICE: &str = "/usr/sbin/service";
+const SYSRC: &str = "/usr/sbin/sysrc";
+const RCCTL: &str = "/usr/sbin/rcctl";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rc {
+    /**
+     * service(8) and sysrc(8), as on FreeBSD.
+     */
+    Service,
+    /**
+     * rcctl(8), as on OpenBSD.
+     */
+    Rcctl,
+}
+
+fn command(rc: Rc, verb: &str, name: &str) -> Vec<String> {
+    let args: Vec<&str> = match (rc, verb) {
+        (Rc::Service, "enable") => {
+            return vec![SYSRC.to_string(), format!("{}_enable=YES", name)];
+        }
+        (Rc::Service, v) => vec![SERVICE, name, v],
+        (Rc::Rcctl, "enabled") => vec![RCCTL, "get", name, "status"],
+        (Rc::Rcctl, "status") => vec![RCCTL, "check", name],
+        (Rc::Rcctl, v) => vec![RCCTL, v, name],
+    };
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+pub fn enabled(log: &Logger, rc: Rc, name: &str) -> Result<bool> {
+    Ok(ensure::query_status(log, &command(rc, "enabled", name))?.success())
+}
+
+pub fn running(log: &Logger, rc: Rc, name: &str) -> Result<bool> {
+    Ok(ensure::query_status(log, &command(rc, "status", name))?.success())
+}
+
+/**
+ * Ensure that a service is enabled and running, restarting it if it was
+ * already running and "need_restart" is set.  Returns true if anything was
+ * done.
+ */
+pub fn ensure_running(log: &Logger, rc: Rc, name: &str, need_restart: bool)
+    -> Result<bool>
+{
+    let mut changed = false;
+
+    if !enabled(log, rc, name)? {
+        info!(log, "rc.d service {}: enabling", name);
+        ensure::run(log, &command(rc, "enable", name))?;
+        changed = true;
+    }
+
+    if !running(log, rc, name)? {
+        info!(log, "rc.d service {}: starting", name);
+        ensure::run(log, &command(rc, "start", name))?;
+        changed = true;
+    } else if need_restart {
+        info!(log, "rc.d service {}: restarting", name);
+        ensure::run(log, &command(rc, "restart", name))?;
+        changed = true;
+    }
+
+    Ok(changed)
+}
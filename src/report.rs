@@ -0,0 +1,131 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The run report is a single JSON object describing a whole run: the outcome
+ * and duration of each role and step, the changes made by each step
+ * (including diffs), and some facts about the host.  It is written at the end
+ * of the run, whether or not the run succeeded, to the file named with
+ * "--report", and is sent in a POST request to the URL named with
+ * "--report-url".  Unlike the JSON lines log, which accumulates, the report
+ * file is replaced by each run.
+ */
+
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::plan::Change;
+
+const POST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    /**
+     * The version of the confomat library.
+     */
+    pub version: &'a str,
+    pub host: &'a str,
+    /**
+     * When the run started, in seconds since the epoch.
+     */
+    pub started: f64,
+    pub duration_ms: u64,
+    pub dry_run: bool,
+    /**
+     * "complete" or "failed".
+     */
+    pub result: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /**
+     * The exit status confomat would give for the run (see EXIT_CHANGED).
+     */
+    pub exit_status: i32,
+    pub facts: serde_json::Value,
+    pub roles: Vec<RoleReport<'a>>,
+    /**
+     * Every step, in the order in which each finished.
+     */
+    pub steps: Vec<StepReport<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoleReport<'a> {
+    pub role: &'a str,
+    pub result: &'a str,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepReport<'a> {
+    pub role: &'a str,
+    pub step: &'a str,
+    pub resource: &'a str,
+    pub result: &'a str,
+    /**
+     * Whether this step was run by another step, and is thus part of it.
+     */
+    pub nested: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+    pub duration_ms: u64,
+    pub changes: &'a [Change],
+}
+
+pub fn post(url: &str, body: String) -> Result<()> {
+    let c = reqwest::blocking::Client::builder()
+        .timeout(POST_TIMEOUT)
+        .build()?;
+
+    let res = c.post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()?;
+    if !res.status().is_success() {
+        bail!("status {}", res.status());
+    }
+
+    Ok(())
+}
+
+/**
+ * Write the report to a file, or send it to a URL, or both.  A failure to do
+ * either is reported, but does not fail the run.
+ */
+pub fn write(log: &Logger, path: Option<&Path>, url: Option<&str>,
+    report: &Report)
+{
+    let body = match serde_json::to_string_pretty(report) {
+        Ok(b) => b,
+        Err(e) => {
+            warn!(log, "could not serialize run report: {}", e);
+            return;
+        }
+    };
+
+    if let Some(path) = path {
+        let tmp = path.with_extension("tmp");
+        let res = std::fs::write(&tmp, format!("{}\n", body))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        match res {
+            Ok(()) => info!(log, "wrote run report to {}", path.display()),
+            Err(e) => warn!(log, "could not write run report {}: {}",
+                path.display(), e),
+        }
+    }
+
+    if let Some(url) = url {
+        match post(url, body) {
+            Ok(()) => info!(log, "sent run report to {}", url),
+            Err(e) => warn!(log, "could not send run report to {}: {}", url,
+                e),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * External secret sources, from which templates fetch values at apply time
+ * with "{{ secret("PATH") }}", rather than having them kept (even encrypted)
+ * in the data directory.  The source is configured in
+ * "<dir>/secret_source.toml", as either a HashiCorp Vault server:
+ *
+ *      [vault]
+ *      address = "https://vault.example.com:8200"
+ *      mount = "secret"
+ *
+ * where PATH is "NAME#FIELD", to read one field of a secret in a version 2
+ * key/value secrets engine (the field may be omitted if the secret has only
+ * one); or a plain HTTPS service:
+ *
+ *      [https]
+ *      url = "https://secrets.example.com/v1"
+ *
+ * which is sent a GET request for "URL/PATH", with the token as a bearer
+ * token, and must respond with the value as the body.
+ *
+ * The token is read from "token_file" (by default, "/var/confomat/vault.token"
+ * or "/var/confomat/secrets.token") or, if that does not exist, from the
+ * VAULT_TOKEN or CONFOMAT_SECRETS_TOKEN environment variable.
+ */
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::STATE_DIR;
+
+const CONFIG: &str = "secret_source.toml";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * A source of secret values, looked up by path.  Implementations must not log
+ * the values they return.
+ */
+pub trait SecretSource: Send + Sync {
+    fn describe(&self) -> String;
+    fn fetch(&self, log: &Logger, path: &str) -> Result<String>;
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VaultConfig {
+    address: String,
+    /**
+     * The mount point of the key/value secrets engine.  The default is
+     * "secret".
+     */
+    #[serde(default)]
+    mount: Option<String>,
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HttpsConfig {
+    url: String,
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    vault: Option<VaultConfig>,
+    #[serde(default)]
+    https: Option<HttpsConfig>,
+}
+
+fn token(file: Option<&Path>, default: &str, var: &str) -> Result<String> {
+    let file = file.map(|f| f.to_path_buf())
+        .unwrap_or_else(|| Path::new(STATE_DIR).join(default));
+
+    let token = match std::fs::read_to_string(&file) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            match std::env::var(var) {
+                Ok(t) => t,
+                Err(_) => bail!("no secret source token: create {} or set {}",
+                    file.display(), var),
+            }
+        }
+        Err(e) => bail!("reading {}: {}", file.display(), e),
+    };
+
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        bail!("secret source token is empty");
+    }
+    Ok(token)
+}
+
+fn client() -> Result<reqwest::blocking::Client> {
+    Ok(reqwest::blocking::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()?)
+}
+
+pub struct Vault {
+    address: String,
+    mount: String,
+    token: String,
+}
+
+impl SecretSource for Vault {
+    fn describe(&self) -> String {
+        format!("Vault at {} ({})", self.address, self.mount)
+    }
+
+    fn fetch(&self, log: &Logger, path: &str) -> Result<String> {
+        let (name, field) = match path.split_once('#') {
+            Some((n, f)) => (n, Some(f)),
+            None => (path, None),
+        };
+        let url = format!("{}/v1/{}/data/{}",
+            self.address.trim_end_matches('/'), self.mount,
+            name.trim_start_matches('/'));
+        info!(log, "fetching secret {} from Vault", name);
+
+        let res = client()?.get(&url)
+            .header("X-Vault-Token", &self.token)
+            .send()?;
+        if !res.status().is_success() {
+            bail!("Vault secret {}: status {}", name, res.status());
+        }
+
+        /*
+         * A version 2 key/value secret is returned as:
+         *
+         *      { "data": { "data": { "FIELD": "VALUE", ... }, ... }, ... }
+         */
+        let body: serde_json::Value = res.json()?;
+        let data = match body.get("data").and_then(|d| d.get("data"))
+            .and_then(|d| d.as_object())
+        {
+            Some(d) => d,
+            None => bail!("Vault secret {}: no data in response", name),
+        };
+        let value = match field {
+            Some(f) => data.get(f),
+            None if data.len() == 1 => data.values().next(),
+            None => bail!("Vault secret {} has {} fields; use \"{}#FIELD\"",
+                name, data.len(), name),
+        };
+        match value {
+            Some(serde_json::Value::String(s)) => Ok(s.clone()),
+            Some(_) => bail!("Vault secret {}: field is not a string", path),
+            None => bail!("Vault secret {}: no such field", path),
+        }
+    }
+}
+
+pub struct Https {
+    url: String,
+    token: String,
+}
+
+impl SecretSource for Https {
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn fetch(&self, log: &Logger, path: &str) -> Result<String> {
+        let url = format!("{}/{}", self.url.trim_end_matches('/'),
+            path.trim_start_matches('/'));
+        info!(log, "fetching secret {} from {}", path, self.url);
+
+        let res = client()?.get(&url)
+            .bearer_auth(&self.token)
+            .send()?;
+        if !res.status().is_success() {
+            bail!("secret {}: status {}", path, res.status());
+        }
+
+        Ok(res.text()?)
+    }
+}
+
+/**
+ * Read "<dir>/secret_source.toml", if it exists, and set up that source.
+ */
+pub fn read(dir: &Path) -> Result<Option<Box<dyn SecretSource>>> {
+    let p = dir.join(CONFIG);
+    let text = match std::fs::read_to_string(&p) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!("reading {}: {}", p.display(), e),
+    };
+    let c: Config = match toml::from_str(&text) {
+        Ok(c) => c,
+        Err(e) => bail!("parsing {}: {}", p.display(), e),
+    };
+
+    match (c.vault, c.https) {
+        (Some(v), None) => {
+            if !v.address.starts_with("https://") {
+                bail!("{}: Vault address must be an https:// URL",
+                    p.display());
+            }
+            Ok(Some(Box::new(Vault {
+                token: token(v.token_file.as_deref(), "vault.token",
+                    "VAULT_TOKEN")?,
+                address: v.address,
+                mount: v.mount.unwrap_or_else(|| "secret".to_string()),
+            })))
+        }
+        (None, Some(h)) => {
+            if !h.url.starts_with("https://") {
+                bail!("{}: url must be an https:// URL", p.display());
+            }
+            Ok(Some(Box::new(Https {
+                token: token(h.token_file.as_deref(), "secrets.token",
+                    "CONFOMAT_SECRETS_TOKEN")?,
+                url: h.url,
+            })))
+        }
+        (None, None) => bail!("{}: configure either [vault] or [https]",
+            p.display()),
+        (Some(_), Some(_)) => bail!("{}: configure only one of [vault] and \
+            [https]", p.display()),
+    }
+}
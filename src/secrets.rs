@@ -0,0 +1,208 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Secrets are files in the data directory which have been encrypted with
+ * age(1) (a ".age" suffix) or GnuPG (a ".gpg" suffix).  They are decrypted in
+ * memory as they are needed, and are never written out in cleartext other
+ * than to their final destination.
+ *
+ * Files encrypted with age are decrypted with an identity (private key)
+ * taken from, in order of preference:
+ *
+ *      the file named with "--secrets-key"
+ *      /var/confomat/secrets.key
+ *      the "config/age_identity" property of svc:/site/confomat:default
+ *
+ * Files encrypted with GnuPG are decrypted with whatever keys are available
+ * to gpg(1) for the user running confomat.
+ *
+ * If "<dir>/secrets.toml.age" (or ".gpg") exists, it is decrypted at startup
+ * and its contents are made available to templates as the "secrets" table of
+ * role variables.
+ */
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use anyhow::{Result, bail};
+
+use super::common::{OutputExt, STATE_DIR};
+
+pub const EXTENSIONS: &[&str] = &["age", "gpg"];
+
+const AGE: &str = "age";
+const GPG: &str = "gpg";
+const SVCPROP: &str = "/usr/bin/svcprop";
+const KEY_FMRI: &str = "svc:/site/confomat:default";
+const KEY_PROP: &str = "config/age_identity";
+
+enum Identity {
+    File(PathBuf),
+    Literal(String),
+}
+
+pub struct Secrets {
+    keyfile: Option<PathBuf>,
+    /*
+     * The age identity is located the first time it is needed.
+     */
+    identity: Mutex<Option<Identity>>,
+}
+
+/**
+ * Append an extension to a path; e.g., "server.key" becomes "server.key.age".
+ */
+pub fn with_extension(p: &Path, ext: &str) -> PathBuf {
+    let mut s = p.as_os_str().to_os_string();
+    s.push(".");
+    s.push(ext);
+    PathBuf::from(s)
+}
+
+impl Secrets {
+    pub fn new(keyfile: Option<PathBuf>) -> Secrets {
+        Secrets {
+            keyfile,
+            identity: Mutex::new(None),
+        }
+    }
+
+    fn identity(&self) -> Result<Identity> {
+        if let Some(k) = &self.keyfile {
+            if !k.is_file() {
+                bail!("secrets key {} does not exist", k.display());
+            }
+            return Ok(Identity::File(k.clone()));
+        }
+
+        let k = Path::new(STATE_DIR).join("secrets.key");
+        if k.is_file() {
+            return Ok(Identity::File(k));
+        }
+
+        let out = Command::new(SVCPROP)
+            .env_clear()
+            .args(["-p", KEY_PROP, KEY_FMRI])
+            .output()?;
+        if !out.status.success() {
+            bail!("no secrets key: use --secrets-key, create {}, or set \
+                {} on {} ({})", k.display(), KEY_PROP, KEY_FMRI, out.info());
+        }
+        let key = String::from_utf8(out.stdout)?.trim().to_string();
+        if key.is_empty() {
+            bail!("property {} on {} is empty", KEY_PROP, KEY_FMRI);
+        }
+
+        /*
+         * svcprop(1) escapes special characters in string values, but an age
+         * identity contains none.
+         */
+        Ok(Identity::Literal(key))
+    }
+
+    fn age(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut id = self.identity.lock().unwrap();
+        if id.is_none() {
+            *id = Some(self.identity()?);
+        }
+
+        let mut cmd = Command::new(AGE);
+        cmd.arg("--decrypt");
+        let stdin = match id.as_ref().unwrap() {
+            Identity::File(f) => {
+                cmd.arg("-i").arg(f);
+                None
+            }
+            Identity::Literal(key) => {
+                cmd.args(["-i", "-"]);
+                Some(key.clone())
+            }
+        };
+        drop(id);
+        cmd.arg(path);
+
+        cmd.stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+
+        let mut child = cmd.spawn()?;
+        if let Some(key) = stdin {
+            let mut w = child.stdin.take().unwrap();
+            w.write_all(key.as_bytes())?;
+            w.write_all(b"\n")?;
+        }
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            bail!("decrypting {}: {}", path.display(),
+                String::from_utf8_lossy(&out.stderr).trim());
+        }
+
+        Ok(out.stdout)
+    }
+
+    fn gpg(&self, path: &Path) -> Result<Vec<u8>> {
+        let out = Command::new(GPG)
+            .args(["--batch", "--quiet", "--decrypt"])
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()?;
+        if !out.status.success() {
+            /*
+             * Only report stderr, as stdout may contain part of the secret.
+             */
+            bail!("decrypting {}: {}", path.display(),
+                String::from_utf8_lossy(&out.stderr).trim());
+        }
+
+        Ok(out.stdout)
+    }
+
+    /**
+     * Decrypt an encrypted file, choosing the method from its extension.
+     */
+    pub fn decrypt(&self, path: &Path) -> Result<Vec<u8>> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("age") => self.age(path),
+            Some("gpg") => self.gpg(path),
+            _ => bail!("{} is not a .age or .gpg file", path.display()),
+        }
+    }
+
+    /**
+     * Decrypt and parse "<dir>/secrets.toml.age" (or ".gpg"), if it exists.
+     */
+    pub fn load_vars(&self, dir: &Path) -> Result<Option<serde_json::Value>> {
+        for ext in EXTENSIONS.iter() {
+            let p = with_extension(&dir.join("secrets.toml"), ext);
+            if !p.is_file() {
+                continue;
+            }
+
+            let text = String::from_utf8(self.decrypt(&p)?)?;
+            return match toml::from_str(&text) {
+                Ok(v) => Ok(Some(v)),
+                Err(e) => bail!("parsing {}: {}", p.display(), e),
+            };
+        }
+
+        Ok(None)
+    }
+}
+
+/**
+ * Collect every string within a table of secrets, so that we can avoid
+ * showing them in diffs of rendered templates.
+ */
+pub fn strings(v: &serde_json::Value, out: &mut Vec<String>) {
+    match v {
+        serde_json::Value::String(s) if !s.is_empty() => out.push(s.clone()),
+        serde_json::Value::Array(a) => a.iter().for_each(|v| strings(v, out)),
+        serde_json::Value::Object(m) => m.values()
+            .for_each(|v| strings(v, out)),
+        _ => (),
+    }
+}
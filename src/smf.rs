@@ -0,0 +1,165 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure;
+use super::plan::{Action, Change};
+
+/*
+ * Split the output of svcprop(1), in which multiple values are separated by
+ * whitespace and any whitespace or backslashes within a value are escaped with
+ * a backslash.
+ */
+fn split_values(val: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut esc = false;
+    let mut any = false;
+
+    for c in val.trim_end_matches('\n').chars() {
+        if esc {
+            cur.push(c);
+            esc = false;
+        } else if c == '\\' {
+            esc = true;
+            any = true;
+        } else if c.is_whitespace() {
+            if any {
+                out.push(cur);
+                cur = String::new();
+                any = false;
+            }
+        } else {
+            cur.push(c);
+            any = true;
+        }
+    }
+    if any {
+        out.push(cur);
+    }
+
+    /*
+     * An empty string value is rendered as a pair of double quotes.
+     */
+    out.into_iter()
+        .map(|v| if v == "\"\"" { String::new() } else { v })
+        .collect()
+}
+
+/**
+ * Read the values of a property (e.g., "config/nameserver") of a service or
+ * instance.  Returns None if the property, or its property group, does not
+ * exist.
+ */
+pub fn property(fmri: &str, prop: &str) -> Result<Option<Vec<String>>> {
+    let out = std::process::Command::new("/usr/bin/svcprop")
+        .env_clear()
+        .arg("-p").arg(prop)
+        .arg(fmri)
+        .output()?;
+    if !out.status.success() {
+        /*
+         * svcprop(1) exits non-zero if the property does not exist; make sure
+         * the service exists so that we do not mask other errors.
+         */
+        let chk = std::process::Command::new("/usr/bin/svcs")
+            .env_clear()
+            .arg("-H")
+            .arg(fmri)
+            .output()?;
+        if !chk.status.success() {
+            bail!("svcs {} failed: {}", fmri, chk.info());
+        }
+        return Ok(None);
+    }
+    let val = String::from_utf8(out.stdout)?;
+
+    Ok(Some(split_values(&val)))
+}
+
+fn quote(v: &str) -> String {
+    format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/**
+ * Ensure that a property of a service or instance has exactly the given list
+ * of values, of the given type (e.g., "astring" or "net_address").  The
+ * property group is created, with type "application", if it does not exist.
+ * The service is not refreshed; returns true if a refresh is required.
+ */
+pub fn ensure_property<S: AsRef<str>>(log: &Logger, fmri: &str, prop: &str,
+    proptype: &str, values: &[S])
+    -> Result<bool>
+{
+    let want: Vec<&str> = values.iter().map(|v| v.as_ref()).collect();
+
+    let pg = match prop.split('/').next() {
+        Some(pg) if pg.len() < prop.len() => pg,
+        _ => bail!("property \"{}\" must be of the form group/name", prop),
+    };
+
+    let have = property(fmri, prop)?;
+    match &have {
+        Some(have) if have == &want => {
+            info!(log, "smf {} {} ok ({:?})", fmri, prop, have);
+            return Ok(false);
+        }
+        Some(have) => {
+            info!(log, "smf {} {} is {:?}, want {:?}", fmri, prop, have, want);
+        }
+        None => {
+            info!(log, "smf {} {} missing, want {:?}", fmri, prop, want);
+        }
+    }
+
+    let mut change = Change::new(Action::Modify,
+        format!("smf property {} {}", fmri, prop))
+        .detail(format!("{:?} -> {:?}", have, want))
+        .after(format!("{:?}", want));
+    if let Some(have) = &have {
+        change = change.before(format!("{:?}", have));
+    }
+    if dry_run_skip(log, change)
+    {
+        return Ok(true);
+    }
+
+    if have.is_none() && property(fmri, pg)?.is_none() {
+        info!(log, "smf {} adding property group {}", fmri, pg);
+        ensure::run(log, &["/usr/sbin/svccfg", "-s", fmri, "addpg", pg,
+            "application"])?;
+    }
+
+    let vals = format!("({})", want.iter()
+        .map(|v| quote(v))
+        .collect::<Vec<_>>()
+        .join(" "));
+    ensure::run(log, &["/usr/sbin/svccfg", "-s", fmri, "setprop", prop, "=",
+        &format!("{}:", proptype), &vals])?;
+
+    Ok(true)
+}
+
+pub fn refresh(log: &Logger, fmri: &str) -> Result<()> {
+    info!(log, "refreshing smf {}", fmri);
+    ensure::run(log, &["/usr/sbin/svcadm", "refresh", fmri])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svcprop_values() {
+        assert_eq!(split_values("10.0.0.1 10.0.0.2\n"),
+            vec!["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(split_values("files\\ dns\n"), vec!["files dns"]);
+        assert_eq!(split_values("C:\\\\dir a\\ b"), vec!["C:\\dir", "a b"]);
+        assert_eq!(split_values("\"\"\n"), vec![""]);
+        assert!(split_values("\n").is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The OpenSSH server configuration.  A bad sshd_config can lock everyone
+ * out, so every candidate configuration is first checked with "sshd -t", and
+ * is only installed (and sshd only restarted) if sshd accepts it.
+ */
+
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, Ownership};
+use super::OS;
+
+pub const CONFIG: &str = "/etc/ssh/sshd_config";
+pub const DROPIN_DIR: &str = "/etc/ssh/sshd_config.d";
+
+/*
+ * The SMF instance which runs sshd on illumos.
+ */
+pub const SSH_FMRI: &str = "svc:/network/ssh:default";
+
+fn sshd(os: &OS) -> &'static str {
+    if os.is_illumos() {
+        "/usr/lib/ssh/sshd"
+    } else {
+        "/usr/sbin/sshd"
+    }
+}
+
+/**
+ * The name of the service which runs sshd: a systemd unit on Linux, or an
+ * rc.d service on FreeBSD and OpenBSD.
+ */
+pub fn service(os: &OS) -> &'static str {
+    match os {
+        OS::Debian | OS::Ubuntu => "ssh",
+        _ => "sshd",
+    }
+}
+
+/*
+ * Check a candidate configuration with "sshd -t", which parses the file and
+ * checks that the host keys it names can be loaded.
+ */
+fn validate(log: &Logger, os: &OS, conf: &str) -> Result<()> {
+    let tmp = temp_file("sshd_config", conf.as_bytes())?;
+
+    let out = std::process::Command::new(sshd(os))
+        .env_clear()
+        .arg("-t")
+        .arg("-f").arg(tmp.path())
+        .output()?;
+
+    if !out.status.success() {
+        bail!("sshd rejected candidate configuration: {}", out.info());
+    }
+
+    info!(log, "sshd accepted candidate configuration");
+    Ok(())
+}
+
+/**
+ * Validate and install the whole of sshd_config.  Returns true if the
+ * configuration changed and sshd must be restarted.
+ */
+pub fn config(log: &Logger, os: &OS, contents: &str) -> Result<bool> {
+    validate(log, os, contents)?;
+
+    ensure::contents(log, rooted(CONFIG)?, contents.as_bytes(),
+        &Ownership::Names("root", os.root_group()), 0o644)
+}
+
+/**
+ * Validate and install a drop-in fragment, "sshd_config.d/NAME.conf".  The
+ * fragment is checked as sshd would read it with an Include at the top of
+ * sshd_config: followed by the rest of the current configuration.
+ */
+pub fn fragment(log: &Logger, os: &OS, name: &str, contents: &str)
+    -> Result<bool>
+{
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        bail!("invalid sshd_config fragment name \"{}\"", name);
+    }
+
+    let main = match std::fs::read_to_string(rooted(CONFIG)?) {
+        Ok(s) => s,
+        Err(e) => bail!("reading {}: {}", CONFIG, e),
+    };
+    if !main.lines().any(|l| {
+        l.trim_start().to_ascii_lowercase().starts_with("include") &&
+            l.contains("sshd_config.d")
+    }) {
+        warn!(log, "{} does not include {}; the fragment will have no \
+            effect", CONFIG, DROPIN_DIR);
+    }
+
+    let mut candidate = contents.to_string();
+    if !candidate.ends_with('\n') {
+        candidate.push('\n');
+    }
+    candidate.push_str(&main);
+    validate(log, os, &candidate)?;
+
+    let dir = rooted(DROPIN_DIR)?;
+    let mut did_work = ensure::directory(log, &dir, "root",
+        os.root_group(), 0o755)?;
+    if ensure::contents(log, dir.join(format!("{}.conf", name)),
+        contents.as_bytes(), &Ownership::Names("root", os.root_group()),
+        0o644)?
+    {
+        did_work = true;
+    }
+    Ok(did_work)
+}
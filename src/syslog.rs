@@ -0,0 +1,101 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * With "--syslog", the start and end of the run, the result of each role, and
+ * each failed step are also logged with syslog(3C), to the daemon facility,
+ * so that they are collected along with the other logs from the host.  Each
+ * message is a list of fields, with any value containing spaces quoted:
+ *
+ *      event=role_end role=nginx result=changed duration_ms=1520
+ *      event=step_end role=nginx step=ensure_file resource=/etc/nginx.conf
+ *          result=failed error="permission denied"
+ *
+ * Failures are logged at LOG_ERR, and everything else at LOG_NOTICE.
+ */
+
+use std::ffi::CString;
+
+use super::journal::Event;
+
+pub struct Syslog {
+    dry_run: bool,
+}
+
+/*
+ * The value of a field, quoted if necessary.
+ */
+fn value(v: &str) -> String {
+    if !v.is_empty() && !v.contains(|c: char| c.is_whitespace() || c == '"') {
+        v.to_string()
+    } else {
+        format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")
+            .replace('\n', " "))
+    }
+}
+
+impl Syslog {
+    pub fn open(dry_run: bool) -> Syslog {
+        /*
+         * openlog(3C) retains the identity string, so it must outlive every
+         * later call to syslog(3C).
+         */
+        static IDENT: &[u8] = b"confomat\0";
+        unsafe {
+            libc::openlog(IDENT.as_ptr() as *const libc::c_char,
+                libc::LOG_PID | libc::LOG_NDELAY, libc::LOG_DAEMON);
+        }
+
+        Syslog { dry_run }
+    }
+
+    /**
+     * Log an event, if it is one which we send to syslog.
+     */
+    pub fn write(&self, event: &Event) {
+        let failed = event.result == Some("failed");
+        let wanted = match event.event {
+            "run_start" | "run_end" | "role_end" => true,
+            "step_end" => failed,
+            _ => false,
+        };
+        if !wanted {
+            return;
+        }
+
+        let mut fields = vec![format!("event={}", event.event)];
+        for (k, v) in [
+            ("role", event.role),
+            ("instance", event.instance),
+            ("step", event.step),
+            ("resource", event.resource),
+            ("result", event.result),
+            ("reason", event.reason),
+            ("error", event.error.as_deref()),
+        ].iter() {
+            if let Some(v) = v {
+                fields.push(format!("{}={}", k, value(v)));
+            }
+        }
+        if let Some(ms) = event.duration_ms {
+            fields.push(format!("duration_ms={}", ms));
+        }
+        if let Some(c) = event.changes {
+            fields.push(format!("changes={}", c.len()));
+        }
+        if self.dry_run {
+            fields.push("dry_run=true".to_string());
+        }
+
+        let msg = match CString::new(fields.join(" ").replace('\0', "")) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let pri = if failed { libc::LOG_ERR } else { libc::LOG_NOTICE };
+        unsafe {
+            libc::syslog(libc::LOG_DAEMON | pri,
+                b"%s\0".as_ptr() as *const libc::c_char, msg.as_ptr());
+        }
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Services on Linux systems managed by systemd: unit files, and whether each
+ * unit is enabled and active.  These mirror the SMF primitives, so that a
+ * role can say that a service is running in much the same way on either.
+ */
+
+use std::collections::HashMap;
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, Exec, Ownership};
+
+const SYSTEMCTL: &str = "/usr/bin/systemctl";
+
+/**
+ * The directory in which confomat installs unit files.
+ */
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+
+/**
+ * Is this system managed by systemd?
+ */
+pub fn booted() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        super::sys::has_systemd()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        false
+    }
+}
+
+/**
+ * The properties of a unit which we care about: "LoadState" (e.g., "loaded"
+ * or "not-found"), "ActiveState" (e.g., "active", "inactive", or "failed"),
+ * and "UnitFileState" (e.g., "enabled" or "disabled").
+ */
+pub fn unit_state(log: &Logger, unit: &str, opts: &Exec)
+    -> Result<HashMap<String, String>>
+{
+    let out = ensure::query_output(log, &[SYSTEMCTL, "show", "-p",
+        "LoadState", "-p", "ActiveState", "-p", "UnitFileState", unit], opts)?;
+
+    Ok(out.lines()
+        .filter_map(|l| {
+            let mut kv = l.splitn(2, '=');
+            Some((kv.next()?.to_string(), kv.next()?.to_string()))
+        })
+        .collect())
+}
+
+/**
+ * Install a unit file with these contents, and have systemd reload its
+ * configuration if it changed.  The path is relative to the alternate root,
+ * if there is one.
+ */
+pub fn ensure_unit_file(log: &Logger, unit: &str, contents: &str,
+    opts: &Exec)
+    -> Result<bool>
+{
+    if unit.is_empty() || unit.contains('/') {
+        bail!("invalid unit name \"{}\"", unit);
+    }
+
+    let path = rooted(format!("{}/{}", UNIT_DIR, unit))?;
+    let changed = ensure::contents(log, &path, contents.as_bytes(),
+        &Ownership::Names("root", "root"), 0o644)?;
+    if changed {
+        daemon_reload(log, opts)?;
+    }
+    Ok(changed)
+}
+
+pub fn daemon_reload(log: &Logger, opts: &Exec) -> Result<()> {
+    ensure::run_with(log, &[SYSTEMCTL, "daemon-reload"], opts)
+}
+
+/**
+ * Ensure that a unit is enabled and active, restarting it if it was already
+ * active and "need_restart" is set.  Returns true if anything was done.
+ */
+pub fn ensure_active(log: &Logger, unit: &str, need_restart: bool,
+    opts: &Exec)
+    -> Result<bool>
+{
+    let st = unit_state(log, unit, opts)?;
+    let get = |k: &str| st.get(k).map(|v| v.as_str()).unwrap_or("");
+    let mut changed = false;
+
+    if get("LoadState") == "not-found" && !dry_run() {
+        bail!("systemd unit {} does not exist", unit);
+    }
+
+    match get("UnitFileState") {
+        "enabled" | "static" | "generated" | "alias" => (),
+        s => {
+            info!(log, "systemd unit {}: {}, enabling...", unit, s);
+            ensure::run_with(log, &[SYSTEMCTL, "enable", unit], opts)?;
+            changed = true;
+        }
+    }
+
+    match get("ActiveState") {
+        "active" if need_restart => {
+            info!(log, "systemd unit {}: restarting...", unit);
+            ensure::run_with(log, &[SYSTEMCTL, "restart", unit], opts)?;
+            changed = true;
+        }
+        "active" => (),
+        s => {
+            if s == "failed" {
+                ensure::run_with(log, &[SYSTEMCTL, "reset-failed", unit],
+                    opts)?;
+            }
+            info!(log, "systemd unit {}: {}, starting...", unit, s);
+            ensure::run_with(log, &[SYSTEMCTL, "start", unit], opts)?;
+            changed = true;
+        }
+    }
+
+    if changed && !dry_run() && opts.root.is_none() {
+        /*
+         * "systemctl start" waits for the unit to start, but a service which
+         * exits straight away may still be reported as active for a moment.
+         */
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let st = unit_state(log, unit, opts)?;
+        match st.get("ActiveState").map(|s| s.as_str()) {
+            Some("active") => info!(log, "systemd unit {}: active!", unit),
+            s => bail!("systemd unit {} is not active ({:?})", unit, s),
+        }
+    }
+
+    Ok(changed)
+}
@@ -0,0 +1,385 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * A small template language for rendering configuration files from role
+ * variables.  The supported constructs are:
+ *
+ *      {{ name }}                          substitute the value of a variable
+ *      {{ secret("path") }}                substitute a value fetched from the
+ *                                          external secret source
+ *      {% if COND %} ... {% else %} ... {% endif %}
+ *      {% for item in name %} ... {% endfor %}
+ *
+ * where COND is one of:
+ *
+ *      name                                the variable is set, and is not
+ *                                          false, zero, or empty
+ *      not name                            the opposite
+ *      name == value                       the variable has this value
+ *      name != value                       the opposite
+ *
+ * The value in a comparison is interpreted as JSON if possible (e.g., 8080,
+ * true, or "lx"), and is otherwise taken to be a bare string.
+ *
+ * Names are dotted paths into the variables; e.g., "nginx.port".  The facts
+ * about the system are under "facts" (e.g., "facts.nodename", or
+ * "facts.ipaddrs.net0" for the address of an interface, or, in the global
+ * zone, "facts.zones" for a list of the non-global zones with their "name",
+ * "brand", "state", "path", "addresses", and "datasets"), and the role being
+ * applied is "role.name" (and "role.instance").  Within a loop, the loop
+ * variable shadows any variable of the same name.  As in many other template
+ * languages, a newline directly after a {% ... %} tag is dropped, so that
+ * lines containing only a tag do not appear in the output.
+ */
+
+use serde_json::Value;
+use anyhow::{Result, bail};
+
+use super::vars;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var(String),
+    Secret(String),
+    If {
+        cond: Vec<String>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        item: String,
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Text(String),
+    Var(String),
+    Tag(Vec<String>),
+}
+
+fn tokenise(input: &str) -> Result<Vec<Token>> {
+    let mut out = Vec::new();
+    let mut rest = input;
+    let mut line = 1;
+
+    while !rest.is_empty() {
+        let next = match (rest.find("{{"), rest.find("{%")) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        let start = match next {
+            Some(start) => start,
+            None => {
+                out.push(Token::Text(rest.to_string()));
+                break;
+            }
+        };
+
+        if start > 0 {
+            out.push(Token::Text(rest[..start].to_string()));
+            line += rest[..start].matches('\n').count();
+        }
+
+        let tag = rest[start..].starts_with("{%");
+        let close = if tag { "%}" } else { "}}" };
+        let end = match rest[start + 2..].find(close) {
+            Some(end) => start + 2 + end,
+            None => bail!("line {}: unterminated \"{}\"", line,
+                &rest[start..start + 2]),
+        };
+
+        let inner = rest[start + 2..end].trim();
+        line += rest[start..end].matches('\n').count();
+        rest = &rest[end + 2..];
+
+        if tag {
+            out.push(Token::Tag(inner.split_whitespace()
+                .map(|w| w.to_string())
+                .collect()));
+            if rest.starts_with('\n') {
+                rest = &rest[1..];
+                line += 1;
+            }
+        } else {
+            if inner.is_empty() || inner.contains(char::is_whitespace) {
+                bail!("line {}: invalid substitution \"{{{{ {} }}}}\"", line,
+                    inner);
+            }
+            out.push(Token::Var(inner.to_string()));
+        }
+    }
+
+    Ok(out)
+}
+
+/*
+ * Parse tokens into a list of nodes, stopping at (and returning) the first
+ * tag which closes the enclosing block; e.g., "else" or "endif".
+ */
+fn parse(tokens: &mut std::vec::IntoIter<Token>)
+    -> Result<(Vec<Node>, Option<String>)>
+{
+    let mut out = Vec::new();
+
+    while let Some(t) = tokens.next() {
+        match t {
+            Token::Text(s) => out.push(Node::Text(s)),
+            Token::Var(s) => match s.strip_prefix("secret(")
+                .and_then(|s| s.strip_suffix(')'))
+            {
+                Some(arg) => match serde_json::from_str::<Value>(arg) {
+                    Ok(Value::String(path)) => out.push(Node::Secret(path)),
+                    _ => bail!("invalid substitution \"{{{{ {} }}}}\": the \
+                        path must be a quoted string", s),
+                },
+                None => out.push(Node::Var(s)),
+            },
+            Token::Tag(words) => {
+                let w: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+                match w.as_slice() {
+                    ["if", ..] => {
+                        let cond = words[1..].to_vec();
+                        let (then, end) = parse(tokens)?;
+                        let otherwise = match end.as_deref() {
+                            Some("endif") => Vec::new(),
+                            Some("else") => match parse(tokens)? {
+                                (otherwise, Some(e)) if e == "endif" => {
+                                    otherwise
+                                }
+                                _ => bail!("\"else\" without \"endif\""),
+                            },
+                            _ => bail!("\"if {}\" without \"endif\"",
+                                cond.join(" ")),
+                        };
+                        out.push(Node::If { cond, then, otherwise });
+                    }
+                    ["for", item, "in", name] => {
+                        let item = item.to_string();
+                        let name = name.to_string();
+                        let body = match parse(tokens)? {
+                            (body, Some(e)) if e == "endfor" => body,
+                            _ => bail!("\"for {} in {}\" without \"endfor\"",
+                                item, name),
+                        };
+                        out.push(Node::For { item, name, body });
+                    }
+                    ["else"] | ["endif"] | ["endfor"] => {
+                        return Ok((out, Some(w[0].to_string())));
+                    }
+                    _ => bail!("invalid tag \"{{% {} %}}\"", words.join(" ")),
+                }
+            }
+        }
+    }
+
+    Ok((out, None))
+}
+
+/*
+ * Look up a dotted path, first among the loop variables (innermost first) and
+ * then in the variables.
+ */
+fn lookup<'a>(vars: &'a Value, scope: &'a [(String, Value)], name: &str)
+    -> Option<&'a Value>
+{
+    let (first, rest) = match name.find('.') {
+        Some(i) => (&name[..i], Some(&name[i + 1..])),
+        None => (name, None),
+    };
+
+    match (scope.iter().rev().find(|(n, _)| n == first), rest) {
+        (Some((_, v)), None) => Some(v),
+        (Some((_, v)), Some(rest)) => vars::lookup(v, rest),
+        (None, _) => vars::lookup(vars, name),
+    }
+}
+
+fn truthy(v: Option<&Value>) -> bool {
+    match v {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64().map(|f| f != 0.0).unwrap_or(true),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(a)) => !a.is_empty(),
+        Some(Value::Object(m)) => !m.is_empty(),
+    }
+}
+
+fn test(cond: &[String], vars: &Value, scope: &[(String, Value)])
+    -> Result<bool>
+{
+    let w: Vec<&str> = cond.iter().map(|w| w.as_str()).collect();
+
+    let (name, op, value) = match w.as_slice() {
+        [name] => return Ok(truthy(lookup(vars, scope, name))),
+        ["not", name] => return Ok(!truthy(lookup(vars, scope, name))),
+        [name, op @ "==", value] | [name, op @ "!=", value] => {
+            (name, op, value)
+        }
+        _ => bail!("invalid condition \"{}\"", w.join(" ")),
+    };
+
+    let value = match serde_json::from_str::<Value>(value) {
+        Ok(v) => v,
+        Err(_) => Value::String(value.to_string()),
+    };
+    let equal = lookup(vars, scope, name) == Some(&value);
+
+    Ok(if *op == "==" { equal } else { !equal })
+}
+
+/**
+ * Fetches the value for "{{ secret("path") }}".
+ */
+pub type SecretFn<'a> = &'a dyn Fn(&str) -> Result<String>;
+
+fn emit(out: &mut String, nodes: &[Node], vars: &Value,
+    scope: &mut Vec<(String, Value)>, secret: SecretFn)
+    -> Result<()>
+{
+    for n in nodes.iter() {
+        match n {
+            Node::Text(s) => out.push_str(s),
+            Node::Var(name) => match lookup(vars, scope, name) {
+                Some(Value::String(s)) => out.push_str(s),
+                Some(v @ Value::Number(_)) | Some(v @ Value::Bool(_)) => {
+                    out.push_str(&v.to_string());
+                }
+                Some(v) => bail!("variable \"{}\" cannot be substituted: {}",
+                    name, v),
+                None => bail!("variable \"{}\" is not set", name),
+            },
+            Node::Secret(path) => match secret(path) {
+                Ok(v) => out.push_str(&v),
+                Err(e) => bail!("secret \"{}\": {}", path, e),
+            },
+            Node::If { cond, then, otherwise } => {
+                if test(cond, vars, scope)? {
+                    emit(out, then, vars, scope, secret)?;
+                } else {
+                    emit(out, otherwise, vars, scope, secret)?;
+                }
+            }
+            Node::For { item, name, body } => {
+                let list = match lookup(vars, scope, name) {
+                    Some(Value::Array(a)) => a.clone(),
+                    None | Some(Value::Null) => Vec::new(),
+                    Some(v) => bail!("variable \"{}\" is not a list: {}",
+                        name, v),
+                };
+                for v in list {
+                    scope.push((item.to_string(), v));
+                    let res = emit(out, body, vars, scope, secret);
+                    scope.pop();
+                    res?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Render a template with the given variables, which must be an object (i.e.,
+ * a table of names and values), fetching any secrets with "secret".
+ */
+pub fn render(input: &str, vars: &Value, secret: SecretFn)
+    -> Result<String>
+{
+    let mut tokens = tokenise(input)?.into_iter();
+    let nodes = match parse(&mut tokens)? {
+        (nodes, None) => nodes,
+        (_, Some(end)) => bail!("unexpected \"{}\"", end),
+    };
+
+    let mut out = String::new();
+    emit(&mut out, &nodes, vars, &mut Vec::new(), secret)?;
+    Ok(out)
+}
+
+/**
+ * Evaluate a condition, as would appear in an "if" tag, with the given
+ * variables; e.g., "facts.zonename == global".
+ */
+pub fn condition(cond: &str, vars: &Value) -> Result<bool> {
+    let words: Vec<String> = cond.split_whitespace()
+        .map(|w| w.to_string())
+        .collect();
+
+    test(&words, vars, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn no_secret(path: &str) -> Result<String> {
+        bail!("no secret {}", path)
+    }
+
+    fn r(input: &str, vars: &Value) -> Result<String> {
+        render(input, vars, &no_secret)
+    }
+
+    #[test]
+    fn substitution() {
+        let vars = json!({
+            "nginx": { "port": 8080, "tls": true },
+            "name": "web",
+        });
+
+        assert_eq!(r("listen {{ nginx.port }};\n", &vars).unwrap(),
+            "listen 8080;\n");
+        assert_eq!(r("{{name}}/{{ nginx.tls }}", &vars).unwrap(), "web/true");
+        assert!(r("{{ missing }}", &vars).is_err());
+        assert!(r("{{ nginx }}", &vars).is_err());
+        assert!(r("{{ name", &vars).is_err());
+        assert!(r("{{ a b }}", &vars).is_err());
+    }
+
+    #[test]
+    fn blocks() {
+        let vars = json!({
+            "tls": false,
+            "port": 80,
+            "backends": [ "a", "b" ],
+        });
+
+        assert_eq!(r("{% if tls %}\nssl on;\n{% else %}\nssl off;\n\
+            {% endif %}\n", &vars).unwrap(), "ssl off;\n");
+        assert_eq!(r("{% if port == 80 %}http{% endif %}", &vars).unwrap(),
+            "http");
+        assert_eq!(r("{% if not missing %}unset{% endif %}", &vars).unwrap(),
+            "unset");
+        assert_eq!(r("{% for b in backends %}\nserver {{ b }};\n\
+            {% endfor %}\n", &vars).unwrap(), "server a;\nserver b;\n");
+        assert!(r("{% if tls %}", &vars).is_err());
+        assert!(r("{% endfor %}", &vars).is_err());
+        assert!(r("{% for b in port %}{% endfor %}", &vars).is_err());
+    }
+
+    #[test]
+    fn conditions() {
+        let vars = json!({
+            "facts": { "zonename": "global", "cpus": 8 },
+            "registered": { "version": "1.2" },
+        });
+
+        assert!(condition("facts.zonename == global", &vars).unwrap());
+        assert!(condition("facts.zonename != web", &vars).unwrap());
+        assert!(condition("facts.cpus == 8", &vars).unwrap());
+        assert!(!condition("facts.cpus == \"8\"", &vars).unwrap());
+        assert!(condition("registered.version == \"1.2\"", &vars).unwrap());
+        assert!(condition("not facts.missing", &vars).unwrap());
+        assert!(condition("facts.zonename is global", &vars).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * The time zone of the system.  On illumos, this is "TZ" in
+ * /etc/default/init, which init(1M) reads at boot and passes on to every
+ * service, so that a change only takes full effect at the next boot.
+ * Elsewhere, /etc/localtime is a symbolic link to the file for the zone in
+ * the zoneinfo database, which each process reads when it starts; on Debian
+ * and Ubuntu, the name of the zone is also kept in /etc/timezone.
+ */
+
+use std::path::{Component, Path, PathBuf};
+
+use slog::Logger;
+use anyhow::{Result, bail};
+
+use super::common::rooted;
+use super::ensure::{self, FileType, Ownership};
+use super::OS;
+
+const INIT_DEFAULTS: &str = "/etc/default/init";
+const LOCALTIME: &str = "/etc/localtime";
+const TIMEZONE: &str = "/etc/timezone";
+
+fn zoneinfo(os: &OS) -> &'static Path {
+    if os.is_illumos() {
+        Path::new("/usr/share/lib/zoneinfo")
+    } else {
+        Path::new("/usr/share/zoneinfo")
+    }
+}
+
+/**
+ * Check that "tz" names a zone in the zoneinfo database (beneath the
+ * alternate root, if there is one); e.g., "Australia/Sydney" or "UTC".
+ * Returns the path of the file for the zone.
+ */
+pub fn validate(os: &OS, tz: &str) -> Result<PathBuf> {
+    let rel = Path::new(tz);
+    if tz.is_empty() || !rel.components().all(|c| matches!(c,
+        Component::Normal(_)))
+    {
+        bail!("invalid time zone \"{}\"", tz);
+    }
+
+    let path = zoneinfo(os).join(rel);
+    match ensure::check(rooted(&path)?)? {
+        Some(fi) if fi.filetype != FileType::Directory => Ok(path),
+        Some(_) => bail!("time zone \"{}\" is a directory in {}", tz,
+            zoneinfo(os).display()),
+        None => bail!("time zone \"{}\" is not in {}", tz,
+            zoneinfo(os).display()),
+    }
+}
+
+/**
+ * Ensure that the time zone of the system is "tz".
+ */
+pub fn ensure(log: &Logger, os: &OS, tz: &str) -> Result<bool> {
+    let path = validate(os, tz)?;
+
+    if os.is_illumos() {
+        return ensure::key_values(log, rooted(INIT_DEFAULTS)?, &[("TZ", tz)],
+            &Ownership::Names("root", "sys"), 0o644);
+    }
+
+    let group = os.root_group();
+    let mut did_work = ensure::symlink(log, rooted(LOCALTIME)?, &path, "root",
+        group)?;
+    if matches!(os, OS::Debian | OS::Ubuntu) && ensure::contents(log,
+        rooted(TIMEZONE)?, format!("{}\n", tz).as_bytes(),
+        &Ownership::Names("root", group), 0o644)?
+    {
+        did_work = true;
+    }
+
+    Ok(did_work)
+}
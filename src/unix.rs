@@ -0,0 +1,165 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Functions and types which are the same on every UNIX system we support,
+ * and which each platform module (e.g., illumos or linux) provides as its
+ * own.
+ */
+
+use std::os::raw::{c_char, c_int};
+use std::process::exit;
+use std::ffi::{CString, CStr};
+use std::collections::HashMap;
+use anyhow::Result;
+
+use super::common::errno;
+use super::error::Error;
+
+/**
+ * An entry from the user_attr(4) database, which only illumos has.
+ */
+#[derive(Debug, PartialEq)]
+pub struct UserAttr {
+    pub name: String,
+    pub attr: HashMap<String, String>,
+}
+
+impl UserAttr {
+    pub fn profiles(&self) -> Vec<String> {
+        if let Some(p) = self.attr.get("profiles") {
+            p.split(',')
+                .map(|s| s.trim().to_string())
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+pub fn nodename() -> String {
+    unsafe {
+        let mut un: libc::utsname = std::mem::zeroed();
+        if libc::uname(&mut un) < 0 {
+            eprintln!("uname failure");
+            exit(100);
+        }
+        std::ffi::CStr::from_ptr(un.nodename.as_mut_ptr())
+    }.to_str().unwrap().to_string()
+}
+
+/**
+ * The IDs of the groups in /etc/group which list this user as a member.  (On
+ * macOS, the groups are not in /etc/group; see the macos module.)
+ */
+#[cfg(not(target_os = "macos"))]
+pub fn get_group_ids_for_user(name: &str) -> Result<Vec<u32>> {
+    let mut gids = Vec::new();
+
+    for l in std::fs::read_to_string("/etc/group")?.lines() {
+        let f: Vec<&str> = l.split(':').collect();
+        if f.len() != 4 || !f[3].split(',').any(|m| m.trim() == name) {
+            continue;
+        }
+        if let Ok(gid) = f[2].parse() {
+            gids.push(gid);
+        }
+    }
+
+    Ok(gids)
+}
+
+/**
+ * Look up the ID of a user or group by name in a passwd(4) or group(4) file,
+ * rather than through the name service; e.g., for an alternate root.
+ */
+pub fn get_id_from_file(path: &std::path::Path, name: &str)
+    -> Result<Option<u32>>
+{
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            return Err(Error::io(&format!("reading {}", path.display()), path,
+                e));
+        }
+    };
+
+    for l in text.lines() {
+        let f: Vec<&str> = l.split(':').collect();
+        if f.len() >= 3 && f[0] == name {
+            match f[2].parse() {
+                Ok(id) => return Ok(Some(id)),
+                Err(_) => return Err(Error::validation(
+                    path.display().to_string(),
+                    format!("invalid ID for {}", name)).into()),
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/**
+ * A pseudo-terminal, from openpty(3C).
+ */
+pub struct Pty {
+    pub master: std::fs::File,
+    pub slave: std::fs::File,
+    /**
+     * The path of the slave device; e.g., "/dev/pts/3".
+     */
+    pub name: CString,
+}
+
+pub fn openpty() -> Result<Pty> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut master: c_int = -1;
+    let mut slave: c_int = -1;
+    let mut name = [0 as c_char; 128];
+    let mut ws = libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    if unsafe {
+        libc::openpty(&mut master, &mut slave, name.as_mut_ptr(),
+            std::ptr::null_mut(), &mut ws)
+    } != 0 {
+        return Err(Error::SystemCall {
+            call: "openpty".to_string(),
+            errno: errno(),
+        }.into());
+    }
+
+    Ok(Pty {
+        master: unsafe { std::fs::File::from_raw_fd(master) },
+        slave: unsafe { std::fs::File::from_raw_fd(slave) },
+        name: unsafe { CStr::from_ptr(name.as_ptr()) }.to_owned(),
+    })
+}
+
+/**
+ * Start a new session, with the terminal at this path as its controlling
+ * terminal.  This may be called between fork and exec.
+ */
+pub fn set_controlling_tty(name: &CStr) -> std::io::Result<()> {
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    /*
+     * A session leader without a controlling terminal acquires one when it
+     * opens a terminal without O_NOCTTY.  The descriptor itself is not
+     * needed, as stdio is already connected to the terminal.
+     */
+    let fd = unsafe { libc::open(name.as_ptr(), libc::O_RDWR) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    unsafe { libc::close(fd) };
+    Ok(())
+}
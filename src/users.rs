@@ -0,0 +1,379 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Local user accounts and groups.  The commands used to create and alter them
+ * depend on the platform: useradd(1M) and its relatives on illumos, the
+ * shadow-utils commands of the same names on Linux, pw(8) on FreeBSD, user(8)
+ * and group(8) on OpenBSD, and dscl(1) and dseditgroup(8) on macOS.
+ */
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::common::Unsupported;
+use super::ensure;
+use super::osops::os_ops;
+use super::OS;
+
+/**
+ * The account management commands of a platform.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tools {
+    /**
+     * useradd, usermod, and groupadd, as on illumos and Linux.
+     */
+    UserAdd,
+    /**
+     * pw(8), as on FreeBSD.
+     */
+    Pw,
+    /**
+     * user(8) and group(8), as on OpenBSD.
+     */
+    User,
+    /**
+     * dscl(1) and dseditgroup(8), as on macOS.
+     */
+    Dscl,
+}
+
+const DSCL: &str = "/usr/bin/dscl";
+const DSEDITGROUP: &str = "/usr/sbin/dseditgroup";
+
+/*
+ * Directory Services does not choose a UID for a new user, so we choose the
+ * one after the highest in use, as System Settings would.  The UIDs below
+ * 501 are reserved for the system.
+ */
+const FIRST_DSCL_UID: u32 = 501;
+
+pub fn tools(os: &OS) -> Result<Tools> {
+    if os.is_illumos() || os.is_linux() {
+        Ok(Tools::UserAdd)
+    } else if *os == OS::FreeBSD {
+        Ok(Tools::Pw)
+    } else if *os == OS::OpenBSD {
+        Ok(Tools::User)
+    } else if *os == OS::MacOS {
+        Ok(Tools::Dscl)
+    } else {
+        Err(Unsupported::new("users", &format!("{:?}", os)).into())
+    }
+}
+
+/**
+ * A local user account.  Attributes which are None are chosen by the system
+ * when the user is created, and are otherwise left alone.
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct User {
+    pub name: String,
+    /**
+     * If the user already exists with a different UID, that is an error
+     * rather than something to be changed, as files would be left behind.
+     */
+    pub uid: Option<u32>,
+    /**
+     * The primary group, which must already exist.
+     */
+    pub group: Option<String>,
+    /**
+     * The complete list of supplementary groups: the user is removed from any
+     * other.
+     */
+    pub groups: Option<Vec<String>>,
+    pub home: Option<String>,
+    pub shell: Option<String>,
+    pub comment: Option<String>,
+}
+
+fn command(tools: Tools, verb: &str, name: &str, opts: Vec<String>)
+    -> Vec<String>
+{
+    match tools {
+        Tools::UserAdd => {
+            let mut args = vec![format!("/usr/sbin/{}", verb)];
+            args.extend(opts);
+            args.push(name.to_string());
+            args
+        }
+        Tools::Pw => {
+            let mut args = vec!["/usr/sbin/pw".to_string(), verb.to_string(),
+                name.to_string()];
+            args.extend(opts);
+            args
+        }
+        Tools::User => {
+            /*
+             * "useradd" is "user add", "groupadd" is "group add", and so on.
+             */
+            let (noun, op) = verb.split_at(verb.len() - 3);
+            let mut args = vec![format!("/usr/sbin/{}", noun), op.to_string()];
+            args.extend(opts);
+            args.push(name.to_string());
+            args
+        }
+        Tools::Dscl => unreachable!("dscl has no {} command", verb),
+    }
+}
+
+fn next_dscl_uid(log: &Logger) -> Result<u32> {
+    let out = ensure::query_output(log, &[DSCL, ".", "-list", "/Users",
+        "UniqueID"], &ensure::Exec::default())?;
+
+    Ok(out.lines()
+        .filter_map(|l| l.split_whitespace().nth(1)?.parse::<u32>().ok())
+        .filter(|uid| *uid >= FIRST_DSCL_UID && *uid < 65534)
+        .max()
+        .map(|uid| uid + 1)
+        .unwrap_or(FIRST_DSCL_UID))
+}
+
+/**
+ * As ensure_user(), but with dscl(1), which sets one attribute of the user
+ * record at a time, and dseditgroup(8), which adds the user to and removes
+ * it from each supplementary group.
+ */
+fn ensure_user_dscl(log: &Logger, u: &User) -> Result<bool> {
+    let record = format!("/Users/{}", u.name);
+    let cur = os_ops().passwd_by_name(&u.name)?;
+    let mut attrs: Vec<(&str, String)> = Vec::new();
+
+    let gid = match &u.group {
+        Some(g) => match os_ops().group_by_name(g)? {
+            Some(gr) => Some(gr.gid),
+            None => bail!("group {} does not exist", g),
+        },
+        None => None,
+    };
+
+    let have_groups = match &cur {
+        None => {
+            let uid = match u.uid {
+                Some(uid) => uid,
+                None => next_dscl_uid(log)?,
+            };
+            attrs.push(("UniqueID", uid.to_string()));
+            /*
+             * Every user must have a primary group: by default, "staff", as
+             * for the users created by System Settings.
+             */
+            attrs.push(("PrimaryGroupID", gid.unwrap_or(20).to_string()));
+            if let Some(home) = &u.home {
+                attrs.push(("NFSHomeDirectory", home.to_string()));
+            }
+            if let Some(shell) = &u.shell {
+                attrs.push(("UserShell", shell.to_string()));
+            }
+            if let Some(comment) = &u.comment {
+                attrs.push(("RealName", comment.to_string()));
+            }
+            Vec::new()
+        }
+        Some(pw) => {
+            if let Some(uid) = u.uid {
+                if pw.uid != uid {
+                    bail!("user {} has UID {}, not {}", u.name, pw.uid, uid);
+                }
+            }
+            if let Some(gid) = gid {
+                if pw.gid != gid {
+                    attrs.push(("PrimaryGroupID", gid.to_string()));
+                }
+            }
+            if u.home.is_some() && u.home != pw.dir {
+                attrs.push(("NFSHomeDirectory", u.home.clone().unwrap()));
+            }
+            if u.shell.is_some() && u.shell != pw.shell {
+                attrs.push(("UserShell", u.shell.clone().unwrap()));
+            }
+            if u.comment.is_some() && u.comment != pw.gecos {
+                attrs.push(("RealName", u.comment.clone().unwrap()));
+            }
+            os_ops().group_ids_for_user(&u.name)?
+        }
+    };
+
+    let mut changed = false;
+    if cur.is_none() {
+        info!(log, "creating user {}", u.name);
+        ensure::run(log, &[DSCL, ".", "-create", &record])?;
+        changed = true;
+    } else if !attrs.is_empty() {
+        info!(log, "altering user {}", u.name);
+    }
+    for (k, v) in attrs.iter() {
+        ensure::run(log, &[DSCL, ".", "-create", &record, k, v])?;
+        changed = true;
+    }
+
+    if let Some(groups) = &u.groups {
+        let mut want = Vec::new();
+        for g in groups.iter() {
+            let gid = match os_ops().group_by_name(g)? {
+                Some(gr) => gr.gid,
+                None => bail!("group {} does not exist", g),
+            };
+            want.push(gid);
+            if !have_groups.contains(&gid) {
+                info!(log, "adding user {} to group {}", u.name, g);
+                ensure::run(log, &[DSEDITGROUP, "-o", "edit", "-a", &u.name,
+                    "-t", "user", g])?;
+                changed = true;
+            }
+        }
+        for gid in have_groups.iter().filter(|gid| !want.contains(gid)) {
+            let g = match os_ops().group_by_id(*gid)?.and_then(|gr| gr.name) {
+                Some(g) => g,
+                None => bail!("group {} has no name", gid),
+            };
+            info!(log, "removing user {} from group {}", u.name, g);
+            ensure::run(log, &[DSEDITGROUP, "-o", "edit", "-d", &u.name,
+                "-t", "user", &g])?;
+            changed = true;
+        }
+    }
+
+    if !changed {
+        info!(log, "user {} ok", u.name);
+    }
+    Ok(changed)
+}
+
+/**
+ * Ensure that a user exists, with the given attributes.  Returns true if the
+ * user was created or altered.
+ */
+pub fn ensure_user(log: &Logger, tools: Tools, u: &User) -> Result<bool> {
+    if tools == Tools::Dscl {
+        return ensure_user_dscl(log, u);
+    }
+
+    let cur = os_ops().passwd_by_name(&u.name)?;
+    let mut opts: Vec<String> = Vec::new();
+    let mut set = |flag: &str, val: String| {
+        opts.push(flag.to_string());
+        opts.push(val);
+    };
+
+    let pw = match cur {
+        Some(pw) => pw,
+        None => {
+            if let Some(uid) = u.uid {
+                set("-u", uid.to_string());
+            }
+            if let Some(g) = &u.group {
+                set("-g", g.to_string());
+            }
+            if let Some(groups) = &u.groups {
+                set("-G", groups.join(","));
+            }
+            if let Some(home) = &u.home {
+                set("-d", home.to_string());
+            }
+            if let Some(shell) = &u.shell {
+                set("-s", shell.to_string());
+            }
+            if let Some(comment) = &u.comment {
+                set("-c", comment.to_string());
+            }
+
+            info!(log, "creating user {}", u.name);
+            ensure::run(log, &command(tools, "useradd", &u.name, opts))?;
+            return Ok(true);
+        }
+    };
+
+    if let Some(uid) = u.uid {
+        if pw.uid != uid {
+            bail!("user {} has UID {}, not {}", u.name, pw.uid, uid);
+        }
+    }
+    if let Some(g) = &u.group {
+        match os_ops().group_by_name(g)? {
+            Some(gr) if gr.gid == pw.gid => (),
+            Some(_) => set("-g", g.to_string()),
+            None => bail!("group {} does not exist", g),
+        }
+    }
+    if let Some(groups) = &u.groups {
+        let mut want = Vec::new();
+        for g in groups.iter() {
+            match os_ops().group_by_name(g)? {
+                Some(gr) => want.push(gr.gid),
+                None => bail!("group {} does not exist", g),
+            }
+        }
+        want.sort_unstable();
+        want.dedup();
+        let mut have = os_ops().group_ids_for_user(&u.name)?;
+        have.sort_unstable();
+        have.dedup();
+        if want != have {
+            set("-G", groups.join(","));
+        }
+    }
+    if u.home.is_some() && u.home != pw.dir {
+        set("-d", u.home.clone().unwrap());
+    }
+    if u.shell.is_some() && u.shell != pw.shell {
+        set("-s", u.shell.clone().unwrap());
+    }
+    if u.comment.is_some() && u.comment != pw.gecos {
+        set("-c", u.comment.clone().unwrap());
+    }
+
+    if opts.is_empty() {
+        info!(log, "user {} ok", u.name);
+        return Ok(false);
+    }
+
+    info!(log, "altering user {}", u.name);
+    ensure::run(log, &command(tools, "usermod", &u.name, opts))?;
+    Ok(true)
+}
+
+/**
+ * Ensure that a group exists, with the given GID if there is one.  As with
+ * users, an existing group with a different GID is an error.
+ */
+pub fn ensure_group(log: &Logger, tools: Tools, name: &str, gid: Option<u32>)
+    -> Result<bool>
+{
+    if let Some(gr) = os_ops().group_by_name(name)? {
+        if let Some(gid) = gid {
+            if gr.gid != gid {
+                bail!("group {} has GID {}, not {}", name, gr.gid, gid);
+            }
+        }
+        info!(log, "group {} ok", name);
+        return Ok(false);
+    }
+
+    info!(log, "creating group {}", name);
+    if tools == Tools::Dscl {
+        /*
+         * Unlike dscl(1), dseditgroup(8) chooses a GID if we do not.
+         */
+        let mut args = vec![DSEDITGROUP.to_string(), "-o".to_string(),
+            "create".to_string()];
+        if let Some(gid) = gid {
+            args.push("-i".to_string());
+            args.push(gid.to_string());
+        }
+        args.push(name.to_string());
+        ensure::run(log, &args)?;
+        return Ok(true);
+    }
+
+    let mut opts = Vec::new();
+    if let Some(gid) = gid {
+        opts.push("-g".to_string());
+        opts.push(gid.to_string());
+    }
+    ensure::run(log, &command(tools, "groupadd", name, opts))?;
+    Ok(true)
+}
@@ -0,0 +1,146 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Role variables are collected from several sources, each of which may
+ * override values set by the sources before it:
+ *
+ *      <dir>/vars.toml                 variables for every host
+ *      <dir>/env/<env>/vars.toml       variables for the environment
+ *      <dir>/hosts.toml                the "vars" for this host, if any
+ *      <dir>/vars/<nodename>.toml      variables for this host
+ *      --vars FILE                     (in the order given)
+ *      -e NAME=VALUE                   (in the order given)
+ *
+ * Tables are merged recursively, so that a later source may override a single
+ * value within a table without replacing the rest of it.
+ */
+
+use std::path::Path;
+
+use serde_json::{Map, Value};
+use anyhow::{Result, bail};
+
+pub fn empty() -> Value {
+    Value::Object(Map::new())
+}
+
+/**
+ * Merge the variables in "from" into "into".  Where both contain a table of
+ * the same name, the tables are merged; otherwise, values from "from"
+ * replace those in "into".
+ */
+pub fn merge(into: &mut Value, from: Value) {
+    match (into, from) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (k, v) in b {
+                match a.get_mut(&k) {
+                    Some(existing) => merge(existing, v),
+                    None => {
+                        a.insert(k, v);
+                    }
+                }
+            }
+        }
+        (into, from) => *into = from,
+    }
+}
+
+/**
+ * Read a TOML file of variables, returning None if the file does not exist.
+ */
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Value>> {
+    let path = path.as_ref();
+
+    match jmclib::toml::read_file::<Value>(path) {
+        Ok(Some(v @ Value::Object(_))) => Ok(Some(v)),
+        Ok(Some(_)) => bail!("vars file {} is not a table", path.display()),
+        Ok(None) => Ok(None),
+        Err(e) => bail!("reading vars {}: {}", path.display(), e),
+    }
+}
+
+/**
+ * Parse a NAME=VALUE assignment from the command line.  The name may be a
+ * dotted path into nested tables; e.g., "nginx.port=8080".  The value is
+ * interpreted as JSON if possible, so that numbers, booleans, and lists have
+ * their natural type, and is otherwise taken to be a string.
+ */
+pub fn assignment(arg: &str) -> Result<Value> {
+    let (name, value) = match arg.find('=') {
+        Some(i) => (&arg[..i], &arg[i + 1..]),
+        None => bail!("variable assignment \"{}\" must be NAME=VALUE", arg),
+    };
+
+    if name.split('.').any(|p| p.is_empty()) {
+        bail!("invalid variable name \"{}\"", name);
+    }
+
+    let mut v = match serde_json::from_str::<Value>(value) {
+        Ok(v) => v,
+        Err(_) => Value::String(value.to_string()),
+    };
+    for p in name.rsplit('.') {
+        let mut m = Map::new();
+        m.insert(p.to_string(), v);
+        v = Value::Object(m);
+    }
+
+    Ok(v)
+}
+
+/**
+ * Look up a variable by its dotted path; e.g., "nginx.port".
+ */
+pub fn lookup<'a>(vars: &'a Value, name: &str) -> Option<&'a Value> {
+    let mut v = vars;
+    for p in name.split('.') {
+        v = match v {
+            Value::Object(m) => m.get(p)?,
+            Value::Array(a) => a.get(p.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_tables() {
+        let mut vars = json!({
+            "nginx": { "port": 80, "user": "www" },
+            "list": [ 1, 2 ],
+        });
+        merge(&mut vars, json!({
+            "nginx": { "port": 8080 },
+            "list": [ 3 ],
+            "new": true,
+        }));
+
+        assert_eq!(vars, json!({
+            "nginx": { "port": 8080, "user": "www" },
+            "list": [ 3 ],
+            "new": true,
+        }));
+    }
+
+    #[test]
+    fn assignments() {
+        assert_eq!(assignment("nginx.port=8080").unwrap(),
+            json!({ "nginx": { "port": 8080 } }));
+        assert_eq!(assignment("tls=true").unwrap(), json!({ "tls": true }));
+        assert_eq!(assignment("hosts=[\"a\",\"b\"]").unwrap(),
+            json!({ "hosts": [ "a", "b" ] }));
+        assert_eq!(assignment("name=web=1").unwrap(),
+            json!({ "name": "web=1" }));
+        assert_eq!(assignment("empty=").unwrap(), json!({ "empty": "" }));
+        assert!(assignment("name").is_err());
+        assert!(assignment("nginx..port=1").is_err());
+        assert!(assignment("=1").is_err());
+    }
+}
@@ -0,0 +1,229 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+/*
+ * Encrypted ZFS datasets.  The wrapping key for each dataset is kept as an
+ * encrypted role file (see Context::secret()), and is installed on the host,
+ * readable only by root, at the location recorded in the "keylocation"
+ * property of the dataset.  The key can then be loaded, and the dataset
+ * mounted, without any interaction each time confomat is applied; e.g., after
+ * a reboot.
+ */
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use slog::{Logger, info};
+use anyhow::{Result, bail};
+
+use super::ensure::{self, Exec};
+
+const ZFS: &str = "/usr/sbin/zfs";
+
+/*
+ * The directory for keys which are not given an explicit location.
+ */
+const KEY_DIR: &str = "/etc/zfs/keys";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyFormat {
+    /**
+     * 32 bytes of key material.
+     */
+    Raw,
+    /**
+     * 64 hexadecimal digits.
+     */
+    Hex,
+    /**
+     * A passphrase of between 8 and 512 bytes, from which the key is derived.
+     */
+    Passphrase,
+}
+
+impl KeyFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            KeyFormat::Raw => "raw",
+            KeyFormat::Hex => "hex",
+            KeyFormat::Passphrase => "passphrase",
+        }
+    }
+
+    /*
+     * Check key material in this format, as zfs(1M) would reject it only once
+     * the dataset is created or the key loaded.  A trailing newline is
+     * permitted in hex and passphrase keys.
+     */
+    pub fn check(&self, key: &[u8]) -> Result<()> {
+        let text = || key.strip_suffix(b"\n").unwrap_or(key);
+        match self {
+            KeyFormat::Raw if key.len() != 32 => {
+                bail!("raw key is {} bytes, rather than 32", key.len());
+            }
+            KeyFormat::Hex if text().len() != 64 ||
+                !text().iter().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                bail!("hex key is not 64 hexadecimal digits");
+            }
+            KeyFormat::Passphrase if text().len() < 8 ||
+                text().len() > 512 =>
+            {
+                bail!("passphrase must be between 8 and 512 bytes");
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncryptedDataset {
+    /**
+     * e.g., "tank/data".
+     */
+    pub name: String,
+    /**
+     * The encrypted role file holding the key, without its ".age" or ".gpg"
+     * suffix.
+     */
+    pub key_secret: PathBuf,
+    pub key_format: KeyFormat,
+    /**
+     * Where to install the key.  The default is "/etc/zfs/keys/NAME", with
+     * any "/" in the dataset name replaced by "_".
+     */
+    pub key_path: Option<PathBuf>,
+    /**
+     * The encryption algorithm, if not the ZFS default; e.g., "aes-256-gcm".
+     */
+    pub encryption: Option<String>,
+    /**
+     * Other properties to set when the dataset is created; e.g.,
+     * ("mountpoint", "/data").
+     */
+    pub properties: Vec<(String, String)>,
+}
+
+impl EncryptedDataset {
+    pub fn key_path(&self) -> PathBuf {
+        match &self.key_path {
+            Some(p) => p.clone(),
+            None => Path::new(KEY_DIR).join(self.name.replace('/', "_")),
+        }
+    }
+}
+
+/*
+ * The named properties of a dataset, or None if it does not exist.
+ */
+fn properties(log: &Logger, name: &str, props: &[&str])
+    -> Result<Option<BTreeMap<String, String>>>
+{
+    let st = ensure::query_status(log, &[ZFS, "list", "-H", "-o", "name",
+        name])?;
+    if !st.success() {
+        return Ok(None);
+    }
+
+    let out = ensure::query_output(log, &[ZFS, "get", "-H", "-p", "-o",
+        "property,value", &props.join(","), name], &Exec::default())?;
+    let mut m = BTreeMap::new();
+    for l in out.lines() {
+        match l.split_once('\t') {
+            Some((k, v)) => m.insert(k.to_string(), v.to_string()),
+            None => bail!("unexpected zfs get output: {:?}", l),
+        };
+    }
+    Ok(Some(m))
+}
+
+/**
+ * Ensure that an encrypted dataset exists, with the key at this location.
+ * The key must already have been installed.  An existing dataset which is
+ * not encrypted cannot be converted, and is an error.
+ */
+pub fn encrypted(log: &Logger, ds: &EncryptedDataset) -> Result<bool> {
+    let location = format!("file://{}", ds.key_path().display());
+
+    let props = match properties(log, &ds.name, &["encryption",
+        "keyformat", "keylocation"])?
+    {
+        Some(p) => p,
+        None => {
+            info!(log, "create encrypted dataset: {}", ds.name);
+
+            let mut args = vec![ZFS.to_string(), "create".to_string()];
+            let mut opt = |k: &str, v: &str| {
+                args.push("-o".to_string());
+                args.push(format!("{}={}", k, v));
+            };
+            opt("encryption", ds.encryption.as_deref().unwrap_or("on"));
+            opt("keyformat", ds.key_format.as_str());
+            opt("keylocation", &location);
+            for (k, v) in ds.properties.iter() {
+                opt(k, v);
+            }
+            args.push(ds.name.clone());
+
+            ensure::run(log, &args)?;
+            return Ok(true);
+        }
+    };
+
+    let prop = |k: &str| props.get(k).map(|v| v.as_str()).unwrap_or("");
+    if prop("encryption") == "off" {
+        bail!("dataset {} exists, but is not encrypted", ds.name);
+    }
+    if prop("keyformat") != ds.key_format.as_str() {
+        bail!("dataset {} has key format {}, not {}", ds.name,
+            prop("keyformat"), ds.key_format.as_str());
+    }
+
+    if prop("keylocation") == location {
+        info!(log, "encrypted dataset {} exists already", ds.name);
+        return Ok(false);
+    }
+
+    info!(log, "dataset {} keylocation: {} -> {}", ds.name,
+        prop("keylocation"), location);
+    ensure::run(log, &[ZFS, "set", &format!("keylocation={}", location),
+        &ds.name])?;
+    Ok(true)
+}
+
+/**
+ * Ensure that the key for an encrypted dataset is loaded (from its
+ * keylocation), and, unless it is not to be mounted, that it is mounted.
+ */
+pub fn unlocked(log: &Logger, name: &str) -> Result<bool> {
+    let props = match properties(log, name, &["encryption", "keystatus",
+        "mounted", "canmount", "mountpoint", "type"])?
+    {
+        Some(p) => p,
+        None => bail!("dataset {} does not exist", name),
+    };
+    let prop = |k: &str| props.get(k).map(|v| v.as_str()).unwrap_or("");
+
+    let mut did_work = false;
+
+    if prop("encryption") != "off" && prop("keystatus") != "available" {
+        info!(log, "load key for dataset {}", name);
+        ensure::run(log, &[ZFS, "load-key", name])?;
+        did_work = true;
+    }
+
+    if prop("type") == "filesystem" && prop("mounted") != "yes" &&
+        prop("canmount") == "on" &&
+        !["none", "legacy"].contains(&prop("mountpoint"))
+    {
+        info!(log, "mount dataset {}", name);
+        ensure::run(log, &[ZFS, "mount", name])?;
+        did_work = true;
+    }
+
+    if !did_work {
+        info!(log, "dataset {} is unlocked", name);
+    }
+    Ok(did_work)
+}
@@ -0,0 +1,1063 @@
+/*
+ * Copyright 2020 Oxide Computer Company
+ */
+
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use slog::{Logger, info, warn};
+use anyhow::{Result, bail};
+
+use super::common::*;
+use super::ensure::{self, Create, Exec, FileType, Ownership};
+use super::{EXIT_CHANGED, EXIT_SKIPPED};
+use super::plan::{Action, Change};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZoneState {
+    Configured,
+    Incomplete,
+    Installed,
+    Ready,
+    Running,
+    ShuttingDown,
+    Down,
+    Other(String),
+}
+
+impl ZoneState {
+    fn from_str(val: &str) -> ZoneState {
+        match val {
+            "configured" => ZoneState::Configured,
+            "incomplete" => ZoneState::Incomplete,
+            "installed" => ZoneState::Installed,
+            "ready" => ZoneState::Ready,
+            "running" => ZoneState::Running,
+            "shutting_down" => ZoneState::ShuttingDown,
+            "down" => ZoneState::Down,
+            s => ZoneState::Other(s.to_string()),
+        }
+    }
+
+    /**
+     * The state as zoneadm(1M) reports it; e.g., "running".
+     */
+    pub fn as_str(&self) -> &str {
+        match self {
+            ZoneState::Configured => "configured",
+            ZoneState::Incomplete => "incomplete",
+            ZoneState::Installed => "installed",
+            ZoneState::Ready => "ready",
+            ZoneState::Running => "running",
+            ZoneState::ShuttingDown => "shutting_down",
+            ZoneState::Down => "down",
+            ZoneState::Other(s) => s,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    pub id: Option<i32>,
+    pub name: String,
+    pub state: ZoneState,
+    pub path: String,
+    pub uuid: Option<String>,
+    pub brand: String,
+    pub iptype: String,
+}
+
+/*
+ * Parse one line of "zoneadm list -p" output, which has the form:
+ *
+ *      zoneid:zonename:state:zonepath:uuid:brand:ip-type
+ *
+ * The zone ID is "-" for zones that are not running.
+ */
+fn parse_zone(line: &str) -> Result<Zone> {
+    let t: Vec<&str> = line.split(':').collect();
+    if t.len() < 7 {
+        bail!("unexpected zoneadm line: {:?}", t);
+    }
+
+    Ok(Zone {
+        id: if t[0] == "-" { None } else { Some(t[0].parse()?) },
+        name: t[1].to_string(),
+        state: ZoneState::from_str(t[2]),
+        path: t[3].to_string(),
+        uuid: if t[4].is_empty() { None } else { Some(t[4].to_string()) },
+        brand: t[5].to_string(),
+        iptype: t[6].to_string(),
+    })
+}
+
+pub fn zones() -> Result<Vec<Zone>> {
+    let out = std::process::Command::new("/usr/sbin/zoneadm")
+        .env_clear()
+        .arg("list").arg("-cp")
+        .output()?;
+    if !out.status.success() {
+        bail!("zoneadm list failed: {}", out.info());
+    }
+    let val = String::from_utf8(out.stdout)?;
+
+    val.lines().map(parse_zone).collect()
+}
+
+pub fn zone(name: &str) -> Result<Option<Zone>> {
+    Ok(zones()?.into_iter().find(|z| z.name == name))
+}
+
+/*
+ * Check the state of the multi-user milestone within a running zone.  Returns
+ * true once the milestone is online.
+ */
+fn milestone_online(name: &str) -> Result<bool> {
+    let out = std::process::Command::new("/usr/bin/svcs")
+        .env_clear()
+        .arg("-z").arg(name)
+        .arg("-Ho").arg("sta")
+        .arg("svc:/milestone/multi-user:default")
+        .output()?;
+    if !out.status.success() {
+        /*
+         * The SMF repository in a freshly booted zone may not be available
+         * for a short time.
+         */
+        return Ok(false);
+    }
+    let val = String::from_utf8(out.stdout)?;
+    Ok(val.trim() == "ON")
+}
+
+/*
+ * Zones of these brands do not run SMF, so there is no milestone for us to
+ * wait on once they have booted.
+ */
+fn has_smf(brand: &str) -> bool {
+    !matches!(brand, "bhyve" | "kvm" | "lx")
+}
+
+/*
+ * How long to wait for a zone to reach the running state, with the multi-user
+ * milestone online, from wherever it started.
+ */
+const RUNNING_TIMEOUT: Duration = Duration::from_secs(900);
+
+pub fn running(log: &Logger, name: &str) -> Result<bool> {
+    let mut did_work = false;
+    let deadline = Instant::now() + RUNNING_TIMEOUT;
+
+    loop {
+        let z = if let Some(z) = zone(name)? {
+            z
+        } else if dry_run_skip(log, Change::new(Action::Modify,
+            format!("zone {}", name))
+            .detail("install and boot"))
+        {
+            /*
+             * In dry-run mode, the zone configuration may not yet exist
+             * because we did not create it.
+             */
+            return Ok(true);
+        } else {
+            bail!("zone {} is not configured", name);
+        };
+
+        match z.state {
+            ZoneState::Configured | ZoneState::Installed | ZoneState::Ready
+                if dry_run_skip(log, Change::new(Action::Modify,
+                format!("zone {}", name))
+                .detail(format!("state {:?} -> running", z.state))) =>
+            {
+                return Ok(true);
+            }
+            ZoneState::Configured => {
+                did_work = true;
+                info!(log, "zone {} is configured, installing...", name);
+                ensure::run(log, &["/usr/sbin/zoneadm", "-z", name,
+                    "install"])?;
+            }
+            ZoneState::Installed | ZoneState::Ready => {
+                did_work = true;
+                info!(log, "zone {} is {}, booting...", name,
+                    z.state.as_str());
+                ensure::run(log, &["/usr/sbin/zoneadm", "-z", name, "boot"])?;
+            }
+            ZoneState::Running if !has_smf(&z.brand) => {
+                info!(log, "zone {} is running", name);
+                return Ok(did_work);
+            }
+            ZoneState::Running => {
+                if milestone_online(name)? {
+                    info!(log, "zone {} is running, multi-user online", name);
+                    return Ok(did_work);
+                }
+                info!(log, "zone {} is running, waiting for multi-user...",
+                    name);
+            }
+            ZoneState::Incomplete => {
+                bail!("zone {} is incomplete; manual intervention required",
+                    name);
+            }
+            s => {
+                warn!(log, "zone {}: unexpected state {:?}", name, s);
+            }
+        }
+
+        if Instant::now() >= deadline {
+            bail!("zone {} did not come up within {}s", name,
+                RUNNING_TIMEOUT.as_secs());
+        }
+        sleep(1);
+    }
+}
+
+/*
+ * The location, within the root of a non-global zone, where we stage a copy of
+ * ourselves and the confomat data directory in order to apply roles within
+ * that zone.
+ */
+const ZONE_STAGING: &str = "/var/tmp/confomat-gz";
+
+/**
+ * Apply roles within a running zone, returning the exit status of the run in
+ * the zone (see EXIT_CHANGED).
+ */
+pub fn apply_roles(log: &Logger, name: &str, exe: &Path, dir: &Path,
+    roles: &[&str])
+    -> Result<i32>
+{
+    let z = match zone(name)? {
+        Some(z) if z.state == ZoneState::Running => z,
+        _ if dry_run_skip(log, Change::new(Action::Exec,
+            format!("roles {:?} in zone {}", roles, name))
+            .detail("zone is not yet running")) => return Ok(EXIT_CHANGED),
+        Some(z) => bail!("zone {} is {:?}, not running", name, z.state),
+        None => bail!("zone {} is not configured", name),
+    };
+
+    /*
+     * Staging ourselves within the zone writes to its root, so a dry run
+     * stops here rather than running the roles in the zone with "-n".
+     */
+    if dry_run_skip(log, Change::new(Action::Exec,
+        format!("roles {:?} in zone {}", roles, name)))
+    {
+        return Ok(EXIT_CHANGED);
+    }
+
+    /*
+     * The zone controls everything beneath its root, so any symbolic links on
+     * the way to the staging directory are resolved as the zone would see
+     * them, rather than followed out into the global zone.  The staging
+     * directory itself, and everything we put in it, is created afresh, so
+     * that nothing the zone planted there is followed either.
+     */
+    let root = PathBuf::from(&z.path).join("root");
+    let staging = resolve(&root, Path::new(ZONE_STAGING))?;
+    let parent = staging.parent().unwrap();
+    match ensure::check(parent)? {
+        Some(fi) if fi.filetype == FileType::Directory => (),
+        Some(fi) => bail!("{} is a {:?}, not a directory", parent.display(),
+            fi.filetype),
+        None => bail!("{} does not exist", parent.display()),
+    }
+
+    match ensure::check(&staging)? {
+        Some(fi) if fi.filetype == FileType::Directory => {
+            info!(log, "removing stale staging directory {}",
+                staging.display());
+            std::fs::remove_dir_all(&staging)?;
+        }
+        Some(fi) => {
+            info!(log, "removing stale staging {:?} {}", fi.filetype,
+                staging.display());
+            std::fs::remove_file(&staging)?;
+        }
+        None => (),
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+    info!(log, "staging confomat in zone {} at {}", name, staging.display());
+    let mut src = std::fs::File::open(exe)?;
+    let mut dst = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o755)
+        .open(staging.join("confomat"))?;
+    std::io::copy(&mut src, &mut dst)?;
+    drop(dst);
+    ensure::query(log, &["/usr/bin/cp", "-rP", dir.to_str().unwrap(),
+        staging.join("data").to_str().unwrap()])?;
+
+    let zexe = format!("{}/confomat", ZONE_STAGING);
+    let zdir = format!("{}/data", ZONE_STAGING);
+    let mut args: Vec<&str> = vec![&zexe, "-d", &zdir];
+    args.extend(roles);
+
+    let res = ensure::query_status_with(log, &args, &Exec {
+        zone: Some(name.to_string()),
+        show_output: true,
+        ..Default::default()
+    }).and_then(|es| match es.code() {
+        Some(c) if c >= 0 && c & !(EXIT_CHANGED | EXIT_SKIPPED) == 0 => Ok(c),
+        _ => bail!("confomat in zone {} failed: {:?}", name, es),
+    });
+
+    /*
+     * remove_dir_all() removes symbolic links, rather than following them, so
+     * this cannot reach outside the staging directory.
+     */
+    if let Err(e) = std::fs::remove_dir_all(&staging) {
+        warn!(log, "could not remove staging directory {}: {}",
+            staging.display(), e);
+    }
+
+    res
+}
+
+/**
+ * Resource controls for a zone.  Any control left as None is not managed.
+ * Memory sizes are expressed as zonecfg(1M) would accept them; e.g., "512M"
+ * or "4G".
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZoneLimits {
+    pub physical: Option<String>,
+    pub swap: Option<String>,
+    pub locked: Option<String>,
+    pub ncpus: Option<f64>,
+    pub cpu_shares: Option<u32>,
+}
+
+/*
+ * Convert a size (e.g., "512M", "4G", or a plain byte count) to bytes.
+ */
+fn parse_size(val: &str) -> Result<u64> {
+    let val = val.trim();
+    let (num, mult) = match val.chars().last() {
+        Some('K') | Some('k') => (&val[..val.len() - 1], 1u64 << 10),
+        Some('M') | Some('m') => (&val[..val.len() - 1], 1u64 << 20),
+        Some('G') | Some('g') => (&val[..val.len() - 1], 1u64 << 30),
+        Some('T') | Some('t') => (&val[..val.len() - 1], 1u64 << 40),
+        Some(_) => (val, 1),
+        None => bail!("empty size"),
+    };
+
+    let num: f64 = match num.parse() {
+        Ok(n) => n,
+        Err(e) => bail!("invalid size \"{}\": {}", val, e),
+    };
+    Ok((num * mult as f64) as u64)
+}
+
+/*
+ * Read the properties within a zone resource using "zonecfg info".  The output
+ * has lines of the form "physical: 1G"; properties that are aliases for
+ * resource controls are surrounded by square brackets.
+ */
+fn zonecfg_info(name: &str, resource: &str) -> Result<Vec<(String, String)>> {
+    let out = std::process::Command::new("/usr/sbin/zonecfg")
+        .env_clear()
+        .arg("-z").arg(name)
+        .arg("info").arg(resource)
+        .output()?;
+    if !out.status.success() {
+        bail!("zonecfg info {} failed: {}", resource, out.info());
+    }
+    let val = String::from_utf8(out.stdout)?;
+
+    Ok(val.lines().filter_map(|l| {
+        let l = l.trim().trim_start_matches('[').trim_end_matches(']');
+        let t: Vec<&str> = l.splitn(2, ':').collect();
+        if t.len() == 2 && !t[1].trim().is_empty() {
+            Some((t[0].trim().to_string(), t[1].trim().to_string()))
+        } else {
+            None
+        }
+    }).collect())
+}
+
+fn prop<'a>(props: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    props.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+fn size_differs(want: &Option<String>, have: Option<&str>) -> Result<bool> {
+    Ok(match (want, have) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(w), Some(h)) => parse_size(w)? != parse_size(h)?,
+    })
+}
+
+/*
+ * Apply a resource control to a running zone with prctl(1).
+ */
+fn prctl(log: &Logger, name: &str, rctl: &str, value: u64) -> Result<()> {
+    ensure::run(log, &["/usr/bin/prctl", "-n", rctl, "-v", &value.to_string(),
+        "-r", "-i", "zone", name])
+}
+
+/**
+ * Ensure the resource controls for a zone match the requested values.  The
+ * persistent zone configuration is updated, and if the zone is running we
+ * attempt to apply each change live.  Returns whether any change was made, and
+ * a list of changes that could not be applied live and which require a reboot
+ * of the zone to take effect.
+ */
+pub fn limits(log: &Logger, name: &str, lim: &ZoneLimits)
+    -> Result<(bool, Vec<String>)>
+{
+    let z = if let Some(z) = zone(name)? {
+        z
+    } else if dry_run_skip(log, Change::new(Action::Modify,
+        format!("zone {}", name))
+        .detail(format!("resource controls {:?}", lim)))
+    {
+        return Ok((true, Vec::new()));
+    } else {
+        bail!("zone {} is not configured", name);
+    };
+    let live = z.state == ZoneState::Running;
+    let mut reboot = Vec::new();
+    let mut cmds: Vec<String> = Vec::new();
+
+    /*
+     * Memory caps:
+     */
+    let mem = zonecfg_info(name, "capped-memory")?;
+    let mut memset = Vec::new();
+    for (key, want) in [("physical", &lim.physical), ("swap", &lim.swap),
+        ("locked", &lim.locked)].iter()
+    {
+        if size_differs(want, prop(&mem, key))? {
+            let want = want.as_ref().unwrap();
+            info!(log, "zone {} {} is {:?}, want {}", name, key,
+                prop(&mem, key), want);
+            memset.push(format!("set {}={}", key, want));
+
+            if !live {
+                continue;
+            }
+
+            let res = match *key {
+                "physical" => ensure::run(log, &["/usr/sbin/rcapadm", "-z",
+                    name, "-m", want]),
+                "swap" => prctl(log, name, "zone.max-swap",
+                    parse_size(want)?),
+                _ => prctl(log, name, "zone.max-locked-memory",
+                    parse_size(want)?),
+            };
+            if let Err(e) = res {
+                warn!(log, "could not apply {} live: {}", key, e);
+                reboot.push(format!("capped-memory {}={}", key, want));
+            }
+        }
+    }
+    if !memset.is_empty() {
+        let verb = if mem.is_empty() { "add" } else { "select" };
+        cmds.push(format!("{} capped-memory; {}; end", verb,
+            memset.join("; ")));
+    }
+
+    /*
+     * CPU cap:
+     */
+    if let Some(want) = lim.ncpus {
+        let cpu = zonecfg_info(name, "capped-cpu")?;
+        let have: Option<f64> = prop(&cpu, "ncpus")
+            .and_then(|v| v.parse().ok());
+        if have.map(|h| (h - want).abs() > 0.001).unwrap_or(true) {
+            info!(log, "zone {} ncpus is {:?}, want {}", name, have, want);
+            let verb = if cpu.is_empty() { "add" } else { "select" };
+            cmds.push(format!("{} capped-cpu; set ncpus={:.2}; end", verb,
+                want));
+
+            if live {
+                let cap = (want * 100.0).round() as u64;
+                if let Err(e) = prctl(log, name, "zone.cpu-cap", cap) {
+                    warn!(log, "could not apply ncpus live: {}", e);
+                    reboot.push(format!("capped-cpu ncpus={}", want));
+                }
+            }
+        }
+    }
+
+    /*
+     * Fair share scheduler shares:
+     */
+    if let Some(want) = lim.cpu_shares {
+        let shares = zonecfg_info(name, "cpu-shares")?;
+        let have: Option<u32> = prop(&shares, "cpu-shares")
+            .and_then(|v| v.parse().ok());
+        if have != Some(want) {
+            info!(log, "zone {} cpu-shares is {:?}, want {}", name, have,
+                want);
+            cmds.push(format!("set cpu-shares={}", want));
+
+            if live {
+                if let Err(e) = prctl(log, name, "zone.cpu-shares",
+                    u64::from(want))
+                {
+                    warn!(log, "could not apply cpu-shares live: {}", e);
+                    reboot.push(format!("cpu-shares={}", want));
+                }
+            }
+        }
+    }
+
+    if cmds.is_empty() {
+        info!(log, "zone {} resource controls ok", name);
+        return Ok((false, reboot));
+    }
+
+    zonecfg(log, name, &cmds)?;
+
+    Ok((true, reboot))
+}
+
+/*
+ * Run a sequence of zonecfg(1M) subcommands against a zone, committing the
+ * result.
+ */
+fn zonecfg(log: &Logger, name: &str, cmds: &[String]) -> Result<()> {
+    ensure::run(log, &["/usr/sbin/zonecfg", "-z", name,
+        &format!("{}; commit", cmds.join("; "))])
+}
+
+/*
+ * Create the configuration for a zone that does not yet exist.  Returns false
+ * if the zone was already configured, in which case the brand is checked.
+ */
+fn create(log: &Logger, name: &str, brand: &str, zonepath: &str,
+    extra: &[String])
+    -> Result<bool>
+{
+    if let Some(z) = zone(name)? {
+        if z.brand != brand {
+            bail!("zone {} has brand {}, not {}", name, z.brand, brand);
+        }
+        return Ok(false);
+    }
+
+    info!(log, "creating {} zone {} at {}", brand, name, zonepath);
+    let mut cmds = vec![
+        "create -b".to_string(),
+        format!("set brand={}", brand),
+        format!("set zonepath={}", zonepath),
+        "set ip-type=exclusive".to_string(),
+        "set autoboot=true".to_string(),
+    ];
+    cmds.extend_from_slice(extra);
+
+    zonecfg(log, name, &cmds)?;
+    Ok(true)
+}
+
+/*
+ * Ensure that a string "attr" resource with the given name and value exists in
+ * the configuration of a zone.  Returns true if the value was changed.
+ */
+fn attr(log: &Logger, name: &str, attr: &str, value: &str) -> Result<bool> {
+    let have = zonecfg_info(name, &format!("attr name={}", attr))?;
+
+    match prop(&have, "value") {
+        Some(v) if v == value => {
+            info!(log, "zone {} attr {} ok ({})", name, attr, value);
+            Ok(false)
+        }
+        Some(v) => {
+            info!(log, "zone {} attr {} is {}, want {}", name, attr, v, value);
+            zonecfg(log, name, &[
+                format!("select attr name={}", attr),
+                format!("set value=\"{}\"", value),
+                "end".to_string(),
+            ])?;
+            Ok(true)
+        }
+        None => {
+            info!(log, "zone {} attr {} missing, want {}", name, attr, value);
+            zonecfg(log, name, &[
+                "add attr".to_string(),
+                format!("set name={}", attr),
+                "set type=string".to_string(),
+                format!("set value=\"{}\"", value),
+                "end".to_string(),
+            ])?;
+            Ok(true)
+        }
+    }
+}
+
+/*
+ * Ensure that a "net" resource exists for the given physical link.
+ */
+fn net(log: &Logger, name: &str, physical: &str) -> Result<bool> {
+    let have = zonecfg_info(name, &format!("net physical={}", physical))?;
+    if prop(&have, "physical").is_some() {
+        return Ok(false);
+    }
+
+    info!(log, "zone {} adding net {}", name, physical);
+    zonecfg(log, name, &[
+        "add net".to_string(),
+        format!("set physical={}", physical),
+        "end".to_string(),
+    ])?;
+    Ok(true)
+}
+
+/*
+ * Ensure that a "device" resource exists with the given match pattern.
+ */
+fn device(log: &Logger, name: &str, path: &str) -> Result<bool> {
+    let have = zonecfg_info(name, &format!("device match={}", path))?;
+    if prop(&have, "match").is_some() {
+        return Ok(false);
+    }
+
+    info!(log, "zone {} adding device {}", name, path);
+    zonecfg(log, name, &[
+        "add device".to_string(),
+        format!("set match={}", path),
+        "end".to_string(),
+    ])?;
+    Ok(true)
+}
+
+/*
+ * Ensure that a ZFS volume of the given size exists.  An existing volume is
+ * never resized.
+ */
+fn zvol(log: &Logger, dataset: &str, size: &str) -> Result<bool> {
+    if ensure::query(log, &["/usr/sbin/zfs", "list", "-H", "-o", "name",
+        dataset]).is_ok()
+    {
+        info!(log, "volume {} exists already", dataset);
+        return Ok(false);
+    }
+
+    info!(log, "create volume {} ({})", dataset, size);
+    ensure::run(log, &["/usr/sbin/zfs", "create", "-p", "-V", size,
+        dataset])?;
+    Ok(true)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BhyveDisk {
+    /**
+     * The name of the ZFS volume backing this disk; e.g.,
+     * "rpool/vm/web0/disk0".
+     */
+    pub dataset: String,
+    /**
+     * The size of the volume to create if it does not yet exist.
+     */
+    pub size: String,
+}
+
+/**
+ * The definition of a guest virtual machine hosted in a bhyve-branded zone.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BhyveVm {
+    pub zonepath: String,
+    pub vcpus: u32,
+    pub memory: String,
+    /**
+     * Disks for the guest.  The first disk is used as the boot disk.
+     */
+    pub disks: Vec<BhyveDisk>,
+    /**
+     * The VNICs over which the guest is connected to the network.
+     */
+    pub vnics: Vec<String>,
+    pub vnc: bool,
+    /**
+     * The path, in the global zone, of a cloud-init user-data file to provide
+     * to the guest.
+     */
+    pub cloud_init: Option<String>,
+}
+
+/**
+ * Ensure that a bhyve-branded zone exists with the requested configuration,
+ * and that it is installed and running.  Returns whether any change was made,
+ * and whether the guest needs to be rebooted to pick up a configuration change
+ * made while it was running.
+ */
+pub fn bhyve(log: &Logger, name: &str, vm: &BhyveVm) -> Result<(bool, bool)> {
+    if vm.disks.is_empty() {
+        bail!("bhyve zone {} requires at least one disk", name);
+    }
+
+    let mut did_work = false;
+
+    for d in vm.disks.iter() {
+        if zvol(log, &d.dataset, &d.size)? {
+            did_work = true;
+        }
+    }
+
+    let was_running = zone(name)?
+        .map(|z| z.state == ZoneState::Running)
+        .unwrap_or(false);
+
+    if create(log, name, "bhyve", &vm.zonepath, &[])? {
+        did_work = true;
+    }
+    if zone(name)?.is_none() && dry_run() {
+        return Ok((did_work, false));
+    }
+
+    let mut changed = false;
+    for (i, d) in vm.disks.iter().enumerate() {
+        changed |= device(log, name,
+            &format!("/dev/zvol/rdsk/{}", d.dataset))?;
+        let a = if i == 0 {
+            "bootdisk".to_string()
+        } else {
+            format!("disk{}", i - 1)
+        };
+        changed |= attr(log, name, &a, &d.dataset)?;
+    }
+    for vnic in vm.vnics.iter() {
+        changed |= net(log, name, vnic)?;
+    }
+    changed |= attr(log, name, "vcpus", &vm.vcpus.to_string())?;
+    changed |= attr(log, name, "ram", &vm.memory)?;
+    changed |= attr(log, name, "vnc", if vm.vnc { "on" } else { "off" })?;
+    if let Some(ci) = &vm.cloud_init {
+        changed |= attr(log, name, "cloud-init", ci)?;
+    }
+
+    if running(log, name)? {
+        did_work = true;
+    }
+
+    Ok((did_work || changed, was_running && changed))
+}
+
+/**
+ * The definition of an lx-branded zone, for running Linux workloads.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LxZone {
+    pub zonepath: String,
+    /**
+     * The path, in the global zone, of an image tarball from which the root
+     * file system of the zone will be seeded at install time.
+     */
+    pub image: String,
+    /**
+     * The Linux kernel version to emulate; e.g., "4.4".
+     */
+    pub kernel_version: String,
+    pub vnics: Vec<String>,
+}
+
+/**
+ * Ensure that an lx-branded zone exists with the requested configuration, is
+ * installed from the nominated image, and is running.  Returns whether any
+ * change was made, and whether the zone needs to be rebooted to pick up a
+ * configuration change made while it was running.
+ */
+pub fn lx(log: &Logger, name: &str, lz: &LxZone) -> Result<(bool, bool)> {
+    let mut did_work = false;
+
+    let was_running = zone(name)?
+        .map(|z| z.state == ZoneState::Running)
+        .unwrap_or(false);
+
+    if create(log, name, "lx", &lz.zonepath, &[])? {
+        did_work = true;
+    }
+    if zone(name)?.is_none() && dry_run() {
+        return Ok((did_work, false));
+    }
+
+    let mut changed = attr(log, name, "kernel-version", &lz.kernel_version)?;
+    for vnic in lz.vnics.iter() {
+        changed |= net(log, name, vnic)?;
+    }
+
+    /*
+     * The lx brand requires an image from which to install, so we must
+     * perform the installation here rather than leaving it to running().
+     */
+    if zone(name)?.map(|z| z.state == ZoneState::Configured).unwrap_or(false) {
+        match ensure::check(&lz.image)? {
+            Some(fi) if fi.filetype == FileType::File => (),
+            Some(fi) => bail!("image {} is a {:?}, not a file", lz.image,
+                fi.filetype),
+            None => bail!("image {} does not exist", lz.image),
+        }
+
+        did_work = true;
+        info!(log, "installing lx zone {} from {}", name, lz.image);
+        ensure::run(log, &["/usr/sbin/zoneadm", "-z", name, "install",
+            "-s", &lz.image])?;
+    }
+
+    if running(log, name)? {
+        did_work = true;
+    }
+
+    Ok((did_work || changed, was_running && changed))
+}
+
+/**
+ * Ensure that a zone exists which was created as a clone of an existing
+ * installed source zone.  The configuration of the source zone is used as a
+ * template, with the zone path and any additional zonecfg(1M) subcommands in
+ * "overrides" applied on top.  The zone is installed by cloning the source
+ * zone (which, for zones on ZFS, uses a snapshot and clone of the source zone
+ * path) rather than a fresh install.
+ */
+pub fn cloned(log: &Logger, source: &str, name: &str, zonepath: &str,
+    overrides: &[&str])
+    -> Result<bool>
+{
+    let mut did_work = false;
+
+    let z = if let Some(z) = zone(name)? {
+        z
+    } else {
+        let src = if let Some(src) = zone(source)? {
+            src
+        } else {
+            bail!("source zone {} is not configured", source);
+        };
+
+        if dry_run_skip(log, Change::new(Action::Create,
+            format!("zone {}", name))
+            .detail(format!("clone of {}", source)))
+        {
+            return Ok(true);
+        }
+
+        info!(log, "creating zone {} from template {} ({})", name, source,
+            src.brand);
+        let mut cmds = vec![
+            format!("create -t {}", source),
+            format!("set zonepath={}", zonepath),
+        ];
+        cmds.extend(overrides.iter().map(|o| o.to_string()));
+        zonecfg(log, name, &cmds)?;
+        did_work = true;
+
+        match zone(name)? {
+            Some(z) => z,
+            None => bail!("zone {} is not configured after creating it",
+                name),
+        }
+    };
+
+    if z.state != ZoneState::Configured {
+        info!(log, "zone {} is {:?}, not cloning", name, z.state);
+        return Ok(did_work);
+    }
+
+    match zone(source)? {
+        Some(src) if src.state == ZoneState::Installed => (),
+        Some(src) => bail!("source zone {} must be installed and halted to \
+            clone, but is {:?}", source, src.state),
+        None => bail!("source zone {} is not configured", source),
+    }
+
+    info!(log, "cloning zone {} from {}", name, source);
+    ensure::run(log, &["/usr/sbin/zoneadm", "-z", name, "clone", source])?;
+
+    Ok(true)
+}
+
+/**
+ * Locate the root file system of a zone, as seen from the global zone.
+ */
+pub fn root(name: &str) -> Result<PathBuf> {
+    match zone(name)? {
+        Some(z) if z.state == ZoneState::Configured ||
+            z.state == ZoneState::Incomplete =>
+        {
+            bail!("zone {} is {:?}, and has no root", name, z.state)
+        }
+        Some(z) => Ok(PathBuf::from(z.path).join("root")),
+        None => bail!("zone {} is not configured", name),
+    }
+}
+
+/*
+ * Look up an entry in a passwd(4) or group(4) style file within the zone root,
+ * returning the numeric ID in the third field.
+ */
+fn zone_id(root: &Path, file: &str, name: &str) -> Result<u32> {
+    let p = resolve(root, Path::new(file))?;
+    let lines = std::fs::read_to_string(&p)?;
+
+    for l in lines.lines() {
+        let t: Vec<&str> = l.split(':').collect();
+        if t.len() >= 3 && t[0] == name {
+            return Ok(t[2].parse()?);
+        }
+    }
+
+    bail!("{} not found in {}", name, p.display());
+}
+
+/*
+ * Translate user and group names to numeric IDs using the user database of
+ * the zone, rather than that of the global zone.
+ */
+fn ownership(root: &Path, owner: &str, group: &str) -> Result<(u32, u32)> {
+    Ok((zone_id(root, "/etc/passwd", owner)?,
+        zone_id(root, "/etc/group", group)?))
+}
+
+pub fn directory(log: &Logger, name: &str, dir: &Path, owner: &str,
+    group: &str, mode: u32)
+    -> Result<bool>
+{
+    let root = root(name)?;
+    let (uid, gid) = ownership(&root, owner, group)?;
+    let dir = resolve(&root, dir)?;
+
+    ensure::directory_as(log, &dir, &Ownership::Ids(uid, gid), mode)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn file(log: &Logger, name: &str, src: &Path, dst: &Path, owner: &str,
+    group: &str, mode: u32, create: Create)
+    -> Result<bool>
+{
+    let root = root(name)?;
+    let (uid, gid) = ownership(&root, owner, group)?;
+    let dst = resolve(&root, dst)?;
+
+    ensure::file_as(log, src, &dst, &Ownership::Ids(uid, gid), mode, create)
+}
+
+/**
+ * Facts about a non-global zone, as seen from the global zone.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneFacts {
+    pub name: String,
+    pub brand: String,
+    pub state: ZoneState,
+    pub path: String,
+    /**
+     * IP addresses, without prefix length.  For running zones with SMF these
+     * are the addresses currently configured within the zone; otherwise, we
+     * fall back to any "allowed-address" properties in the configuration.
+     */
+    pub addresses: Vec<String>,
+    pub datasets: Vec<String>,
+}
+
+fn running_addresses(log: &Logger, name: &str) -> Result<Vec<String>> {
+    let val = ensure::query_output(log,
+        &["/usr/sbin/ipadm", "show-addr", "-p", "-o", "addr"], &Exec {
+            zone: Some(name.to_string()),
+            ..Default::default()
+        })?;
+
+    Ok(val.lines()
+        .map(|l| l.split('/').next().unwrap().replace("\\:", ":"))
+        .filter(|a| !a.is_empty() && a != "127.0.0.1" && a != "::1")
+        .collect())
+}
+
+pub fn facts(log: &Logger) -> Result<Vec<ZoneFacts>> {
+    let mut out = Vec::new();
+
+    for z in zones()?.into_iter().filter(|z| z.name != "global") {
+        let datasets = zonecfg_info(&z.name, "dataset")?.into_iter()
+            .filter(|(k, _)| k == "name")
+            .map(|(_, v)| v)
+            .collect();
+
+        let mut addresses = Vec::new();
+        if z.state == ZoneState::Running && has_smf(&z.brand) {
+            match running_addresses(log, &z.name) {
+                Ok(a) => addresses = a,
+                Err(e) => warn!(log, "zone {} addresses: {}", z.name, e),
+            }
+        }
+        if addresses.is_empty() {
+            addresses = zonecfg_info(&z.name, "net")?.into_iter()
+                .filter(|(k, _)| k == "allowed-address")
+                .map(|(_, v)| v.split('/').next().unwrap().to_string())
+                .collect();
+        }
+
+        out.push(ZoneFacts {
+            name: z.name,
+            brand: z.brand,
+            state: z.state,
+            path: z.path,
+            addresses,
+            datasets,
+        });
+    }
+
+    Ok(out)
+}
+
+/**
+ * Ensure that a dataset is delegated to a zone with a "dataset" resource.
+ * Returns whether the configuration was changed, and whether the zone must be
+ * rebooted before the delegation takes effect.
+ */
+pub fn delegate(log: &Logger, name: &str, dataset: &str)
+    -> Result<(bool, bool)>
+{
+    let z = if let Some(z) = zone(name)? {
+        z
+    } else if dry_run_skip(log, Change::new(Action::Modify,
+        format!("zone {}", name))
+        .detail(format!("delegate dataset {}", dataset)))
+    {
+        return Ok((true, false));
+    } else {
+        bail!("zone {} is not configured", name);
+    };
+
+    let have = zonecfg_info(name, &format!("dataset name={}", dataset))?;
+    if prop(&have, "name").is_some() {
+        info!(log, "dataset {} already delegated to zone {}", dataset, name);
+        return Ok((false, false));
+    }
+
+    info!(log, "delegating dataset {} to zone {}", dataset, name);
+    zonecfg(log, name, &[
+        "add dataset".to_string(),
+        format!("set name={}", dataset),
+        "end".to_string(),
+    ])?;
+
+    /*
+     * A delegated dataset is only made visible within the zone when the zone
+     * boots.
+     */
+    let reboot = z.state == ZoneState::Running || z.state == ZoneState::Ready;
+
+    Ok((true, reboot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size(" 4g ").unwrap(), 4 << 30);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_size("2T").unwrap(), 2 << 40);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("12X").is_err());
+    }
+}